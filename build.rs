@@ -0,0 +1,17 @@
+use std::process::Command;
+
+// tell --version which commit comprs was built from. outside a git checkout,
+// or without git, COMPRS_GIT_HASH is left unset and only the version shows
+fn main() {
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    let output = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output();
+    if let Ok(output) = output {
+        let hash = String::from_utf8_lossy(&output.stdout);
+        if output.status.success() && !hash.trim().is_empty() {
+            println!("cargo:rustc-env=COMPRS_GIT_HASH={}", hash.trim());
+        }
+    }
+}
@@ -0,0 +1,638 @@
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+};
+
+use crate::{log::warning, progress::Progress};
+
+use image::{
+    codecs::gif::{GifEncoder, Repeat},
+    Delay, ExtendedColorType, Frame, RgbaImage,
+};
+
+/// order in which captured frames are played back
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Forward,
+    Reverse,
+    Boomerang,
+}
+
+impl Direction {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "forward" => Ok(Self::Forward),
+            "reverse" => Ok(Self::Reverse),
+            "boomerang" => Ok(Self::Boomerang),
+            _ => Err(format!(
+                "invalid gif direction `{s}`, expected forward|reverse|boomerang"
+            )),
+        }
+    }
+
+    /// number of frames played back for `captured` captured frames
+    pub fn frame_count(self, captured: usize) -> usize {
+        match self {
+            Self::Boomerang if captured > 2 => 2 * captured - 2,
+            _ => captured,
+        }
+    }
+
+    /// reorder frames captured in refinement order, boomerang plays forward
+    /// then backward without repeating the first and last frames
+    pub fn arrange<T: Clone>(self, mut frames: Vec<T>) -> Vec<T> {
+        match self {
+            Self::Forward => frames,
+            Self::Reverse => {
+                frames.reverse();
+                frames
+            }
+            Self::Boomerang => {
+                let n = frames.len();
+                if n > 2 {
+                    let back: Vec<T> = frames[1..n - 1].iter().rev().cloned().collect();
+                    frames.extend(back);
+                }
+                frames
+            }
+        }
+    }
+}
+
+/// how capture points are distributed over the iterations
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Spacing {
+    Linear,
+    Exp,
+    Log,
+}
+
+impl Spacing {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "linear" => Ok(Self::Linear),
+            "exp" => Ok(Self::Exp),
+            "log" => Ok(Self::Log),
+            _ => Err(format!(
+                "invalid gif spacing `{s}`, expected linear|exp|log"
+            )),
+        }
+    }
+}
+
+/// iterator over the (strictly increasing) iterations at which a frame is captured
+///
+/// every mode captures about `total / delta` frames:
+/// - linear captures every `delta` iterations
+/// - exp captures at geometrically growing iterations, dense at the start
+/// - log captures at logarithmically growing iterations, dense at the end
+///
+/// exp and log always end on `total`
+///
+/// alternatively the capture points can be listed explicitly with `Schedule::list`
+#[derive(Clone)]
+pub struct Schedule {
+    spacing: Spacing,
+    delta: u32,
+    total: u32,
+    frames: u32,
+    k: u32,
+    last: u32,
+    list: Option<std::vec::IntoIter<u32>>,
+}
+
+impl Schedule {
+    pub fn new(spacing: Spacing, delta: u32, total: u32) -> Self {
+        let frames = total.checked_div(delta).map_or(0, |n| n.max(1));
+        Self {
+            spacing,
+            delta,
+            total,
+            frames,
+            k: 0,
+            last: 0,
+            list: None,
+        }
+    }
+
+    /// capture at the given iterations, out of order and duplicate entries are
+    /// sorted and removed and entries past `total` are dropped with a warning
+    pub fn list(mut points: Vec<u32>, total: u32) -> Self {
+        if !points.is_sorted_by(|a, b| a < b) {
+            warning!("frame list is not strictly ascending, sorting and removing duplicates");
+            points.sort_unstable();
+            points.dedup();
+        }
+        if points.iter().any(|&p| p > total) {
+            warning!("frame list goes past {total} iterations, extra frames are dropped");
+            points.retain(|&p| p <= total);
+        }
+        // iteration 0 is always captured as the first frame
+        points.retain(|&p| p > 0);
+
+        let mut schedule = Self::new(Spacing::Linear, 0, total);
+        schedule.list = Some(points.into_iter());
+        schedule
+    }
+
+    /// the k-th capture point, k in 1..=frames
+    fn point(&self, k: u32) -> u32 {
+        if self.spacing == Spacing::Linear {
+            return k * self.delta;
+        }
+        if k == self.frames {
+            return self.total;
+        }
+        let total = self.total as f64;
+        let p = match self.spacing {
+            Spacing::Exp => total.powf(k as f64 / self.frames as f64),
+            _ => total * (1.0 + k as f64).ln() / (1.0 + self.frames as f64).ln(),
+        };
+        p.ceil() as u32
+    }
+}
+
+impl Iterator for Schedule {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        if let Some(list) = &mut self.list {
+            return list.next();
+        }
+        if self.total == 0 {
+            return None;
+        }
+        while self.k < self.frames {
+            self.k += 1;
+            let p = self.point(self.k);
+            // rounding can map several k onto the same iteration
+            if p > self.last && p <= self.total {
+                self.last = p;
+                return Some(p);
+            }
+        }
+        None
+    }
+}
+
+/// parse a comma separated list of iterations, e.g. `10,100,1000`
+pub fn parse_frame_list(s: &str) -> Result<Vec<u32>, String> {
+    s.split(',')
+        .map(|p| {
+            p.trim()
+                .parse()
+                .map_err(|_| format!("invalid frame list entry `{p}`"))
+        })
+        .collect()
+}
+
+/// gif repeat setting for playing the animation `loops` times, 0 loops forever
+///
+/// the loop extension counts repeats after the first play, so playing once
+/// means leaving the extension out entirely
+pub fn loops_to_repeat(loops: u32) -> Result<Option<Repeat>, String> {
+    match loops {
+        0 => Ok(Some(Repeat::Infinite)),
+        1 => Ok(None),
+        n => match u16::try_from(n - 1) {
+            Ok(r) => Ok(Some(Repeat::Finite(r))),
+            Err(_) => Err(format!(
+                "gif loop count must be at most {}",
+                u16::MAX as u32 + 1
+            )),
+        },
+    }
+}
+
+/// container that animation frames are written to
+///
+/// frames are borrowed so the caller can reuse their buffers, an encoder only
+/// copies a frame when the library it writes with needs to own it
+pub trait FrameEncoder {
+    fn encode(&mut self, buf: &RgbaImage, delay: Delay) -> Result<(), String>;
+
+    fn finish(self: Box<Self>) -> Result<(), String>;
+}
+
+/// write the color channels of `buf` to `rgb`, replacing what it held
+fn rgba_to_rgb(buf: &RgbaImage, rgb: &mut Vec<u8>) {
+    rgb.clear();
+    rgb.extend(buf.pixels().flat_map(|p| [p[0], p[1], p[2]]));
+}
+
+impl<W: Write> FrameEncoder for GifEncoder<W> {
+    fn encode(&mut self, buf: &RgbaImage, delay: Delay) -> Result<(), String> {
+        self.encode_frame(Frame::from_parts(buf.clone(), 0, 0, delay))
+            .map_err(|_| "error in encoding gif".into())
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// animated png, keeps full 24-bit color unlike gif
+///
+/// apng declares its frame count up front. if fewer than `frames` frames are
+/// encoded, as when ctrl-c stops refining, the last one is repeated
+pub struct ApngEncoder<W: Write> {
+    writer: png::Writer<W>,
+    /// the last frame without its alpha channel, kept to reuse its allocation
+    rgb: Vec<u8>,
+    /// frames declared but not encoded yet
+    missing: u32,
+}
+
+impl<W: Write> ApngEncoder<W> {
+    pub fn new(w: W, width: u32, height: u32, frames: u32, loops: u32) -> Result<Self, String> {
+        let mut encoder = png::Encoder::new(w, width, height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        if encoder.set_animated(frames, loops).is_err() {
+            return Err("apng needs at least one frame".into());
+        }
+        match encoder.write_header() {
+            Ok(writer) => Ok(Self {
+                writer,
+                rgb: Vec::new(),
+                missing: frames,
+            }),
+            Err(_) => Err("error in encoding apng".into()),
+        }
+    }
+}
+
+impl<W: Write> FrameEncoder for ApngEncoder<W> {
+    fn encode(&mut self, buf: &RgbaImage, delay: Delay) -> Result<(), String> {
+        let (numer, denom) = delay.numer_denom_ms();
+        let ms = (numer / denom.max(1)).min(u16::MAX as u32) as u16;
+        // the alpha channel only matters to the gif quantizer
+        rgba_to_rgb(buf, &mut self.rgb);
+        self.missing = self.missing.saturating_sub(1);
+        self.writer
+            .set_frame_delay(ms, 1000)
+            .and_then(|_| self.writer.write_image_data(&self.rgb))
+            .map_err(|_| "error in encoding apng".into())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), String> {
+        // copies that take no time leave the animation looking the same
+        for _ in 0..self.missing {
+            self.writer
+                .set_frame_delay(0, 1000)
+                .and_then(|_| self.writer.write_image_data(&self.rgb))
+                .map_err(|_| "error in encoding apng")?;
+        }
+        self.writer
+            .finish()
+            .map_err(|_| "error in encoding apng".into())
+    }
+}
+
+/// numbered full color png files in a directory, e.g. for feeding to a video encoder
+pub struct PngDirEncoder {
+    dir: PathBuf,
+    index: usize,
+    rgb: Vec<u8>,
+}
+
+/// frames can go in `dir` if it is missing or empty, or if `force` is set
+pub fn check_frames_dir(dir: &str, force: bool) -> Result<(), String> {
+    if !Path::new(dir).exists() {
+        return Ok(());
+    }
+    let Ok(mut entries) = fs::read_dir(dir) else {
+        return Err(format!("unable to read frames directory `{dir}`"));
+    };
+    if !force && entries.next().is_some() {
+        return Err(format!(
+            "frames directory `{dir}` is not empty, use -force to overwrite"
+        ));
+    }
+    Ok(())
+}
+
+impl PngDirEncoder {
+    /// create `dir` if needed, a directory that already has files in it is
+    /// only written to when `force` is set
+    pub fn new(dir: &str, force: bool) -> Result<Self, String> {
+        if fs::create_dir_all(dir).is_err() {
+            return Err(format!("unable to create frames directory `{dir}`"));
+        }
+        check_frames_dir(dir, force)?;
+        Ok(Self {
+            dir: PathBuf::from(dir),
+            index: 0,
+            rgb: Vec::new(),
+        })
+    }
+}
+
+impl FrameEncoder for PngDirEncoder {
+    fn encode(&mut self, buf: &RgbaImage, _delay: Delay) -> Result<(), String> {
+        self.index += 1;
+        let path = self.dir.join(format!("frame_{:06}.png", self.index));
+        rgba_to_rgb(buf, &mut self.rgb);
+        let (w, h) = buf.dimensions();
+        image::save_buffer(path, &self.rgb, w, h, ExtendedColorType::Rgb8)
+            .map_err(|err| err.to_string())
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// video written by piping raw frames into an ffmpeg process
+///
+/// ffmpeg plays frames at a constant rate, so longer delays (i.e. `hold_last`)
+/// are approximated by repeating the frame
+pub struct VideoEncoder {
+    child: Child,
+    fps: u32,
+    rgb: Vec<u8>,
+}
+
+impl VideoEncoder {
+    pub fn new(
+        ffmpeg: &str,
+        output: &str,
+        width: u32,
+        height: u32,
+        fps: u32,
+    ) -> Result<Self, String> {
+        let spawned = Command::new(ffmpeg)
+            .args(["-loglevel", "error", "-y"])
+            .args(["-f", "rawvideo", "-pix_fmt", "rgb24"])
+            .args(["-s", &format!("{width}x{height}")])
+            .args(["-r", &fps.to_string()])
+            .args(["-i", "-", output])
+            .stdin(Stdio::piped())
+            .spawn();
+        match spawned {
+            Ok(child) => Ok(Self {
+                child,
+                fps,
+                rgb: Vec::new(),
+            }),
+            Err(_) => Err(format!("unable to run `{ffmpeg}`, is ffmpeg installed?")),
+        }
+    }
+}
+
+impl FrameEncoder for VideoEncoder {
+    fn encode(&mut self, buf: &RgbaImage, delay: Delay) -> Result<(), String> {
+        let (numer, denom) = delay.numer_denom_ms();
+        let repeats = (numer as u64 * self.fps as u64 / (denom.max(1) as u64 * 1000)).max(1);
+        rgba_to_rgb(buf, &mut self.rgb);
+        let Some(stdin) = self.child.stdin.as_mut() else {
+            return Err("ffmpeg input is closed".into());
+        };
+        for _ in 0..repeats {
+            if stdin.write_all(&self.rgb).is_err() {
+                return Err("error writing frame to ffmpeg".into());
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), String> {
+        // closing stdin tells ffmpeg there are no more frames
+        drop(self.child.stdin.take());
+        match self.child.wait() {
+            Ok(status) if status.success() => Ok(()),
+            Ok(status) => Err(format!("ffmpeg exited with {status}")),
+            Err(_) => Err("unable to wait for ffmpeg".into()),
+        }
+    }
+}
+
+/// writes captured frames to an animation
+///
+/// forward animations are streamed to the encoder as they are captured, only
+/// holding back the latest frame so it can be given the `hold_last` delay.
+/// other directions need every frame before the first one can be written.
+///
+/// once a forward frame is encoded its buffer is kept for `take_buffer`, so
+/// the caller can render the next frame into it instead of allocating
+pub struct Animation {
+    encoder: Box<dyn FrameEncoder>,
+    direction: Direction,
+    delay: Delay,
+    hold_last: Delay,
+    tween: u32,
+    pending: Option<RgbaImage>,
+    buffered: Vec<RgbaImage>,
+    first: Option<RgbaImage>,
+    last: Option<RgbaImage>,
+    captured: usize,
+    /// a frame the encoder is done with
+    spare: Option<RgbaImage>,
+    /// tweens are blended into this one after another
+    tween_buf: Option<RgbaImage>,
+}
+
+impl Animation {
+    pub fn new(
+        encoder: Box<dyn FrameEncoder>,
+        direction: Direction,
+        delay: Delay,
+        hold_last: Delay,
+    ) -> Self {
+        Self {
+            encoder,
+            direction,
+            delay,
+            hold_last,
+            tween: 0,
+            pending: None,
+            buffered: Vec::new(),
+            first: None,
+            last: None,
+            captured: 0,
+            spare: None,
+            tween_buf: None,
+        }
+    }
+
+    /// frames played before and after the captured frames, regardless of direction
+    pub fn with_bookends(mut self, first: Option<RgbaImage>, last: Option<RgbaImage>) -> Self {
+        self.first = first;
+        self.last = last;
+        self
+    }
+
+    /// blend `tween` extra frames between each pair of consecutive frames
+    pub fn with_tween(mut self, tween: u32) -> Self {
+        self.tween = tween;
+        self
+    }
+
+    /// number of frames pushed so far
+    pub fn captured(&self) -> usize {
+        self.captured
+    }
+
+    /// a buffer of an already encoded frame to draw the next frame into, if
+    /// there is one. its contents are stale
+    pub fn take_buffer(&mut self) -> Option<RgbaImage> {
+        self.spare.take()
+    }
+
+    pub fn push(&mut self, buf: RgbaImage) -> Result<(), String> {
+        self.captured += 1;
+        if self.direction != Direction::Forward {
+            self.buffered.push(buf);
+            return Ok(());
+        }
+        if let Some(first) = self.first.take() {
+            self.emit(first)?;
+        }
+        self.emit(buf)
+    }
+
+    /// queue the next frame in playback order, the previous frame (and any
+    /// tweens leading up to this one) can be encoded now that it isn't the last
+    fn emit(&mut self, buf: RgbaImage) -> Result<(), String> {
+        if let Some(prev) = self.pending.take() {
+            self.encoder.encode(&prev, self.delay)?;
+            for k in 1..=self.tween {
+                let blended = self.tween_buf.get_or_insert_with(|| prev.clone());
+                blend_into(blended, &prev, &buf, k, self.tween + 1);
+                self.encoder.encode(blended, self.delay)?;
+            }
+            self.spare = Some(prev);
+        }
+        self.pending = Some(buf);
+        Ok(())
+    }
+
+    pub fn finish(mut self, progress: &mut Progress) -> Result<(), String> {
+        let mut frames: Vec<RgbaImage> = self.first.take().into_iter().collect();
+        frames.extend(self.direction.arrange(std::mem::take(&mut self.buffered)));
+        frames.extend(self.last.take());
+
+        let n = frames.len();
+        for (i, buf) in frames.into_iter().enumerate() {
+            self.emit(buf)?;
+            progress.encode(i + 1, n);
+        }
+        // the final frame keeps its own delay instead of being duplicated
+        if let Some(buf) = self.pending.take() {
+            self.encoder.encode(&buf, self.hold_last)?;
+        }
+        self.encoder.finish()
+    }
+}
+
+/// number of frames played back for `frames` frames with `tween` blended frames between each pair
+pub fn tweened_count(frames: usize, tween: u32) -> usize {
+    frames + frames.saturating_sub(1) * tween as usize
+}
+
+/// write the k-th of `steps - 1` frames blending `a` into `b` to `out`, rounded
+/// to the nearest value
+fn blend_into(out: &mut RgbaImage, a: &RgbaImage, b: &RgbaImage, k: u32, steps: u32) {
+    for (out, (x, y)) in out.iter_mut().zip(a.iter().zip(b.iter())) {
+        let mixed = *x as u32 * (steps - k) + *y as u32 * k;
+        *out = ((mixed + steps / 2) / steps) as u8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn points(schedule: Schedule) -> Vec<u32> {
+        schedule.collect()
+    }
+
+    #[test]
+    fn linear_captures_every_delta() {
+        assert_eq!(
+            points(Schedule::new(Spacing::Linear, 5, 20)),
+            [5, 10, 15, 20]
+        );
+        assert_eq!(
+            points(Schedule::new(Spacing::Linear, 5, 22)),
+            [5, 10, 15, 20]
+        );
+        assert_eq!(points(Schedule::new(Spacing::Linear, 1, 3)), [1, 2, 3]);
+        // a delta past the total never captures
+        assert_eq!(points(Schedule::new(Spacing::Linear, 30, 20)), []);
+    }
+
+    #[test]
+    fn exp_doubles_up_to_the_total() {
+        assert_eq!(
+            points(Schedule::new(Spacing::Exp, 100, 1000)),
+            [2, 4, 8, 16, 32, 64, 126, 252, 502, 1000]
+        );
+    }
+
+    #[test]
+    fn log_gathers_near_the_total() {
+        assert_eq!(
+            points(Schedule::new(Spacing::Log, 10, 100)),
+            [29, 46, 58, 68, 75, 82, 87, 92, 97, 100]
+        );
+    }
+
+    #[test]
+    fn schedules_are_strictly_increasing_and_end_in_range() {
+        for spacing in [Spacing::Linear, Spacing::Exp, Spacing::Log] {
+            for (delta, total) in [(1, 20), (3, 7), (7, 3), (1, 1), (250, 100_000)] {
+                let p = points(Schedule::new(spacing, delta, total));
+                let case = format!("{spacing:?} every {delta} of {total}");
+                assert!(p.windows(2).all(|w| w[0] < w[1]), "{case}: {p:?}");
+                assert!(p.iter().all(|&p| p > 0 && p <= total), "{case}: {p:?}");
+                // roughly total / delta frames, fewer when rounding merges them
+                assert!(p.len() as u32 <= (total / delta).max(1), "{case}: {p:?}");
+                if spacing != Spacing::Linear {
+                    assert_eq!(p.last(), Some(&total), "{case}");
+                }
+            }
+            assert_eq!(points(Schedule::new(spacing, 5, 0)), [], "{spacing:?}");
+        }
+    }
+
+    #[test]
+    fn exp_gathers_at_the_start_and_log_at_the_end() {
+        let first_gap = |p: &[u32]| p[1] - p[0];
+        let last_gap = |p: &[u32]| p[p.len() - 1] - p[p.len() - 2];
+        let exp = points(Schedule::new(Spacing::Exp, 1000, 100_000));
+        assert!(first_gap(&exp) < last_gap(&exp));
+        assert!(exp[exp.len() / 2] < 10_000, "{exp:?}");
+        let log = points(Schedule::new(Spacing::Log, 1000, 100_000));
+        assert!(first_gap(&log) > last_gap(&log));
+        assert!(log[log.len() / 2] > 50_000, "{log:?}");
+    }
+
+    #[test]
+    fn frame_lists_are_sorted_deduplicated_and_clamped() {
+        let list = |p: &[u32], total| points(Schedule::list(p.to_vec(), total));
+        assert_eq!(list(&[10, 100, 1000], 5000), [10, 100, 1000]);
+        assert_eq!(list(&[1000, 10, 100], 5000), [10, 100, 1000]);
+        assert_eq!(list(&[10, 100, 10, 100, 5], 5000), [5, 10, 100]);
+        assert_eq!(list(&[10, 100, 1000, 5000], 500), [10, 100]);
+        assert_eq!(list(&[5000, 10, 10, 6000], 5000), [10, 5000]);
+        // iteration 0 is the first frame already
+        assert_eq!(list(&[0, 3, 0], 10), [3]);
+        assert_eq!(list(&[20, 30], 10), []);
+    }
+
+    #[test]
+    fn frame_lists_parse_with_spaces() {
+        assert_eq!(parse_frame_list("10,100, 1000"), Ok(vec![10, 100, 1000]));
+        assert_eq!(parse_frame_list("7"), Ok(vec![7]));
+        for s in ["10,,20", "10,x", "-5", ""] {
+            assert!(parse_frame_list(s).is_err(), "{s}");
+        }
+        assert_eq!(
+            parse_frame_list("1,two").unwrap_err(),
+            "invalid frame list entry `two`"
+        );
+    }
+}
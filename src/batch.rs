@@ -0,0 +1,250 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Condvar, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use ::image::ImageFormat;
+
+use crate::{
+    error::Error,
+    image::bytes_per_pixel,
+    interrupt,
+    log::{error, info},
+    porcelain::{self, Record},
+    tree::{bytes_per_split, Split},
+};
+
+/// what compressing one input gave, a note for the user or an error
+pub type JobResult = Result<Option<String>, Error>;
+
+/// the files to compress, from what was given on the command line
+pub struct Inputs {
+    pub paths: Vec<String>,
+    /// where each path is under the directory it was found in, empty for
+    /// files that were given directly
+    pub subdirs: Vec<PathBuf>,
+    /// hidden files and files that aren't images, found in directories
+    pub skipped: usize,
+}
+
+impl Inputs {
+    /// files in `given` as they are, and directories replaced by the images
+    /// in them in name order. `recursive` also goes into subdirectories,
+    /// except hidden ones
+    pub fn expand(given: Vec<String>, recursive: bool) -> Result<Self, Error> {
+        let mut ret = Self {
+            paths: Vec::new(),
+            subdirs: Vec::new(),
+            skipped: 0,
+        };
+        for path in given {
+            if Path::new(&path).is_dir() {
+                ret.add_dir(Path::new(&path), PathBuf::new(), recursive)?;
+            } else {
+                ret.paths.push(path);
+                ret.subdirs.push(PathBuf::new());
+            }
+        }
+        Ok(ret)
+    }
+
+    fn add_dir(&mut self, root: &Path, subdir: PathBuf, recursive: bool) -> Result<(), Error> {
+        let dir = root.join(&subdir);
+        let Ok(entries) = fs::read_dir(&dir) else {
+            return Err(Error::Input(format!(
+                "unable to read directory `{}`",
+                dir.display()
+            )));
+        };
+        let mut entries: Vec<PathBuf> = entries.filter_map(|e| Some(e.ok()?.path())).collect();
+        entries.sort();
+        for path in entries {
+            let Some(name) = path.file_name() else {
+                continue;
+            };
+            let hidden = name.to_string_lossy().starts_with('.');
+            if path.is_dir() {
+                if recursive && !hidden {
+                    self.add_dir(root, subdir.join(name), recursive)?;
+                }
+                continue;
+            }
+            let image = ImageFormat::from_path(&path).is_ok_and(|f| f.reading_enabled());
+            match path.to_str() {
+                Some(p) if image && !hidden => {
+                    self.paths.push(p.into());
+                    self.subdirs.push(subdir.clone());
+                }
+                _ => self.skipped += 1,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// where the output named `default` goes in `out_dir`, under `subdir`. the
+/// directory is created if it doesn't exist yet and `create` is set
+pub fn output_in(
+    out_dir: &Path,
+    subdir: &Path,
+    default: &str,
+    create: bool,
+) -> Result<String, String> {
+    let dir = out_dir.join(subdir);
+    if create && fs::create_dir_all(&dir).is_err() {
+        return Err(format!(
+            "unable to create output directory `{}`",
+            dir.display()
+        ));
+    }
+    let Some(name) = Path::new(default).file_name() else {
+        return Err("failed to get file name".into());
+    };
+    match dir.join(name).to_str() {
+        Some(path) => Ok(path.into()),
+        None => Err("failed to convert file path to string".into()),
+    }
+}
+
+/// number of jobs when -jobs isn't given
+pub fn default_jobs() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// rough peak memory in bytes of compressing the image at `path`: the sum and
+/// square sum arrays, the decoded image, the canvas and frame being encoded
+/// when animating, and the node arena and queue
+pub fn footprint(path: &str, iterations: u32, split: Split, animate: bool) -> u64 {
+    let Ok((w, h)) = ::image::image_dimensions(path) else {
+        // it will fail to load anyway
+        return 0;
+    };
+    footprint_of(w, h, iterations, split, animate)
+}
+
+/// the same as `footprint` for an image of `w` x `h`
+pub fn footprint_of(w: u32, h: u32, iterations: u32, split: Split, animate: bool) -> u64 {
+    let pixels = (w as u64 + 1) * (h as u64 + 1);
+    let per_pixel =
+        bytes_per_pixel(w as u64 * h as u64) as u64 + 3 + if animate { 2 * 4 } else { 0 };
+    pixels * per_pixel + iterations as u64 * bytes_per_split(split) as u64
+}
+
+/// memory that jobs may use at once, half of what the system has available
+fn memory_budget() -> Option<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|l| l.starts_with("MemAvailable:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024 / 2)
+}
+
+/// run `work` on the index of every input with up to `jobs` threads, printing
+/// one line for each input as it finishes with the note `work` returns, if
+/// any, or its error. a job only starts when
+/// its `footprint` fits in the memory budget next to the running ones, but a
+/// job always runs when nothing else is, however large it is
+pub fn run(
+    inputs: &[String],
+    jobs: usize,
+    footprint: impl Fn(&str) -> u64 + Sync,
+    work: impl Fn(usize) -> JobResult + Sync,
+) -> Vec<JobResult> {
+    let budget = memory_budget().unwrap_or(u64::MAX);
+    let next = AtomicUsize::new(0);
+    let in_use = Mutex::new(0u64);
+    let freed = Condvar::new();
+    let results: Mutex<Vec<Option<JobResult>>> = Mutex::new(inputs.iter().map(|_| None).collect());
+
+    thread::scope(|scope| {
+        for _ in 0..jobs.min(inputs.len()) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(input) = inputs.get(i) else {
+                    break;
+                };
+                if interrupt::requested() {
+                    break;
+                }
+                let need = footprint(input);
+                {
+                    let mut used = in_use.lock().unwrap();
+                    while *used > 0 && used.saturating_add(need) > budget {
+                        used = freed.wait(used).unwrap();
+                    }
+                    *used += need;
+                }
+
+                let result = work(i);
+                match &result {
+                    Ok(Some(note)) => info!("{input}: {note}"),
+                    Ok(None) => info!("{input}: done"),
+                    Err(err) => error!("{input}: {err}"),
+                }
+                porcelain::result(input, result.as_ref().err());
+
+                *in_use.lock().unwrap() -= need;
+                freed.notify_all();
+                results.lock().unwrap()[i] = Some(result);
+            });
+        }
+    });
+
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .zip(inputs)
+        .map(|(r, input)| {
+            r.unwrap_or_else(|| {
+                let why = if interrupt::requested() {
+                    "not started, ctrl-c was pressed"
+                } else {
+                    "not processed"
+                };
+                let err = Error::Other(why.into());
+                porcelain::result(input, Some(&err));
+                Err(err)
+            })
+        })
+        .collect()
+}
+
+/// print how many inputs were compressed, skipped and failed in `elapsed`,
+/// and which failed, and return the exit code of the first that failed
+pub fn summarize(inputs: &Inputs, results: &[JobResult], elapsed: Duration) -> i32 {
+    let failed: Vec<(&String, &Error)> = inputs
+        .paths
+        .iter()
+        .zip(results)
+        .filter_map(|(input, r)| r.as_ref().err().map(|err| (input, err)))
+        .collect();
+    info!(
+        "{} processed, {} skipped, {} failed in {:.2}s",
+        results.len() - failed.len(),
+        inputs.skipped,
+        failed.len(),
+        elapsed.as_secs_f64()
+    );
+    if porcelain::enabled() {
+        Record::new("summary")
+            .field("processed", results.len() - failed.len())
+            .field("skipped", inputs.skipped)
+            .field("failed", failed.len())
+            .field("seconds", format!("{:.2}", elapsed.as_secs_f64()))
+            .emit();
+    }
+    if failed.is_empty() {
+        return 0;
+    }
+    error!("{} of {} inputs failed:", failed.len(), inputs.paths.len());
+    for (input, err) in &failed {
+        error!("  {input}: {err}");
+    }
+    failed[0].1.code()
+}
@@ -0,0 +1,284 @@
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    process,
+    time::UNIX_EPOCH,
+};
+
+use crate::{
+    image::ImageData,
+    log::{verbose, warning},
+};
+
+// -cache-dir keeps the prefix sums of every input in a directory, so
+// compressing an image again skips building them, and decoding it too when
+// nothing else needs its pixels. a cache file is named after a hash of the
+// canonical path of its input, integers are little endian:
+//
+//   MAGIC, VERSION (u16)
+//   length of the key (u16) and the key: the canonical path of the input,
+//   its size and when it was modified, a line each
+//   the prefix sums as `ImageData::write_cache` writes them, with their
+//   own crc32
+//
+// a file with another key or version is stale and one that doesn't read
+// back is corrupt. either way the sums are built again and the file
+// replaced, a cache file is never used for anything it doesn't match
+
+const MAGIC: [u8; 4] = *b"CPSM";
+const VERSION: u16 = 1;
+
+/// the canonical path of `input_file` and the key its cache file has to
+/// have, `None` if it can't be looked at
+fn key(input_file: &str) -> Option<(String, String)> {
+    let path = fs::canonicalize(input_file).ok()?;
+    let meta = fs::metadata(&path).ok()?;
+    let modified = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    let path = path.to_string_lossy().into_owned();
+    let key = format!(
+        "{path}\n{}\n{}.{:09}",
+        meta.len(),
+        modified.as_secs(),
+        modified.subsec_nanos()
+    );
+    Some((path, key))
+}
+
+/// the cache file in `dir` of the input at the canonical `path`
+fn cache_file(dir: &str, path: &str) -> PathBuf {
+    Path::new(dir).join(format!("{:08x}.sums", crc32fast::hash(path.as_bytes())))
+}
+
+/// the prefix sums of `input_file` kept in `dir`, if they are there and
+/// still match it
+pub fn load(dir: &str, input_file: &str) -> Option<ImageData> {
+    let (path, key) = key(input_file)?;
+    let file = cache_file(dir, &path);
+    let f = File::open(&file).ok()?;
+    let len = f.metadata().ok()?.len();
+    match read(BufReader::new(f), len, &key) {
+        Ok(Some(data)) => {
+            verbose!("{input_file}: prefix sums read from `{}`", file.display());
+            Some(data)
+        }
+        Ok(None) => {
+            verbose!("{input_file}: cached prefix sums are stale, building them again");
+            None
+        }
+        Err(err) => {
+            warning!(
+                "{input_file}: ignoring `{}`, {err}. building the prefix sums again",
+                file.display()
+            );
+            None
+        }
+    }
+}
+
+/// the image data in a cache file of `len` bytes, or `None` if it is stale
+fn read(mut r: impl Read, len: u64, key: &str) -> Result<Option<ImageData>, String> {
+    let mut header = [0; 8];
+    r.read_exact(&mut header)
+        .map_err(|_| "cache file is truncated")?;
+    if header[..4] != MAGIC {
+        return Err("not a comprs cache file".into());
+    }
+    if u16::from_le_bytes([header[4], header[5]]) != VERSION {
+        return Ok(None);
+    }
+    let key_len = u16::from_le_bytes([header[6], header[7]]) as usize;
+    let mut found = vec![0; key_len];
+    r.read_exact(&mut found)
+        .map_err(|_| "cache file is truncated")?;
+    if found != key.as_bytes() {
+        return Ok(None);
+    }
+    let Some(rest) = len.checked_sub(8 + key_len as u64) else {
+        return Err("cache file is truncated".into());
+    };
+    ImageData::read_cache(r, rest).map(Some)
+}
+
+/// keep the prefix sums of `input_file` in `dir`. failing to only costs the
+/// next run the time to build them, so it is a warning
+pub fn store(dir: &str, input_file: &str, data: &ImageData) {
+    let Some((path, key)) = key(input_file) else {
+        return;
+    };
+    let file = cache_file(dir, &path);
+    // written next to the cache file and renamed over it, so a run reading
+    // it at the same time never sees half of one
+    let tmp = file.with_extension(format!("{}.tmp", process::id()));
+    if let Err(err) = write(dir, &tmp, &key, data)
+        .and_then(|_| fs::rename(&tmp, &file).map_err(|err| err.to_string()))
+    {
+        let _ = fs::remove_file(&tmp);
+        warning!("unable to cache the prefix sums of `{input_file}` in `{dir}`: {err}");
+        return;
+    }
+    verbose!("{input_file}: prefix sums cached in `{}`", file.display());
+}
+
+fn write(dir: &str, tmp: &Path, key: &str, data: &ImageData) -> Result<(), String> {
+    let key_len = u16::try_from(key.len()).map_err(|_| "its path is too long")?;
+    fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+    let mut w = BufWriter::new(File::create(tmp).map_err(|err| err.to_string())?);
+    w.write_all(&MAGIC)
+        .and_then(|_| w.write_all(&VERSION.to_le_bytes()))
+        .and_then(|_| w.write_all(&key_len.to_le_bytes()))
+        .and_then(|_| w.write_all(key.as_bytes()))
+        .map_err(|err| err.to_string())?;
+    data.write_cache(&mut w)?;
+    w.flush().map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+    use crate::image::RegionSource;
+
+    /// a directory of its own in the temp directory, removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("comprs-cache-{}-{name}", process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        fn path(&self, name: &str) -> String {
+            self.0.join(name).to_str().unwrap().to_string()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// save a `width` x `height` png whose pixels depend on `seed` at
+    /// `path`, and give its prefix sums
+    fn input(path: &str, width: u32, height: u32, seed: u32) -> ImageData {
+        let colors = RgbImage::from_fn(width, height, |x, y| {
+            Rgb([
+                (x * 37 + y * 11 + seed) as u8,
+                (x * y) as u8,
+                (seed * 3) as u8,
+            ])
+        });
+        colors.save(path).unwrap();
+        ImageData::from_rgb8(&colors).unwrap()
+    }
+
+    /// every region of `a` and `b` sums the same
+    fn assert_same_sums(a: &ImageData, b: &ImageData) {
+        assert_eq!((a.height(), a.width()), (b.height(), b.width()));
+        for top in 0..a.height() {
+            for left in 0..a.width() {
+                let (tl, br) = ((top, left), (a.height() - 1, a.width() - 1));
+                assert_eq!(a.sums(tl, br), b.sums(tl, br), "{tl:?}");
+                assert_eq!(a.sums((0, 0), tl), b.sums((0, 0), tl), "{tl:?}");
+            }
+        }
+    }
+
+    /// a cache directory with the sums of one input stored in it
+    fn stored(dir: &TempDir) -> (String, String, PathBuf) {
+        let (cache, png) = (dir.path("cache"), dir.path("in.png"));
+        store(&cache, &png, &input(&png, 13, 9, 1));
+        let file = cache_file(&cache, &key(&png).unwrap().0);
+        assert!(file.is_file());
+        (cache, png, file)
+    }
+
+    #[test]
+    fn stored_sums_load_back() {
+        let dir = TempDir::new("round-trip");
+        let (cache, png) = (dir.path("cache"), dir.path("in.png"));
+        let data = input(&png, 13, 9, 1);
+        assert!(load(&cache, &png).is_none());
+        store(&cache, &png, &data);
+        assert_same_sums(&load(&cache, &png).unwrap(), &data);
+        // the same file by another path
+        let name = dir.0.file_name().unwrap().to_str().unwrap();
+        let other = format!("{cache}/../../{name}/in.png");
+        assert_same_sums(&load(&cache, &other).unwrap(), &data);
+        assert!(load(&cache, &dir.path("missing.png")).is_none());
+        // nothing is left behind but the cache file
+        assert_eq!(fs::read_dir(&cache).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn stale_cache_files_are_ignored_and_replaced() {
+        let dir = TempDir::new("stale");
+        let (cache, png, file) = stored(&dir);
+        // a new image at the same path, written later
+        thread::sleep(Duration::from_millis(20));
+        let data = input(&png, 9, 13, 2);
+        assert!(load(&cache, &png).is_none());
+        store(&cache, &png, &data);
+        assert_same_sums(&load(&cache, &png).unwrap(), &data);
+
+        // from another version of the format
+        let mut bytes = fs::read(&file).unwrap();
+        bytes[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        fs::write(&file, &bytes).unwrap();
+        assert!(load(&cache, &png).is_none());
+        let (_, key) = key(&png).unwrap();
+        assert!(matches!(
+            read(bytes.as_slice(), bytes.len() as u64, &key),
+            Ok(None)
+        ));
+
+        // of another file that hashes the same
+        let bytes = fs::read(&file).unwrap();
+        let other = key.replace("in.png", "other.png");
+        assert!(matches!(
+            read(bytes.as_slice(), bytes.len() as u64, &other),
+            Ok(None)
+        ));
+    }
+
+    #[test]
+    fn corrupt_cache_files_are_rejected() {
+        let dir = TempDir::new("corrupt");
+        let (cache, png, file) = stored(&dir);
+        let (_, key) = key(&png).unwrap();
+        let good = fs::read(&file).unwrap();
+        let read = |bytes: &[u8]| read(bytes, bytes.len() as u64, &key).err();
+        assert_eq!(read(&good), None);
+
+        let mut magic = good.clone();
+        magic[0] = b'X';
+        assert_eq!(read(&magic).unwrap(), "not a comprs cache file");
+        for cut in [0, 7, 8 + key.len() - 1] {
+            assert_eq!(
+                read(&good[..cut]).unwrap(),
+                "cache file is truncated",
+                "{cut}"
+            );
+        }
+        let err = read(&good[..good.len() - 1]).unwrap();
+        assert!(err.starts_with("prefix sums are"), "{err}");
+        let mut flipped = good.clone();
+        let middle = good.len() - (good.len() - 8 - key.len()) / 2;
+        flipped[middle] ^= 1;
+        assert!(read(&flipped)
+            .unwrap()
+            .starts_with("prefix sums are corrupt"));
+
+        // and load builds them again rather than use them
+        for bytes in [magic, flipped, good[..good.len() / 2].to_vec()] {
+            fs::write(&file, &bytes).unwrap();
+            assert!(load(&cache, &png).is_none());
+        }
+    }
+}
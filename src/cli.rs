@@ -0,0 +1,410 @@
+use crate::error;
+
+// every flag comprs takes, so the help text is grouped and an argument that
+// looks like a flag but isn't one can be told apart from an input file
+
+pub struct Flag {
+    /// the form `real_main` matches on
+    pub name: &'static str,
+    /// the form with two dashes, usually just the name with another dash
+    pub long: &'static str,
+    /// what follows the flag, empty if nothing does
+    pub value: &'static str,
+    pub help: &'static str,
+}
+
+const fn flag(
+    name: &'static str,
+    long: &'static str,
+    value: &'static str,
+    help: &'static str,
+) -> Flag {
+    Flag {
+        name,
+        long,
+        value,
+        help,
+    }
+}
+
+pub const GROUPS: [(&str, &[Flag]); 5] = [
+    (
+        "input and output",
+        &[
+            flag("-o", "--output", "output-file", "[optional] where to save output image, supports .{jpg,png,...}"),
+            flag("-out-dir", "--out-dir", "dir", "[optional] save the output of every input file in dir instead of next to it, keeping its default name. dir is created if it doesn't exist, can't be used with -o"),
+            flag("-name-template", "--name-template", "template", "[optional] name the outputs saved without -o by this template instead of {stem}-comprs.{ext}. {stem} is the input file name without its extension, {ext} its extension (png if it has none, gif for animations), {iter} the iterations, {leaves} the regions that many iterations make (fewer if every region is fully refined first, can't be used with -resume or -tiles), {split} the split strategy, {w} and {h} the size of the image. {{ and }} are literal braces. checkpoints are named by it too and then need {iter}. -o replaces it"),
+            flag("-cache-dir", "--cache-dir", "dir", "[optional] keep the prefix sums of every input in dir, so compressing it again while it hasn't changed skips building them, and decoding it unless -encode-lossless, -ssim or an animation showing the original needs its pixels. a cache file is 36 bytes per pixel, 48 above 16 megapixels. a stale or damaged one is built again and replaced. dir is created if it doesn't exist, not used with -tiles"),
+            flag("-recursive", "--recursive", "", "[optional] also compress the images in the subdirectories of an input directory, -out-dir mirrors them"),
+            flag("-dry-run", "--dry-run", "", "[optional] check the flags, the outputs and the header of each input, print the size, the memory it would take and the files it would write, then stop without compressing. exits with the code compressing would fail with, see exit codes below"),
+            flag("-interactive", "--interactive", "", "[optional] once refined, keep the tree and read commands from stdin: refine n, outline hex-code, outline none, paint x,y hex-code, save file, stats and quit. the outputs given on the command line are written after quit, from the tree as it is then. can't be used with more than one input file, -tiles, -gif, -frames or -dry-run"),
+            flag("-watch", "--watch", "", "[optional] once done, compress again each time an input changes until ctrl-c, printing the time before each run. inputs are polled, and a change counts once they have stayed the same for half a second. runs after the first overwrite their outputs and a failed run doesn't stop watching. a directory needs -out-dir outside it, can't be used with -dry-run or -interactive"),
+            flag("-force", "--force", "", "[optional] overwrite outputs that already exist and allow writing frames into a non-empty -frames-dir. the input file is never overwritten"),
+            flag("-open", "--open", "", "[optional] show the output in the default viewer of the desktop once it is saved, the directory with -frames-dir. not done for more than one input file or with -dry-run, failing to open it is only a warning"),
+            flag("-quiet", "--quiet", "", "[optional] only print errors, which go to stderr, and what flags like -stats are asked to print. also hides progress"),
+            flag("-verbose", "--verbose", "", "[optional] also print the options each input is compressed with, when refining stops early, and how long each phase took, on stderr"),
+            flag("-timings", "--timings", "", "[optional] print how long decoding, building the prefix sums, refining, rendering and encoding took, and how many animation frame buffers were allocated"),
+            flag("-config", "--config", "file", "[optional] read default flags from this toml file instead of ~/.config/comprs/config.toml. each key is a flag without its dashes, e.g. iter = 8000. COMPRS_* environment variables set flags too, e.g. COMPRS_ITER=8000 or COMPRS_LABEL=1, and replace the config file. flags given on the command line win over both. variables with a bad value are ignored with a warning"),
+            flag("-no-config", "--no-config", "", "[optional] don't read a config file"),
+            flag("-h", "--help", "", "print this help"),
+            flag("-V", "--version", "", "print the version of comprs, the commit it was built from and its features"),
+        ],
+    ),
+    (
+        "refining",
+        &[
+            flag("-iter", "--iterations", "iterations", "number of times to split the quad-tree, at least 1. more iterations means higher quality image"),
+            flag("-split", "--split", "strategy", "[optional] where regions are split, one of midpoint|adaptive|kd|kd-adaptive|grid9, defaults to midpoint. adaptive tries a few rows and columns around the middle and keeps the one that leaves the least error. kd splits each region in two across its longer side instead of in four, grid9 in nine through its thirds"),
+            flag("-outline", "--outline", "hex-code", "[optional] color to outline each sub-region of the quad-tree (e.g. -outline FF0000)"),
+            flag("-checkpoints", "--checkpoints", "list", "[optional] also save the still image at each of these comma separated iterations, e.g. 1000,5000,20000, as <output>-<iterations>. can't be used with -tiles"),
+            flag("-undo", "--undo", "n", "[optional] take back the last n splits once refining is done, the still image, -encode, -export-json and -leaf-at show the tree without them. animations and checkpoints are captured before. can't be used with -tiles"),
+            flag("-resume", "--resume", "file", "[optional] go on refining a tree saved with -encode or -export-json from the image it was made from instead of starting over, -iter counts the splits made after it. the tree can't have been saved with -encode-lossless, can't be used with more than one input file or -tiles"),
+            flag("-jobs", "--jobs", "n", "[optional] how many input files to compress at once when more than one is given, defaults to the number of cpus"),
+            flag("-tiles", "--tiles", "k", "[optional] split the image into a k by k grid of tiles and refine them on every core at once, the iterations are shared by how detailed each tile is. regions never cross tiles so seams can show, -outline draws them. needs the rayon feature, can't be used with -gif or -frames"),
+        ],
+    ),
+    (
+        "animation",
+        &[
+            flag("-gif", "--gif", "save-delta[:file]", "[optional] save the algorithm process as a gif (or an apng if output-file ends in .png/.apng), save the image every `save-delta` iterations. can be given more than once as `save-delta:output-file` to save extra gifs from the same run"),
+            flag("-frames", "--frames", "list", "[optional] save the algorithm process as a gif, save the image at the given comma separated iterations (e.g. -frames 10,100,1000)"),
+            flag("-fps", "--fps", "frames", "[optional] playback speed of the gif in frames per second, requires -gif or -frames"),
+            flag("-hold-last", "--hold-last", "ms", "[optional] how long to show the last frame of the gif, defaults to 2000, requires -gif or -frames"),
+            flag("-gif-direction", "--gif-direction", "d", "[optional] playback order of the gif, one of forward|reverse|boomerang, requires -gif or -frames"),
+            flag("-gif-spacing", "--gif-spacing", "s", "[optional] how gif frames are spread over the iterations, one of linear|exp|log, requires -gif"),
+            flag("-loop", "--loop", "count", "[optional] how many times the gif plays, 0 loops forever, requires -gif or -frames"),
+            flag("-start-with-original", "--start-with-original", "", "[optional] show the original image before the first frame of the gif"),
+            flag("-end-with-original", "--end-with-original", "", "[optional] show the original image after the last frame of the gif"),
+            flag("-gif-quality", "--gif-quality", "speed", "[optional] gif palette quantization speed from 1 to 30, defaults to 30. lower is slower but reduces color banding, requires -gif or -frames"),
+            flag("-gif-scale", "--gif-scale", "scale", "[optional] render animation frames at this fraction of the image size, from 0.1 to 1.0, defaults to 1.0. the still output keeps full size, requires -gif or -frames"),
+            flag("-frames-dir", "--frames-dir", "dir", "[optional] save each frame as a numbered png in dir instead of making a gif, requires -gif or -frames"),
+            flag("-video", "--video", "file", "[optional] pipe the frames to ffmpeg to make a video (e.g. .mp4, .webm) instead of a gif, requires -gif or -frames"),
+            flag("-ffmpeg", "--ffmpeg", "path", "[optional] ffmpeg executable used by -video, defaults to ffmpeg"),
+            flag("-gif-heatmap", "--gif-heatmap", "file", "[optional] also save an animation of where the remaining error is, requires -gif or -frames"),
+            flag("-tween", "--tween", "frames", "[optional] number of blended frames to insert between each pair of frames, requires -gif or -frames"),
+            flag("-label", "--label", "", "[optional] write the iteration and leaf count on each frame of the gif, requires -gif or -frames"),
+            flag("-label-psnr", "--label-psnr", "", "[optional] also write the psnr of the frame against the original on its label, from the error the tree keeps as it is refined. requires -label"),
+            flag("-label-corner", "--label-corner", "c", "[optional] corner of the label, one of tl|tr|bl|br, defaults to tl"),
+            flag("-label-color", "--label-color", "hex", "[optional] color of the label text, defaults to FFFFFF"),
+            flag("-stable-palette", "--stable-palette", "", "[optional] use one palette built from the original image for every gif frame, stops flat regions from flickering, requires -gif or -frames"),
+            flag("-delta-frames", "--delta-frames", "", "[optional] only store the part of each gif frame that changed, makes long animations much smaller, requires -gif or -frames"),
+            flag("-gif-dither", "--gif-dither", "d", "[optional] dithering when reducing gif frames to 256 colors, one of none|ordered|fs, defaults to none, requires -gif or -frames"),
+        ],
+    ),
+    (
+        "trees",
+        &[
+            flag("-encode", "--encode", "file", "[optional] also save the refined tree as a compact .qt file, a few bytes per region no matter how large the image is"),
+            flag("-encode-entropy", "--encode-entropy", "", "[optional] range code the .qt file, usually a quarter smaller but slower to write and read, requires -encode"),
+            flag("-encode-lossless", "--encode-lossless", "", "[optional] also store what the render is missing from the original image in the .qt file, so -decode gives back the original exactly unless it scales, outlines or prunes the render, requires -encode"),
+            flag("-export-json", "--export-json", "file", "[optional] also save the position, size, depth and color of every region as json"),
+            flag("-pretty", "--pretty", "", "[optional] put each region on its own line in the json, requires -export-json"),
+            flag("-leaf-at", "--leaf-at", "x,y", "[optional] print the bounds, depth, color and error of the region that covers pixel x,y once the tree is refined or decoded. decoded trees don't have errors, can't be used with more than one input file or -tiles"),
+            flag("-stats", "--stats", "", "[optional] print how many regions and nodes the tree has, how deep and large its regions are and the error they leave once it is refined or decoded. compressing also prints the mse and psnr of the render against the original, worked out from the prefix sums without rendering, the size of the input and output files, the size of the tree as a .qt file (the -encode file, or worked out without range coding or a residual) against the raw rgb pixels and the input, and how long it took. -export-json includes the tree numbers. decoded trees don't have errors, can't be used with more than one input file or -tiles"),
+            flag("-ssim", "--ssim", "", "[optional] also put the ssim of the luma of the render against the original in -stats and -report, over 11x11 gaussian windows. it is 1 when they are the same and falls as the render looks less like it, which follows how blocky it looks better than psnr. it needs the decoded image and a pass over every pixel of both, so it is only worked out when asked for. needs -stats or -report, can't be used with -decode or -tiles"),
+            flag("-suggest-iter", "--suggest-iter", "", "[optional] record the mse after every iteration up to -iter and print the iteration at the knee of that curve in decibels, past which splits stop paying off as much, with its psnr. the knee is where the curve is furthest below the line from its first point to its last, so it moves with -iter: give more iterations than you'd use. can't be used with -decode or -tiles"),
+            flag("-apply", "--apply", "", "[optional] take the tree back to the suggested iteration before saving anything, so the outputs are of it. requires -suggest-iter, can't be used with -undo, -checkpoints, -gif or -frames"),
+            flag("-target-psnr", "--target-psnr", "decibels", "[optional] stop refining once the psnr of the render against the original reaches this many decibels, worked out from the prefix sums as it goes. -iter is still the most iterations it runs, can't be used with -decode or -tiles"),
+            flag("-error-curve", "--error-curve", "file", "[optional] also save the mse and psnr of the render against the original as refining goes on, a csv row of iteration,leaves,mse,psnr at iteration 0, every -error-curve-step iterations and where refining stopped. each row is written out as it is made, so a stopped run keeps what it got to. can't be used with more than one input file or -tiles"),
+            flag("-error-curve-step", "--error-curve-step", "n", "[optional] iterations between the rows of the error curve, defaults to 1, requires -error-curve"),
+            flag("-report", "--report", "file", "[optional] also save what the run did as json: the options after the config file and the environment, the input, why refining stopped, the -stats numbers, the iteration, leaves and psnr of every animation frame, the time each phase took and every output. several inputs get one file with an entry each and a summary, an input that failed has its error. can't be used with -dry-run"),
+            flag("-porcelain", "--porcelain", "", "[optional] print only records for scripts on stdout, a line of key=value pairs per event: start, progress, encode, stopped, leaf, stats, saved, result for each input and summary after several. the format is versioned and described in the readme. errors still go to stderr too, can't be used with -dry-run or -interactive"),
+        ],
+    ),
+    (
+        "decoding",
+        &[
+            flag("-decode", "--decode", "file", "render a tree saved with -encode or -export-json instead of compressing an image, requires -o"),
+            flag("-scale", "--scale", "scale", "[optional] render the decoded tree at this fraction of the image size, from 0.1 to 1.0, defaults to 1.0, requires -decode"),
+            flag("-prune-to-leaves", "--prune-to-leaves", "n", "[optional] undo the splits that matter least until the decoded tree has at most n regions, requires -decode"),
+        ],
+    ),
+];
+
+/// a subcommand, what it's for and the flags it takes
+pub struct Command {
+    pub name: &'static str,
+    /// what it takes besides flags
+    pub operands: &'static str,
+    pub help: &'static str,
+    /// the groups of `GROUPS` it takes every flag of
+    groups: &'static [&'static str],
+    /// the flags of other groups it takes
+    flags: &'static [&'static str],
+    /// flags it takes that aren't optional for it
+    needs: &'static [&'static str],
+}
+
+/// what every command takes
+const COMMON: [&str; 6] = ["-quiet", "-verbose", "-config", "-no-config", "-h", "-V"];
+
+/// what rendering a saved tree takes
+const RENDER: &[&str] = &[
+    "-o",
+    "-force",
+    "-open",
+    "-outline",
+    "-scale",
+    "-prune-to-leaves",
+    "-leaf-at",
+    "-stats",
+    "-porcelain",
+    "-timings",
+];
+
+pub const COMMANDS: [Command; 5] = [
+    Command {
+        name: "compress",
+        operands: "<input-file>...",
+        help: "refine each image into a quad-tree and save its render, and the tree with -encode or -export-json",
+        groups: &["input and output", "refining", "trees"],
+        flags: &[],
+        needs: &[],
+    },
+    Command {
+        name: "animate",
+        operands: "<input-file>...",
+        help: "compress and also save how each image was refined as a gif, apng, video or frames, needs -gif or -frames",
+        groups: &["input and output", "refining", "animation", "trees"],
+        flags: &[],
+        needs: &[],
+    },
+    Command {
+        name: "decode",
+        operands: "<tree-file>",
+        help: "render a tree saved with -encode or -export-json, needs -o",
+        groups: &[],
+        flags: RENDER,
+        needs: &["-o"],
+    },
+    Command {
+        name: "stats",
+        operands: "<tree-file>",
+        help: "print the -stats numbers of a tree saved with -encode or -export-json without rendering it",
+        groups: &[],
+        flags: &["-prune-to-leaves", "-leaf-at", "-porcelain", "-timings"],
+        needs: &[],
+    },
+    Command {
+        name: "restyle",
+        operands: "<tree-file>",
+        help: "render a saved tree again with another outline or at another scale, needs -o and -outline or -scale",
+        groups: &[],
+        flags: RENDER,
+        needs: &["-o"],
+    },
+];
+
+impl Command {
+    /// whether the command takes `flag`, given by its name
+    pub fn takes(&self, flag: &str) -> bool {
+        COMMON.contains(&flag)
+            || self.flags.contains(&flag)
+            || GROUPS
+                .iter()
+                .filter(|(title, _)| self.groups.contains(title))
+                .any(|(_, flags)| flags.iter().any(|f| f.name == flag))
+    }
+
+    /// the command with its operands and flags, without `usage: `
+    pub fn usage(&self, program: &str) -> String {
+        let mut ret = format!("{program} {} {}", self.name, self.operands);
+        for f in flags().filter(|f| self.takes(f.name) && f.name != "-h" && f.name != "-V") {
+            ret += &if f.value.is_empty() {
+                format!(" [{}]", f.name)
+            } else if f.help.starts_with("[optional]") && !self.needs.contains(&f.name) {
+                format!(" [{} {}]", f.name, f.value)
+            } else {
+                format!(" {} <{}>", f.name, f.value)
+            };
+        }
+        ret
+    }
+}
+
+/// the command called `name`, if any
+pub fn command(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|c| c.name == name)
+}
+
+/// the cargo features comprs was built with
+fn features() -> Vec<&'static str> {
+    let mut ret = Vec::new();
+    if cfg!(feature = "rayon") {
+        ret.push("rayon");
+    }
+    ret
+}
+
+pub fn print_version() {
+    println!("comprs {}", comprs::version());
+    let features = features();
+    if features.is_empty() {
+        println!("features: none");
+    } else {
+        println!("features: {}", features.join(", "));
+    }
+}
+
+pub fn flags() -> impl Iterator<Item = &'static Flag> {
+    GROUPS.iter().flat_map(|(_, flags)| flags.iter())
+}
+
+/// whether `arg` is meant as a flag rather than an input file, a lone `-`
+/// isn't
+pub fn is_flag(arg: &str) -> bool {
+    arg.len() > 1 && arg.starts_with('-')
+}
+
+/// the name of the flag `arg` is, in either form
+pub fn canonical(arg: &str) -> Option<&'static str> {
+    flags()
+        .find(|f| f.name == arg || f.long == arg)
+        .map(|f| f.name)
+}
+
+/// the flag `arg` was most likely meant to be, in the same form, if any is
+/// close enough to be a typo. case doesn't count, so -v finds -V
+pub fn suggest(arg: &str) -> Option<&'static str> {
+    let long = arg.starts_with("--");
+    flags()
+        .map(|f| if long { f.long } else { f.name })
+        .map(|name| {
+            (
+                edit_distance(&arg.to_lowercase(), &name.to_lowercase()),
+                name,
+            )
+        })
+        .filter(|&(d, name)| d <= (name.len() / 3).max(1))
+        .min_by_key(|&(d, _)| d)
+        .map(|(_, name)| name)
+}
+
+/// the fewest single character insertions, deletions and substitutions that
+/// turn `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitute = diagonal + (ca != cb) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = substitute.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// a line per flag under a heading per group, only the flags of `command`
+/// if one is given
+pub fn print_help(command: Option<&Command>) {
+    match command {
+        Some(c) => println!("{}", c.help),
+        None => {
+            println!("commands:");
+            for c in &COMMANDS {
+                print_line(c.name, c.help);
+            }
+            print_line("diff a b -o file [-force]", "compare two trees of the same image saved with -encode or -export-json, save where they were split differently in white and where they agree in black, and print how much differs");
+            print_line("completions shell", "print a tab completion script for bash, zsh or fish, e.g. comprs completions bash > /etc/bash_completion.d/comprs");
+            println!("`comprs <command> -h` lists only the flags of that command. without a command comprs compresses, animates or decodes as the flags say and takes all of them, which is deprecated and goes away in the next release");
+        }
+    }
+    println!();
+    println!("every flag can also be given with two dashes, e.g. --split");
+    println!();
+    let operand = match command {
+        Some(c) if c.operands == "<tree-file>" => ("tree-file", "a tree saved with -encode or -export-json"),
+        _ => ("input-file...", "path to input image, supports .{jpg,png,...}. several can be given, each is saved next to itself. a directory stands for every image in it, hidden files and files that aren't images are skipped"),
+    };
+    print_line(operand.0, operand.1);
+    for (title, flags) in GROUPS {
+        let flags: Vec<&Flag> = flags
+            .iter()
+            .filter(|f| command.is_none_or(|c| c.takes(f.name)))
+            .collect();
+        if flags.is_empty() {
+            continue;
+        }
+        println!();
+        println!("{title}:");
+        for f in flags {
+            let mut names = f.name.to_string();
+            if f.long[1..] != *f.name {
+                names += &format!(", {}", f.long);
+            }
+            if !f.value.is_empty() {
+                names += &format!(" {}", f.value);
+            }
+            print_line(&names, f.help);
+        }
+    }
+    println!();
+    println!("exit codes, of the first input that failed when there are several:");
+    let codes = [
+        (0, "done, also when every region was fully refined before -iter ran out"),
+        (error::FAILURE, "anything without a code of its own"),
+        (error::USAGE, "the flags or their values can't be used, together or with this input"),
+        (error::INPUT, "an input file, tree file or directory couldn't be opened or read"),
+        (error::DECODE, "an input isn't an image or a tree comprs can decode"),
+        (error::OUTPUT, "an output couldn't be created or written, exists without -force or is the input. checked before any work, along with the directory it goes in"),
+        (error::INTERRUPTED, "ctrl-c stopped refining, what was refined by then is saved. a second ctrl-c quits without saving"),
+    ];
+    for (code, meaning) in codes {
+        print_line(&code.to_string(), meaning);
+    }
+}
+
+fn print_line(names: &str, help: &str) {
+    println!("  {names:<30} - {help}");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    /// the names in `source` of every `<before>"-name"` it has
+    fn names<'a>(source: &'a str, before: &str) -> BTreeSet<&'a str> {
+        source
+            .split(before)
+            .skip(1)
+            .filter_map(|rest| rest.strip_prefix('"')?.split('"').next())
+            .filter(|name| is_flag(name))
+            .collect()
+    }
+
+    #[test]
+    fn every_registered_flag_is_parsed() {
+        // real_main matches every flag on its name, but -config and
+        // -no-config are taken out by config::apply first
+        let mut parsed = names(include_str!("main.rs"), "arg == ");
+        parsed.extend(names(include_str!("config.rs"), "Some("));
+        let registered: BTreeSet<&str> = flags().map(|f| f.name).collect();
+        assert_eq!(parsed, registered);
+    }
+
+    #[test]
+    fn typos_suggest_the_closest_flag_in_the_same_form() {
+        for (typo, expected) in [
+            ("-iters", Some("-iter")),
+            ("-itr", Some("-iter")),
+            ("--iteratons", Some("--iterations")),
+            ("--outlin", Some("--outline")),
+            ("-v", Some("-V")),
+            ("-O", Some("-o")),
+            ("-xyzzy", None),
+            ("--frobnicate", None),
+        ] {
+            assert_eq!(suggest(typo), expected, "{typo}");
+        }
+        for f in flags() {
+            assert_eq!(canonical(f.name), Some(f.name));
+            assert_eq!(canonical(f.long), Some(f.name));
+            assert_eq!(suggest(f.long), Some(f.long));
+        }
+    }
+}
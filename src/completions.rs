@@ -0,0 +1,171 @@
+use crate::cli::{self, Flag};
+
+// tab completion scripts for `comprs completions <shell>`, made from the flag
+// table so a new flag completes as soon as it is added there
+
+/// the values flags take from a fixed list
+const CHOICES: [(&str, &str); 5] = [
+    ("-split", "midpoint adaptive kd kd-adaptive grid9"),
+    ("-gif-direction", "forward reverse boomerang"),
+    ("-gif-spacing", "linear exp log"),
+    ("-label-corner", "tl tr bl br"),
+    ("-gif-dither", "none ordered fs"),
+];
+
+/// the commands of `cli::COMMANDS` and the ones with a parser of their own
+fn subcommands() -> String {
+    let mut names: Vec<&str> = cli::COMMANDS.iter().map(|c| c.name).collect();
+    names.extend(["diff", "completions"]);
+    names.join(" ")
+}
+
+pub const SHELLS: [&str; 3] = ["bash", "zsh", "fish"];
+
+/// what completes after a flag
+enum Completion {
+    /// the flag takes no value
+    Switch,
+    /// a value that can't be completed, like a number
+    Value,
+    Files,
+    Dirs,
+    Choices(&'static str),
+}
+
+fn completion(flag: &Flag) -> Completion {
+    if let Some((_, choices)) = CHOICES.iter().find(|(name, _)| *name == flag.name) {
+        return Completion::Choices(choices);
+    }
+    match flag.value {
+        "" => Completion::Switch,
+        "file" | "output-file" | "path" => Completion::Files,
+        "dir" => Completion::Dirs,
+        _ => Completion::Value,
+    }
+}
+
+/// the first sentence of the help of `flag`, without the [optional]
+fn summary(flag: &Flag) -> &'static str {
+    let help = flag.help.trim_start_matches("[optional] ");
+    help.split(". ").next().unwrap_or(help)
+}
+
+/// the completion script for `shell`, `None` if it isn't one of `SHELLS`
+pub fn script(shell: &str) -> Option<String> {
+    match shell {
+        "bash" => Some(bash()),
+        "zsh" => Some(zsh()),
+        "fish" => Some(fish()),
+        _ => None,
+    }
+}
+
+fn bash() -> String {
+    let names = |f: &Flag| format!("{}|{}", f.name, f.long);
+    let mut cases = String::new();
+    let mut takes_value = Vec::new();
+    for f in cli::flags() {
+        let reply = match completion(f) {
+            Completion::Switch => continue,
+            Completion::Value => {
+                takes_value.push(names(f));
+                continue;
+            }
+            Completion::Files => "COMPREPLY=($(compgen -f -- \"$cur\"))".to_string(),
+            Completion::Dirs => "COMPREPLY=($(compgen -d -- \"$cur\"))".to_string(),
+            Completion::Choices(c) => format!("COMPREPLY=($(compgen -W \"{c}\" -- \"$cur\"))"),
+        };
+        cases += &format!(
+            "        {})\n            {reply}\n            return ;;\n",
+            names(f)
+        );
+    }
+    if !takes_value.is_empty() {
+        cases += &format!(
+            "        {})\n            return ;;\n",
+            takes_value.join("|")
+        );
+    }
+    let all: Vec<&str> = cli::flags().flat_map(|f| [f.name, f.long]).collect();
+    let subcommands = subcommands();
+    format!(
+        r#"_comprs() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}"
+    local prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+    COMPREPLY=()
+    if [[ $COMP_CWORD -eq 1 && "$cur" != -* ]]; then
+        COMPREPLY=($(compgen -W "{subcommands}" -- "$cur") $(compgen -f -- "$cur"))
+        return
+    fi
+    case "$prev" in
+{cases}    esac
+    if [[ "$cur" == -* ]]; then
+        COMPREPLY=($(compgen -W "{}" -- "$cur"))
+    else
+        COMPREPLY=($(compgen -f -- "$cur"))
+    fi
+}}
+complete -o filenames -F _comprs comprs
+"#,
+        all.join(" ")
+    )
+}
+
+fn zsh() -> String {
+    let mut specs = String::new();
+    for f in cli::flags() {
+        let description = summary(f)
+            .replace('\\', "\\\\")
+            .replace('[', "\\[")
+            .replace(']', "\\]")
+            .replace(':', "\\:")
+            .replace('\'', "'\\''");
+        let action = match completion(f) {
+            Completion::Switch => String::new(),
+            Completion::Value => format!(":{}: ", f.value.replace(':', "\\:")),
+            Completion::Files => format!(":{}:_files", f.value),
+            Completion::Dirs => format!(":{}:_files -/", f.value),
+            Completion::Choices(c) => format!(":{}:({c})", f.value),
+        };
+        specs += &format!(
+            "    '({name} {long})'{{{name},{long}}}'[{description}]{action}' \\\n",
+            name = f.name,
+            long = f.long
+        );
+    }
+    let subcommands = subcommands();
+    format!(
+        "#compdef comprs
+
+_arguments \\
+{specs}    '1: :{{_files; compadd {subcommands}}}' \\
+    '*:input-file:_files'
+"
+    )
+}
+
+fn fish() -> String {
+    let subcommands = subcommands();
+    let mut ret = format!(
+        "complete -c comprs -n __fish_use_subcommand -a '{subcommands}'\n\
+         complete -c comprs -n '__fish_seen_subcommand_from completions' -x -a '{}'\n",
+        SHELLS.join(" ")
+    );
+    for f in cli::flags() {
+        let short = if f.name.len() == 2 { "-s" } else { "-o" };
+        let description = summary(f).replace('\\', "\\\\").replace('\'', "\\'");
+        let action = match completion(f) {
+            Completion::Switch => String::new(),
+            Completion::Value => " -x".into(),
+            Completion::Files => " -r -F".into(),
+            Completion::Dirs => " -x -a '(__fish_complete_directories)'".into(),
+            Completion::Choices(c) => format!(" -x -a '{c}'"),
+        };
+        ret += &format!(
+            "complete -c comprs {short} {} -l {} -d '{description}'{action}\n",
+            &f.name[1..],
+            &f.long[2..]
+        );
+    }
+    ret
+}
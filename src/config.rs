@@ -0,0 +1,729 @@
+use std::{env, fs, path::PathBuf};
+
+use crate::cli::{self, Command};
+
+// defaults for flags from a toml file, ~/.config/comprs/config.toml unless
+// -config names another one. each key is a flag without its dashes, e.g.
+//
+//     iter = 8000
+//     outline = "FF0000"
+//     gif-quality = 10
+//     label = true
+//     frames = [10, 100, 1000]
+//
+// the file only sets defaults, so a flag on the command line replaces the
+// key for it. only the part of toml that flags need is read: keys at the top
+// level with strings, numbers, booleans and arrays of them
+//
+// COMPRS_* environment variables set the same flags, e.g. COMPRS_ITER=8000,
+// and sit between the two: they replace the config file and the command
+// line replaces them
+
+/// environment variables that set flags start with this
+const ENV_PREFIX: &str = "COMPRS_";
+
+/// flags that only make sense for one run, so the config file and the
+/// environment can't set them
+const PER_RUN: [&str; 18] = [
+    "-dry-run",
+    "-interactive",
+    "-watch",
+    "-o",
+    "-h",
+    "-V",
+    "-decode",
+    "-resume",
+    "-leaf-at",
+    "-encode",
+    "-export-json",
+    "-error-curve",
+    "-report",
+    "-video",
+    "-gif-heatmap",
+    "-frames-dir",
+    "-config",
+    "-no-config",
+];
+
+/// the flags -decode takes, the only keys used when decoding
+const DECODE: [&str; 10] = [
+    "-outline",
+    "-scale",
+    "-prune-to-leaves",
+    "-stats",
+    "-porcelain",
+    "-timings",
+    "-quiet",
+    "-verbose",
+    "-force",
+    "-open",
+];
+
+const ANIMATE: &[&str] = &["-gif", "-frames"];
+
+/// keys that are dropped unless one of `needs` is given, or if one of
+/// `excludes` is given on the command line, so a default for them doesn't
+/// stop runs they don't apply to
+struct Rule {
+    flags: &'static [&'static str],
+    needs: &'static [&'static str],
+    excludes: &'static [&'static str],
+}
+
+const RULES: [Rule; 15] = [
+    Rule {
+        flags: &[
+            "-fps",
+            "-hold-last",
+            "-gif-direction",
+            "-loop",
+            "-start-with-original",
+            "-end-with-original",
+            "-tween",
+            "-label",
+            "-stable-palette",
+            "-delta-frames",
+            "-gif-dither",
+            "-gif-scale",
+            "-gif-quality",
+        ],
+        needs: ANIMATE,
+        excludes: &[],
+    },
+    Rule {
+        flags: &["-gif-spacing"],
+        needs: &["-gif"],
+        excludes: &[],
+    },
+    Rule {
+        flags: &["-label-psnr", "-label-corner", "-label-color"],
+        needs: &["-label"],
+        excludes: &[],
+    },
+    Rule {
+        flags: &["-ffmpeg"],
+        needs: &["-video"],
+        excludes: &[],
+    },
+    Rule {
+        flags: &["-encode-entropy", "-encode-lossless"],
+        needs: &["-encode"],
+        excludes: &[],
+    },
+    Rule {
+        flags: &["-pretty"],
+        needs: &["-export-json"],
+        excludes: &[],
+    },
+    Rule {
+        flags: &["-error-curve-step"],
+        needs: &["-error-curve"],
+        excludes: &[],
+    },
+    Rule {
+        flags: &["-ssim"],
+        needs: &["-stats", "-report"],
+        excludes: &[],
+    },
+    Rule {
+        flags: &["-apply"],
+        needs: &["-suggest-iter"],
+        excludes: &["-undo", "-checkpoints", "-gif", "-frames"],
+    },
+    Rule {
+        flags: &["-tiles"],
+        needs: &[],
+        excludes: &[
+            "-gif",
+            "-frames",
+            "-undo",
+            "-resume",
+            "-checkpoints",
+            "-leaf-at",
+            "-stats",
+            "-ssim",
+            "-suggest-iter",
+            "-target-psnr",
+            "-encode",
+            "-export-json",
+            "-error-curve",
+        ],
+    },
+    Rule {
+        flags: &["-gif"],
+        needs: &[],
+        excludes: &["-frames", "-tiles"],
+    },
+    Rule {
+        flags: &["-frames"],
+        needs: &[],
+        excludes: &["-gif", "-tiles"],
+    },
+    Rule {
+        flags: &["-quiet"],
+        needs: &[],
+        excludes: &["-verbose"],
+    },
+    Rule {
+        flags: &["-verbose"],
+        needs: &[],
+        excludes: &["-quiet"],
+    },
+    Rule {
+        flags: &["-out-dir"],
+        needs: &[],
+        excludes: &["-o"],
+    },
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Bool(bool),
+    Array(Vec<Value>),
+}
+
+/// a flag the config file sets and what follows it
+type Entry = (&'static str, Option<String>);
+
+/// `args` with the defaults from the config file and the environment in
+/// front of them, and the warnings about keys and variables that were
+/// ignored. -config and -no-config are taken out of `args`, and defaults
+/// for flags `command` doesn't take are left out
+pub fn apply(
+    args: Vec<String>,
+    command: Option<&Command>,
+    check: impl Fn(&str, &str) -> Result<(), String>,
+) -> Result<(Vec<String>, Vec<String>), String> {
+    let mut path = None;
+    let mut no_config = false;
+    let mut rest = Vec::with_capacity(args.len());
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match cli::canonical(&arg) {
+            Some("-config") => match args.next() {
+                Some(p) => path = Some(PathBuf::from(p)),
+                None => return Err("config file not specified".into()),
+            },
+            Some("-no-config") => no_config = true,
+            _ => rest.push(arg),
+        }
+    }
+    if no_config && path.is_some() {
+        return Err("-config and -no-config can't be used together".into());
+    }
+    let (vars, mut warnings) = from_env(check);
+    let rest = merge(vars, rest, command);
+    if no_config {
+        return Ok((rest, warnings));
+    }
+    let path = match path {
+        Some(p) => p,
+        None => match default_path() {
+            Some(p) if p.is_file() => p,
+            _ => return Ok((rest, warnings)),
+        },
+    };
+    let Ok(text) = fs::read_to_string(&path) else {
+        return Err(format!("unable to read config file `{}`", path.display()));
+    };
+    let (entries, file_warnings) = parse(&text)
+        .and_then(entries)
+        .map_err(|err| format!("invalid config file `{}`: {err}", path.display()))?;
+    warnings.extend(file_warnings);
+    Ok((merge(entries, rest, command), warnings))
+}
+
+/// the flags `COMPRS_*` environment variables set, e.g. COMPRS_ITER=8000 or
+/// COMPRS_GIF_QUALITY=10, and warnings for the variables that are ignored.
+/// they may be set for every shell, so a value `check` turns down is only
+/// a warning
+fn from_env(check: impl Fn(&str, &str) -> Result<(), String>) -> (Vec<Entry>, Vec<String>) {
+    let mut ret = Vec::new();
+    let mut warnings = Vec::new();
+    let mut vars: Vec<(String, Option<String>)> = env::vars_os()
+        .filter_map(|(var, value)| {
+            let var = var.into_string().ok()?;
+            var.starts_with(ENV_PREFIX)
+                .then(|| (var, value.into_string().ok()))
+        })
+        .collect();
+    vars.sort();
+    for (var, value) in vars {
+        let key = var[ENV_PREFIX.len()..].to_lowercase().replace('_', "-");
+        let flag = cli::flags().find(|f| f.name[1..] == key || f.long[2..] == key);
+        let Some(flag) = flag else {
+            warnings.push(format!("unknown environment variable `{var}` is ignored"));
+            continue;
+        };
+        if PER_RUN.contains(&flag.name) {
+            warnings.push(format!(
+                "{} can't be set from the environment, `{var}` is ignored",
+                flag.name
+            ));
+            continue;
+        }
+        let Some(value) = value else {
+            warnings.push(format!("`{var}` isn't valid unicode, it is ignored"));
+            continue;
+        };
+        if flag.value.is_empty() {
+            match value.to_lowercase().as_str() {
+                "1" | "true" | "yes" => ret.push((flag.name, None)),
+                "0" | "false" | "no" | "" => (),
+                _ => warnings.push(format!("`{var}` has to be 1 or 0, it is ignored")),
+            }
+            continue;
+        }
+        match check(flag.name, &value) {
+            Ok(()) => ret.push((flag.name, Some(value))),
+            Err(err) => warnings.push(format!("`{var}={value}` is ignored, {err}")),
+        }
+    }
+    (ret, warnings)
+}
+
+/// where the config file is looked for when -config isn't given
+fn default_path() -> Option<PathBuf> {
+    let dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("comprs").join("config.toml"))
+}
+
+/// the flags `keys` set, and warnings for the keys that aren't flags the
+/// config file can set
+fn entries(keys: Vec<(String, Value)>) -> Result<(Vec<Entry>, Vec<String>), String> {
+    let mut ret = Vec::new();
+    let mut warnings = Vec::new();
+    for (key, value) in keys {
+        let flag = cli::flags().find(|f| f.name[1..] == key || f.long[2..] == key);
+        let Some(flag) = flag else {
+            warnings.push(format!("unknown key `{key}` in the config file is ignored"));
+            continue;
+        };
+        if PER_RUN.contains(&flag.name) {
+            warnings.push(format!(
+                "`{key}` can't be set in the config file, it is ignored"
+            ));
+            continue;
+        }
+        let arg = match (flag.value.is_empty(), value) {
+            (true, Value::Bool(true)) => None,
+            (true, Value::Bool(false)) => continue,
+            (true, _) => return Err(format!("`{key}` has to be true or false")),
+            (false, Value::Bool(_)) => return Err(format!("`{key}` needs a {}", flag.value)),
+            (false, value) => Some(to_arg(&key, value)?),
+        };
+        ret.push((flag.name, arg));
+    }
+    Ok((ret, warnings))
+}
+
+/// the value as it would be written after the flag, arrays separated by
+/// commas
+fn to_arg(key: &str, value: Value) -> Result<String, String> {
+    match value {
+        Value::String(s) => Ok(s),
+        Value::Integer(n) => Ok(n.to_string()),
+        Value::Float(f) => Ok(f.to_string()),
+        Value::Bool(_) => Err(format!("`{key}` can't be true or false")),
+        Value::Array(items) => {
+            let items = items
+                .into_iter()
+                .map(|v| match v {
+                    Value::Array(_) => Err(format!("`{key}` can't have arrays in it")),
+                    v => to_arg(key, v),
+                })
+                .collect::<Result<Vec<String>, String>>()?;
+            Ok(items.join(","))
+        }
+    }
+}
+
+/// the config entries the command line doesn't replace and that apply to
+/// this run, followed by the command line
+fn merge(mut entries: Vec<Entry>, args: Vec<String>, command: Option<&Command>) -> Vec<String> {
+    let mut given: Vec<&str> = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if let Some(flag) = cli::flags().find(|f| f.name == arg || f.long == arg) {
+            given.push(flag.name);
+            if !flag.value.is_empty() {
+                iter.next();
+            }
+        }
+    }
+    entries.retain(|(flag, _)| !given.contains(flag));
+    if given.contains(&"-decode") {
+        entries.retain(|(flag, _)| DECODE.contains(flag));
+    }
+    if let Some(c) = command {
+        entries.retain(|(flag, _)| c.takes(flag));
+    }
+    // dropping one key can leave another without what it needs
+    loop {
+        let present: Vec<&str> = given
+            .iter()
+            .copied()
+            .chain(entries.iter().map(|&(flag, _)| flag))
+            .collect();
+        let len = entries.len();
+        entries.retain(|(flag, _)| {
+            RULES.iter().filter(|r| r.flags.contains(flag)).all(|r| {
+                (r.needs.is_empty() || r.needs.iter().any(|n| present.contains(n)))
+                    && !r.excludes.iter().any(|e| given.contains(e))
+            })
+        });
+        if entries.len() == len {
+            break;
+        }
+    }
+    let mut ret = Vec::with_capacity(entries.len() * 2 + args.len());
+    for (flag, arg) in entries {
+        ret.push(flag.to_string());
+        ret.extend(arg);
+    }
+    ret.extend(args);
+    ret
+}
+
+/// the keys of `text` and their values in the order they are written
+fn parse(text: &str) -> Result<Vec<(String, Value)>, String> {
+    let mut ret: Vec<(String, Value)> = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let at = |err: String| format!("line {}: {err}", i + 1);
+        if line.starts_with('[') {
+            return Err(at(
+                "tables aren't supported, every key goes at the top level".into(),
+            ));
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(at("expected `key = value`".into()));
+        };
+        let key = parse_key(key.trim()).map_err(at)?;
+        let mut chars = value.trim().chars().peekable();
+        let value = parse_value(&mut chars).map_err(at)?;
+        skip_whitespace(&mut chars);
+        match chars.next() {
+            None | Some('#') => (),
+            Some(c) => return Err(at(format!("unexpected `{c}` after the value"))),
+        }
+        if ret.iter().any(|(k, _)| *k == key) {
+            return Err(at(format!("`{key}` is set more than once")));
+        }
+        ret.push((key, value));
+    }
+    Ok(ret)
+}
+
+fn parse_key(key: &str) -> Result<String, String> {
+    if let Some(quoted) = key.strip_prefix('"').and_then(|k| k.strip_suffix('"')) {
+        return Ok(quoted.into());
+    }
+    let bare = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    if key.is_empty() || !key.chars().all(bare) {
+        return Err(format!("invalid key `{key}`"));
+    }
+    Ok(key.replace('_', "-"))
+}
+
+type Chars<'a> = std::iter::Peekable<std::str::Chars<'a>>;
+
+fn skip_whitespace(chars: &mut Chars) {
+    while chars.next_if(|c| *c == ' ' || *c == '\t').is_some() {}
+}
+
+fn parse_value(chars: &mut Chars) -> Result<Value, String> {
+    skip_whitespace(chars);
+    match chars.peek() {
+        Some('"') => {
+            chars.next();
+            parse_string(chars).map(Value::String)
+        }
+        Some('\'') => {
+            chars.next();
+            let mut ret = String::new();
+            loop {
+                match chars.next() {
+                    Some('\'') => return Ok(Value::String(ret)),
+                    Some(c) => ret.push(c),
+                    None => return Err("unterminated string".into()),
+                }
+            }
+        }
+        Some('[') => {
+            chars.next();
+            parse_array(chars).map(Value::Array)
+        }
+        Some(_) => {
+            let mut word = String::new();
+            while let Some(c) = chars.next_if(|c| !matches!(c, ',' | ']' | '#' | ' ' | '\t')) {
+                word.push(c);
+            }
+            parse_word(&word)
+        }
+        None => Err("missing value".into()),
+    }
+}
+
+fn parse_string(chars: &mut Chars) -> Result<String, String> {
+    let mut ret = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(ret),
+            Some('\\') => match chars.next() {
+                Some('"') => ret.push('"'),
+                Some('\\') => ret.push('\\'),
+                Some('n') => ret.push('\n'),
+                Some('t') => ret.push('\t'),
+                Some(c) => return Err(format!("unsupported escape `\\{c}` in string")),
+                None => return Err("unterminated string".into()),
+            },
+            Some(c) => ret.push(c),
+            None => return Err("unterminated string".into()),
+        }
+    }
+}
+
+fn parse_array(chars: &mut Chars) -> Result<Vec<Value>, String> {
+    let mut ret = Vec::new();
+    loop {
+        skip_whitespace(chars);
+        match chars.peek() {
+            Some(']') => {
+                chars.next();
+                return Ok(ret);
+            }
+            None => return Err("unterminated array, arrays go on one line".into()),
+            Some(_) => ret.push(parse_value(chars)?),
+        }
+        skip_whitespace(chars);
+        match chars.next() {
+            Some(',') => (),
+            Some(']') => return Ok(ret),
+            Some(c) => return Err(format!("expected `,` or `]` in array, found `{c}`")),
+            None => return Err("unterminated array, arrays go on one line".into()),
+        }
+    }
+}
+
+fn parse_word(word: &str) -> Result<Value, String> {
+    match word {
+        "true" => return Ok(Value::Bool(true)),
+        "false" => return Ok(Value::Bool(false)),
+        _ => (),
+    }
+    let number = word.replace('_', "");
+    if let Ok(n) = number.parse() {
+        return Ok(Value::Integer(n));
+    }
+    if number.contains(['.', 'e', 'E']) {
+        if let Ok(f) = number.parse::<f64>() {
+            if f.is_finite() {
+                return Ok(Value::Float(f));
+            }
+        }
+    }
+    Err(format!("invalid value `{word}`, strings need quotes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(s: &str) -> Value {
+        Value::String(s.into())
+    }
+
+    /// the error parsing `text` gives, which has to be one
+    fn parse_err(text: &str) -> String {
+        parse(text).expect_err(text)
+    }
+
+    #[test]
+    fn keys_take_every_kind_of_value() {
+        let text = "\
+iter = 8_000
+gif_quality = 10
+\"label-color\" = 'FF0000'
+scale = 0.5
+big = 1e3
+label = true
+stable-palette = false
+frames = [10, 100, 1_000, ]
+empty = []
+nested = [[1, 2], \"three\"]
+";
+        assert_eq!(
+            parse(text).unwrap(),
+            [
+                ("iter".into(), Value::Integer(8000)),
+                ("gif-quality".into(), Value::Integer(10)),
+                ("label-color".into(), string("FF0000")),
+                ("scale".into(), Value::Float(0.5)),
+                ("big".into(), Value::Float(1000.0)),
+                ("label".into(), Value::Bool(true)),
+                ("stable-palette".into(), Value::Bool(false)),
+                (
+                    "frames".into(),
+                    Value::Array(vec![
+                        Value::Integer(10),
+                        Value::Integer(100),
+                        Value::Integer(1000)
+                    ])
+                ),
+                ("empty".into(), Value::Array(vec![])),
+                (
+                    "nested".into(),
+                    Value::Array(vec![
+                        Value::Array(vec![Value::Integer(1), Value::Integer(2)]),
+                        string("three")
+                    ])
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn strings_take_escapes_in_double_quotes_only() {
+        let text = r##"
+a = "say \"hi\"\tand\nbye \\ there"
+b = 'C:\frames\n'
+c = "# not a comment"
+d = ""
+"##;
+        assert_eq!(
+            parse(text).unwrap(),
+            [
+                ("a".into(), string("say \"hi\"\tand\nbye \\ there")),
+                ("b".into(), string("C:\\frames\\n")),
+                ("c".into(), string("# not a comment")),
+                ("d".into(), string("")),
+            ]
+        );
+        for (text, err) in [
+            (r#"a = "\x""#, "line 1: unsupported escape `\\x` in string"),
+            (r#"a = "open"#, "line 1: unterminated string"),
+            (r#"a = "ends in \"#, "line 1: unterminated string"),
+            ("a = 'open", "line 1: unterminated string"),
+        ] {
+            assert_eq!(parse_err(text), err, "{text}");
+        }
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_skipped() {
+        let text = "
+# the defaults
+   # indented
+
+iter = 500 # after a value
+frames = [1, 2] # after an array
+label = true#right after
+";
+        assert_eq!(
+            parse(text).unwrap(),
+            [
+                ("iter".into(), Value::Integer(500)),
+                (
+                    "frames".into(),
+                    Value::Array(vec![Value::Integer(1), Value::Integer(2)])
+                ),
+                ("label".into(), Value::Bool(true)),
+            ]
+        );
+        assert_eq!(parse("# only a comment\n\n").unwrap(), []);
+    }
+
+    #[test]
+    fn what_isnt_supported_is_an_error_with_its_line() {
+        for (text, err) in [
+            (
+                "iter = 5\n[compress]\nlabel = true",
+                "line 2: tables aren't supported, every key goes at the top level",
+            ),
+            (
+                "[[inputs]]",
+                "line 1: tables aren't supported, every key goes at the top level",
+            ),
+            (
+                "frames = [1, 2",
+                "line 1: unterminated array, arrays go on one line",
+            ),
+            (
+                "frames = [\n1, 2]",
+                "line 1: unterminated array, arrays go on one line",
+            ),
+            (
+                "frames = [1 2]",
+                "line 1: expected `,` or `]` in array, found `2`",
+            ),
+            ("\niter", "line 2: expected `key = value`"),
+            ("= 5", "line 1: invalid key ``"),
+            ("gif.quality = 5", "line 1: invalid key `gif.quality`"),
+            ("iter =", "line 1: missing value"),
+            (
+                "outline = FF0000",
+                "line 1: invalid value `FF0000`, strings need quotes",
+            ),
+            (
+                "scale = inf",
+                "line 1: invalid value `inf`, strings need quotes",
+            ),
+            ("iter = 5 6", "line 1: unexpected `6` after the value"),
+            ("iter = 5\niter = 6", "line 2: `iter` is set more than once"),
+            (
+                "gif-quality = 1\ngif_quality = 2",
+                "line 2: `gif-quality` is set more than once",
+            ),
+        ] {
+            assert_eq!(parse_err(text), err, "{text}");
+        }
+    }
+
+    #[test]
+    fn unknown_keys_and_per_run_flags_are_warnings() {
+        let keys = parse("xyzzy = 1\niterations = 50\ndry-run = true\nlabel = true").unwrap();
+        let (entries, warnings) = entries(keys).unwrap();
+        assert_eq!(entries, [("-iter", Some("50".into())), ("-label", None)]);
+        assert_eq!(
+            warnings,
+            [
+                "unknown key `xyzzy` in the config file is ignored",
+                "`dry-run` can't be set in the config file, it is ignored",
+            ]
+        );
+    }
+
+    #[test]
+    fn values_of_the_wrong_type_are_errors() {
+        for (text, err) in [
+            ("label = 5", "`label` has to be true or false"),
+            ("label = \"yes\"", "`label` has to be true or false"),
+            ("iter = true", "`iter` needs a iterations"),
+            ("outline = false", "`outline` needs a hex-code"),
+            ("frames = [[1, 2]]", "`frames` can't have arrays in it"),
+            ("frames = [1, true]", "`frames` can't be true or false"),
+        ] {
+            let keys = parse(text).unwrap();
+            assert_eq!(entries(keys).expect_err(text), err, "{text}");
+        }
+        let keys = parse("frames = [10, 100]\nscale = 0.5\nlabel = false").unwrap();
+        let (entries, _) = entries(keys).unwrap();
+        assert_eq!(
+            entries,
+            [
+                ("-frames", Some("10,100".into())),
+                ("-scale", Some("0.5".into()))
+            ]
+        );
+    }
+}
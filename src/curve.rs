@@ -0,0 +1,175 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+};
+
+use crate::{image::psnr, tree::Tree};
+
+// -error-curve writes how the error of the render falls as the tree is
+// refined, the rate-distortion curve of the image, as csv:
+//
+//   iteration,leaves,mse,psnr
+//
+// with a row at iteration 0, every `step` iterations and wherever refining
+// stopped. the mse is `Tree::mse`, so a row costs nothing next to a split.
+// it falls with nearly every split, but leaves are rendered in their mean
+// rounded down, so on noisy images a split can raise it by a hair.
+// every row is flushed as it is written, so a run that is stopped or fails
+// still leaves every row up to there
+
+pub struct ErrorCurve {
+    w: BufWriter<File>,
+    path: String,
+    step: u32,
+    /// the iteration of the last row, so the end isn't written twice
+    last: Option<u32>,
+}
+
+impl ErrorCurve {
+    /// create the csv at `path` with its header, a row is due every `step`
+    /// iterations
+    pub fn create(path: &str, step: u32) -> Result<Self, String> {
+        let file = File::create(path).map_err(|err| format!("unable to create `{path}`: {err}"))?;
+        let mut ret = Self {
+            w: BufWriter::new(file),
+            path: path.into(),
+            step,
+            last: None,
+        };
+        ret.line("iteration,leaves,mse,psnr")?;
+        Ok(ret)
+    }
+
+    /// write the row of the tree at iteration `i` if one is due
+    pub fn refined(&mut self, i: u32, tree: &Tree) -> Result<(), String> {
+        if i.is_multiple_of(self.step) {
+            self.row(i, tree)?;
+        }
+        Ok(())
+    }
+
+    /// write the row of the tree where refining stopped at iteration `i`,
+    /// unless it was already due
+    pub fn finish(&mut self, i: u32, tree: &Tree) -> Result<(), String> {
+        if self.last != Some(i) {
+            self.row(i, tree)?;
+        }
+        Ok(())
+    }
+
+    fn row(&mut self, i: u32, tree: &Tree) -> Result<(), String> {
+        let Some(mse) = tree.mse() else {
+            return Err("the tree has no image to measure its error against".into());
+        };
+        let psnr = psnr(mse);
+        let psnr = if psnr.is_infinite() {
+            "inf".into()
+        } else {
+            format!("{psnr:.4}")
+        };
+        self.line(&format!("{i},{},{mse:.6},{psnr}", tree.leaf_count()))?;
+        self.last = Some(i);
+        Ok(())
+    }
+
+    fn line(&mut self, line: &str) -> Result<(), String> {
+        writeln!(self.w, "{line}")
+            .and_then(|_| self.w.flush())
+            .map_err(|err| format!("error in writing `{}`: {err}", self.path))
+    }
+}
+
+/// the index of the knee of `curve`, a value that falls as refining goes on
+/// with one point per iteration from 0: where it is furthest below the
+/// chord from the first point to the last once both axes are scaled to 0..1,
+/// past which splits stop paying off as much as they did. 0 if it never
+/// fell, and the last point if it never fell faster than the chord, as then
+/// every split pays off as much
+pub fn knee(curve: &[f64]) -> usize {
+    let (Some(&first), Some(&last)) = (curve.first(), curve.last()) else {
+        return 0;
+    };
+    if first <= last {
+        return 0;
+    }
+    let n = (curve.len() - 1) as f64;
+    // the chord is 1 - x once the curve is scaled to end at 0
+    let below = |i: usize| (1.0 - i as f64 / n) - (curve[i] - last) / (first - last);
+    // a straight line is a hair off the chord from rounding, and shouldn't
+    // get a knee from it
+    let mut best = (curve.len() - 1, 1e-9);
+    for i in 0..curve.len() {
+        let d = below(i);
+        if d > best.1 {
+            best = (i, d);
+        }
+    }
+    best.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a curve of `len` points that falls in a straight line from 100 to
+    /// `at_knee` at point `knee`, and then in another to 0
+    fn bent(len: usize, knee: usize, at_knee: f64) -> Vec<f64> {
+        let last = (len - 1) as f64;
+        (0..len)
+            .map(|i| {
+                let i = i as f64;
+                let k = knee as f64;
+                if i <= k {
+                    100.0 - (100.0 - at_knee) * i / k
+                } else {
+                    at_knee * (last - i) / (last - k)
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bent_curves_have_their_knee_at_the_bend() {
+        for (len, k, at_knee) in [
+            (101, 20, 10.0),
+            (101, 50, 20.0),
+            (1001, 300, 5.0),
+            (11, 1, 50.0),
+        ] {
+            assert_eq!(knee(&bent(len, k, at_knee)), k, "{len} points bent at {k}");
+        }
+    }
+
+    #[test]
+    fn convex_curves_have_their_knee_where_they_level_off() {
+        // falls by half every 10 points, the chord is furthest above it
+        // where its slope matches the chord's
+        let curve: Vec<f64> = (0..=200).map(|i| 0.5f64.powf(i as f64 / 10.0)).collect();
+        let slope = curve[200] - curve[0];
+        let expected = (1..=200)
+            .find(|&i| curve[i] - curve[i - 1] > slope / 200.0)
+            .unwrap()
+            - 1;
+        let k = knee(&curve);
+        assert!(k.abs_diff(expected) <= 1, "{k} vs {expected}");
+        assert!((20..=60).contains(&k), "{k}");
+
+        // a steeper fall gets an earlier knee
+        let steeper: Vec<f64> = (0..=200).map(|i| 0.5f64.powf(i as f64 / 3.0)).collect();
+        assert!(knee(&steeper) < k);
+    }
+
+    #[test]
+    fn curves_without_a_bend_have_a_knee_at_an_end() {
+        assert_eq!(knee(&[]), 0);
+        assert_eq!(knee(&[5.0]), 0);
+        // never falls
+        assert_eq!(knee(&[5.0; 10]), 0);
+        assert_eq!(knee(&[1.0, 2.0, 3.0]), 0);
+        // every split pays off as much, or more later on
+        let straight: Vec<f64> = (0..50).map(|i| 100.0 - i as f64 * 0.37).collect();
+        assert_eq!(knee(&straight), 49);
+        let concave: Vec<f64> = (0..50).map(|i| 2500.0 - (i * i) as f64).collect();
+        assert_eq!(knee(&concave), 49);
+    }
+}
@@ -0,0 +1,230 @@
+use crate::image::RGB;
+
+// an adaptive range coder in the style of lzma's: every symbol is coded with
+// the frequencies its model has seen so far, so nothing but the coded bytes
+// has to be stored and the decoder learns the same frequencies as it goes
+
+/// ranges are kept at least this large, so dividing by a model total never
+/// loses more than a byte of precision
+const TOP: u32 = 1 << 24;
+/// added to a symbol's frequency every time it is coded
+const INCREMENT: u32 = 24;
+/// frequencies are halved once their total passes this, which keeps the
+/// division precise and lets models follow local changes
+const MAX_TOTAL: u32 = 1 << 16;
+
+pub struct RangeEncoder {
+    low: u64,
+    range: u32,
+    /// the byte that carries from `low` may still change, it and the 0xff
+    /// bytes after it are held back until they can't
+    cache: u8,
+    pending: u64,
+    out: Vec<u8>,
+}
+
+impl RangeEncoder {
+    pub fn new() -> Self {
+        Self {
+            low: 0,
+            range: u32::MAX,
+            cache: 0,
+            pending: 1,
+            out: Vec::new(),
+        }
+    }
+
+    fn encode(&mut self, cum: u32, freq: u32, total: u32) {
+        self.range /= total;
+        self.low += cum as u64 * self.range as u64;
+        self.range *= freq;
+        while self.range < TOP {
+            self.range <<= 8;
+            self.shift_low();
+        }
+    }
+
+    fn shift_low(&mut self) {
+        if self.low < 0xff00_0000 || self.low >= 1 << 32 {
+            let carry = (self.low >> 32) as u8;
+            let mut byte = self.cache;
+            while self.pending > 0 {
+                self.out.push(byte.wrapping_add(carry));
+                byte = 0xff;
+                self.pending -= 1;
+            }
+            self.cache = (self.low >> 24) as u8;
+        }
+        self.pending += 1;
+        self.low = (self.low & 0x00ff_ffff) << 8;
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        for _ in 0..5 {
+            self.shift_low();
+        }
+        self.out
+    }
+}
+
+pub struct RangeDecoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+    range: u32,
+    code: u32,
+}
+
+impl<'a> RangeDecoder<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        let mut ret = Self {
+            data,
+            pos: 0,
+            range: u32::MAX,
+            code: 0,
+        };
+        for _ in 0..5 {
+            ret.code = (ret.code << 8) | ret.next_byte() as u32;
+        }
+        ret
+    }
+
+    /// past the end of the data every byte reads as zero, `overrun` says if
+    /// that happened
+    fn next_byte(&mut self) -> u8 {
+        let ret = self.data.get(self.pos).copied().unwrap_or(0);
+        self.pos += 1;
+        ret
+    }
+
+    /// true if decoding needed more bytes than there are
+    pub fn overrun(&self) -> bool {
+        self.pos > self.data.len()
+    }
+
+    /// true if decoding stopped before the end of the data, an encoder
+    /// writes exactly as many bytes as its decoder reads
+    pub fn has_trailing_data(&self) -> bool {
+        self.pos < self.data.len()
+    }
+
+    /// where in `0..total` the next symbol falls, `consume` has to be called
+    /// with its range before anything else is decoded
+    fn target(&mut self, total: u32) -> u32 {
+        self.range /= total;
+        // only corrupt data can go past the end
+        (self.code / self.range).min(total - 1)
+    }
+
+    fn consume(&mut self, cum: u32, freq: u32) {
+        self.code -= cum * self.range;
+        self.range *= freq;
+        while self.range < TOP {
+            self.range <<= 8;
+            self.code = (self.code << 8) | self.next_byte() as u32;
+        }
+    }
+}
+
+/// frequencies of the symbols `0..n` seen so far, every symbol starts out
+/// as if it was seen once
+pub struct Model {
+    freqs: Vec<u32>,
+    total: u32,
+}
+
+impl Model {
+    pub fn new(symbols: usize) -> Self {
+        Self {
+            freqs: vec![1; symbols],
+            total: symbols as u32,
+        }
+    }
+
+    pub fn encode(&mut self, enc: &mut RangeEncoder, symbol: usize) {
+        let cum = self.freqs[..symbol].iter().sum();
+        enc.encode(cum, self.freqs[symbol], self.total);
+        self.update(symbol);
+    }
+
+    pub fn decode(&mut self, dec: &mut RangeDecoder) -> usize {
+        let target = dec.target(self.total);
+        let mut cum = 0;
+        let mut symbol = 0;
+        while cum + self.freqs[symbol] <= target {
+            cum += self.freqs[symbol];
+            symbol += 1;
+        }
+        dec.consume(cum, self.freqs[symbol]);
+        self.update(symbol);
+        symbol
+    }
+
+    fn update(&mut self, symbol: usize) {
+        self.freqs[symbol] += INCREMENT;
+        self.total += INCREMENT;
+        if self.total > MAX_TOTAL {
+            self.total = 0;
+            for f in self.freqs.iter_mut() {
+                *f = f.div_ceil(2);
+                self.total += *f;
+            }
+        }
+    }
+}
+
+/// codes a color as its difference from a prediction. green is coded first
+/// and red and blue as their difference from green's, since the channels of
+/// a region usually get brighter or darker together. differences wrap
+/// around, so every color can be coded against any prediction
+pub struct ColorModel {
+    /// one per kind of prediction
+    green: [Model; 2],
+    /// one per kind of prediction and size of green's difference
+    red: Vec<Model>,
+    blue: Vec<Model>,
+}
+
+/// sizes of green's difference that red and blue are coded separately for
+const GREEN_BUCKETS: usize = 8;
+
+impl ColorModel {
+    pub fn new() -> Self {
+        let models = || (0..2 * GREEN_BUCKETS).map(|_| Model::new(256)).collect();
+        Self {
+            green: [Model::new(256), Model::new(256)],
+            red: models(),
+            blue: models(),
+        }
+    }
+
+    /// the models red and blue use after green differed by `dg`
+    fn context(kind: usize, dg: u8) -> usize {
+        let size = (dg as i8).unsigned_abs();
+        let bucket = (u8::BITS - size.leading_zeros()) as usize;
+        kind * GREEN_BUCKETS + bucket.min(GREEN_BUCKETS - 1)
+    }
+
+    /// `kind` tells apart predictions that are usually close from ones that
+    /// usually aren't, each kind learns its own differences
+    pub fn encode(&mut self, enc: &mut RangeEncoder, color: RGB<u8>, pred: RGB<u8>, kind: usize) {
+        let dg = color.g.wrapping_sub(pred.g);
+        let dr = color.r.wrapping_sub(pred.r).wrapping_sub(dg);
+        let db = color.b.wrapping_sub(pred.b).wrapping_sub(dg);
+        let ctx = Self::context(kind, dg);
+        self.green[kind].encode(enc, dg as usize);
+        self.red[ctx].encode(enc, dr as usize);
+        self.blue[ctx].encode(enc, db as usize);
+    }
+
+    pub fn decode(&mut self, dec: &mut RangeDecoder, pred: RGB<u8>, kind: usize) -> RGB<u8> {
+        let dg = self.green[kind].decode(dec) as u8;
+        let ctx = Self::context(kind, dg);
+        let dr = self.red[ctx].decode(dec) as u8;
+        let db = self.blue[ctx].decode(dec) as u8;
+        RGB::new(
+            pred.r.wrapping_add(dr).wrapping_add(dg),
+            pred.g.wrapping_add(dg),
+            pred.b.wrapping_add(db).wrapping_add(dg),
+        )
+    }
+}
@@ -0,0 +1,60 @@
+use std::fmt;
+
+// why comprs failed, as far as a script calling it needs to know. each kind
+// has its own exit code so `case $?` can tell a missing file from one that
+// isn't an image without reading stderr. the codes are listed in -h
+
+/// something without a code of its own
+pub const FAILURE: i32 = 1;
+pub const USAGE: i32 = 2;
+pub const INPUT: i32 = 3;
+pub const DECODE: i32 = 4;
+pub const OUTPUT: i32 = 5;
+/// ctrl-c stopped refining, what was refined is saved
+pub const INTERRUPTED: i32 = 6;
+
+#[derive(Debug)]
+pub enum Error {
+    /// the flags or their values can't be used, together or with this input
+    Usage(String),
+    /// an input file, tree file or directory couldn't be opened or read
+    Input(String),
+    /// an input was read but isn't an image or tree comprs can decode
+    Decode(String),
+    /// an output couldn't be created or written
+    Output(String),
+    Other(String),
+}
+
+impl Error {
+    /// the exit code comprs ends with because of this
+    pub fn code(&self) -> i32 {
+        match self {
+            Error::Usage(_) => USAGE,
+            Error::Input(_) => INPUT,
+            Error::Decode(_) => DECODE,
+            Error::Output(_) => OUTPUT,
+            Error::Other(_) => FAILURE,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Usage(msg)
+            | Error::Input(msg)
+            | Error::Decode(msg)
+            | Error::Output(msg)
+            | Error::Other(msg) => f.write_str(msg),
+        }
+    }
+}
+
+/// the errors of helpers that don't say what went wrong, which are rare
+/// enough that `?` can pass them on as they are
+impl From<String> for Error {
+    fn from(msg: String) -> Self {
+        Error::Other(msg)
+    }
+}
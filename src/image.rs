@@ -1,12 +1,26 @@
 use std::{
+    fmt::Debug,
+    fs::File,
+    io::{BufReader, Read, Write},
     ops::{Add, Div, Mul, Sub},
-    vec,
+    sync::OnceLock,
 };
 
-use image::ImageReader;
+use crc32fast::Hasher;
 
-use crate::psa::{PrefixSum2D, Zero};
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, Rgb, RgbImage};
+use tiff::{
+    decoder::{Decoder, DecodingResult},
+    ColorType,
+};
+
+use crate::{
+    error::Error,
+    log::warning,
+    psa::{Accumulate, Fenwick2D, PrefixSum2D, SparseTable2D, Zero},
+};
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct RGB<T> {
     pub r: T,
@@ -32,6 +46,24 @@ impl From<RGB<u8>> for RGB<u64> {
     }
 }
 
+impl From<RGB<u8>> for RGB<u32> {
+    fn from(value: RGB<u8>) -> Self {
+        RGB::new(value.r.into(), value.g.into(), value.b.into())
+    }
+}
+
+impl From<RGB<u8>> for RGB<i64> {
+    fn from(value: RGB<u8>) -> Self {
+        RGB::new(value.r.into(), value.g.into(), value.b.into())
+    }
+}
+
+impl From<RGB<u32>> for RGB<u64> {
+    fn from(value: RGB<u32>) -> Self {
+        RGB::new(value.r.into(), value.g.into(), value.b.into())
+    }
+}
+
 impl<T: Add<Output = T>> Add for RGB<T> {
     type Output = Self;
     fn add(self, rhs: Self) -> Self::Output {
@@ -56,56 +88,615 @@ where
     }
 }
 
+impl<T: Accumulate> Accumulate for RGB<T> {
+    fn checked_add(self, rhs: Self) -> Option<Self> {
+        Some(Self::new(
+            self.r.checked_add(rhs.r)?,
+            self.g.checked_add(rhs.g)?,
+            self.b.checked_add(rhs.b)?,
+        ))
+    }
+}
+
 impl Zero for RGB<u64> {
     fn zero() -> Self {
         Self::new(0, 0, 0)
     }
 }
 
+impl Zero for RGB<u32> {
+    fn zero() -> Self {
+        Self::new(0, 0, 0)
+    }
+}
+
+/// for sums of differences between images, like a render and its residual,
+/// which go below zero
+impl Zero for RGB<i64> {
+    fn zero() -> Self {
+        Self::new(0, 0, 0)
+    }
+}
+
+/// the largest image whose square sums are guaranteed to fit in u64: the
+/// whole-image total of one channel is at most 255 * 255 per pixel, so this is
+/// u64::MAX / 65025 = 283,686,952,306,183 pixels. every other value (sums,
+/// means, variances, metrics) is bounded by the square sum. no input comes
+/// near it, MAX_INPUT_PIXELS is what limits them, and the bound that decides
+/// anything in practice is MAX_SMALL_PIXELS
+pub const MAX_PIXELS: u64 = u64::MAX / (255 * 255);
+
+/// the most pixels an input comprs can compress has. the image crate won't
+/// decode more than 512 MiB and no image takes less than a byte per pixel,
+/// so a tree or render larger than this didn't come from comprs
+pub const MAX_INPUT_PIXELS: u64 = 512 * 1024 * 1024;
+
+/// the largest image whose plain sums fit in u32, 255 per pixel per channel,
+/// u32::MAX / 255 = 16,843,009 pixels. this covers photos up to about 16
+/// megapixels, larger ones sum in u64. square sums are always u64
+const MAX_SMALL_PIXELS: u64 = u32::MAX as u64 / 255;
+
+/// the sum and square sum of a region side by side, so one prefix sum array
+/// holds both and a query reads one set of cache lines for the two
+#[derive(Debug, Clone, Copy)]
+struct Moments<T> {
+    sum: RGB<T>,
+    square_sum: RGB<u64>,
+}
+
+impl<T: Add<Output = T>> Add for Moments<T> {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self::Output {
+        Self {
+            sum: self.sum + rhs.sum,
+            square_sum: self.square_sum + rhs.square_sum,
+        }
+    }
+}
+
+impl<T: Sub<Output = T>> Sub for Moments<T> {
+    type Output = Self;
+    fn sub(self, rhs: Self) -> Self::Output {
+        Self {
+            sum: self.sum - rhs.sum,
+            square_sum: self.square_sum - rhs.square_sum,
+        }
+    }
+}
+
+/// the moments of a single pixel
+impl<T> From<RGB<u8>> for Moments<T>
+where
+    RGB<T>: From<RGB<u8>>,
+{
+    fn from(p: RGB<u8>) -> Self {
+        let (r, g, b) = (p.r as u64, p.g as u64, p.b as u64);
+        Self {
+            sum: p.into(),
+            square_sum: RGB::new(r * r, g * g, b * b),
+        }
+    }
+}
+
+impl<T> Accumulate for Moments<T>
+where
+    RGB<T>: Accumulate,
+{
+    fn checked_add(self, rhs: Self) -> Option<Self> {
+        Some(Self {
+            sum: self.sum.checked_add(rhs.sum)?,
+            square_sum: self.square_sum.checked_add(rhs.square_sum)?,
+        })
+    }
+}
+
+impl<T> Zero for Moments<T>
+where
+    RGB<T>: Zero,
+{
+    fn zero() -> Self {
+        Self {
+            sum: RGB::zero(),
+            square_sum: RGB::<u64>::zero(),
+        }
+    }
+}
+
+/// prefix sums with the narrowest sum type that can't overflow for the image
+enum MomentSums {
+    /// at most `MAX_SMALL_PIXELS`, a sixth less memory per pixel
+    Small(PrefixSum2D<Moments<u32>>),
+    Large(PrefixSum2D<Moments<u64>>),
+    /// after `ImageData::update_pixel`, slower to sum but pixels can change
+    Editable(Fenwick2D<Moments<u64>>),
+}
+
+impl MomentSums {
+    /// (height, width) of the image
+    fn dimensions(&self) -> (usize, usize) {
+        match self {
+            MomentSums::Small(psa) => (psa.height(), psa.width()),
+            MomentSums::Large(psa) => (psa.height(), psa.width()),
+            MomentSums::Editable(fenwick) => (fenwick.height(), fenwick.width()),
+        }
+    }
+
+    /// the sums of a rectangle. debug builds and the strict feature check it
+    /// first, so a bad rectangle panics saying what is wrong with it instead
+    /// of with an index deep in the prefix sums
+    fn query(&self, top_left: (usize, usize), bottom_right: (usize, usize)) -> Moments<u64> {
+        fn sum<T: Add<Output = T> + Sub<Output = T> + Copy + Debug>(
+            psa: &PrefixSum2D<Moments<T>>,
+            top_left: (usize, usize),
+            bottom_right: (usize, usize),
+        ) -> Moments<T>
+        where
+            RGB<T>: Zero,
+        {
+            if cfg!(any(debug_assertions, feature = "strict")) {
+                psa.try_query_sum(top_left, bottom_right)
+                    .unwrap_or_else(|err| {
+                        panic!("can't sum region {top_left:?} to {bottom_right:?}: {err}")
+                    })
+            } else {
+                psa.query_sum(top_left, bottom_right)
+            }
+        }
+        match self {
+            MomentSums::Small(psa) => {
+                let Moments { sum, square_sum } = sum(psa, top_left, bottom_right);
+                Moments {
+                    sum: sum.into(),
+                    square_sum,
+                }
+            }
+            MomentSums::Large(psa) => sum(psa, top_left, bottom_right),
+            MomentSums::Editable(fenwick) if cfg!(any(debug_assertions, feature = "strict")) => {
+                fenwick
+                    .try_query_sum(top_left, bottom_right)
+                    .unwrap_or_else(|err| {
+                        panic!("can't sum region {top_left:?} to {bottom_right:?}: {err}")
+                    })
+            }
+            MomentSums::Editable(fenwick) => fenwick.query_sum(top_left, bottom_right),
+        }
+    }
+}
+
+/// writes row `i` of `colors` as moments into a row of prefix sum cells
+fn moment_rows<T>(colors: &RgbImage) -> impl Fn(usize, &mut [Moments<T>]) + Sync + '_
+where
+    RGB<T>: From<RGB<u8>>,
+{
+    let row_len = colors.width() as usize * 3;
+    let samples = colors.as_raw();
+    move |i, row| {
+        let pixels = samples[i * row_len..(i + 1) * row_len].chunks_exact(3);
+        for (cell, p) in row.iter_mut().zip(pixels) {
+            *cell = RGB::new(p[0], p[1], p[2]).into();
+        }
+    }
+}
+
+/// prefix sums of every pixel of `colors` and its square, with sums of type
+/// `T`, built a row at a time. debug builds and the strict feature check
+/// every addition, the image size limits should make that never fail
+fn moment_sums<T>(colors: &RgbImage) -> Result<PrefixSum2D<Moments<T>>, String>
+where
+    T: Add<Output = T> + Sub<Output = T> + Copy + Debug + Send + Sync,
+    RGB<T>: From<RGB<u8>> + Zero + Accumulate,
+{
+    let (w, h) = colors.dimensions();
+    if cfg!(any(debug_assertions, feature = "strict")) {
+        PrefixSum2D::try_from_row_fn(h as usize, w as usize, moment_rows(colors))
+            .map_err(|err| err.to_string())
+    } else {
+        // with rayon, rows are summed on every core
+        PrefixSum2D::from_row_fn(h as usize, w as usize, moment_rows(colors))
+    }
+}
+
+/// a sum as `ImageData::write_cache` stores it, little endian
+trait Sample: Copy {
+    const BYTES: usize;
+    fn put(self, out: &mut Vec<u8>);
+    fn get(bytes: &[u8]) -> Self;
+}
+
+impl Sample for u32 {
+    const BYTES: usize = 4;
+    fn put(self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+    fn get(bytes: &[u8]) -> Self {
+        u32::from_le_bytes(bytes.try_into().unwrap())
+    }
+}
+
+impl Sample for u64 {
+    const BYTES: usize = 8;
+    fn put(self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+    fn get(bytes: &[u8]) -> Self {
+        u64::from_le_bytes(bytes.try_into().unwrap())
+    }
+}
+
+/// bytes a cell of `Moments<T>` takes in a cache file
+fn cell_bytes<T: Sample>() -> usize {
+    3 * T::BYTES + 3 * u64::BYTES
+}
+
+/// write the prefix sums in `psa` a row at a time, adding them to `crc`
+fn write_sums<T>(
+    psa: &PrefixSum2D<Moments<T>>,
+    w: &mut impl Write,
+    crc: &mut Hasher,
+) -> std::io::Result<()>
+where
+    T: Add<Output = T> + Sub<Output = T> + Copy + Debug + Sample,
+    RGB<T>: Zero,
+{
+    let stride = psa.width() + 1;
+    let mut buf = Vec::with_capacity(stride * cell_bytes::<T>());
+    for row in psa.raw().chunks_exact(stride) {
+        buf.clear();
+        for Moments { sum, square_sum } in row {
+            for v in [sum.r, sum.g, sum.b] {
+                v.put(&mut buf);
+            }
+            for v in [square_sum.r, square_sum.g, square_sum.b] {
+                v.put(&mut buf);
+            }
+        }
+        crc.update(&buf);
+        w.write_all(&buf)?;
+    }
+    Ok(())
+}
+
+/// read back what `write_sums` wrote for a `height` x `width` image
+fn read_sums<T>(
+    height: usize,
+    width: usize,
+    r: &mut impl Read,
+    crc: &mut Hasher,
+) -> Result<PrefixSum2D<Moments<T>>, String>
+where
+    T: Add<Output = T> + Sub<Output = T> + Copy + Debug + Sample,
+    RGB<T>: Zero,
+{
+    let stride = width + 1;
+    let mut data = Vec::with_capacity((height + 1) * stride);
+    let mut buf = vec![0; stride * cell_bytes::<T>()];
+    for _ in 0..=height {
+        r.read_exact(&mut buf)
+            .map_err(|_| "prefix sums are truncated")?;
+        crc.update(&buf);
+        data.extend(buf.chunks_exact(cell_bytes::<T>()).map(|cell| {
+            let sum = |k: usize| T::get(&cell[k * T::BYTES..(k + 1) * T::BYTES]);
+            let square =
+                |k: usize| u64::get(&cell[3 * T::BYTES + 8 * k..3 * T::BYTES + 8 * (k + 1)]);
+            Moments {
+                sum: RGB::new(sum(0), sum(1), sum(2)),
+                square_sum: RGB::new(square(0), square(1), square(2)),
+            }
+        }));
+    }
+    PrefixSum2D::from_raw(height, width, data)
+}
+
+/// bytes of prefix sums per pixel of an image with `pixels` pixels
+pub fn bytes_per_pixel(pixels: u64) -> usize {
+    if pixels <= MAX_SMALL_PIXELS {
+        std::mem::size_of::<Moments<u32>>()
+    } else {
+        std::mem::size_of::<Moments<u64>>()
+    }
+}
+
+/// the sums of an image. `view` makes a window of it that sums like a whole
+/// image
 pub struct ImageData {
     height: usize,
     width: usize,
-    sums: PrefixSum2D<RGB<u64>>,
-    square_sums: PrefixSum2D<RGB<u64>>,
+    moments: MomentSums,
+    /// averages of the aligned 2x2, 4x4, 8x8, ... blocks, a level for each
+    /// size. a level is built the first time `average` needs it
+    mips: Vec<OnceLock<Vec<RGB<u8>>>>,
+    /// min and max tables of red, green and blue, built the first time
+    /// `channel_range` needs them
+    extremes: OnceLock<[SparseTable2D<u8>; 3]>,
 }
 
+/// side of the tiles the min and max tables are kept for. a table for every
+/// pixel would take many times the memory of the prefix sums
+const EXTREMES_TILE: usize = 16;
+
 impl ImageData {
-    pub fn new(data: &Vec<Vec<RGB<u64>>>) -> Result<Self, String> {
-        let sums = PrefixSum2D::new(&data)?;
-        let squares = data
-            .into_iter()
-            .map(|row| row.into_iter().map(|x| x.comp_prod(*x)).collect())
-            .collect();
-        let square_sums = PrefixSum2D::new(&squares)?;
+    /// the image with rows of `data`, which all have to be as long as the
+    /// first and hold 8 bit colors. `from_rgb8` takes an image buffer
+    /// straight
+    pub fn new(data: &[Vec<RGB<u64>>]) -> Result<Self, String> {
+        let width = data.first().map_or(0, Vec::len);
+        let mut colors = RgbImage::new(width as u32, data.len() as u32);
+        for (y, row) in data.iter().enumerate() {
+            if row.len() != width {
+                return Err(format!(
+                    "row {y} has length {}, expected width {width}",
+                    row.len()
+                ));
+            }
+            for (x, c) in row.iter().enumerate() {
+                let channels = [c.r, c.g, c.b].map(u8::try_from);
+                let [Ok(r), Ok(g), Ok(b)] = channels else {
+                    return Err(format!("pixel {x},{y} isn't an 8 bit color"));
+                };
+                colors.put_pixel(x as u32, y as u32, Rgb([r, g, b]));
+            }
+        }
+        Self::from_rgb8(&colors)
+    }
+
+    pub fn from_rgb8(colors: &RgbImage) -> Result<Self, String> {
+        let (w, h) = colors.dimensions();
+        if w as u64 * h as u64 > MAX_PIXELS {
+            return Err(format!(
+                "image is too large, {w}x{h} is more than {MAX_PIXELS} pixels and would overflow the square sums"
+            ));
+        }
+        let moments = if w as u64 * h as u64 <= MAX_SMALL_PIXELS {
+            MomentSums::Small(moment_sums(colors)?)
+        } else {
+            MomentSums::Large(moment_sums(colors)?)
+        };
+        let (height, width) = moments.dimensions();
         Ok(Self {
-            height: sums.height(),
-            width: sums.width(),
-            sums,
-            square_sums,
+            height,
+            width,
+            moments,
+            mips: mip_levels(height, width),
+            extremes: OnceLock::new(),
         })
     }
 
-    pub fn from_path(path: &String) -> Result<Self, String> {
-        let Ok(img) = ImageReader::open(path) else {
-            return Err("unable to open image".into());
+    /// the window from `top_left` to `bottom_right` (inclusive) of this
+    /// image, which sums it as if it were the whole image. the sums aren't
+    /// copied, the view borrows them
+    pub fn view(
+        &self,
+        top_left: (usize, usize),
+        bottom_right: (usize, usize),
+    ) -> Result<ImageDataView<'_>, String> {
+        ImageDataView {
+            image: self,
+            height: self.height,
+            width: self.width,
+            origin: (0, 0),
+        }
+        .view(top_left, bottom_right)
+    }
+
+    /// store the prefix sums to be read back by `read_cache`, little endian:
+    ///
+    ///   bytes per plain sum (u8, 4 or 8), height, width (u32 each)
+    ///   every prefix sum row by row, the first row and column included: the
+    ///   sums of red, green and blue and then their square sums (u64)
+    ///   crc32 of every byte before it
+    ///
+    /// the sums change once a pixel is painted, so those can't be stored
+    pub fn write_cache(&self, mut w: impl Write) -> Result<(), String> {
+        let bytes = match self.moments {
+            MomentSums::Small(_) => u32::BYTES,
+            MomentSums::Large(_) => u64::BYTES,
+            MomentSums::Editable(_) => return Err("painted images aren't cached".into()),
         };
-        let Ok(decoded) = img.decode() else {
-            return Err("unable to decode image".into());
+        let mut header = vec![bytes as u8];
+        (self.height as u32).put(&mut header);
+        (self.width as u32).put(&mut header);
+        let mut crc = Hasher::new();
+        crc.update(&header);
+        let result = w.write_all(&header).and_then(|_| match &self.moments {
+            MomentSums::Small(psa) => write_sums(psa, &mut w, &mut crc),
+            MomentSums::Large(psa) => write_sums(psa, &mut w, &mut crc),
+            MomentSums::Editable(_) => unreachable!(),
+        });
+        result
+            .and_then(|_| w.write_all(&crc.finalize().to_le_bytes()))
+            .map_err(|err| err.to_string())
+    }
+
+    /// the image data `write_cache` stored, from a reader with `len` bytes
+    /// left. anything that isn't exactly what `from_rgb8` would have built
+    /// for an image of that size is an error, so a damaged file is never
+    /// used
+    pub fn read_cache(mut r: impl Read, len: u64) -> Result<Self, String> {
+        let mut header = [0; 9];
+        r.read_exact(&mut header)
+            .map_err(|_| "prefix sums are truncated")?;
+        let height = u32::get(&header[1..5]) as usize;
+        let width = u32::get(&header[5..9]) as usize;
+        let pixels = height as u64 * width as u64;
+        let bytes = if pixels <= MAX_SMALL_PIXELS {
+            u32::BYTES
+        } else {
+            u64::BYTES
         };
-        let Some(colors) = decoded.as_rgb8() else {
-            return Err("unable to convert image to RGB8".into());
+        if pixels == 0 || pixels > MAX_PIXELS || header[0] as usize != bytes {
+            return Err("prefix sums have a corrupt header".into());
+        }
+        // checked before reading, so a bad size never allocates
+        let cell = 3 * bytes as u64 + 3 * u64::BYTES as u64;
+        let expected = (height as u64 + 1) * (width as u64 + 1) * cell + 9 + 4;
+        if len != expected {
+            return Err(format!(
+                "prefix sums are {len} bytes where a {width}x{height} image needs {expected}"
+            ));
+        }
+        let mut crc = Hasher::new();
+        crc.update(&header);
+        let moments = if bytes == u32::BYTES {
+            MomentSums::Small(read_sums(height, width, &mut r, &mut crc)?)
+        } else {
+            MomentSums::Large(read_sums(height, width, &mut r, &mut crc)?)
         };
+        let mut stored = [0; 4];
+        r.read_exact(&mut stored)
+            .map_err(|_| "prefix sums are truncated")?;
+        let (stored, found) = (u32::from_le_bytes(stored), crc.finalize());
+        if stored != found {
+            return Err(format!(
+                "prefix sums are corrupt, their checksum is {found:08x} but was stored as {stored:08x}"
+            ));
+        }
+        Ok(Self {
+            height,
+            width,
+            moments,
+            mips: mip_levels(height, width),
+            extremes: OnceLock::new(),
+        })
+    }
 
-        let (w, h) = colors.dimensions();
-        let mut data = vec![vec![RGB::zero(); w as usize]; h as usize];
-        for (x, y, pixel) in colors.enumerate_pixels() {
-            let rgb = RGB::new(pixel[0], pixel[1], pixel[2]);
-            data[y as usize][x as usize] = rgb.into();
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// change the pixel at row `y` and column `x` to `color`. the first
+    /// change moves the sums into a structure that can take changes, which
+    /// answers every query after it in O(log height * log width) instead of
+    /// O(1), so images that never change don't pay for it
+    pub fn update_pixel(&mut self, y: usize, x: usize, color: RGB<u8>) -> Result<(), String> {
+        if y >= self.height || x >= self.width {
+            return Err(format!(
+                "pixel {x},{y} is outside the {}x{} image",
+                self.width, self.height
+            ));
+        }
+        let moments = &mut self.moments;
+        if !matches!(moments, MomentSums::Editable(_)) {
+            let (height, width) = moments.dimensions();
+            let fenwick = Fenwick2D::from_row_fn(height, width, |i, row| {
+                for (j, cell) in row.iter_mut().enumerate() {
+                    *cell = moments.query((i, j), (i, j));
+                }
+            })?;
+            *moments = MomentSums::Editable(fenwick);
         }
+        let old = moments.query((y, x), (y, x));
+        let MomentSums::Editable(fenwick) = moments else {
+            unreachable!()
+        };
+        fenwick.update((y, x), old, color.into());
+        // built again if they're needed
+        self.mips = mip_levels(self.height, self.width);
+        self.extremes = OnceLock::new();
+        Ok(())
+    }
 
-        Self::new(&data)
+    /// the smallest and largest value of each channel from `top_left` to
+    /// `bottom_right` (inclusive). nothing pays for the tables this needs
+    /// until the first call, which builds them in O(pixels). a query looks
+    /// through the pixels along the edges of the region, O(height + width)
+    pub fn channel_range(
+        &self,
+        top_left: (usize, usize),
+        bottom_right: (usize, usize),
+    ) -> (RGB<u8>, RGB<u8>) {
+        check_window(self.height, self.width, top_left, bottom_right);
+        let tables = self.extremes.get_or_init(|| {
+            let channel = |c: fn(RGB<u64>) -> u64| {
+                let pixel = |i, j| c(self.moments.query((i, j), (i, j)).sum) as u8;
+                // the sums are never of an empty image
+                SparseTable2D::tiled_from_fn(self.height, self.width, EXTREMES_TILE, pixel).unwrap()
+            };
+            [channel(|p| p.r), channel(|p| p.g), channel(|p| p.b)]
+        });
+        let [r, g, b] = tables
+            .each_ref()
+            .map(|t| t.query_min_max(top_left, bottom_right));
+        (RGB::new(r.0, g.0, b.0), RGB::new(r.1, g.1, b.1))
     }
 
+    /// the sums of a rectangle of this image
+    fn query(&self, top_left: (usize, usize), bottom_right: (usize, usize)) -> Moments<u64> {
+        check_window(self.height, self.width, top_left, bottom_right);
+        self.moments.query(top_left, bottom_right)
+    }
+
+    /// the average of the `side` x `side` block at `top_left` from the mip
+    /// level of that size, or `None` if the block isn't a power of two that
+    /// starts on a multiple of its side
+    fn mip_average(&self, top_left: (usize, usize), side: usize) -> Option<RGB<u64>> {
+        let (top, left) = top_left;
+        if !side.is_power_of_two() || top % side != 0 || left % side != 0 {
+            return None;
+        }
+        if top + side > self.height || left + side > self.width {
+            return None;
+        }
+        let level = self
+            .mips
+            .get((side.trailing_zeros() as usize).checked_sub(1)?)?;
+        let cols = self.width / side;
+        let blocks = level.get_or_init(|| {
+            let pixels = (side * side) as u64;
+            let rows = self.height / side;
+            (0..rows * cols)
+                .map(|i| {
+                    let (y, x) = (i / cols * side, i % cols * side);
+                    let sum = self.moments.query((y, x), (y + side - 1, x + side - 1)).sum;
+                    let avg = sum / pixels;
+                    // an average of 8 bit colors is one too
+                    RGB::new(avg.r as u8, avg.g as u8, avg.b as u8)
+                })
+                .collect()
+        });
+        Some(blocks[top / side * cols + left / side].into())
+    }
+}
+
+/// an empty mip level for every power of two block from 2x2 up to the
+/// largest that fits in a `height` x `width` image
+fn mip_levels(height: usize, width: usize) -> Vec<OnceLock<Vec<RGB<u8>>>> {
+    let side = height.min(width);
+    let levels = if side < 2 { 0 } else { side.ilog2() };
+    (0..levels).map(|_| OnceLock::new()).collect()
+}
+
+/// debug builds and the strict feature panic when `bottom_right` is outside
+/// a `height` x `width` window. the sums would catch most of those, but not
+/// ones that are still inside the image a view is of
+fn check_window(
+    height: usize,
+    width: usize,
+    top_left: (usize, usize),
+    bottom_right: (usize, usize),
+) {
+    if cfg!(any(debug_assertions, feature = "strict"))
+        && (bottom_right.0 >= height || bottom_right.1 >= width)
+    {
+        panic!("can't sum region {top_left:?} to {bottom_right:?}: it is outside the {width}x{height} window");
+    }
+}
+
+/// a window of an `ImageData` made by `view`, which sums it as if it were
+/// the whole image. positions are counted from the top left of the window
+#[derive(Clone, Copy)]
+pub struct ImageDataView<'a> {
+    image: &'a ImageData,
+    height: usize,
+    width: usize,
+    /// (row, column) of the window in the image
+    origin: (usize, usize),
+}
+
+impl<'a> ImageDataView<'a> {
     pub fn height(&self) -> usize {
         self.height
     }
@@ -114,29 +705,877 @@ impl ImageData {
         self.width
     }
 
-    pub fn sum(&self, top_left: (usize, usize), bottom_right: (usize, usize)) -> RGB<u64> {
-        self.sums.query_sum(top_left, bottom_right)
+    /// the window from `top_left` to `bottom_right` (inclusive) of this one,
+    /// as a view of the same image
+    pub fn view(
+        &self,
+        top_left: (usize, usize),
+        bottom_right: (usize, usize),
+    ) -> Result<ImageDataView<'a>, String> {
+        if bottom_right.0 >= self.height
+            || bottom_right.1 >= self.width
+            || top_left.0 > bottom_right.0
+            || top_left.1 > bottom_right.1
+        {
+            return Err(format!(
+                "{top_left:?} to {bottom_right:?} isn't a window of the {}x{} image",
+                self.width, self.height
+            ));
+        }
+        Ok(Self {
+            image: self.image,
+            height: bottom_right.0 - top_left.0 + 1,
+            width: bottom_right.1 - top_left.1 + 1,
+            origin: (self.origin.0 + top_left.0, self.origin.1 + top_left.1),
+        })
+    }
+
+    /// `top_left` and `bottom_right` of the window, where they are in the
+    /// image
+    fn translate(
+        &self,
+        top_left: (usize, usize),
+        bottom_right: (usize, usize),
+    ) -> ((usize, usize), (usize, usize)) {
+        check_window(self.height, self.width, top_left, bottom_right);
+        let (i, j) = self.origin;
+        (
+            (top_left.0 + i, top_left.1 + j),
+            (bottom_right.0 + i, bottom_right.1 + j),
+        )
     }
 
-    pub fn average(&self, top_left: (usize, usize), bottom_right: (usize, usize)) -> RGB<u64> {
+    /// `ImageData::channel_range` of a region of the window
+    pub fn channel_range(
+        &self,
+        top_left: (usize, usize),
+        bottom_right: (usize, usize),
+    ) -> (RGB<u8>, RGB<u8>) {
+        let (top_left, bottom_right) = self.translate(top_left, bottom_right);
+        self.image.channel_range(top_left, bottom_right)
+    }
+}
+
+/// an image a tree can refine, the whole of an `ImageData` or a view of
+/// one. positions are counted from its own top left
+pub trait RegionSource {
+    fn height(&self) -> usize;
+
+    fn width(&self) -> usize;
+
+    /// the sums of every channel and of their squares from `top_left` to
+    /// `bottom_right` (inclusive)
+    fn sums(&self, top_left: (usize, usize), bottom_right: (usize, usize)) -> (RGB<u64>, RGB<u64>);
+
+    fn sum(&self, top_left: (usize, usize), bottom_right: (usize, usize)) -> RGB<u64> {
+        self.sums(top_left, bottom_right).0
+    }
+
+    fn average(&self, top_left: (usize, usize), bottom_right: (usize, usize)) -> RGB<u64> {
+        // summed first, so a bad rectangle is caught there
+        let sum = self.sum(top_left, bottom_right);
         let height = (bottom_right.0 - top_left.0 + 1) as u64;
         let width = (bottom_right.1 - top_left.1 + 1) as u64;
-        /* println!(
-            "calc average, sum: {:?}, pixels: {}",
-            self.sum(top_left, bottom_right),
-            (height * width)
-        ); */
-        self.sum(top_left, bottom_right) / (height * width)
+        sum / (height * width)
     }
 
-    pub fn metric(&self, top_left: (usize, usize), bottom_right: (usize, usize)) -> u64 {
+    fn metric(&self, top_left: (usize, usize), bottom_right: (usize, usize)) -> u64 {
+        let (sum, square_sum) = self.sums(top_left, bottom_right);
         let height = (bottom_right.0 - top_left.0 + 1) as u64;
         let width = (bottom_right.1 - top_left.1 + 1) as u64;
-
-        let mean = self.sum(top_left, bottom_right) / (height * width);
-        let square_sum = self.square_sums.query_sum(top_left, bottom_right);
+        let mean = sum / (height * width);
 
         let variance = square_sum / (height * width) - mean.comp_prod(mean);
         (variance.r + variance.g + variance.b) * (height * width)
     }
+
+    /// the squared difference of every channel of every pixel from
+    /// `top_left` to `bottom_right` to `color`, summed. that is exactly the
+    /// error of filling the rectangle with `color`: square_sum - 2 * color *
+    /// sum + pixels * color^2 for each channel. `metric` is close to it for
+    /// the mean color, but rounds the mean and the variance down on the way
+    fn squared_error(
+        &self,
+        top_left: (usize, usize),
+        bottom_right: (usize, usize),
+        color: RGB<u8>,
+    ) -> u128 {
+        let (sum, square_sum) = self.sums(top_left, bottom_right);
+        let pixels =
+            ((bottom_right.0 - top_left.0 + 1) * (bottom_right.1 - top_left.1 + 1)) as u128;
+        let channel = |square_sum: u64, sum: u64, c: u8| {
+            let c = c as u128;
+            // a sum of squares, so this is never below zero
+            square_sum as u128 + pixels * c * c - 2 * c * sum as u128
+        };
+        channel(square_sum.r, sum.r, color.r)
+            + channel(square_sum.g, sum.g, color.g)
+            + channel(square_sum.b, sum.b, color.b)
+    }
+}
+
+impl RegionSource for ImageData {
+    fn height(&self) -> usize {
+        self.height
+    }
+
+    fn width(&self) -> usize {
+        self.width
+    }
+
+    fn sums(&self, top_left: (usize, usize), bottom_right: (usize, usize)) -> (RGB<u64>, RGB<u64>) {
+        let Moments { sum, square_sum } = self.query(top_left, bottom_right);
+        (sum, square_sum)
+    }
+
+    /// aligned power of two squares come from the mip levels without
+    /// touching the sums, anything else is summed
+    fn average(&self, top_left: (usize, usize), bottom_right: (usize, usize)) -> RGB<u64> {
+        let height = bottom_right.0.wrapping_sub(top_left.0).wrapping_add(1);
+        let width = bottom_right.1.wrapping_sub(top_left.1).wrapping_add(1);
+        if height == width {
+            if let Some(avg) = self.mip_average(top_left, height) {
+                return avg;
+            }
+        }
+        let sum = self.sum(top_left, bottom_right);
+        sum / (height * width) as u64
+    }
+}
+
+impl RegionSource for ImageDataView<'_> {
+    fn height(&self) -> usize {
+        self.height
+    }
+
+    fn width(&self) -> usize {
+        self.width
+    }
+
+    fn sums(&self, top_left: (usize, usize), bottom_right: (usize, usize)) -> (RGB<u64>, RGB<u64>) {
+        let (top_left, bottom_right) = self.translate(top_left, bottom_right);
+        self.image.sums(top_left, bottom_right)
+    }
+
+    fn average(&self, top_left: (usize, usize), bottom_right: (usize, usize)) -> RGB<u64> {
+        let (top_left, bottom_right) = self.translate(top_left, bottom_right);
+        self.image.average(top_left, bottom_right)
+    }
+}
+
+/// peak signal to noise ratio in decibels of 8 bit channels that differ by
+/// `mse`, infinite when they don't differ at all
+pub fn psnr(mse: f64) -> f64 {
+    10.0 * (255.0 * 255.0 / mse).log10()
+}
+
+/// the mean structural similarity of the luma of two images of the same
+/// size, as Wang et al. define it: over every place an 11x11 gaussian window
+/// with a sigma of 1.5 fits, with the constants for 8 bit channels. 1 when
+/// they are the same, images under 11 pixels across get the largest odd
+/// window that fits
+pub fn ssim(a: &RgbImage, b: &RgbImage) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+    debug_assert_eq!(a.dimensions(), b.dimensions());
+    let (w, h) = (a.width() as usize, a.height() as usize);
+    if w == 0 || h == 0 {
+        return 1.0;
+    }
+    let r = 5.min((w - 1) / 2).min((h - 1) / 2);
+    let n = 2 * r + 1;
+    let kernel: Vec<f64> = (0..n)
+        .map(|k| (-((k as f64 - r as f64).powi(2)) / (2.0 * 1.5 * 1.5)).exp())
+        .collect();
+    let total: f64 = kernel.iter().sum();
+    let kernel: Vec<f64> = kernel.iter().map(|g| g / total).collect();
+    let luma = |img: &RgbImage, x: usize, y: usize| {
+        let [r, g, b] = img.get_pixel(x as u32, y as u32).0;
+        0.299 * r as f64 + 0.587 * g as f64 + 0.114 * b as f64
+    };
+
+    // the gaussian is separable, so every row is filtered across and the
+    // last `n` of those down. each holds the means of a, b, a², b² and ab
+    let cols = w - 2 * r;
+    let mut rows = vec![vec![[0.0; 5]; cols]; n];
+    let mut sum = 0.0;
+    for y in 0..h {
+        let moments: Vec<[f64; 5]> = (0..w)
+            .map(|x| {
+                let (p, q) = (luma(a, x, y), luma(b, x, y));
+                [p, q, p * p, q * q, p * q]
+            })
+            .collect();
+        let row = &mut rows[y % n];
+        for (x, out) in row.iter_mut().enumerate() {
+            *out = [0.0; 5];
+            for (g, m) in kernel.iter().zip(&moments[x..x + n]) {
+                for (o, v) in out.iter_mut().zip(m) {
+                    *o += g * v;
+                }
+            }
+        }
+        if y + 1 < n {
+            continue;
+        }
+        for x in 0..cols {
+            let mut m = [0.0; 5];
+            for (k, g) in kernel.iter().enumerate() {
+                let row = &rows[(y + 1 + k) % n];
+                for (o, v) in m.iter_mut().zip(&row[x]) {
+                    *o += g * v;
+                }
+            }
+            let [mu_a, mu_b, aa, bb, ab] = m;
+            let var_a = aa - mu_a * mu_a;
+            let var_b = bb - mu_b * mu_b;
+            let cov = ab - mu_a * mu_b;
+            sum += (2.0 * mu_a * mu_b + C1) * (2.0 * cov + C2)
+                / ((mu_a * mu_a + mu_b * mu_b + C1) * (var_a + var_b + C2));
+        }
+    }
+    sum / (cols * (h - 2 * r)) as f64
+}
+
+/// decode the image at `path` into RGB8
+pub fn load_rgb8(path: &str) -> Result<RgbImage, Error> {
+    match ImageFormat::from_path(path) {
+        Ok(ImageFormat::Tiff) => decode_tiff(path),
+        _ => decode_image(path),
+    }
+}
+
+/// the width, height and color type of the image at `path`, read from its
+/// header without decoding the pixels
+pub fn probe(path: &str) -> Result<(u32, u32, String), Error> {
+    if let Ok(ImageFormat::Tiff) = ImageFormat::from_path(path) {
+        let Ok(file) = File::open(path) else {
+            return Err(Error::Input(format!("unable to open image `{path}`")));
+        };
+        let Ok(mut decoder) = Decoder::new(BufReader::new(file)) else {
+            return Err(Error::Decode("unable to decode tiff".into()));
+        };
+        return match (decoder.dimensions(), decoder.colortype()) {
+            (Ok((w, h)), Ok(color_type)) => Ok((w, h, format!("{color_type:?}"))),
+            _ => Err(Error::Decode("unable to read tiff header".into())),
+        };
+    }
+    let Ok(img) = ImageReader::open(path) else {
+        return Err(Error::Input(format!("unable to open image `{path}`")));
+    };
+    let Ok(decoder) = img.into_decoder() else {
+        return Err(Error::Decode("unable to decode image".into()));
+    };
+    let (w, h) = decoder.dimensions();
+    Ok((w, h, format!("{:?}", decoder.color_type())))
+}
+
+fn decode_image(path: &str) -> Result<RgbImage, Error> {
+    let Ok(img) = ImageReader::open(path) else {
+        return Err(Error::Input(format!("unable to open image `{path}`")));
+    };
+    let Ok(decoded) = img.decode() else {
+        return Err(Error::Decode("unable to decode image".into()));
+    };
+    Ok(to_rgb8(decoded))
+}
+
+/// convert any decoded image to RGB8, anything above 8 bits per channel
+/// goes through the 16-bit path and is rounded down to 8 bits
+fn to_rgb8(decoded: DynamicImage) -> RgbImage {
+    if decoded.color().bytes_per_pixel() / decoded.color().channel_count() <= 1 {
+        return decoded.into_rgb8();
+    }
+
+    let wide = decoded.into_rgb16();
+    let (w, h) = wide.dimensions();
+    let narrow = wide.into_raw().into_iter().map(u16_to_u8).collect();
+    RgbImage::from_raw(w, h, narrow).expect("buffer has the same dimensions")
+}
+
+fn u16_to_u8(v: u16) -> u8 {
+    scale(v.into(), u16::MAX.into())
+}
+
+/// rescale a sample in [0, max] to [0, 255]
+fn scale(x: u32, max: u32) -> u8 {
+    ((x * 255 + max / 2) / max) as u8
+}
+
+/// standard CMYK -> RGB conversion, all channels normalized to [0, max]
+fn cmyk_to_rgb(c: u32, m: u32, y: u32, k: u32, max: u32) -> [u8; 3] {
+    let channel =
+        |x: u32| ((max - x) as u64 * (max - k) as u64 * 255 / (max as u64 * max as u64)) as u8;
+    [channel(c), channel(m), channel(y)]
+}
+
+type SampleToRgb = fn(&[u32], u32) -> [u8; 3];
+
+/// decode the first page of a tiff, the image crate can't handle CMYK at
+/// depths above 8 bits so colors are converted by hand. anything else falls
+/// back to the image crate
+fn decode_tiff(path: &str) -> Result<RgbImage, Error> {
+    let Ok(file) = File::open(path) else {
+        return Err(Error::Input(format!("unable to open image `{path}`")));
+    };
+    let Ok(mut decoder) = Decoder::new(BufReader::new(file)) else {
+        return Err(Error::Decode("unable to decode tiff".into()));
+    };
+    if decoder.more_images() {
+        warning!("tiff has multiple pages, only the first page is used");
+    }
+
+    let Ok((w, h)) = decoder.dimensions() else {
+        return Err(Error::Decode("unable to read tiff dimensions".into()));
+    };
+    let Ok(color_type) = decoder.colortype() else {
+        return Err(Error::Decode("unable to read tiff color type".into()));
+    };
+    // palettes, YCbCr and the like are left to the image crate
+    let (channels, rgb): (usize, SampleToRgb) = match color_type {
+        ColorType::Gray(_) => (1, |p, max| [scale(p[0], max); 3]),
+        ColorType::GrayA(_) => (2, |p, max| [scale(p[0], max); 3]),
+        ColorType::RGB(_) => (3, |p, max| [p[0], p[1], p[2]].map(|x| scale(x, max))),
+        ColorType::RGBA(_) => (4, |p, max| [p[0], p[1], p[2]].map(|x| scale(x, max))),
+        ColorType::CMYK(_) => (4, |p, max| cmyk_to_rgb(p[0], p[1], p[2], p[3], max)),
+        _ => return decode_image(path),
+    };
+    let Ok(result) = decoder.read_image() else {
+        return Err(Error::Decode("unable to decode tiff".into()));
+    };
+
+    // widen everything to u32 so 8 and 16 bit samples share one path, and
+    // leave float and 32 bit samples to the image crate too
+    let (samples, max): (Vec<u32>, u32) = match result {
+        DecodingResult::U8(v) => (v.into_iter().map(u32::from).collect(), u8::MAX as u32),
+        DecodingResult::U16(v) => (v.into_iter().map(u32::from).collect(), u16::MAX as u32),
+        _ => return decode_image(path),
+    };
+
+    let pixels: Vec<u8> = samples
+        .chunks_exact(channels)
+        .flat_map(|p| rgb(p, max))
+        .collect();
+    match RgbImage::from_raw(w, h, pixels) {
+        Some(img) => Ok(img),
+        None => Err(Error::Decode("tiff has the wrong number of samples".into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use tiff::encoder::{colortype, TiffEncoder};
+
+    use super::*;
+    use crate::psa::BuildError;
+
+    /// a file in the temp directory that is removed when dropped
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            let name = format!("comprs-{}-{name}", std::process::id());
+            Self(std::env::temp_dir().join(name))
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    /// a 2x2 tiff of `samples` in the color type `C`, one page per entry
+    fn write_tiff<C>(name: &str, pages: &[&[C::Inner]]) -> TempFile
+    where
+        C: colortype::ColorType,
+        [C::Inner]: tiff::encoder::TiffValue,
+    {
+        let file = TempFile::new(name);
+        let mut encoder = TiffEncoder::new(fs::File::create(&file.0).unwrap()).unwrap();
+        for samples in pages {
+            encoder.write_image::<C>(2, 2, samples).unwrap();
+        }
+        file
+    }
+
+    fn rgb8([r, g, b]: [u8; 3]) -> RGB<u8> {
+        RGB::new(r, g, b)
+    }
+
+    fn pixels(img: &RgbImage) -> Vec<[u8; 3]> {
+        img.pixels().map(|p| p.0).collect()
+    }
+
+    /// a width x height image whose pixels aren't all the same
+    fn pattern(width: u32, height: u32) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, y| {
+            Rgb([(x * 37 + y * 11) as u8, (x * y) as u8, (200 + x - y) as u8])
+        })
+    }
+
+    /// every region of `a` and `b` sums and scores the same
+    fn assert_same_sums(a: &impl RegionSource, b: &impl RegionSource) {
+        assert_eq!((a.height(), a.width()), (b.height(), b.width()));
+        for top in 0..a.height() {
+            for left in 0..a.width() {
+                for bottom in top..a.height() {
+                    for right in left..a.width() {
+                        let (tl, br) = ((top, left), (bottom, right));
+                        assert_eq!(a.sum(tl, br), b.sum(tl, br));
+                        assert_eq!(a.metric(tl, br), b.metric(tl, br));
+                        assert_eq!(a.average(tl, br), b.average(tl, br));
+                    }
+                }
+            }
+        }
+    }
+
+    /// `range` gives the min and max of each channel of every region of
+    /// `colors`, found by looking at every pixel
+    fn assert_channel_ranges(
+        range: impl Fn((usize, usize), (usize, usize)) -> (RGB<u8>, RGB<u8>),
+        colors: &RgbImage,
+    ) {
+        let (width, height) = (colors.width() as usize, colors.height() as usize);
+        for top in 0..height {
+            for left in 0..width {
+                for bottom in top..height {
+                    for right in left..width {
+                        let region: Vec<[u8; 3]> = (top..=bottom)
+                            .flat_map(|y| (left..=right).map(move |x| (x as u32, y as u32)))
+                            .map(|(x, y)| colors.get_pixel(x, y).0)
+                            .collect();
+                        let channel = |c: usize| region.iter().map(move |p| p[c]);
+                        let min = [0, 1, 2].map(|c| channel(c).min().unwrap());
+                        let max = [0, 1, 2].map(|c| channel(c).max().unwrap());
+                        let (tl, br) = ((top, left), (bottom, right));
+                        assert_eq!(range(tl, br), (rgb8(min), rgb8(max)), "{tl:?} to {br:?}");
+                    }
+                }
+            }
+        }
+    }
+
+    /// the sums and square sums in two prefix sum arrays of their own rather
+    /// than side by side in one, so a region reads two sets of cache lines
+    struct TwoPlanes {
+        sum: PrefixSum2D<RGB<u64>>,
+        square_sum: PrefixSum2D<RGB<u64>>,
+    }
+
+    impl TwoPlanes {
+        fn new(colors: &RgbImage) -> Self {
+            let (h, w) = (colors.height() as usize, colors.width() as usize);
+            let pixel = |i: usize, j: usize| {
+                let [r, g, b] = colors.get_pixel(j as u32, i as u32).0;
+                RGB::new(r as u64, g as u64, b as u64)
+            };
+            Self {
+                sum: PrefixSum2D::from_fn(h, w, pixel).unwrap(),
+                square_sum: PrefixSum2D::from_fn(h, w, |i, j| pixel(i, j).comp_prod(pixel(i, j)))
+                    .unwrap(),
+            }
+        }
+    }
+
+    impl RegionSource for TwoPlanes {
+        fn height(&self) -> usize {
+            self.sum.height()
+        }
+
+        fn width(&self) -> usize {
+            self.sum.width()
+        }
+
+        fn sums(
+            &self,
+            top_left: (usize, usize),
+            bottom_right: (usize, usize),
+        ) -> (RGB<u64>, RGB<u64>) {
+            (
+                self.sum.query_sum(top_left, bottom_right),
+                self.square_sum.query_sum(top_left, bottom_right),
+            )
+        }
+    }
+
+    #[test]
+    fn interleaved_moments_match_two_planes() {
+        for (width, height) in [(1, 1), (9, 1), (1, 7), (12, 10)] {
+            let colors = pattern(width, height);
+            let planes = TwoPlanes::new(&colors);
+            let small = ImageData::from_rgb8(&colors).unwrap();
+            assert!(matches!(small.moments, MomentSums::Small(_)));
+            let large = ImageData {
+                height: height as usize,
+                width: width as usize,
+                moments: MomentSums::Large(moment_sums(&colors).unwrap()),
+                mips: mip_levels(height as usize, width as usize),
+                extremes: OnceLock::new(),
+            };
+            for data in [&small, &large] {
+                assert_same_sums(data, &planes);
+                let (h, w) = (height as usize, width as usize);
+                for (tl, br) in [((0, 0), (h - 1, w - 1)), ((h / 2, w / 3), (h - 1, w / 2))] {
+                    assert_eq!(data.sums(tl, br), planes.sums(tl, br));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sums_past_the_limits_overflow() {
+        // MAX_PIXELS and MAX_SMALL_PIXELS divide the largest sum by the most
+        // a pixel adds. scaled down to 2x3, a sixth of the largest sum per
+        // pixel fits and a little more overflows at the last cell
+        let square = |v: u64| Moments {
+            sum: RGB::new(0u64, 0, 0),
+            square_sum: RGB::new(0, v, 0),
+        };
+        let sum = |v: u32| Moments {
+            sum: RGB::new(0, 0, v),
+            square_sum: RGB::new(0, 0, 0),
+        };
+        let fits = PrefixSum2D::try_from_row_fn(2, 3, |_, row| row.fill(square(u64::MAX / 6)));
+        let total = fits.unwrap().query_sum((0, 0), (1, 2));
+        assert_eq!(total.square_sum.g, u64::MAX / 6 * 6);
+        let over = PrefixSum2D::try_from_row_fn(2, 3, |_, row| row.fill(square(u64::MAX / 6 + 1)));
+        assert_eq!(over.err(), Some(BuildError::Overflow { row: 1, column: 2 }));
+
+        let fits = PrefixSum2D::try_from_row_fn(2, 3, |_, row| row.fill(sum(u32::MAX / 6)));
+        assert!(fits.is_ok());
+        let over = PrefixSum2D::try_from_row_fn(2, 3, |_, row| row.fill(sum(u32::MAX / 6 + 1)));
+        assert_eq!(over.err(), Some(BuildError::Overflow { row: 1, column: 2 }));
+    }
+
+    #[test]
+    fn checked_and_unchecked_moment_sums_agree() {
+        // debug builds only take the checked path, so the other is built here
+        for (width, height) in [(1, 1), (13, 1), (7, 9)] {
+            let colors = pattern(width, height);
+            let (w, h) = (width as usize, height as usize);
+            let checked: PrefixSum2D<Moments<u32>> = moment_sums(&colors).unwrap();
+            let unchecked = PrefixSum2D::from_row_fn(h, w, moment_rows(&colors)).unwrap();
+            for (a, b) in checked.raw().iter().zip(unchecked.raw()) {
+                assert_eq!((a.sum, a.square_sum), (b.sum, b.square_sum));
+            }
+            let total = checked.query_sum((0, 0), (h - 1, w - 1));
+            let brute = colors.pixels().fold(RGB::new(0, 0, 0), |acc, p| {
+                let [r, g, b] = p.0.map(u32::from);
+                RGB::new(acc.r + r, acc.g + g, acc.b + b)
+            });
+            assert_eq!(total.sum, brute);
+        }
+    }
+
+    /// every aligned power of two square of `data` averages the same as its
+    /// sum over its pixels
+    fn assert_mip_averages(data: &ImageData) {
+        let mut side = 2;
+        while side <= data.height().min(data.width()) {
+            for top in (0..=data.height() - side).step_by(side) {
+                for left in (0..=data.width() - side).step_by(side) {
+                    let (tl, br) = ((top, left), (top + side - 1, left + side - 1));
+                    let exact = data.sum(tl, br) / (side * side) as u64;
+                    assert_eq!(data.average(tl, br), exact, "{side}x{side} at {tl:?}");
+                }
+            }
+            side *= 2;
+        }
+    }
+
+    #[test]
+    fn mip_averages_match_exact_queries() {
+        for (width, height) in [(1, 9), (2, 2), (45, 37), (64, 64), (130, 17)] {
+            let data = ImageData::from_rgb8(&pattern(width, height)).unwrap();
+            assert!(data.mips.iter().all(|level| level.get().is_none()));
+            assert_mip_averages(&data);
+            assert!(data.mips.iter().all(|level| level.get().is_some()));
+        }
+    }
+
+    #[test]
+    fn mip_levels_are_built_when_first_used() {
+        let data = ImageData::from_rgb8(&pattern(45, 37)).unwrap();
+        // 2x2 up to 32x32
+        assert_eq!(data.mips.len(), 5);
+        // not square, aligned or a power of two, so summed
+        for (tl, br) in [((0, 0), (3, 1)), ((1, 1), (4, 4)), ((0, 0), (2, 2))] {
+            data.average(tl, br);
+        }
+        assert!(data.mips.iter().all(|level| level.get().is_none()));
+        data.average((8, 4), (11, 7));
+        let built: Vec<bool> = data
+            .mips
+            .iter()
+            .map(|level| level.get().is_some())
+            .collect();
+        assert_eq!(built, [false, true, false, false, false]);
+    }
+
+    #[test]
+    fn mip_averages_follow_painted_pixels() {
+        let mut data = ImageData::from_rgb8(&pattern(16, 12)).unwrap();
+        assert_mip_averages(&data);
+        for (x, y, p) in [(0, 0, [255, 0, 128]), (15, 11, [0, 255, 7])] {
+            data.update_pixel(y, x, rgb8(p)).unwrap();
+            assert_mip_averages(&data);
+        }
+    }
+
+    #[test]
+    fn channel_range_matches_every_pixel() {
+        // wider than a tile, so regions use the tile table and the pixels
+        for (width, height) in [(1, 1), (23, 2), (19, 17)] {
+            let colors = pattern(width, height);
+            let data = ImageData::from_rgb8(&colors).unwrap();
+            assert_channel_ranges(|tl, br| data.channel_range(tl, br), &colors);
+        }
+    }
+
+    #[test]
+    fn channel_range_follows_painted_pixels() {
+        let mut colors = pattern(18, 3);
+        let mut data = ImageData::from_rgb8(&colors).unwrap();
+        assert_channel_ranges(|tl, br| data.channel_range(tl, br), &colors);
+        for (x, y, p) in [(0, 0, [255, 0, 128]), (17, 2, [0, 255, 7])] {
+            data.update_pixel(y as usize, x as usize, rgb8(p)).unwrap();
+            colors.put_pixel(x, y, Rgb(p));
+            assert_channel_ranges(|tl, br| data.channel_range(tl, br), &colors);
+        }
+    }
+
+    #[test]
+    fn views_sum_like_a_cropped_copy() {
+        let colors = pattern(21, 18);
+        let data = ImageData::from_rgb8(&colors).unwrap();
+        for (x, y, w, h) in [
+            (0, 0, 21, 18),
+            (3, 5, 1, 1),
+            (4, 2, 12, 1),
+            (17, 0, 4, 18),
+            (5, 6, 16, 12),
+        ] {
+            let view = data.view((y, x), (y + h - 1, x + w - 1)).unwrap();
+            let crop = image::imageops::crop_imm(&colors, x as u32, y as u32, w as u32, h as u32)
+                .to_image();
+            assert_same_sums(&view, &ImageData::from_rgb8(&crop).unwrap());
+            assert_channel_ranges(|tl, br| view.channel_range(tl, br), &crop);
+        }
+    }
+
+    #[test]
+    fn views_of_views_are_views_of_the_image() {
+        let data = ImageData::from_rgb8(&pattern(21, 18)).unwrap();
+        let outer = data.view((2, 3), (15, 20)).unwrap();
+        let nested = outer.view((1, 1), (4, 6)).unwrap();
+        let direct = data.view((3, 4), (6, 9)).unwrap();
+        assert_same_sums(&nested, &direct);
+    }
+
+    #[test]
+    fn views_outside_the_image_are_errors() {
+        let data = ImageData::from_rgb8(&pattern(21, 18)).unwrap();
+        let err = data.view((0, 0), (18, 0)).err().unwrap();
+        assert_eq!(err, "(0, 0) to (18, 0) isn't a window of the 21x18 image");
+        let err = data.view((5, 5), (4, 9)).err().unwrap();
+        assert_eq!(err, "(5, 5) to (4, 9) isn't a window of the 21x18 image");
+        // inside the image but not the window
+        let view = data.view((2, 3), (5, 8)).unwrap();
+        let err = view.view((0, 0), (4, 0)).err().unwrap();
+        assert_eq!(err, "(0, 0) to (4, 0) isn't a window of the 6x4 image");
+    }
+
+    #[test]
+    #[cfg(any(debug_assertions, feature = "strict"))]
+    #[should_panic(expected = "it is outside the 6x4 window")]
+    fn views_panic_on_regions_outside_the_window() {
+        let data = ImageData::from_rgb8(&pattern(21, 18)).unwrap();
+        let view = data.view((2, 3), (5, 8)).unwrap();
+        view.sum((0, 0), (4, 5));
+    }
+
+    /// `data` as `write_cache` stores it
+    fn cached(data: &ImageData) -> Vec<u8> {
+        let mut ret = Vec::new();
+        data.write_cache(&mut ret).unwrap();
+        ret
+    }
+
+    #[test]
+    fn cached_sums_read_back_the_same() {
+        for (width, height) in [(1, 1), (1, 17), (23, 1), (21, 18)] {
+            let data = ImageData::from_rgb8(&pattern(width, height)).unwrap();
+            let bytes = cached(&data);
+            let read = ImageData::read_cache(bytes.as_slice(), bytes.len() as u64).unwrap();
+            assert_same_sums(&read, &data);
+            assert_eq!(cached(&read), bytes, "{width}x{height}");
+        }
+    }
+
+    #[test]
+    fn damaged_cached_sums_are_errors() {
+        let data = ImageData::from_rgb8(&pattern(7, 5)).unwrap();
+        let bytes = cached(&data);
+        let read = |bytes: &[u8]| ImageData::read_cache(bytes, bytes.len() as u64).err();
+        let expected = bytes.len();
+        assert_eq!(read(&[]).unwrap(), "prefix sums are truncated");
+        assert_eq!(read(&bytes[..5]).unwrap(), "prefix sums are truncated");
+        for cut in [9, expected / 2, expected - 1] {
+            assert_eq!(
+                read(&bytes[..cut]).unwrap(),
+                format!("prefix sums are {cut} bytes where a 7x5 image needs {expected}")
+            );
+        }
+        // told the right length, but it ends early
+        let short = ImageData::read_cache(&bytes[..expected - 1], expected as u64);
+        assert_eq!(short.err().unwrap(), "prefix sums are truncated");
+
+        // a byte anywhere in the sums or the checksum
+        for at in [9, 10, expected / 2, expected - 5, expected - 1] {
+            let mut flipped = bytes.clone();
+            flipped[at] ^= 0x10;
+            let err = read(&flipped).unwrap();
+            assert!(err.starts_with("prefix sums are corrupt"), "{at}: {err}");
+        }
+        // the size and the width of the sums
+        for (at, value) in [(0, 8), (0, 0), (1, 0), (5, 0), (1, 6)] {
+            let mut header = bytes.clone();
+            header[at] = value;
+            assert!(read(&header).is_some(), "{at}: {value}");
+        }
+        let mut zero = bytes.clone();
+        zero[1..5].fill(0);
+        assert_eq!(read(&zero).unwrap(), "prefix sums have a corrupt header");
+    }
+
+    #[test]
+    fn painted_images_arent_cached() {
+        let mut data = ImageData::from_rgb8(&pattern(4, 3)).unwrap();
+        data.update_pixel(1, 1, RGB::new(1, 2, 3)).unwrap();
+        let err = data.write_cache(&mut Vec::new()).err().unwrap();
+        assert_eq!(err, "painted images aren't cached");
+    }
+
+    #[test]
+    fn new_matches_from_rgb8() {
+        let colors = pattern(6, 5);
+        let rows: Vec<Vec<RGB<u64>>> = (0..5)
+            .map(|y| {
+                let pixel = |x| colors.get_pixel(x, y).0;
+                (0..6).map(|x| rgb8(pixel(x)).into()).collect()
+            })
+            .collect();
+        let from_rows = ImageData::new(&rows).unwrap();
+        assert_same_sums(&from_rows, &ImageData::from_rgb8(&colors).unwrap());
+    }
+
+    #[test]
+    fn new_rejects_ragged_rows() {
+        let row = |n| vec![RGB::new(1, 2, 3); n];
+        for (rows, err) in [
+            (
+                vec![row(3), row(2), row(3)],
+                "row 1 has length 2, expected width 3",
+            ),
+            (
+                vec![row(3), row(3), row(4)],
+                "row 2 has length 4, expected width 3",
+            ),
+            (
+                vec![row(3), row(0), row(3)],
+                "row 1 has length 0, expected width 3",
+            ),
+        ] {
+            assert_eq!(ImageData::new(&rows).err().unwrap(), err);
+        }
+    }
+
+    #[test]
+    fn new_rejects_colors_above_8_bits() {
+        let rows = vec![vec![RGB::new(0, 0, 0), RGB::new(1, 256, 0)]];
+        let err = ImageData::new(&rows).err().unwrap();
+        assert_eq!(err, "pixel 1,0 isn't an 8 bit color");
+    }
+
+    #[test]
+    fn tiff_rgb8() {
+        let samples = [255, 0, 0, 0, 255, 0, 0, 0, 255, 10, 20, 30];
+        let file = write_tiff::<colortype::RGB8>("rgb8.tiff", &[&samples]);
+        let img = load_rgb8(file.path()).unwrap();
+        assert_eq!(img.dimensions(), (2, 2));
+        assert_eq!(
+            pixels(&img),
+            [[255, 0, 0], [0, 255, 0], [0, 0, 255], [10, 20, 30]]
+        );
+    }
+
+    #[test]
+    fn tiff_rgb16_is_rounded_to_8_bits() {
+        let samples = [65535, 0, 32896, 257, 514, 771, 0, 0, 0, 128, 65407, 65535];
+        let file = write_tiff::<colortype::RGB16>("rgb16.tiff", &[&samples]);
+        let img = load_rgb8(file.path()).unwrap();
+        assert_eq!(
+            pixels(&img),
+            [[255, 0, 128], [1, 2, 3], [0, 0, 0], [0, 255, 255]]
+        );
+    }
+
+    #[test]
+    fn tiff_cmyk8() {
+        // white, cyan, black and a half tone of magenta
+        let samples = [0, 0, 0, 0, 255, 0, 0, 0, 0, 0, 0, 255, 0, 128, 0, 0];
+        let file = write_tiff::<colortype::CMYK8>("cmyk8.tiff", &[&samples]);
+        let img = load_rgb8(file.path()).unwrap();
+        assert_eq!(
+            pixels(&img),
+            [[255, 255, 255], [0, 255, 255], [0, 0, 0], [255, 127, 255]]
+        );
+    }
+
+    #[test]
+    fn tiff_gray8() {
+        let file = write_tiff::<colortype::Gray8>("gray8.tiff", &[&[0, 64, 128, 255]]);
+        let img = load_rgb8(file.path()).unwrap();
+        assert_eq!(
+            pixels(&img),
+            [[0, 0, 0], [64, 64, 64], [128, 128, 128], [255, 255, 255]]
+        );
+    }
+
+    #[test]
+    fn tiff_uses_the_first_page() {
+        let first = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+        let second = [200; 12];
+        let file = write_tiff::<colortype::RGB8>("pages.tiff", &[&first, &second]);
+        let img = load_rgb8(file.path()).unwrap();
+        assert_eq!(
+            pixels(&img),
+            [[1, 2, 3], [4, 5, 6], [7, 8, 9], [10, 11, 12]]
+        );
+    }
+
+    #[test]
+    fn tiff_float_samples_go_to_the_image_crate() {
+        // which can't read them either, but says so with its own error
+        let samples = [1.0; 12];
+        let file = write_tiff::<colortype::RGB32Float>("rgbf32.tiff", &[&samples]);
+        match load_rgb8(file.path()) {
+            Err(Error::Decode(e)) => assert_eq!(e, "unable to decode image"),
+            _ => panic!("float samples were decoded"),
+        }
+    }
+
+    #[test]
+    fn tiff_that_isnt_there() {
+        let file = TempFile::new("missing.tiff");
+        assert!(matches!(load_rgb8(file.path()), Err(Error::Input(_))));
+    }
 }
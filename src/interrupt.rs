@@ -0,0 +1,37 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// the first ctrl-c stops refining at the next iteration instead of ending
+// comprs, so a long run still saves what it has refined and finishes its
+// animations cleanly. the handler only sets a flag and puts the default
+// handler back, so a second ctrl-c ends comprs at once
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// catch ctrl-c from here on. only done on unix, elsewhere ctrl-c still
+/// ends comprs straight away
+pub fn install() {
+    #[cfg(unix)]
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            handle as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn handle(_: libc::c_int) {
+    const NOTE: &[u8] =
+        b"\nstopping after this iteration to save what is refined, ctrl-c again to quit now\n";
+    REQUESTED.store(true, Ordering::Relaxed);
+    // only async signal safe calls here, so no eprintln
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_DFL);
+        libc::write(libc::STDERR_FILENO, NOTE.as_ptr().cast(), NOTE.len());
+    }
+}
+
+/// whether ctrl-c was pressed, checked between iterations
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::Relaxed)
+}
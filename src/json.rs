@@ -0,0 +1,477 @@
+use std::{iter::Peekable, str::Chars};
+
+/// the parts of json that -export-json and -report write. numbers are only
+/// unsigned and without exponents
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(u64),
+    /// a number with a fractional part
+    Fraction(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut chars = s.chars().peekable();
+        let ret = parse_value(&mut chars, 0)?;
+        skip_whitespace(&mut chars);
+        match chars.next() {
+            None => Ok(ret),
+            Some(c) => Err(format!("unexpected `{c}` after the end of the json")),
+        }
+    }
+
+    /// the value of `key`, if this is an object that has it
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Json::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    /// an object of `fields` in this order
+    pub fn object<'a>(fields: impl IntoIterator<Item = (&'a str, Json)>) -> Self {
+        Json::Object(fields.into_iter().map(|(k, v)| (k.into(), v)).collect())
+    }
+
+    /// this value as json text with a field or item per line, indented by
+    /// two spaces a level. arrays of numbers and strings stay on one line.
+    /// fractions that aren't finite have no json of their own and are null
+    pub fn pretty(&self) -> String {
+        let mut ret = String::new();
+        self.write_pretty(&mut ret, 0);
+        ret.push('\n');
+        ret
+    }
+
+    fn write_pretty(&self, out: &mut String, depth: usize) {
+        let indent = "  ".repeat(depth + 1);
+        match self {
+            Json::Null => out.push_str("null"),
+            Json::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+            Json::Number(n) => out.push_str(&n.to_string()),
+            Json::Fraction(f) if !f.is_finite() => out.push_str("null"),
+            // with its `.`, so it reads back as a fraction
+            Json::Fraction(f) if f.fract() == 0.0 => out.push_str(&format!("{f:.1}")),
+            Json::Fraction(f) => out.push_str(&f.to_string()),
+            Json::String(s) => write_string(out, s),
+            Json::Array(items) if items.iter().all(Json::is_scalar) => {
+                out.push('[');
+                for (k, item) in items.iter().enumerate() {
+                    if k > 0 {
+                        out.push_str(", ");
+                    }
+                    item.write_pretty(out, depth);
+                }
+                out.push(']');
+            }
+            Json::Array(items) => {
+                out.push('[');
+                for (k, item) in items.iter().enumerate() {
+                    out.push_str(if k == 0 { "\n" } else { ",\n" });
+                    out.push_str(&indent);
+                    item.write_pretty(out, depth + 1);
+                }
+                out.push('\n');
+                out.push_str(&indent[2..]);
+                out.push(']');
+            }
+            Json::Object(fields) if fields.is_empty() => out.push_str("{}"),
+            Json::Object(fields) => {
+                out.push('{');
+                for (k, (key, value)) in fields.iter().enumerate() {
+                    out.push_str(if k == 0 { "\n" } else { ",\n" });
+                    out.push_str(&indent);
+                    write_string(out, key);
+                    out.push_str(": ");
+                    value.write_pretty(out, depth + 1);
+                }
+                out.push('\n');
+                out.push_str(&indent[2..]);
+                out.push('}');
+            }
+        }
+    }
+
+    fn is_scalar(&self) -> bool {
+        !matches!(self, Json::Array(_) | Json::Object(_))
+    }
+}
+
+/// `s` quoted, with what json can't hold as it is escaped
+fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+type Input<'a> = Peekable<Chars<'a>>;
+
+/// how deep arrays and objects can be inside each other, so a file of
+/// brackets is an error instead of running out of stack
+const MAX_DEPTH: usize = 128;
+
+fn skip_whitespace(chars: &mut Input) {
+    while chars.next_if(|c| c.is_ascii_whitespace()).is_some() {}
+}
+
+fn expect(chars: &mut Input, expected: char) -> Result<(), String> {
+    skip_whitespace(chars);
+    match chars.next() {
+        Some(c) if c == expected => Ok(()),
+        Some(c) => Err(format!("expected `{expected}` in json, found `{c}`")),
+        None => Err(format!("expected `{expected}` in json, found the end")),
+    }
+}
+
+/// the value at `chars`, inside `depth` arrays and objects
+fn parse_value(chars: &mut Input, depth: usize) -> Result<Json, String> {
+    skip_whitespace(chars);
+    match chars.peek() {
+        Some('{' | '[') if depth == MAX_DEPTH => Err(format!(
+            "json nests arrays and objects more than {MAX_DEPTH} deep"
+        )),
+        Some('{') => parse_object(chars, depth + 1),
+        Some('[') => parse_array(chars, depth + 1),
+        Some('"') => parse_string(chars).map(Json::String),
+        Some(c) if c.is_ascii_digit() => parse_number(chars),
+        Some('t') => parse_literal(chars, "true", Json::Bool(true)),
+        Some('f') => parse_literal(chars, "false", Json::Bool(false)),
+        Some('n') => parse_literal(chars, "null", Json::Null),
+        Some(c) => Err(format!("unsupported json value starting with `{c}`")),
+        None => Err("json ends where a value was expected".into()),
+    }
+}
+
+/// the items between `open` and `close` separated by commas, each read by `item`
+fn parse_list<T>(
+    chars: &mut Input,
+    open: char,
+    close: char,
+    mut item: impl FnMut(&mut Input) -> Result<T, String>,
+) -> Result<Vec<T>, String> {
+    expect(chars, open)?;
+    let mut ret = Vec::new();
+    skip_whitespace(chars);
+    if chars.next_if_eq(&close).is_some() {
+        return Ok(ret);
+    }
+    loop {
+        ret.push(item(chars)?);
+        skip_whitespace(chars);
+        match chars.next() {
+            Some(',') => continue,
+            Some(c) if c == close => return Ok(ret),
+            Some(c) => return Err(format!("expected `,` or `{close}` in json, found `{c}`")),
+            None => return Err(format!("expected `,` or `{close}` in json, found the end")),
+        }
+    }
+}
+
+fn parse_object(chars: &mut Input, depth: usize) -> Result<Json, String> {
+    let fields = parse_list(chars, '{', '}', |chars| {
+        skip_whitespace(chars);
+        let key = parse_string(chars)?;
+        expect(chars, ':')?;
+        Ok((key, parse_value(chars, depth)?))
+    })?;
+    Ok(Json::Object(fields))
+}
+
+fn parse_array(chars: &mut Input, depth: usize) -> Result<Json, String> {
+    parse_list(chars, '[', ']', |chars| parse_value(chars, depth)).map(Json::Array)
+}
+
+fn parse_literal(chars: &mut Input, literal: &str, value: Json) -> Result<Json, String> {
+    for expected in literal.chars() {
+        if chars.next_if_eq(&expected).is_none() {
+            return Err(format!("invalid json value, expected `{literal}`"));
+        }
+    }
+    Ok(value)
+}
+
+fn parse_number(chars: &mut Input) -> Result<Json, String> {
+    let mut number = String::new();
+    while let Some(d) = chars.next_if(char::is_ascii_digit) {
+        number.push(d);
+    }
+    let whole = number.len();
+    if chars.next_if_eq(&'.').is_some() {
+        number.push('.');
+        while let Some(d) = chars.next_if(char::is_ascii_digit) {
+            number.push(d);
+        }
+        if number.len() == whole + 1 {
+            return Err("a number in json has no digits after its `.`".into());
+        }
+    }
+    if chars.next_if(|&c| matches!(c, 'e' | 'E')).is_some() {
+        return Err("numbers with exponents aren't supported in json".into());
+    }
+    if number.len() > whole {
+        // parsed whole rather than the two parts added, so a fraction reads
+        // back as exactly the one that was written
+        return Ok(Json::Fraction(number.parse().unwrap()));
+    }
+    number
+        .parse()
+        .map(Json::Number)
+        .map_err(|_| "number in json is too large".into())
+}
+
+fn parse_string(chars: &mut Input) -> Result<String, String> {
+    expect(chars, '"')?;
+    let mut ret = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(ret),
+            Some('\\') => {
+                let escaped = match chars.next() {
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('/') => '/',
+                    Some('b') => '\u{8}',
+                    Some('f') => '\u{c}',
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('u') => {
+                        let unit = parse_hex4(chars)?;
+                        // outside the basic plane a character is two escapes
+                        // of the halves of its utf-16 surrogate pair
+                        let code = if (0xd800..0xdc00).contains(&unit)
+                            && chars.next_if_eq(&'\\').is_some()
+                            && chars.next_if_eq(&'u').is_some()
+                        {
+                            let low = parse_hex4(chars)?;
+                            if !(0xdc00..0xe000).contains(&low) {
+                                return Err("invalid unicode escape in json".into());
+                            }
+                            0x10000 + ((unit - 0xd800) << 10) + (low - 0xdc00)
+                        } else {
+                            unit
+                        };
+                        char::from_u32(code).ok_or("invalid unicode escape in json")?
+                    }
+                    _ => return Err("invalid escape in json string".into()),
+                };
+                ret.push(escaped);
+            }
+            Some(c) => ret.push(c),
+            None => return Err("json ends inside a string".into()),
+        }
+    }
+}
+
+/// the four hex digits after a `\u`
+fn parse_hex4(chars: &mut Input) -> Result<u32, String> {
+    let mut ret = 0;
+    for _ in 0..4 {
+        let digit = chars.next().and_then(|c| c.to_digit(16));
+        ret = ret * 16 + digit.ok_or("invalid unicode escape in json")?;
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a value with every kind of json in it, nested
+    fn sample() -> Json {
+        Json::object([
+            ("null", Json::Null),
+            ("yes", Json::Bool(true)),
+            ("no", Json::Bool(false)),
+            ("zero", Json::Number(0)),
+            ("max", Json::Number(u64::MAX)),
+            ("half", Json::Fraction(0.5)),
+            ("whole", Json::Fraction(3.0)),
+            ("psnr", Json::Fraction(31.415_926_535_897_93)),
+            ("huge", Json::Fraction(1.5e300)),
+            ("tiny", Json::Fraction(2.5e-8)),
+            (
+                "text",
+                Json::String("tab\t\"quote\" \\ nl\n cr\r bell\u{7} é 😀".into()),
+            ),
+            ("empty", Json::String(String::new())),
+            (
+                "numbers",
+                Json::Array(vec![Json::Number(1), Json::Fraction(0.25)]),
+            ),
+            ("none", Json::Array(vec![])),
+            ("nothing", Json::object([])),
+            (
+                "nested",
+                Json::Array(vec![
+                    Json::object([("a", Json::Array(vec![Json::Null]))]),
+                    Json::Array(vec![Json::Array(vec![Json::String("deep".into())])]),
+                ]),
+            ),
+        ])
+    }
+
+    #[test]
+    fn pretty_json_parses_back_the_same() {
+        let json = sample();
+        assert_eq!(Json::parse(&json.pretty()).unwrap(), json);
+        // without the whitespace too
+        let compact: String = json
+            .pretty()
+            .lines()
+            .map(|l| l.trim_start().replace("\": ", "\":"))
+            .collect();
+        assert_eq!(Json::parse(&compact).unwrap(), json);
+        for scalar in [Json::Null, Json::Number(7), Json::Fraction(0.1)] {
+            assert_eq!(Json::parse(&scalar.pretty()).unwrap(), scalar);
+        }
+    }
+
+    #[test]
+    fn fractions_that_arent_finite_are_written_as_null() {
+        for f in [f64::INFINITY, f64::NEG_INFINITY, f64::NAN] {
+            assert_eq!(Json::Fraction(f).pretty(), "null\n");
+        }
+    }
+
+    #[test]
+    fn truncated_json_is_an_error() {
+        let text = sample().pretty();
+        let text = text.trim_end();
+        for (end, _) in text.char_indices().skip(1) {
+            // a prefix that ends in a number is a smaller number
+            if text[..end].ends_with(|c: char| c.is_ascii_digit()) {
+                continue;
+            }
+            assert!(Json::parse(&text[..end]).is_err(), "{}", &text[..end]);
+        }
+        for (text, err) in [
+            ("", "json ends where a value was expected"),
+            ("  ", "json ends where a value was expected"),
+            ("[1, 2", "expected `,` or `]` in json, found the end"),
+            ("[1,", "json ends where a value was expected"),
+            ("{\"a\"", "expected `:` in json, found the end"),
+            ("{\"a\": 1", "expected `,` or `}` in json, found the end"),
+            ("\"open", "json ends inside a string"),
+            ("tru", "invalid json value, expected `true`"),
+            ("nul", "invalid json value, expected `null`"),
+            ("[1] 2", "unexpected `2` after the end of the json"),
+            ("{} {}", "unexpected `{` after the end of the json"),
+            ("[1 2]", "expected `,` or `]` in json, found `2`"),
+            ("{1: 2}", "expected `\"` in json, found `1`"),
+        ] {
+            assert_eq!(Json::parse(text).unwrap_err(), err, "{text}");
+        }
+    }
+
+    #[test]
+    fn escapes_are_read_and_bad_ones_are_errors() {
+        let text = r#""\" \\ \/ \b \f \n \r \t Aé中 😀""#;
+        assert_eq!(
+            Json::parse(text).unwrap(),
+            Json::String("\" \\ / \u{8} \u{c} \n \r \t Aé中 😀".into())
+        );
+        for (text, err) in [
+            (r#""\x""#, "invalid escape in json string"),
+            (r#""\'""#, "invalid escape in json string"),
+            (r#""\"#, "invalid escape in json string"),
+            (r#""\u12""#, "invalid unicode escape in json"),
+            (r#""\u12"#, "invalid unicode escape in json"),
+            (r#""\uzzzz""#, "invalid unicode escape in json"),
+            (r#""\u+041""#, "invalid unicode escape in json"),
+            (r#""\ud83d""#, "invalid unicode escape in json"),
+            (r#""\ud83dA""#, "invalid unicode escape in json"),
+            (r#""\ude00""#, "invalid unicode escape in json"),
+        ] {
+            assert_eq!(Json::parse(text).unwrap_err(), err, "{text}");
+        }
+    }
+
+    #[test]
+    fn numbers_at_the_edges() {
+        for (text, value) in [
+            ("0", Json::Number(0)),
+            ("18446744073709551615", Json::Number(u64::MAX)),
+            ("0.0", Json::Fraction(0.0)),
+            ("3.0", Json::Fraction(3.0)),
+            ("0.1", Json::Fraction(0.1)),
+            ("123.456", Json::Fraction(123.456)),
+            (
+                "18446744073709551616.5",
+                Json::Fraction(18446744073709551616.5),
+            ),
+            ("0.000000000000000000001", Json::Fraction(1e-21)),
+        ] {
+            assert_eq!(Json::parse(text).unwrap(), value, "{text}");
+        }
+        for (text, err) in [
+            ("18446744073709551616", "number in json is too large"),
+            ("99999999999999999999999", "number in json is too large"),
+            ("1.", "a number in json has no digits after its `.`"),
+            ("1.e5", "a number in json has no digits after its `.`"),
+            ("1e5", "numbers with exponents aren't supported in json"),
+            ("1.5E3", "numbers with exponents aren't supported in json"),
+            ("-1", "unsupported json value starting with `-`"),
+            (".5", "unsupported json value starting with `.`"),
+            ("1.2.3", "unexpected `.` after the end of the json"),
+        ] {
+            assert_eq!(Json::parse(text).unwrap_err(), err, "{text}");
+        }
+    }
+
+    #[test]
+    fn nesting_is_limited_without_running_out_of_stack() {
+        let nested = |depth: usize| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        let mut json = Json::parse(&nested(MAX_DEPTH)).unwrap();
+        for _ in 1..MAX_DEPTH {
+            let Json::Array(mut items) = json else {
+                panic!("{json:?}");
+            };
+            json = items.pop().unwrap();
+        }
+        assert_eq!(json, Json::Array(vec![]));
+        let objects = format!("{}1{}", "{\"a\":".repeat(MAX_DEPTH), "}".repeat(MAX_DEPTH));
+        assert!(Json::parse(&objects).is_ok());
+
+        let err = format!("json nests arrays and objects more than {MAX_DEPTH} deep");
+        assert_eq!(Json::parse(&nested(MAX_DEPTH + 1)).unwrap_err(), err);
+        assert_eq!(Json::parse(&"[{\"a\":".repeat(100_000)).unwrap_err(), err);
+        assert_eq!(Json::parse(&"[".repeat(1_000_000)).unwrap_err(), err);
+    }
+}
@@ -0,0 +1,23 @@
+//! compress images by refining a quadtree of flat colored regions, split
+//! where the colors vary the most. the `comprs` binary is the command line
+//! on top of this
+
+pub mod error;
+pub mod image;
+pub mod json;
+pub mod log;
+pub mod psa;
+pub mod qt;
+pub mod residual;
+pub mod tree;
+
+mod entropy;
+
+/// the version and, when it was built in a git checkout, the commit
+pub fn version() -> String {
+    let version = env!("CARGO_PKG_VERSION");
+    match option_env!("COMPRS_GIT_HASH") {
+        Some(hash) => format!("{version} ({hash})"),
+        None => version.into(),
+    }
+}
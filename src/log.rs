@@ -0,0 +1,35 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+// how much comprs says while it works. the command line sets the level from
+// -quiet and -verbose, the library only ever warns, on stderr, and stays
+// silent at Quiet
+
+#[derive(Clone, Copy)]
+pub enum Level {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Normal as u8);
+
+/// set once the flags are parsed, before any work starts
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// whether messages of `level` are shown
+pub fn enabled(level: Level) -> bool {
+    LEVEL.load(Ordering::Relaxed) >= level as u8
+}
+
+/// something that went wrong but didn't stop anything
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Normal) {
+            eprintln!("warning: {}", format_args!($($arg)*));
+        }
+    };
+}
+
+pub(crate) use warning;
@@ -1,36 +1,151 @@
 use std::{
     env,
-    fs::File,
+    fs::{self, File},
     io::BufWriter,
+    iter::Peekable,
     path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
 };
 
-use ::image::{codecs::gif::GifEncoder, Frame};
-use image::{ImageData, RGB};
-use tree::Tree;
+use ::image::{
+    codecs::gif::GifEncoder,
+    imageops::{self, FilterType},
+    Delay, ImageFormat, RgbaImage,
+};
+use anim::{
+    check_frames_dir, loops_to_repeat, parse_frame_list, tweened_count, Animation, ApngEncoder,
+    Direction, FrameEncoder, PngDirEncoder, Schedule, Spacing, VideoEncoder,
+};
+use cli::Command;
+use comprs::{error, image, json, qt, residual, tree};
+use curve::{knee, ErrorCurve};
+use error::Error;
+use image::{load_rgb8, probe, psnr, ImageData, RGB};
+use json::Json;
+use label::{draw_label, Corner};
+use log::{error, info, verbose, warning, Level};
+use palette::{Dither, Palette, QuantizedGifEncoder};
+use porcelain::Record;
+use progress::Progress;
+use report::Report;
+use template::{Template, Values};
+use timing::PhaseTimer;
+use tree::{to_rgba, Canvas, Leaf, LoadedTree, Refined, Split, Tree, TreeStats};
+
+mod anim;
+mod batch;
+mod cache;
+mod cli;
+mod completions;
+mod config;
+mod curve;
+mod interrupt;
+mod label;
+mod log {
+    // how much comprs says while it works. errors always go to stderr, so
+    // stdout only ever has what was asked for: the normal messages, and the
+    // output of flags like -stats that print something. -quiet keeps stdout
+    // empty and -verbose adds detail on stderr. with -porcelain stdout only
+    // has records, see `porcelain`. the level is the library's, so its
+    // warnings follow -quiet too
+
+    pub use comprs::log::{enabled, set_level, Level};
+
+    /// something that stopped comprs or one of its inputs, shown at every
+    /// level
+    macro_rules! error {
+        ($($arg:tt)*) => {
+            eprintln!($($arg)*)
+        };
+    }
+
+    /// something that went wrong but didn't stop anything
+    macro_rules! warning {
+        ($($arg:tt)*) => {
+            if $crate::log::enabled($crate::log::Level::Normal) {
+                eprintln!("warning: {}", format_args!($($arg)*));
+            }
+        };
+    }
+
+    /// what comprs normally says about what it did
+    macro_rules! info {
+        ($($arg:tt)*) => {
+            if $crate::log::enabled($crate::log::Level::Normal) && !$crate::porcelain::enabled() {
+                println!($($arg)*);
+            }
+        };
+    }
+
+    /// detail only -verbose shows, on stderr next to the -timings report
+    macro_rules! verbose {
+        ($($arg:tt)*) => {
+            if $crate::log::enabled($crate::log::Level::Verbose) {
+                eprintln!($($arg)*);
+            }
+        };
+    }
+
+    pub(crate) use {error, info, verbose, warning};
+}
+mod open;
+mod palette;
+mod porcelain;
+mod progress;
+mod report;
+mod session;
+mod template;
+#[cfg(feature = "rayon")]
+mod tiles;
+mod timing;
+mod watch;
 
-mod image;
-mod psa;
-mod tree;
+const DEFAULT_HOLD_LAST_MS: u32 = 2000;
+const DEFAULT_GIF_QUALITY: i32 = 30;
+const DEFAULT_VIDEO_FPS: u32 = 10;
 
-fn print_usage(program: &String) {
-    println!(
-        "usage: {} <input-file> [-o output-file] -iter <iterations> [-outline hex-code] [-gif save-delta]",
+fn usage(program: &String, command: Option<&Command>) -> String {
+    if let Some(c) = command {
+        return format!("usage: {}", c.usage(program));
+    }
+    format!(
+        "usage: {} <input-file>... [-o output-file] -iter <iterations> [-checkpoints iterations] [-undo n] [-resume tree-file] [-split strategy] [-outline hex-code] [-gif save-delta[:output-file] | -frames iterations] [-fps frames-per-second] [-hold-last milliseconds] [-gif-direction direction] [-gif-spacing spacing] [-loop count] [-start-with-original] [-end-with-original] [-gif-quality speed] [-gif-scale scale] [-frames-dir directory] [-force] [-video output-file] [-ffmpeg path] [-gif-heatmap output-file] [-tween frames] [-label] [-label-psnr] [-label-corner corner] [-label-color hex-code] [-stable-palette] [-delta-frames] [-gif-dither dither] [-encode output-file] [-encode-entropy] [-encode-lossless] [-export-json output-file] [-pretty] [-leaf-at x,y] [-stats] [-ssim] [-suggest-iter [-apply]] [-target-psnr decibels] [-error-curve output-file] [-error-curve-step n] [-report output-file] [-porcelain] [-out-dir directory] [-name-template template] [-cache-dir directory] [-recursive] [-dry-run] [-interactive] [-jobs n] [-tiles k] [-timings] [-quiet | -verbose] [-config file | -no-config]",
         program
-    );
+    )
 }
 
-fn print_help() {
-    println!("input-file        - path to input image, supports .{{jpg,png,...}}");
-    println!(
-        "-o output-file    - [optional] where to save output image, supports .{{jpg,png,...}}"
-    );
-    println!("-iter iterations  - number of times to split the quad-tree, more iterations means higher quality image");
-    println!("-outline hex-code - [optional] color to outline each sub-region of the quad-tree (e.g. -outline FF0000)");
-    println!("-gif save-delta   - [optional] save the algorithm process as a gif, save the image every `save-delta` iterations");
+fn decode_usage(program: &String) -> String {
+    format!("       {program} -decode <tree-file> -o <output-file> [-force] [-outline hex-code] [-scale scale] [-prune-to-leaves leaves] [-leaf-at x,y] [-stats] [-porcelain] [-timings]")
+}
+
+fn diff_usage(program: &String) -> String {
+    format!("       {program} diff <tree-file> <tree-file> -o <output-file> [-force]")
+}
+
+fn completions_usage(program: &String) -> String {
+    format!(
+        "       {program} completions <{}>",
+        completions::SHELLS.join("|")
+    )
+}
+
+fn print_usage(program: &String, command: Option<&Command>) {
+    error!("{}", usage(program, command));
+}
+
+fn print_decode_usage(program: &String, command: Option<&Command>) {
+    match command {
+        Some(_) => print_usage(program, command),
+        None => error!("{}", decode_usage(program)),
+    }
+}
+
+fn print_diff_usage(program: &String) {
+    error!("{}", diff_usage(program));
 }
 
-fn file_without_extension(path: &String) -> Result<(String, String), String> {
+fn file_without_extension(path: &str) -> Result<(String, String), String> {
     let file_path = Path::new(path);
     if let Some(stem) = file_path.file_stem() {
         let new_path: PathBuf = match file_path.parent() {
@@ -55,7 +170,107 @@ fn file_without_extension(path: &String) -> Result<(String, String), String> {
     }
 }
 
-fn hex_to_rgb(hex: &String) -> Result<RGB<u8>, String> {
+/// where the output of `input_file` goes when -o isn't given, next to it
+/// and named by -name-template
+fn default_output_file(input_file: &str, options: &Options) -> Result<String, Error> {
+    let path = Path::new(input_file);
+    let Some(stem) = path.file_stem().and_then(|s| s.to_str()) else {
+        return Err(Error::Usage("failed to get file stem".into()));
+    };
+    let extension = path.extension().and_then(|e| e.to_str());
+    let ext = match extension {
+        _ if options.animate_main() => "gif",
+        Some(ext) => ext,
+        None => "png",
+    };
+    let template = match &options.name_template {
+        Some(template) => template.clone(),
+        None => Template::parse(template::DEFAULT)?,
+    };
+    let splits = options
+        .iterations
+        .saturating_sub(options.undo.unwrap_or(0) as u32);
+    let name = template.expand(&Values {
+        stem,
+        ext,
+        iter: options.iterations,
+        leaves: planned_leaves(splits, options.split),
+        split: options.split.unwrap_or(Split::Midpoint).name(),
+        size: template_size(input_file, &template)?,
+    });
+    next_to(input_file, name)
+}
+
+/// the file called `name` in the directory of `path`
+fn next_to(path: &str, name: String) -> Result<String, Error> {
+    match Path::new(path).with_file_name(name).to_str() {
+        Some(path) => Ok(path.into()),
+        None => Err(Error::Usage("failed to convert file path to string".into())),
+    }
+}
+
+/// the regions a new tree has after `splits` splits, unless it runs out of
+/// regions to split first
+fn planned_leaves(splits: u32, split: Option<Split>) -> usize {
+    1 + splits as usize * (split.unwrap_or(Split::Midpoint).children() - 1)
+}
+
+/// the size of `input_file` if `template` needs it, read from its header
+fn template_size(input_file: &str, template: &Template) -> Result<(u32, u32), Error> {
+    if !template.uses("w") && !template.uses("h") {
+        return Ok((0, 0));
+    }
+    let (w, h, _) = probe(input_file)?;
+    Ok((w, h))
+}
+
+/// refuse to write over files that already exist unless `force` is set, and
+/// over the input even when it is
+fn check_overwrites(input_file: &str, outputs: &[&String], force: bool) -> Result<(), String> {
+    let input = fs::canonicalize(input_file).ok();
+    for output in outputs {
+        let Ok(path) = fs::canonicalize(output) else {
+            continue;
+        };
+        if input.as_ref() == Some(&path) {
+            return Err(format!("output `{output}` is the input file"));
+        }
+        if !force {
+            return Err(format!(
+                "`{output}` already exists, use -force to overwrite it"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// fail before any work is done when an output can't be created because
+/// the directory it goes in doesn't exist
+fn check_output_dirs(outputs: &[&String]) -> Result<(), String> {
+    for output in outputs {
+        let Some(dir) = Path::new(output.as_str())
+            .parent()
+            .filter(|d| !d.as_os_str().is_empty())
+        else {
+            continue;
+        };
+        if !dir.exists() {
+            return Err(format!(
+                "can't write `{output}`, directory `{}` doesn't exist",
+                dir.display()
+            ));
+        }
+        if !dir.is_dir() {
+            return Err(format!(
+                "can't write `{output}`, `{}` isn't a directory",
+                dir.display()
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn hex_to_rgb(hex: &str) -> Result<RGB<u8>, String> {
     let hex = hex.trim_start_matches('#');
     if hex.len() != 6 {
         return Err("hex code must be 6 characters long".into());
@@ -68,157 +283,3023 @@ fn hex_to_rgb(hex: &String) -> Result<RGB<u8>, String> {
     Ok(RGB::new(r, g, b))
 }
 
+/// `x,y` as a pixel position
+fn parse_pixel(s: &str) -> Option<(usize, usize)> {
+    let (x, y) = s.split_once(',')?;
+    Some((x.trim().parse().ok()?, y.trim().parse().ok()?))
+}
+
+/// a -target-psnr in decibels, which has to be above 0 and finite since an
+/// exact render is only reached by running out of regions to split
+fn parse_target_psnr(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(t) if t > 0.0 && t.is_finite() => Ok(t),
+        _ => Err("invalid target psnr, must be a number of decibels above 0".into()),
+    }
+}
+
+/// whether `flag` takes `value`, the same way `real_main` parses it. values
+/// from the environment are checked with this first, so one that is wrong
+/// is skipped with a warning instead of stopping every run
+fn check_flag_value(flag: &str, value: &str) -> Result<(), String> {
+    let at_least_1 = |what: &str| match value.parse::<u32>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err(format!("invalid {what}, must be at least 1")),
+    };
+    let number = |what: &str| match value.parse::<u32>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("invalid {what}")),
+    };
+    let fraction = |what: &str| match value.parse::<f64>() {
+        Ok(s) if (0.1..=1.0).contains(&s) => Ok(()),
+        _ => Err(format!("invalid {what}, must be from 0.1 to 1.0")),
+    };
+    match flag {
+        "-iter" => at_least_1("number of iterations"),
+        "-gif" => match value
+            .split_once(':')
+            .map_or(value, |(d, _)| d)
+            .parse::<u32>()
+        {
+            Ok(d) if d > 0 => Ok(()),
+            _ => Err("invalid gif save delta".into()),
+        },
+        "-fps" => at_least_1("frames per second"),
+        "-jobs" => at_least_1("number of jobs"),
+        "-tiles" => at_least_1("number of tiles"),
+        "-error-curve-step" => at_least_1("error curve step"),
+        "-prune-to-leaves" => at_least_1("number of leaves"),
+        "-undo" => number("number of splits to undo"),
+        "-hold-last" => number("hold duration"),
+        "-tween" => number("number of tween frames"),
+        "-loop" => match value.parse() {
+            Ok(l) => loops_to_repeat(l).map(|_| ()),
+            Err(_) => Err("invalid gif loop count".into()),
+        },
+        "-gif-quality" => match value.parse::<i32>() {
+            Ok(q) if (1..=30).contains(&q) => Ok(()),
+            _ => Err("invalid gif quality, must be from 1 to 30".into()),
+        },
+        "-gif-scale" => fraction("gif scale"),
+        "-target-psnr" => parse_target_psnr(value).map(|_| ()),
+        "-scale" => fraction("scale"),
+        "-outline" | "-label-color" => hex_to_rgb(value).map(|_| ()),
+        "-frames" | "-checkpoints" => parse_frame_list(value).map(|_| ()),
+        "-split" => Split::parse(value).map(|_| ()),
+        "-gif-direction" => Direction::parse(value).map(|_| ()),
+        "-gif-spacing" => Spacing::parse(value).map(|_| ()),
+        "-label-corner" => Corner::parse(value).map(|_| ()),
+        "-gif-dither" => Dither::parse(value).map(|_| ()),
+        "-name-template" => Template::parse(value).map(|_| ()),
+        // paths, which are checked when they are used
+        _ => Ok(()),
+    }
+}
+
+/// a line describing the leaf at pixel `x`,`y`, with its error if the tree
+/// has errors
+fn describe_leaf(x: usize, y: usize, leaf: &Leaf, with_error: bool) -> String {
+    let (top, left) = leaf.top_left;
+    let (bottom, right) = leaf.bottom_right;
+    let c = leaf.color;
+    let mut ret = format!(
+        "pixel {x},{y} is in the {}x{} region at {left},{top}, {} splits deep, color #{:02x}{:02x}{:02x}",
+        right - left + 1,
+        bottom - top + 1,
+        leaf.depth,
+        c.r,
+        c.g,
+        c.b
+    );
+    if with_error {
+        ret += &format!(", squared error {}", leaf.metric);
+    }
+    ret
+}
+
+/// the `leaf` record of the leaf at pixel `x`,`y` of `input`, with its error
+/// if the tree has errors
+fn leaf_record(input: &str, x: usize, y: usize, leaf: &Leaf, with_error: bool) -> Record {
+    let (top, left) = leaf.top_left;
+    let (bottom, right) = leaf.bottom_right;
+    let c = leaf.color;
+    let record = Record::new("leaf")
+        .field("input", input)
+        .field("x", x)
+        .field("y", y)
+        .field("left", left)
+        .field("top", top)
+        .field("width", right - left + 1)
+        .field("height", bottom - top + 1)
+        .field("depth", leaf.depth)
+        .field("color", format!("{:02x}{:02x}{:02x}", c.r, c.g, c.b));
+    if with_error {
+        record.field("error", leaf.metric)
+    } else {
+        record
+    }
+}
+
+/// check what compressing `input_file` into `outputs` with `options` needs
+/// without decoding more than the header of the image, and say what would
+/// be done
+fn plan(input_file: &str, outputs: &[&String], options: &Options) -> Result<String, Error> {
+    let (w, h, color) = probe(input_file)?;
+    if let Some((x, y)) = options.leaf_at {
+        if x >= w as usize || y >= h as usize {
+            return Err(Error::Usage(format!(
+                "pixel {x},{y} is outside the {w}x{h} image"
+            )));
+        }
+    }
+    if let Some(dir) = &options.frames_dir {
+        check_frames_dir(dir, options.force).map_err(Error::Output)?;
+    }
+    if let Some(tree_file) = &options.resume {
+        let tree = load_resumable(tree_file, options.split)?;
+        if (tree.width(), tree.height()) != (w as usize, h as usize) {
+            return Err(Error::Usage(format!(
+                "can't resume `{tree_file}`, the tree is of a {}x{} image but the image is {w}x{h}",
+                tree.width(),
+                tree.height()
+            )));
+        }
+    }
+    let split = options.split.unwrap_or(Split::Midpoint);
+    let bytes = batch::footprint_of(w, h, options.iterations, split, options.animate());
+    let outputs: Vec<String> = outputs.iter().map(|p| format!("`{p}`")).collect();
+    Ok(format!(
+        "{w}x{h} {color}, {} iterations with the {} split in about {} MiB, would write {}",
+        options.iterations,
+        split.name(),
+        bytes.div_ceil(1 << 20),
+        outputs.join(", ")
+    ))
+}
+
+/// what -stats reports about a compression on top of the tree
+struct RunStats {
+    /// of the render without outlines against the original
+    mse: f64,
+    /// of the luma of the same, with -ssim
+    ssim: Option<f64>,
+    input_bytes: Option<u64>,
+    /// of the main output, `None` when frames are saved to a directory
+    output_bytes: Option<u64>,
+    /// of the tree in the .qt format, the -encode file when there is one
+    tree_bytes: u64,
+    /// `tree_bytes` is worked out from the packed layout, not a file's size
+    tree_estimated: bool,
+    /// of the image as 8 bit rgb without any header
+    raw_bytes: u64,
+    elapsed: Duration,
+}
+
+impl RunStats {
+    fn ratio(&self) -> Option<f64> {
+        percent(self.output_bytes?, self.input_bytes?)
+    }
+
+    fn tree_raw_ratio(&self) -> Option<f64> {
+        percent(self.tree_bytes, self.raw_bytes)
+    }
+
+    fn tree_input_ratio(&self) -> Option<f64> {
+        percent(self.tree_bytes, self.input_bytes?)
+    }
+}
+
+/// `part` as a percentage of `whole`, `None` when `whole` is empty
+fn percent(part: u64, whole: u64) -> Option<f64> {
+    (whole > 0).then(|| 100.0 * part as f64 / whole as f64)
+}
+
+/// `percent` to three significant digits but at least one decimal, so a
+/// tree a ten thousandth the size of the raw pixels doesn't show as 0.0
+fn format_percent(percent: f64) -> String {
+    let digits = if percent > 0.0 && percent < 10.0 {
+        (2 - percent.log10().floor() as i32).clamp(1, 9) as usize
+    } else {
+        1
+    };
+    format!("{percent:.digits$}")
+}
+
+fn format_psnr(mse: f64) -> String {
+    let psnr = psnr(mse);
+    if psnr.is_infinite() {
+        "inf".into()
+    } else {
+        format!("{psnr:.2}")
+    }
+}
+
+/// a table of `stats`, with the error if the tree has errors
+fn describe_stats(stats: &TreeStats, run: Option<&RunStats>) -> String {
+    let mut ret = format!("leaves          {:>12}\n", stats.leaves);
+    ret += &format!("nodes           {:>12}\n", stats.nodes);
+    ret += &format!("max depth       {:>12}\n", stats.max_depth);
+    ret += &format!("mean depth      {:>12.2}\n", stats.mean_depth);
+    if let Some(run) = run {
+        ret += &format!("squared error   {:>12}\n", stats.error);
+        ret += &format!("mse             {:>12.3}\n", run.mse);
+        ret += &format!("psnr (dB)       {:>12}\n", format_psnr(run.mse));
+        if let Some(ssim) = run.ssim {
+            ret += &format!("ssim            {ssim:>12.4}\n");
+        }
+        if let Some(bytes) = run.input_bytes {
+            ret += &format!("input bytes     {bytes:>12}\n");
+        }
+        if let Some(bytes) = run.output_bytes {
+            ret += &format!("output bytes    {bytes:>12}\n");
+        }
+        if let Some(ratio) = run.ratio() {
+            ret += &format!("output/input    {:>11}%\n", format_percent(ratio));
+        }
+        let estimated = if run.tree_estimated {
+            " (est)"
+        } else {
+            "      "
+        };
+        ret += &format!("tree bytes{estimated}{:>12}\n", run.tree_bytes);
+        ret += &format!("raw rgb bytes   {:>12}\n", run.raw_bytes);
+        if let Some(ratio) = run.tree_raw_ratio() {
+            ret += &format!("tree/raw rgb    {:>11}%\n", format_percent(ratio));
+        }
+        if let Some(ratio) = run.tree_input_ratio() {
+            ret += &format!("tree/input      {:>11}%\n", format_percent(ratio));
+        }
+        ret += &format!("time (s)        {:>12.2}\n", run.elapsed.as_secs_f64());
+    }
+    ret += "region pixels         leaves\n";
+    for (k, count) in stats.leaf_areas.iter().enumerate() {
+        let (low, high) = (1u64 << k, (2u64 << k) - 1);
+        let range = if low == high {
+            low.to_string()
+        } else {
+            format!("{low}-{high}")
+        };
+        ret += &format!("{range:<16}{count:>12}\n");
+    }
+    ret
+}
+
+/// the same as `describe_stats` on one line of key=value pairs, for -porcelain
+fn stats_line(stats: &TreeStats, run: Option<&RunStats>) -> String {
+    let mut fields = vec![
+        format!("leaves={}", stats.leaves),
+        format!("nodes={}", stats.nodes),
+        format!("max_depth={}", stats.max_depth),
+        format!("mean_depth={:.2}", stats.mean_depth),
+    ];
+    if let Some(run) = run {
+        fields.push(format!("error={}", stats.error));
+        fields.push(format!("mse={:.3}", run.mse));
+        fields.push(format!("psnr={}", format_psnr(run.mse)));
+        fields.extend(run.ssim.map(|s| format!("ssim={s:.4}")));
+        fields.extend(run.input_bytes.map(|b| format!("input_bytes={b}")));
+        fields.extend(run.output_bytes.map(|b| format!("output_bytes={b}")));
+        fields.extend(
+            run.ratio()
+                .map(|r| format!("output_percent={}", format_percent(r))),
+        );
+        fields.push(format!("tree_bytes={}", run.tree_bytes));
+        fields.push(format!("tree_estimated={}", run.tree_estimated));
+        fields.push(format!("raw_bytes={}", run.raw_bytes));
+        fields.extend(
+            run.tree_raw_ratio()
+                .map(|r| format!("tree_raw_percent={}", format_percent(r))),
+        );
+        fields.extend(
+            run.tree_input_ratio()
+                .map(|r| format!("tree_input_percent={}", format_percent(r))),
+        );
+        fields.push(format!("seconds={:.2}", run.elapsed.as_secs_f64()));
+    }
+    let areas: Vec<String> = stats.leaf_areas.iter().map(|c| c.to_string()).collect();
+    fields.push(format!("leaf_areas={}", areas.join(",")));
+    fields.join(" ")
+}
+
+/// the numbers of `describe_stats` as json, for -report
+fn stats_json(stats: &TreeStats, run: &RunStats) -> Json {
+    let bytes = |b: Option<u64>| b.map_or(Json::Null, Json::Number);
+    let areas = stats.leaf_areas.iter().map(|&n| Json::Number(n as u64));
+    Json::object([
+        ("leaves", Json::Number(stats.leaves as u64)),
+        ("nodes", Json::Number(stats.nodes as u64)),
+        ("max_depth", Json::Number(stats.max_depth as u64)),
+        ("mean_depth", Json::Fraction(stats.mean_depth)),
+        ("leaf_areas", Json::Array(areas.collect())),
+        ("error", Json::Number(stats.error)),
+        ("mse", Json::Fraction(run.mse)),
+        ("psnr", Json::Fraction(psnr(run.mse))),
+        ("ssim", run.ssim.map_or(Json::Null, Json::Fraction)),
+        ("input_bytes", bytes(run.input_bytes)),
+        ("output_bytes", bytes(run.output_bytes)),
+        ("tree_bytes", Json::Number(run.tree_bytes)),
+        ("tree_estimated", Json::Bool(run.tree_estimated)),
+        ("raw_bytes", Json::Number(run.raw_bytes)),
+        ("seconds", Json::Fraction(run.elapsed.as_secs_f64())),
+    ])
+}
+
+fn is_apng(path: &str) -> bool {
+    let lower = path.to_lowercase();
+    lower.ends_with(".png") || lower.ends_with(".apng")
+}
+
+/// settings for creating an animation file
+struct EncoderOptions {
+    width: u32,
+    height: u32,
+    /// total number of frames that will be encoded
+    frames: u32,
+    loops: Option<u32>,
+    /// gif quantization speed
+    speed: i32,
+    /// only write the changed part of each gif frame
+    delta: bool,
+    dither: Dither,
+}
+
+/// `path` for a still image, with .png added if it has no extension. checked
+/// before any work is done so an extension that can't be written to doesn't
+/// fail once everything else has
+fn still_output(path: String) -> Result<String, String> {
+    if Path::new(&path).extension().is_none() {
+        warning!("`{path}` has no extension, saving it as `{path}.png`");
+        return Ok(path + ".png");
+    }
+    match ImageFormat::from_path(&path) {
+        Ok(format) if format.writing_enabled() => Ok(path),
+        _ => {
+            let formats: Vec<&str> = ImageFormat::all()
+                .filter(|f| f.writing_enabled())
+                .map(|f| f.extensions_str()[0])
+                .collect();
+            Err(format!(
+                "can't save an image as `{path}`, the extension has to be one of {}",
+                formats.join(", ")
+            ))
+        }
+    }
+}
+
+/// `path` for an animation, with .gif added if it has no extension and
+/// `default` is set
+fn animation_output(path: String, default: bool) -> Result<String, String> {
+    if default && Path::new(&path).extension().is_none() {
+        warning!("`{path}` has no extension, saving it as `{path}.gif`");
+        return Ok(path + ".gif");
+    }
+    if !is_apng(&path) && !path.to_lowercase().ends_with(".gif") {
+        return Err(format!(
+            "can't save an animation as `{path}`, the extension has to be gif, png or apng"
+        ));
+    }
+    Ok(path)
+}
+
+/// pick the animation container from the output extension, .png and .apng
+/// give an animated png and .gif a gif
+fn create_encoder(
+    path: &str,
+    options: &EncoderOptions,
+    palette: Option<Palette>,
+) -> Result<Box<dyn FrameEncoder>, String> {
+    let &EncoderOptions {
+        width,
+        height,
+        frames,
+        loops,
+        speed,
+        delta,
+        dither,
+    } = options;
+    let Ok(file) = File::create(path) else {
+        return Err("unable to create new file".into());
+    };
+    let writer = BufWriter::new(file);
+
+    if is_apng(path) {
+        let encoder = ApngEncoder::new(writer, width, height, frames, loops.unwrap_or(1))?;
+        return Ok(Box::new(encoder));
+    }
+
+    if palette.is_some() || delta || dither != Dither::None {
+        let encoder =
+            QuantizedGifEncoder::new(writer, width, height, palette, speed, delta, loops)?
+                .with_dither(dither);
+        return Ok(Box::new(encoder));
+    }
+
+    let mut encoder = GifEncoder::new_with_speed(writer, speed);
+    if let Some(repeat) = loops.map(loops_to_repeat).transpose()?.flatten() {
+        if encoder.set_repeat(repeat).is_err() {
+            return Err("error in encoding gif".into());
+        }
+    }
+    Ok(Box::new(encoder))
+}
+
+/// everything from the command line except the input and output files
+#[derive(Clone)]
+struct Options {
+    iterations: u32,
+    split: Option<Split>,
+    outline: Option<RGB<u8>>,
+    gif_delta: Option<u32>,
+    extra_gifs: Vec<(u32, String)>,
+    frame_list: Option<Vec<u32>>,
+    /// iterations to save a still image at, sorted
+    checkpoints: Option<Vec<u32>>,
+    /// splits to take back after refining
+    undo: Option<usize>,
+    /// tree file to go on refining instead of starting from the whole image
+    resume: Option<String>,
+    fps: Option<u32>,
+    hold_last: Option<u32>,
+    direction: Option<Direction>,
+    spacing: Option<Spacing>,
+    loops: Option<u32>,
+    start_with_original: bool,
+    end_with_original: bool,
+    gif_quality: Option<i32>,
+    gif_scale: Option<f64>,
+    stable_palette: bool,
+    delta_frames: bool,
+    dither: Option<Dither>,
+    quiet: bool,
+    frames_dir: Option<String>,
+    force: bool,
+    /// show the main output in the default viewer once it is saved
+    open: bool,
+    video: Option<String>,
+    ffmpeg: Option<String>,
+    gif_heatmap: Option<String>,
+    tween: Option<u32>,
+    label: bool,
+    /// add the psnr of the frame to its label
+    label_psnr: bool,
+    label_corner: Option<Corner>,
+    label_color: Option<RGB<u8>>,
+    timings: bool,
+    encode: Option<String>,
+    encode_entropy: bool,
+    encode_lossless: bool,
+    export_json: Option<String>,
+    pretty: bool,
+    /// the pixel whose leaf is printed after refining
+    leaf_at: Option<(usize, usize)>,
+    /// print `Tree::stats` and how close the render is after refining
+    stats: bool,
+    /// add the ssim of the render to the stats
+    ssim: bool,
+    /// print the iteration at the knee of the error curve
+    suggest_iter: bool,
+    /// take the tree back to that iteration before saving
+    apply: bool,
+    /// stop refining once the render is this close, in decibels
+    target_psnr: Option<f64>,
+    /// print the stats on one line
+    porcelain: bool,
+    /// check everything and say what would be done instead of doing it
+    dry_run: bool,
+    /// take commands from stdin once refined, see `session`
+    interactive: bool,
+    /// names the outputs when -o isn't given
+    name_template: Option<Template>,
+    /// where the prefix sums of the inputs are kept between runs
+    cache_dir: Option<String>,
+    /// csv of the error as refining goes on, see `curve`
+    error_curve: Option<String>,
+    /// iterations between the rows of the error curve
+    error_curve_step: Option<u32>,
+    /// where -report writes what the run did
+    report: Option<String>,
+    /// every flag as it was resolved from the config file, the environment
+    /// and the command line, for the report
+    resolved: Json,
+    #[cfg(feature = "rayon")]
+    tiles: Option<u32>,
+}
+
+impl Options {
+    /// whether the main output is an animation rather than a still image
+    fn animate_main(&self) -> bool {
+        self.gif_delta.is_some() || self.frame_list.is_some()
+    }
+
+    fn animate(&self) -> bool {
+        self.animate_main() || !self.extra_gifs.is_empty()
+    }
+}
+
+/// an animation that is written to during refinement
+struct Output<'a> {
+    path: &'a str,
+    schedule: Peekable<Schedule>,
+    animation: Animation,
+    heatmap: Option<Animation>,
+    /// of every frame captured, in the order they were
+    frames: Vec<FrameQuality>,
+}
+
+/// how close the tree was to the original when a frame was captured, from
+/// the error it keeps as it is refined so a frame costs no extra render
+struct FrameQuality {
+    iteration: u32,
+    leaves: usize,
+    mse: Option<f64>,
+}
+
+/// the buffer of a frame `animation` has finished encoding, or nothing when
+/// a new one has to be allocated. allocations are counted for -timings
+fn reuse_buffer(animation: &mut Animation, timer: &mut PhaseTimer) -> Option<RgbaImage> {
+    let spare = animation.take_buffer();
+    if spare.is_none() {
+        timer.count("frame buffers", 1);
+    }
+    spare
+}
+
+/// render the current tree once and push it to every output in `capturing`.
+/// the render functions draw into the buffer they're given, or allocate one
+/// when given none, so after the first couple of frames nothing is allocated
+fn push_frames(
+    outputs: &mut [Output],
+    capturing: &[usize],
+    tree: &Tree,
+    i: u32,
+    render_frame: &mut impl FnMut(&Tree, u32, Option<RgbaImage>) -> RgbaImage,
+    render_heatmap: &impl Fn(&Tree, Option<RgbaImage>) -> RgbaImage,
+    timer: &mut PhaseTimer,
+) -> Result<(), String> {
+    let Some((&last, rest)) = capturing.split_last() else {
+        return Ok(());
+    };
+    timer.count("frames", 1);
+    for &k in capturing {
+        outputs[k].frames.push(FrameQuality {
+            iteration: i,
+            leaves: tree.leaf_count(),
+            mse: tree.mse(),
+        });
+    }
+    let spare = reuse_buffer(&mut outputs[last].animation, timer);
+    let buf = timer.time("render", || render_frame(tree, i, spare));
+    for &k in rest {
+        let copy = match reuse_buffer(&mut outputs[k].animation, timer) {
+            Some(mut copy) => {
+                copy.copy_from_slice(&buf);
+                copy
+            }
+            None => buf.clone(),
+        };
+        timer.time("encode", || outputs[k].animation.push(copy))?;
+    }
+    timer.time("encode", || outputs[last].animation.push(buf))?;
+    for &k in capturing {
+        if let Some(h) = &mut outputs[k].heatmap {
+            let spare = reuse_buffer(h, timer);
+            let heatmap = timer.time("render", || render_heatmap(tree, spare));
+            timer.time("encode", || h.push(heatmap))?;
+        }
+    }
+    Ok(())
+}
+
 fn real_main() -> i32 {
-    let mut input_file = None;
+    let mut input_files: Vec<String> = Vec::new();
     let mut output_file = None;
-    let mut iterations: u32 = 0;
+    let mut out_dir: Option<String> = None;
+    let mut recursive = false;
+    let mut iterations: Option<u32> = None;
+    let mut split: Option<Split> = None;
     let mut outline = None;
     let mut gif_delta: Option<u32> = None;
+    let mut extra_gifs: Vec<(u32, String)> = Vec::new();
+    let mut frame_list: Option<Vec<u32>> = None;
+    let mut checkpoints: Option<Vec<u32>> = None;
+    let mut undo: Option<usize> = None;
+    let mut resume: Option<String> = None;
+    let mut fps: Option<u32> = None;
+    let mut hold_last: Option<u32> = None;
+    let mut direction: Option<Direction> = None;
+    let mut spacing: Option<Spacing> = None;
+    let mut loops: Option<u32> = None;
+    let mut start_with_original = false;
+    let mut end_with_original = false;
+    let mut gif_quality: Option<i32> = None;
+    let mut gif_scale: Option<f64> = None;
+    let mut stable_palette = false;
+    let mut delta_frames = false;
+    let mut dither: Option<Dither> = None;
+    let mut quiet = false;
+    let mut verbose = false;
+    let mut jobs: Option<usize> = None;
+    let mut timings = false;
+    let mut tiles: Option<u32> = None;
+    let mut encode: Option<String> = None;
+    let mut encode_entropy = false;
+    let mut encode_lossless = false;
+    let mut export_json: Option<String> = None;
+    let mut pretty = false;
+    let mut decode: Option<String> = None;
+    let mut scale: Option<f64> = None;
+    let mut prune_to: Option<usize> = None;
+    let mut leaf_at: Option<(usize, usize)> = None;
+    let mut stats = false;
+    let mut ssim = false;
+    let mut suggest_iter = false;
+    let mut apply = false;
+    let mut target_psnr: Option<f64> = None;
+    let mut dry_run = false;
+    let mut interactive = false;
+    let mut name_template: Option<Template> = None;
+    let mut cache_dir: Option<String> = None;
+    let mut error_curve: Option<String> = None;
+    let mut error_curve_step: Option<u32> = None;
+    let mut report: Option<String> = None;
+    let mut porcelain = false;
+    let mut frames_dir: Option<String> = None;
+    let mut force = false;
+    let mut open = false;
+    let mut watch = false;
+    let mut video: Option<String> = None;
+    let mut ffmpeg: Option<String> = None;
+    let mut gif_heatmap: Option<String> = None;
+    let mut tween: Option<u32> = None;
+    let mut label = false;
+    let mut label_psnr = false;
+    let mut label_corner: Option<Corner> = None;
+    let mut label_color: Option<RGB<u8>> = None;
 
-    let mut args = env::args();
+    let mut args = env::args().peekable();
     let Some(program_name) = args.next() else {
-        return 1;
+        return error::USAGE;
+    };
+    if args.next_if_eq("diff").is_some() {
+        return diff_main(&program_name, args);
+    }
+    if args.next_if_eq("completions").is_some() {
+        return completions_main(&program_name, args);
+    }
+    // without a command every flag is taken, as before there were commands
+    let command = args.peek().and_then(|arg| cli::command(arg));
+    if command.is_some() {
+        args.next();
+    }
+    let (args, config_warnings) = match config::apply(args.collect(), command, check_flag_value) {
+        Ok(applied) => applied,
+        Err(err) => {
+            error!("{err}");
+            print_usage(&program_name, command);
+            return error::USAGE;
+        }
     };
+    let resolved = report::options(&args);
+    let mut args = args.into_iter();
     while let Some(arg) = args.next() {
+        let arg = match cli::canonical(&arg) {
+            Some(name) => {
+                if let Some(c) = command.filter(|c| !c.takes(name)) {
+                    error!(
+                        "{} doesn't take {arg}, `{program_name} {} -h` lists the flags it does",
+                        c.name, c.name
+                    );
+                    print_usage(&program_name, command);
+                    return error::USAGE;
+                }
+                name.to_string()
+            }
+            None if cli::is_flag(&arg) => {
+                match cli::suggest(&arg) {
+                    Some(name) => error!("unknown flag `{arg}`, did you mean {name}?"),
+                    None => error!("unknown flag `{arg}`"),
+                }
+                print_usage(&program_name, command);
+                return error::USAGE;
+            }
+            None => arg,
+        };
         if arg == "-h" {
-            print_usage(&program_name);
-            print_help();
+            match command {
+                Some(_) => println!("{}", usage(&program_name, command)),
+                None => {
+                    let mut prefix = "usage:";
+                    for c in &cli::COMMANDS {
+                        println!("{prefix} {}", c.usage(&program_name));
+                        prefix = "      ";
+                    }
+                    println!("{}", diff_usage(&program_name));
+                    println!("{}", completions_usage(&program_name));
+                }
+            }
+            cli::print_help(command);
+            return 0;
+        } else if arg == "-V" {
+            cli::print_version();
             return 0;
         } else if arg == "-o" {
             if let Some(of) = args.next() {
                 output_file = Some(of);
             } else {
-                println!("output file not specified");
-                print_usage(&program_name);
-                return 1;
+                error!("output file not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
+            }
+        } else if arg == "-out-dir" {
+            if let Some(d) = args.next() {
+                out_dir = Some(d);
+            } else {
+                error!("output directory not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
+            }
+        } else if arg == "-cache-dir" {
+            if let Some(d) = args.next() {
+                cache_dir = Some(d);
+            } else {
+                error!("cache directory not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
             }
+        } else if arg == "-name-template" {
+            if let Some(t_str) = args.next() {
+                name_template = match Template::parse(&t_str) {
+                    Ok(template) => Some(template),
+                    Err(err) => {
+                        error!("{err}");
+                        print_usage(&program_name, command);
+                        return error::USAGE;
+                    }
+                };
+            } else {
+                error!("name template not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
+            }
+        } else if arg == "-recursive" {
+            recursive = true;
         } else if arg == "-iter" {
             if let Some(i_str) = args.next() {
                 iterations = match i_str.parse() {
-                    Ok(iters) => iters,
+                    Ok(0) => {
+                        error!("-iter 0 would leave the image as one flat region, use at least 1");
+                        print_usage(&program_name, command);
+                        return error::USAGE;
+                    }
+                    Ok(iters) => Some(iters),
                     Err(_) => {
-                        println!("invalid number of iterations");
-                        print_usage(&program_name);
-                        return 1;
+                        error!("invalid number of iterations");
+                        print_usage(&program_name, command);
+                        return error::USAGE;
                     }
                 }
             } else {
-                println!("number of iterations not specified");
-                print_usage(&program_name);
-                return 1;
+                error!("number of iterations not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
             }
         } else if arg == "-outline" {
             if let Some(h_str) = args.next() {
                 outline = match hex_to_rgb(&h_str) {
                     Ok(rgb) => Some(rgb),
                     Err(err) => {
-                        println!("{err}");
-                        return 1;
+                        error!("{err}");
+                        return error::USAGE;
                     }
                 }
             } else {
-                println!("outline hex code not specified");
-                print_usage(&program_name);
-                return 1;
+                error!("outline hex code not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
             }
         } else if arg == "-gif" {
             if let Some(g_str) = args.next() {
-                gif_delta = match g_str.parse() {
-                    Ok(delta) => Some(delta),
-                    Err(_) => {
-                        println!("invalid gif save delta");
-                        print_usage(&program_name);
-                        return 1;
+                // `delta:path` adds another animation next to the main output
+                let (d_str, path) = match g_str.split_once(':') {
+                    Some((d, p)) => (d, Some(p.to_string())),
+                    None => (g_str.as_str(), None),
+                };
+                let delta = match d_str.parse() {
+                    Ok(delta) if delta > 0 => delta,
+                    _ => {
+                        error!("invalid gif save delta");
+                        print_usage(&program_name, command);
+                        return error::USAGE;
                     }
+                };
+                match path {
+                    Some(p) => extra_gifs.push((delta, p)),
+                    None if gif_delta.is_some() => {
+                        error!("-gif without an output path can only be given once");
+                        print_usage(&program_name, command);
+                        return error::USAGE;
+                    }
+                    None => gif_delta = Some(delta),
                 }
             } else {
-                println!("gif save delta not specified");
-                print_usage(&program_name);
-                return 1;
+                error!("gif save delta not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
             }
-        } else {
-            input_file = Some(arg);
-        }
-    }
-
-    let input_file = match input_file {
-        Some(in_s) => in_s,
-        None => {
-            println!("no input file given");
-            print_usage(&program_name);
-            return 1;
-        }
-    };
-
-    let output_file = match output_file {
-        Some(out_s) => out_s,
-        None => match file_without_extension(&input_file) {
-            Ok((stem, extension)) => {
-                if let Some(_) = gif_delta {
-                    format!("{stem}-comprs.gif")
-                } else {
-                    format!("{stem}-comprs.{extension}")
+        } else if arg == "-frames" {
+            if let Some(f_str) = args.next() {
+                frame_list = match parse_frame_list(&f_str) {
+                    Ok(list) => Some(list),
+                    Err(err) => {
+                        error!("{err}");
+                        print_usage(&program_name, command);
+                        return error::USAGE;
+                    }
                 }
+            } else {
+                error!("frame list not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
             }
-            Err(err) => {
-                println!("{err}");
-                return 1;
+        } else if arg == "-checkpoints" {
+            if let Some(c_str) = args.next() {
+                checkpoints = match parse_frame_list(&c_str) {
+                    Ok(list) => Some(list),
+                    Err(err) => {
+                        error!("{err}");
+                        print_usage(&program_name, command);
+                        return error::USAGE;
+                    }
+                }
+            } else {
+                error!("checkpoint list not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
             }
-        },
-    };
-
-    let data = match ImageData::from_path(&input_file) {
-        Ok(d) => d,
-        Err(err) => {
-            println!("{err}");
-            return 1;
-        }
-    };
-
-    let mut tree = Tree::new(data);
-    match gif_delta {
-        Some(delta) => {
-            let mut frames = Vec::new();
-            let buf = tree.render_rgba(outline);
-            frames.push(Frame::new(buf));
-            for i in 1..=iterations {
-                if let Err(err) = tree.refine() {
-                    println!("{err}");
-                    return 1;
+        } else if arg == "-undo" {
+            if let Some(u_str) = args.next() {
+                undo = match u_str.parse() {
+                    Ok(u) => Some(u),
+                    _ => {
+                        error!("invalid number of splits to undo");
+                        print_usage(&program_name, command);
+                        return error::USAGE;
+                    }
                 }
-                if i % delta == 0 {
-                    let buf = tree.render_rgba(outline);
-                    frames.push(Frame::new(buf));
+            } else {
+                error!("number of splits to undo not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
+            }
+        } else if arg == "-resume" {
+            if let Some(r) = args.next() {
+                resume = Some(r);
+            } else {
+                error!("tree file to resume not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
+            }
+        } else if arg == "-fps" {
+            if let Some(f_str) = args.next() {
+                fps = match f_str.parse() {
+                    Ok(f) if f > 0 => Some(f),
+                    _ => {
+                        error!("invalid gif fps");
+                        print_usage(&program_name, command);
+                        return error::USAGE;
+                    }
                 }
+            } else {
+                error!("gif fps not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
             }
-
-            println!("encoding gif...");
-            let Ok(file) = File::create(output_file) else {
-                println!("unable to create new file");
-                return 1;
-            };
-            let writer = BufWriter::new(file);
-            let mut encoder = GifEncoder::new_with_speed(writer, 30);
-            if let Err(_) = encoder.encode_frames(frames) {
-                println!("error in encoding gif");
-                return 1;
+        } else if arg == "-hold-last" {
+            if let Some(h_str) = args.next() {
+                hold_last = match h_str.parse() {
+                    Ok(h) => Some(h),
+                    Err(_) => {
+                        error!("invalid hold duration");
+                        print_usage(&program_name, command);
+                        return error::USAGE;
+                    }
+                }
+            } else {
+                error!("hold duration not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
             }
-        }
-        None => {
-            for _ in 1..=iterations {
-                if let Err(err) = tree.refine() {
-                    println!("{err}");
-                    return 1;
+        } else if arg == "-gif-direction" {
+            if let Some(d_str) = args.next() {
+                direction = match Direction::parse(&d_str) {
+                    Ok(d) => Some(d),
+                    Err(err) => {
+                        error!("{err}");
+                        print_usage(&program_name, command);
+                        return error::USAGE;
+                    }
+                }
+            } else {
+                error!("gif direction not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
+            }
+        } else if arg == "-gif-spacing" {
+            if let Some(s_str) = args.next() {
+                spacing = match Spacing::parse(&s_str) {
+                    Ok(s) => Some(s),
+                    Err(err) => {
+                        error!("{err}");
+                        print_usage(&program_name, command);
+                        return error::USAGE;
+                    }
                 }
+            } else {
+                error!("gif spacing not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
             }
-            if let Err(err) = tree.render_rgb(outline).save(output_file) {
-                println!("{err}");
-                return 1;
+        } else if arg == "-loop" {
+            if let Some(l_str) = args.next() {
+                let Ok(l) = l_str.parse() else {
+                    error!("invalid gif loop count");
+                    print_usage(&program_name, command);
+                    return error::USAGE;
+                };
+                if let Err(err) = loops_to_repeat(l) {
+                    error!("{err}");
+                    return error::USAGE;
+                }
+                loops = Some(l);
+            } else {
+                error!("gif loop count not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
             }
-        }
-    }
-
-    0
+        } else if arg == "-gif-quality" {
+            if let Some(q_str) = args.next() {
+                gif_quality = match q_str.parse() {
+                    Ok(q) if (1..=30).contains(&q) => Some(q),
+                    _ => {
+                        error!("invalid gif quality, must be between 1 and 30");
+                        print_usage(&program_name, command);
+                        return error::USAGE;
+                    }
+                }
+            } else {
+                error!("gif quality not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
+            }
+        } else if arg == "-gif-scale" {
+            if let Some(s_str) = args.next() {
+                gif_scale = match s_str.parse() {
+                    Ok(s) if (0.1..=1.0).contains(&s) => Some(s),
+                    _ => {
+                        error!("invalid gif scale, must be between 0.1 and 1.0");
+                        print_usage(&program_name, command);
+                        return error::USAGE;
+                    }
+                }
+            } else {
+                error!("gif scale not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
+            }
+        } else if arg == "-frames-dir" {
+            if let Some(d) = args.next() {
+                frames_dir = Some(d);
+            } else {
+                error!("frames directory not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
+            }
+        } else if arg == "-force" {
+            force = true;
+        } else if arg == "-open" {
+            open = true;
+        } else if arg == "-watch" {
+            watch = true;
+        } else if arg == "-video" {
+            if let Some(v) = args.next() {
+                video = Some(v);
+            } else {
+                error!("video output file not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
+            }
+        } else if arg == "-ffmpeg" {
+            if let Some(f) = args.next() {
+                ffmpeg = Some(f);
+            } else {
+                error!("ffmpeg path not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
+            }
+        } else if arg == "-gif-heatmap" {
+            if let Some(h) = args.next() {
+                gif_heatmap = Some(h);
+            } else {
+                error!("heatmap output file not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
+            }
+        } else if arg == "-tween" {
+            if let Some(t_str) = args.next() {
+                tween = match t_str.parse() {
+                    Ok(t) => Some(t),
+                    Err(_) => {
+                        error!("invalid number of tween frames");
+                        print_usage(&program_name, command);
+                        return error::USAGE;
+                    }
+                }
+            } else {
+                error!("number of tween frames not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
+            }
+        } else if arg == "-label" {
+            label = true;
+        } else if arg == "-label-psnr" {
+            label_psnr = true;
+        } else if arg == "-label-corner" {
+            if let Some(c_str) = args.next() {
+                label_corner = match Corner::parse(&c_str) {
+                    Ok(c) => Some(c),
+                    Err(err) => {
+                        error!("{err}");
+                        print_usage(&program_name, command);
+                        return error::USAGE;
+                    }
+                }
+            } else {
+                error!("label corner not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
+            }
+        } else if arg == "-label-color" {
+            if let Some(h_str) = args.next() {
+                label_color = match hex_to_rgb(&h_str) {
+                    Ok(rgb) => Some(rgb),
+                    Err(err) => {
+                        error!("{err}");
+                        return error::USAGE;
+                    }
+                }
+            } else {
+                error!("label color hex code not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
+            }
+        } else if arg == "-stable-palette" {
+            stable_palette = true;
+        } else if arg == "-delta-frames" {
+            delta_frames = true;
+        } else if arg == "-split" {
+            if let Some(s_str) = args.next() {
+                split = match Split::parse(&s_str) {
+                    Ok(s) => Some(s),
+                    Err(err) => {
+                        error!("{err}");
+                        print_usage(&program_name, command);
+                        return error::USAGE;
+                    }
+                }
+            } else {
+                error!("split strategy not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
+            }
+        } else if arg == "-gif-dither" {
+            if let Some(d_str) = args.next() {
+                dither = match Dither::parse(&d_str) {
+                    Ok(d) => Some(d),
+                    Err(err) => {
+                        error!("{err}");
+                        print_usage(&program_name, command);
+                        return error::USAGE;
+                    }
+                }
+            } else {
+                error!("gif dither not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
+            }
+        } else if arg == "-jobs" {
+            if let Some(j_str) = args.next() {
+                jobs = match j_str.parse() {
+                    Ok(j) if j > 0 => Some(j),
+                    _ => {
+                        error!("invalid number of jobs, must be at least 1");
+                        print_usage(&program_name, command);
+                        return error::USAGE;
+                    }
+                }
+            } else {
+                error!("number of jobs not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
+            }
+        } else if arg == "-encode" {
+            if let Some(e) = args.next() {
+                encode = Some(e);
+            } else {
+                error!("encoded tree output file not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
+            }
+        } else if arg == "-encode-entropy" {
+            encode_entropy = true;
+        } else if arg == "-encode-lossless" {
+            encode_lossless = true;
+        } else if arg == "-export-json" {
+            if let Some(j) = args.next() {
+                export_json = Some(j);
+            } else {
+                error!("json output file not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
+            }
+        } else if arg == "-pretty" {
+            pretty = true;
+        } else if arg == "-error-curve" {
+            if let Some(c) = args.next() {
+                error_curve = Some(c);
+            } else {
+                error!("error curve output file not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
+            }
+        } else if arg == "-report" {
+            if let Some(r) = args.next() {
+                report = Some(r);
+            } else {
+                error!("report output file not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
+            }
+        } else if arg == "-error-curve-step" {
+            if let Some(s_str) = args.next() {
+                error_curve_step = match s_str.parse() {
+                    Ok(s) if s > 0 => Some(s),
+                    _ => {
+                        error!("invalid error curve step, must be at least 1");
+                        print_usage(&program_name, command);
+                        return error::USAGE;
+                    }
+                }
+            } else {
+                error!("error curve step not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
+            }
+        } else if arg == "-decode" {
+            if let Some(d) = args.next() {
+                decode = Some(d);
+            } else {
+                error!("tree file to decode not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
+            }
+        } else if arg == "-scale" {
+            if let Some(s_str) = args.next() {
+                scale = match s_str.parse() {
+                    Ok(s) if (0.1..=1.0).contains(&s) => Some(s),
+                    _ => {
+                        error!("invalid scale, must be between 0.1 and 1.0");
+                        print_usage(&program_name, command);
+                        return error::USAGE;
+                    }
+                }
+            } else {
+                error!("scale not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
+            }
+        } else if arg == "-prune-to-leaves" {
+            if let Some(p_str) = args.next() {
+                prune_to = match p_str.parse() {
+                    Ok(p) if p > 0 => Some(p),
+                    _ => {
+                        error!("invalid number of leaves, must be at least 1");
+                        print_decode_usage(&program_name, command);
+                        return error::USAGE;
+                    }
+                }
+            } else {
+                error!("number of leaves not specified");
+                print_decode_usage(&program_name, command);
+                return error::USAGE;
+            }
+        } else if arg == "-leaf-at" {
+            if let Some(p_str) = args.next() {
+                leaf_at = match parse_pixel(&p_str) {
+                    Some(p) => Some(p),
+                    None => {
+                        error!("invalid pixel, must be x,y");
+                        print_usage(&program_name, command);
+                        return error::USAGE;
+                    }
+                }
+            } else {
+                error!("pixel not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
+            }
+        } else if arg == "-stats" {
+            stats = true;
+        } else if arg == "-ssim" {
+            ssim = true;
+        } else if arg == "-suggest-iter" {
+            suggest_iter = true;
+        } else if arg == "-apply" {
+            apply = true;
+        } else if arg == "-target-psnr" {
+            if let Some(t_str) = args.next() {
+                target_psnr = match parse_target_psnr(&t_str) {
+                    Ok(t) => Some(t),
+                    Err(err) => {
+                        error!("{err}");
+                        print_usage(&program_name, command);
+                        return error::USAGE;
+                    }
+                }
+            } else {
+                error!("target psnr not specified");
+                return error::USAGE;
+            }
+        } else if arg == "-porcelain" {
+            porcelain = true;
+        } else if arg == "-dry-run" {
+            dry_run = true;
+        } else if arg == "-interactive" {
+            interactive = true;
+        } else if arg == "-tiles" {
+            if let Some(t_str) = args.next() {
+                tiles = match t_str.parse() {
+                    Ok(t) if t > 0 => Some(t),
+                    _ => {
+                        error!("invalid number of tiles, must be at least 1");
+                        print_usage(&program_name, command);
+                        return error::USAGE;
+                    }
+                }
+            } else {
+                error!("number of tiles not specified");
+                print_usage(&program_name, command);
+                return error::USAGE;
+            }
+        } else if arg == "-quiet" {
+            quiet = true;
+        } else if arg == "-verbose" {
+            verbose = true;
+        } else if arg == "-timings" {
+            timings = true;
+        } else if arg == "-start-with-original" {
+            start_with_original = true;
+        } else if arg == "-end-with-original" {
+            end_with_original = true;
+        } else if cli::is_flag(&arg) {
+            // in cli::GROUPS, but nothing above parses it
+            error!("{arg} isn't handled, this is a bug in comprs");
+            return error::FAILURE;
+        } else {
+            input_files.push(arg);
+        }
+    }
+
+    if quiet && verbose {
+        error!("-quiet and -verbose can't be used together");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    log::set_level(if quiet {
+        Level::Quiet
+    } else if verbose {
+        Level::Verbose
+    } else {
+        Level::Normal
+    });
+    if porcelain {
+        porcelain::enable();
+    }
+    for warning in config_warnings {
+        warning!("{warning}");
+    }
+    if gif_delta.is_some() && frame_list.is_some() {
+        error!("-gif and -frames can't be used together");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    // whether the main output is an animation rather than a still image
+    let animate_main = gif_delta.is_some() || frame_list.is_some();
+    let animate = animate_main || !extra_gifs.is_empty();
+    match command.map(|c| c.name) {
+        None => {
+            let instead = if decode.is_some() {
+                "decode"
+            } else if animate {
+                "animate"
+            } else {
+                "compress"
+            };
+            warning!("running comprs without a command is deprecated and stops working in the next release, use `{program_name} {instead}`");
+        }
+        Some("animate") if !animate => {
+            error!("animate needs -gif or -frames, compress saves only the still image");
+            print_usage(&program_name, command);
+            return error::USAGE;
+        }
+        Some(name @ ("decode" | "stats" | "restyle")) => {
+            // the tree is given like an input file, where -decode names it
+            // without a command
+            if input_files.len() != 1 {
+                error!("{name} needs exactly one tree file");
+                print_usage(&program_name, command);
+                return error::USAGE;
+            }
+            decode = input_files.pop();
+            if name == "stats" {
+                stats = true;
+            }
+            if name == "restyle" && outline.is_none() && scale.is_none() {
+                error!("restyle needs -outline or -scale, decode renders the tree as it was saved");
+                print_usage(&program_name, command);
+                return error::USAGE;
+            }
+        }
+        Some(_) => {}
+    }
+    if fps.is_some() && !animate {
+        error!("-fps can only be used with -gif or -frames");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if hold_last.is_some() && !animate {
+        error!("-hold-last can only be used with -gif or -frames");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if direction.is_some() && !animate {
+        error!("-gif-direction can only be used with -gif or -frames");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if spacing.is_some() && gif_delta.is_none() && extra_gifs.is_empty() {
+        error!("-gif-spacing can only be used with -gif, not -frames");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if loops.is_some() && !animate {
+        error!("-loop can only be used with -gif or -frames");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if (start_with_original || end_with_original) && !animate {
+        error!("-start-with-original and -end-with-original can only be used with -gif or -frames");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if frames_dir.is_some() && !animate {
+        error!("-frames-dir can only be used with -gif or -frames");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if video.is_some() && !animate {
+        error!("-video can only be used with -gif or -frames");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if video.is_some() && frames_dir.is_some() {
+        error!("-video and -frames-dir can't be used together");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if ffmpeg.is_some() && video.is_none() {
+        error!("-ffmpeg can only be used with -video");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if gif_heatmap.is_some() && !animate {
+        error!("-gif-heatmap can only be used with -gif or -frames");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if tween.is_some() && !animate {
+        error!("-tween can only be used with -gif or -frames");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if label && !animate {
+        error!("-label can only be used with -gif or -frames");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if (label_psnr || label_corner.is_some() || label_color.is_some()) && !label {
+        error!("-label-psnr, -label-corner and -label-color can only be used with -label");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if stable_palette && !animate {
+        error!("-stable-palette can only be used with -gif or -frames");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if delta_frames && !animate {
+        error!("-delta-frames can only be used with -gif or -frames");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if dither.is_some() && !animate {
+        error!("-gif-dither can only be used with -gif or -frames");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if gif_scale.is_some() && !animate {
+        error!("-gif-scale can only be used with -gif or -frames");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if gif_quality.is_some() && !animate {
+        error!("-gif-quality can only be used with -gif or -frames");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+
+    if tiles.is_some() && !cfg!(feature = "rayon") {
+        error!("-tiles needs comprs to be built with --features rayon");
+        return error::USAGE;
+    }
+    if encode_entropy && encode.is_none() {
+        error!("-encode-entropy can only be used with -encode");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if encode_lossless && encode.is_none() {
+        error!("-encode-lossless can only be used with -encode");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if pretty && export_json.is_none() {
+        error!("-pretty can only be used with -export-json");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if tiles.is_some() && (encode.is_some() || export_json.is_some()) {
+        error!("-tiles can't be used with -encode or -export-json");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if error_curve_step.is_some() && error_curve.is_none() {
+        error!("-error-curve-step can only be used with -error-curve");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if tiles.is_some() && error_curve.is_some() {
+        error!("-tiles can't be used with -error-curve");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if ssim && !stats && report.is_none() {
+        error!("-ssim can only be used with -stats or -report");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if apply && !suggest_iter {
+        error!("-apply can only be used with -suggest-iter");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if apply && (undo.is_some() || checkpoints.is_some() || animate) {
+        error!("-apply can't be used with -undo, -checkpoints, -gif or -frames");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if tiles.is_some() && suggest_iter {
+        error!("-tiles can't be used with -suggest-iter");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if tiles.is_some() && target_psnr.is_some() {
+        error!("-tiles can't be used with -target-psnr");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if report.is_some() && dry_run {
+        error!("-report can't be used with -dry-run, nothing is run to report on");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if let Some(list) = checkpoints
+        .as_mut()
+        .filter(|c| !c.is_sorted_by(|a, b| a < b))
+    {
+        warning!("checkpoint list is not strictly ascending, sorting and removing duplicates");
+        list.sort_unstable();
+        list.dedup();
+    }
+    if let (Some(&last), Some(iterations)) =
+        (checkpoints.as_ref().and_then(|c| c.last()), iterations)
+    {
+        if last > iterations {
+            error!("checkpoint {last} is past the {iterations} iterations given with -iter");
+            print_usage(&program_name, command);
+            return error::USAGE;
+        }
+    }
+    if tiles.is_some() && undo.is_some() {
+        error!("-tiles can't be used with -undo");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if tiles.is_some() && resume.is_some() {
+        error!("-tiles can't be used with -resume");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if tiles.is_some() && checkpoints.is_some() {
+        error!("-tiles can't be used with -checkpoints");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if tiles.is_some() && leaf_at.is_some() {
+        error!("-tiles can't be used with -leaf-at");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if porcelain && (dry_run || interactive) {
+        error!(
+            "-porcelain can't be used with -dry-run or -interactive, they only print for people"
+        );
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if tiles.is_some() && (stats || ssim) {
+        error!("-tiles can't be used with -stats or -ssim");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if tiles.is_some() && animate {
+        error!("-tiles can't be used with -gif or -frames");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if interactive && (tiles.is_some() || animate || dry_run) {
+        error!("-interactive can't be used with -tiles, -gif, -frames or -dry-run");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if scale.is_some() && decode.is_none() {
+        error!("-scale can only be used with -decode");
+        print_decode_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if prune_to.is_some() && decode.is_none() {
+        error!("-prune-to-leaves can only be used with -decode");
+        print_decode_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if let Some(tree_file) = decode {
+        // a tree has nothing to refine or animate, only outline and scale apply
+        if !input_files.is_empty()
+            || out_dir.is_some()
+            || recursive
+            || dry_run
+            || interactive
+            || watch
+            || name_template.is_some()
+            || iterations.is_some()
+            || split.is_some()
+            || checkpoints.is_some()
+            || undo.is_some()
+            || resume.is_some()
+            || animate
+            || encode.is_some()
+            || export_json.is_some()
+            || tiles.is_some()
+            || ssim
+            || suggest_iter
+            || target_psnr.is_some()
+        {
+            error!(
+                "-decode can only be used with -o, -force, -open, -outline, -scale, -prune-to-leaves, -leaf-at, -stats, -porcelain and -timings"
+            );
+            print_decode_usage(&program_name, command);
+            return error::USAGE;
+        }
+        // stats prints what it finds and renders nothing
+        let output_file = match output_file {
+            Some(of) => {
+                let path = match still_output(of) {
+                    Ok(path) => path,
+                    Err(err) => {
+                        error!("{err}");
+                        return error::USAGE;
+                    }
+                };
+                let writable = check_overwrites(&tree_file, &[&path], force)
+                    .and_then(|_| check_output_dirs(&[&path]));
+                if let Err(err) = writable {
+                    error!("{err}");
+                    return error::OUTPUT;
+                }
+                Some(path)
+            }
+            None if command.is_some_and(|c| c.name == "stats") => None,
+            None => {
+                let name = command.map_or("-decode", |c| c.name);
+                error!("{name} needs an output file, give one with -o");
+                print_decode_usage(&program_name, command);
+                return error::USAGE;
+            }
+        };
+        let scale = scale.unwrap_or(1.0);
+        let render = DecodeOptions {
+            outline,
+            scale,
+            prune_to,
+            leaf_at,
+            stats,
+            porcelain,
+            timings,
+        };
+        porcelain::start();
+        let result = decode_tree(&tree_file, output_file.as_deref(), &render);
+        porcelain::result(&tree_file, result.as_ref().err());
+        return match result {
+            Ok(()) => {
+                if let Some(path) = output_file.filter(|_| open) {
+                    open::open(&open::System, &path);
+                }
+                0
+            }
+            Err(err) => {
+                error!("{err}");
+                err.code()
+            }
+        };
+    }
+    if input_files.is_empty() {
+        error!("no input file given");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    let Some(iterations) = iterations else {
+        error!("number of iterations not given, add e.g. -iter 8000, or set iter in the config file or COMPRS_ITER in the environment. more iterations means more detail");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    };
+    // -o names the output outright, so a template from the config file
+    // doesn't get in its way
+    if output_file.is_some() {
+        name_template = None;
+    }
+    if let Some(template) = &name_template {
+        if template.uses("leaves") && (resume.is_some() || tiles.is_some()) {
+            error!("{{leaves}} in -name-template can't be used with -resume or -tiles");
+            print_usage(&program_name, command);
+            return error::USAGE;
+        }
+        if checkpoints.is_some() && !template.uses("iter") {
+            error!("-checkpoints with -name-template needs {{iter}} in the template, or every checkpoint gets the same name");
+            print_usage(&program_name, command);
+            return error::USAGE;
+        }
+    }
+    if output_file.is_some() && out_dir.is_some() {
+        error!("-o and -out-dir can't be used together");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    // it is created when it doesn't exist, a file in its way is an error
+    if let Some(dir) = out_dir.as_ref().filter(|d| Path::new(d).exists()) {
+        if !Path::new(dir).is_dir() {
+            error!("output directory `{dir}` isn't a directory");
+            return error::OUTPUT;
+        }
+    }
+    let any_dir = input_files.iter().any(|p| Path::new(p).is_dir());
+    if recursive && !any_dir {
+        error!("-recursive needs a directory as input");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    let options = Options {
+        iterations,
+        split,
+        outline,
+        gif_delta,
+        extra_gifs,
+        frame_list,
+        checkpoints,
+        undo,
+        resume,
+        fps,
+        hold_last,
+        direction,
+        spacing,
+        loops,
+        start_with_original,
+        end_with_original,
+        gif_quality,
+        gif_scale,
+        stable_palette,
+        delta_frames,
+        dither,
+        quiet,
+        frames_dir,
+        force,
+        open,
+        video,
+        ffmpeg,
+        gif_heatmap,
+        tween,
+        label,
+        label_psnr,
+        label_corner,
+        label_color,
+        timings,
+        encode,
+        encode_entropy,
+        encode_lossless,
+        export_json,
+        pretty,
+        leaf_at,
+        stats,
+        ssim,
+        suggest_iter,
+        apply,
+        target_psnr,
+        porcelain,
+        dry_run,
+        interactive,
+        name_template,
+        cache_dir,
+        error_curve,
+        error_curve_step,
+        report,
+        resolved,
+        #[cfg(feature = "rayon")]
+        tiles,
+    };
+
+    if watch && (dry_run || interactive) {
+        error!("-watch can't be used with -dry-run or -interactive");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if watch && any_dir {
+        // outputs written into a watched directory would be compressed
+        // again as new inputs on the next run
+        let Some(dir) = &out_dir else {
+            error!(
+                "-watch on a directory needs -out-dir, so the outputs aren't taken for new inputs"
+            );
+            print_usage(&program_name, command);
+            return error::USAGE;
+        };
+        let absolute = |p: &str| {
+            fs::canonicalize(p).unwrap_or_else(|_| env::current_dir().unwrap_or_default().join(p))
+        };
+        let out = absolute(dir);
+        if recursive
+            && input_files
+                .iter()
+                .any(|p| Path::new(p).is_dir() && out.starts_with(absolute(p)))
+        {
+            error!("-watch -recursive needs an -out-dir outside the directories it watches");
+            print_usage(&program_name, command);
+            return error::USAGE;
+        }
+    }
+    // written once every input is done, so it is checked before any runs
+    if let Some(path) = &options.report {
+        for input in &input_files {
+            if let Err(err) = check_overwrites(input, &[path], options.force) {
+                error!("{err}");
+                return error::OUTPUT;
+            }
+        }
+    }
+    porcelain::start();
+    interrupt::install();
+    if input_files.len() == 1 && !any_dir {
+        let input_file = &input_files[0];
+        if !watch {
+            return run_single(input_file, output_file, out_dir.as_deref(), &options);
+        }
+        let rerun = Options {
+            force: true,
+            open: false,
+            ..options.clone()
+        };
+        return watch::run(&input_files, false, |again| {
+            let options = if again { &rerun } else { &options };
+            run_single(input_file, output_file.clone(), out_dir.as_deref(), options)
+        });
+    }
+
+    // several inputs each get their own default output names
+    if output_file.is_some() {
+        error!("-o can't be used with more than one input file or a directory");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if !options.extra_gifs.is_empty()
+        || options.gif_heatmap.is_some()
+        || options.encode.is_some()
+        || options.export_json.is_some()
+        || options.error_curve.is_some()
+        || options.frames_dir.is_some()
+        || options.video.is_some()
+    {
+        error!("outputs with a path can't be used with more than one input file");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if options.leaf_at.is_some() {
+        error!("-leaf-at can't be used with more than one input file");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if options.stats {
+        error!("-stats can't be used with more than one input file");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if options.resume.is_some() {
+        error!("-resume can't be used with more than one input file");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if options.interactive {
+        error!("-interactive can't be used with more than one input file");
+        print_usage(&program_name, command);
+        return error::USAGE;
+    }
+    if options.open {
+        warning!("-open only opens the output of a single input file, these outputs aren't opened");
+    }
+    // progress lines from several files would overwrite each other
+    let options = Options {
+        quiet: true,
+        open: false,
+        ..options
+    };
+    let jobs = jobs.unwrap_or_else(batch::default_jobs);
+    if !watch {
+        return run_batch(&input_files, recursive, out_dir.as_deref(), jobs, &options);
+    }
+    let rerun = Options {
+        force: true,
+        ..options.clone()
+    };
+    watch::run(&input_files, recursive, |again| {
+        let options = if again { &rerun } else { &options };
+        run_batch(&input_files, recursive, out_dir.as_deref(), jobs, options)
+    })
+}
+
+/// compress a single input and return the exit code
+fn run_single(
+    input_file: &str,
+    output_file: Option<String>,
+    out_dir: Option<&str>,
+    options: &Options,
+) -> i32 {
+    let output_file = match (output_file, out_dir) {
+        (None, Some(dir)) => {
+            match default_output_file(input_file, options).and_then(|default| {
+                batch::output_in(Path::new(dir), Path::new(""), &default, !options.dry_run)
+                    .map_err(Error::Output)
+            }) {
+                Ok(path) => Some(path),
+                Err(err) => {
+                    error!("{err}");
+                    return err.code();
+                }
+            }
+        }
+        (output_file, _) => output_file,
+    };
+    let mut report = Report::new(options.report.is_some());
+    let result = compress(input_file, output_file, options, &mut report);
+    porcelain::result(input_file, result.as_ref().err());
+    if let Some(path) = &options.report {
+        let entry = report.entry(input_file, result.as_ref().err());
+        if let Err(err) = report::write(path, &report::single(&options.resolved, entry)) {
+            error!("{err}");
+            return err.code();
+        }
+    }
+    match result {
+        Ok(note) => {
+            if let Some(note) = note {
+                info!("{note}");
+            }
+            if interrupt::requested() {
+                error::INTERRUPTED
+            } else {
+                0
+            }
+        }
+        Err(err) => {
+            error!("{err}");
+            err.code()
+        }
+    }
+}
+
+/// compress the images `input_files` stand for, `jobs` at a time, and
+/// return the exit code of the first that failed
+fn run_batch(
+    input_files: &[String],
+    recursive: bool,
+    out_dir: Option<&str>,
+    jobs: usize,
+    options: &Options,
+) -> i32 {
+    let inputs = match batch::Inputs::expand(input_files.to_vec(), recursive) {
+        Ok(inputs) => inputs,
+        Err(err) => {
+            error!("{err}");
+            return err.code();
+        }
+    };
+    let split = options.split.unwrap_or(Split::Midpoint);
+    let footprint =
+        |path: &str| batch::footprint(path, options.iterations, split, options.animate());
+    let start = Instant::now();
+    let entries: Mutex<Vec<Option<Json>>> = Mutex::new(vec![None; inputs.paths.len()]);
+    let results = batch::run(&inputs.paths, jobs, footprint, |i| {
+        let input = &inputs.paths[i];
+        let output_file = match out_dir {
+            Some(dir) => {
+                let default = default_output_file(input, options)?;
+                Some(
+                    batch::output_in(
+                        Path::new(dir),
+                        &inputs.subdirs[i],
+                        &default,
+                        !options.dry_run,
+                    )
+                    .map_err(Error::Output)?,
+                )
+            }
+            None => None,
+        };
+        let mut report = Report::new(options.report.is_some());
+        let result = compress(input, output_file, options, &mut report);
+        entries.lock().unwrap()[i] = Some(report.entry(input, result.as_ref().err()));
+        result
+    });
+    let mut code = batch::summarize(&inputs, &results, start.elapsed());
+    if let Some(path) = &options.report {
+        // inputs that never ran only have their error
+        let entries = entries
+            .into_inner()
+            .unwrap()
+            .into_iter()
+            .zip(&inputs.paths)
+            .zip(&results)
+            .map(|((entry, input), result)| {
+                entry.unwrap_or_else(|| Report::new(true).entry(input, result.as_ref().err()))
+            })
+            .collect();
+        let report = report::batch(&options.resolved, entries, inputs.skipped, start.elapsed());
+        if let Err(err) = report::write(path, &report) {
+            error!("{err}");
+            code = err.code();
+        }
+    }
+    if interrupt::requested() {
+        error::INTERRUPTED
+    } else {
+        code
+    }
+}
+
+/// why refining stopped before it ran every iteration
+#[derive(Clone, Copy, PartialEq)]
+enum Stop {
+    /// no region can be split any further
+    Exhausted,
+    /// ctrl-c was pressed
+    Interrupted,
+    /// the render reached -target-psnr, in decibels
+    Target(f64),
+}
+
+impl Stop {
+    /// interrupted or exhausted, for refining that can't stop at a target
+    #[cfg(feature = "rayon")]
+    fn early() -> Self {
+        if interrupt::requested() {
+            Self::Interrupted
+        } else {
+            Self::Exhausted
+        }
+    }
+
+    /// the reason in the `stopped` record and -report
+    fn name(self) -> &'static str {
+        match self {
+            Self::Exhausted => "exhausted",
+            Self::Interrupted => "interrupted",
+            Self::Target(_) => "target",
+        }
+    }
+}
+
+/// why refining `input_file` stopped after `done` of `iterations`
+/// iterations. -porcelain gets it as a `stopped` record
+fn stopped_note(input_file: &str, done: u32, iterations: u32, stop: Stop) -> String {
+    if porcelain::enabled() {
+        Record::new("stopped")
+            .field("input", input_file)
+            .field("done", done)
+            .field("iterations", iterations)
+            .field("reason", stop.name())
+            .emit();
+    }
+    match stop {
+        Stop::Exhausted => format!(
+            "every region is fully refined, stopped after {done} of {iterations} iterations"
+        ),
+        Stop::Interrupted => {
+            format!("interrupted after {done} of {iterations} iterations, saved what was refined")
+        }
+        Stop::Target(psnr) => format!(
+            "reached the target psnr of {psnr} dB, stopped after {done} of {iterations} iterations"
+        ),
+    }
+}
+
+/// why refining stopped after `done` of `iterations` iterations, the same
+/// reasons as the `stopped` record, for -report. `stop` is `None` when every
+/// iteration ran
+fn stopped_json(done: u32, iterations: u32, stop: Option<Stop>) -> Json {
+    let reason = stop.map_or("iterations", Stop::name);
+    Json::object([
+        ("reason", Json::String(reason.into())),
+        ("done", Json::Number(done as u64)),
+        ("iterations", Json::Number(iterations as u64)),
+    ])
+}
+
+/// the `saved` record of an output of `input`
+fn saved_record(input: &str, path: &str) {
+    Record::new("saved")
+        .field("input", input)
+        .field("path", path)
+        .emit();
+}
+
+/// compress one input, writing every output it asks for and filling in its
+/// entry of `report`. returns a note to show the user when refinement
+/// stopped early
+fn compress(
+    input_file: &str,
+    output_file: Option<String>,
+    options: &Options,
+    report: &mut Report,
+) -> Result<Option<String>, Error> {
+    let &Options {
+        iterations,
+        split,
+        outline,
+        gif_delta,
+        ref extra_gifs,
+        ref frame_list,
+        ref checkpoints,
+        undo,
+        ref resume,
+        fps,
+        hold_last,
+        direction,
+        spacing,
+        loops,
+        start_with_original,
+        end_with_original,
+        gif_quality,
+        gif_scale,
+        stable_palette,
+        delta_frames,
+        dither,
+        quiet,
+        ref frames_dir,
+        force,
+        open,
+        ref video,
+        ref ffmpeg,
+        ref gif_heatmap,
+        tween,
+        label,
+        label_psnr,
+        label_corner,
+        label_color,
+        timings,
+        ref encode,
+        encode_entropy,
+        encode_lossless,
+        ref export_json,
+        pretty,
+        leaf_at,
+        stats,
+        ssim,
+        suggest_iter,
+        apply,
+        target_psnr,
+        porcelain,
+        dry_run,
+        interactive,
+        ref name_template,
+        ref cache_dir,
+        ref error_curve,
+        error_curve_step,
+        report: ref report_file,
+        // the callers put it in the report
+        resolved: _,
+        #[cfg(feature = "rayon")]
+        tiles,
+    } = options;
+    let animate_main = options.animate_main();
+    let animate = options.animate();
+
+    let output_file = match output_file {
+        Some(out_s) => out_s,
+        None => default_output_file(input_file, options)?,
+    };
+    let output_file = if !animate_main {
+        still_output(output_file).map_err(Error::Usage)?
+    } else if frames_dir.is_none() && video.is_none() {
+        animation_output(output_file, true).map_err(Error::Usage)?
+    } else {
+        output_file
+    };
+    for path in extra_gifs.iter().map(|(_, p)| p).chain(gif_heatmap) {
+        animation_output(path.clone(), false).map_err(Error::Usage)?;
+    }
+
+    // checkpoints are stills, so they keep the format of the still output.
+    // without -name-template they are named after the output
+    let (_, extension) = file_without_extension(&output_file)?;
+    let extension = if animate_main {
+        "png".into()
+    } else {
+        extension
+    };
+    let (template, named_after) = match name_template {
+        Some(template) => (template.clone(), input_file),
+        None => (Template::parse(template::CHECKPOINT)?, output_file.as_str()),
+    };
+    let Some(stem) = Path::new(named_after).file_stem().and_then(|s| s.to_str()) else {
+        return Err(Error::Usage("failed to get file stem".into()));
+    };
+    let size = template_size(input_file, &template)?;
+    let checkpoint_paths = checkpoints
+        .iter()
+        .flatten()
+        .map(|&i| {
+            let name = template.expand(&Values {
+                stem,
+                ext: &extension,
+                iter: i,
+                leaves: planned_leaves(i, split),
+                split: split.unwrap_or(Split::Midpoint).name(),
+                size,
+            });
+            Ok((i, next_to(&output_file, name)?))
+        })
+        .collect::<Result<Vec<(u32, String)>, Error>>()?;
+
+    let mut paths: Vec<&String> = extra_gifs.iter().map(|(_, p)| p).collect();
+    paths.extend(checkpoint_paths.iter().map(|(_, p)| p));
+    paths.extend(gif_heatmap);
+    paths.extend(encode);
+    paths.extend(export_json);
+    paths.extend(error_curve);
+    if animate_main && frames_dir.is_none() && video.is_none() {
+        paths.push(&output_file);
+    }
+    for (i, p) in paths.iter().enumerate() {
+        if paths[..i].contains(p) {
+            return Err(Error::Usage(format!(
+                "output path `{p}` is used more than once"
+            )));
+        }
+    }
+    if !animate_main {
+        paths.push(&output_file);
+    }
+    paths.extend(video);
+    check_overwrites(input_file, &paths, force).map_err(Error::Output)?;
+    check_output_dirs(&paths).map_err(Error::Output)?;
+    paths.extend(frames_dir);
+    if dry_run {
+        return plan(input_file, &paths, options).map(Some);
+    }
+    verbose!(
+        "{input_file}: {iterations} iterations, {} split, writing {}",
+        split.unwrap_or(Split::Midpoint).name(),
+        paths
+            .iter()
+            .map(|p| format!("`{p}`"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    let start = Instant::now();
+    let mut timer =
+        PhaseTimer::new(timings || log::enabled(Level::Verbose)).recording(report_file.is_some());
+    #[cfg(feature = "rayon")]
+    if let Some(k) = tiles {
+        let source = timer.time("decode", || load_rgb8(input_file))?;
+        let (img, done) = tiles::compress_tiles(&source, k, iterations, split, outline, &mut timer)
+            .map_err(Error::Usage)?;
+        drop(source);
+        let stop = (done < iterations).then(Stop::early);
+        report.set("stopped", || stopped_json(done, iterations, stop));
+        if let Err(err) = timer.time("encode", || img.save(&output_file)) {
+            return Err(Error::Output(err.to_string()));
+        }
+        report.set("timings", || timer.to_json());
+        report.set("outputs", || {
+            Json::Array(vec![Json::String(output_file.clone())])
+        });
+        if let Some(report) = timer.report() {
+            eprint!("{report}");
+        }
+        if porcelain {
+            saved_record(input_file, &output_file);
+        }
+        if open {
+            open::open(&open::System, &output_file);
+        }
+        return Ok(stop.map(|stop| stopped_note(input_file, done, iterations, stop)));
+    }
+    let cached = cache_dir
+        .as_deref()
+        .and_then(|dir| timer.time("cache", || cache::load(dir, input_file)));
+    // the original in animations, the residual and the ssim need the pixels,
+    // nothing else does once the prefix sums are built
+    let needs_pixels =
+        start_with_original || end_with_original || stable_palette || encode_lossless || ssim;
+    let source = match (&cached, needs_pixels) {
+        (Some(_), false) => None,
+        _ => Some(timer.time("decode", || load_rgb8(input_file))?),
+    };
+    let data = match (cached, &source) {
+        (Some(data), _) => data,
+        (None, Some(source)) => {
+            let data = timer.time("construct", || {
+                ImageData::from_rgb8(source).map_err(Error::Decode)
+            })?;
+            if let Some(dir) = cache_dir {
+                timer.time("cache", || cache::store(dir, input_file, &data));
+            }
+            data
+        }
+        (None, None) => unreachable!("the image is decoded when nothing is cached"),
+    };
+    if let Some((x, y)) = leaf_at {
+        let (w, h) = (data.width(), data.height());
+        if x >= w || y >= h {
+            return Err(Error::Usage(format!(
+                "pixel {x},{y} is outside the {w}x{h} image"
+            )));
+        }
+    }
+    let mut tree =
+        timer.time("construct", || match resume {
+            Some(tree_file) => resume_tree(tree_file, data, iterations, split),
+            None => Ok(Tree::with_capacity_hint(data, iterations)
+                .with_split(split.unwrap_or(Split::Midpoint))),
+        })?;
+    if let Some(tree_file) = resume {
+        verbose!(
+            "{input_file}: resuming `{tree_file}` at {} regions",
+            tree.leaf_count()
+        );
+    }
+    let gif_scale = gif_scale.unwrap_or(1.0);
+    let (frame_height, frame_width) = tree.scaled_dimensions(gif_scale);
+    // only animations that show the original need to keep it around
+    let original = source
+        .as_ref()
+        .filter(|_| start_with_original || end_with_original || stable_palette)
+        .map(|source| {
+            let img = to_rgba(source);
+            if (frame_width, frame_height) == (tree.width(), tree.height()) {
+                img
+            } else {
+                let (w, h) = (frame_width as u32, frame_height as u32);
+                imageops::resize(&img, w, h, FilterType::Triangle)
+            }
+        });
+    // the residual and the ssim are all that need the original once the
+    // tree is built
+    let source = source.filter(|_| encode_lossless || ssim);
+
+    let mut progress = Progress::new(iterations, quiet).records(input_file);
+    let spacing = spacing.unwrap_or(Spacing::Linear);
+    let main_schedule = match (gif_delta, frame_list) {
+        (Some(delta), _) => Some(Schedule::new(spacing, delta, iterations)),
+        (None, Some(list)) => Some(Schedule::list(list.clone(), iterations)),
+        (None, None) => None,
+    };
+
+    let direction = direction.unwrap_or(Direction::Forward);
+    let bookends = start_with_original as usize + end_with_original as usize;
+    let tween = tween.unwrap_or(0);
+    // no fps means no delay between frames, same as Frame::new
+    let delay = match fps {
+        Some(f) => Delay::from_numer_denom_ms(1000, f),
+        None => Delay::from_numer_denom_ms(0, 1),
+    };
+    let hold = Delay::from_numer_denom_ms(hold_last.unwrap_or(DEFAULT_HOLD_LAST_MS), 1);
+    let base_options = EncoderOptions {
+        width: frame_width as u32,
+        height: frame_height as u32,
+        frames: 0,
+        loops,
+        speed: gif_quality.unwrap_or(DEFAULT_GIF_QUALITY),
+        delta: delta_frames,
+        dither: dither.unwrap_or(Dither::None),
+    };
+    let new_animation = |encoder| {
+        let first = original.clone().filter(|_| start_with_original);
+        let last = original.clone().filter(|_| end_with_original);
+        Animation::new(encoder, direction, delay, hold)
+            .with_bookends(first, last)
+            .with_tween(tween)
+    };
+    let palette = || {
+        original
+            .as_ref()
+            .filter(|_| stable_palette)
+            .map(|img| Palette::from_image(img, base_options.speed))
+    };
+
+    // the file the run is for, or the directory its frames are in
+    let main_output = match (animate_main, frames_dir, video) {
+        (true, Some(dir), _) => dir,
+        (true, None, Some(video)) => video,
+        _ => &output_file,
+    };
+    let mut outputs = Vec::new();
+    if let Some(schedule) = main_schedule {
+        let captured = direction.frame_count(1 + schedule.clone().count());
+        let options = EncoderOptions {
+            frames: tweened_count(captured + bookends, tween) as u32,
+            ..base_options
+        };
+        let encoder = match (frames_dir, video) {
+            (Some(dir), _) => {
+                PngDirEncoder::new(dir, force).map(|e| Box::new(e) as Box<dyn FrameEncoder>)
+            }
+            (None, Some(path)) => VideoEncoder::new(
+                ffmpeg.as_deref().unwrap_or("ffmpeg"),
+                path,
+                options.width,
+                options.height,
+                fps.unwrap_or(DEFAULT_VIDEO_FPS),
+            )
+            .map(|e| Box::new(e) as Box<dyn FrameEncoder>),
+            (None, None) => create_encoder(&output_file, &options, palette()),
+        }
+        .map_err(Error::Output)?;
+
+        // the heatmap has no original image to start or end with
+        let mut heatmap = None;
+        if let Some(path) = gif_heatmap {
+            let heatmap_options = EncoderOptions {
+                frames: tweened_count(captured, tween) as u32,
+                ..base_options
+            };
+            let encoder = create_encoder(path, &heatmap_options, None).map_err(Error::Output)?;
+            heatmap = Some(Animation::new(encoder, direction, delay, hold).with_tween(tween));
+        }
+        outputs.push(Output {
+            path: main_output,
+            schedule: schedule.peekable(),
+            animation: new_animation(encoder),
+            heatmap,
+            frames: Vec::new(),
+        });
+    }
+    for (delta, path) in extra_gifs {
+        let schedule = Schedule::new(spacing, *delta, iterations);
+        let captured = direction.frame_count(1 + schedule.clone().count());
+        let options = EncoderOptions {
+            frames: tweened_count(captured + bookends, tween) as u32,
+            ..base_options
+        };
+        let encoder = create_encoder(path, &options, palette()).map_err(Error::Output)?;
+        outputs.push(Output {
+            path,
+            schedule: schedule.peekable(),
+            animation: new_animation(encoder),
+            heatmap: None,
+            frames: Vec::new(),
+        });
+    }
+
+    // only created once there is a frame to render
+    let mut canvas: Option<Canvas> = None;
+    let mut render_frame = |tree: &Tree, i: u32, spare: Option<RgbaImage>| {
+        let canvas = canvas.get_or_insert_with(|| Canvas::new(tree, outline, gif_scale));
+        let img = canvas.update(tree);
+        let mut buf = match spare {
+            Some(mut buf) => {
+                buf.copy_from_slice(img);
+                buf
+            }
+            None => img.clone(),
+        };
+        if label {
+            let mut text = format!("iter {i} leaves {}", tree.leaf_count());
+            if let Some(mse) = tree.mse().filter(|_| label_psnr) {
+                text += &format!(" psnr {}", format_psnr(mse));
+            }
+            let corner = label_corner.unwrap_or(Corner::TopLeft);
+            let color = label_color.unwrap_or(RGB::new(255, 255, 255));
+            draw_label(&mut buf, &text, corner, color);
+        }
+        buf
+    };
+
+    let render_heatmap = |tree: &Tree, spare: Option<RgbaImage>| match spare {
+        Some(mut buf) => {
+            tree.render_heatmap_into(&mut buf, outline);
+            buf
+        }
+        None => tree.render_heatmap(outline, gif_scale),
+    };
+
+    // taken as refinement passes them and rendered at the end
+    let mut snapshots = Vec::with_capacity(checkpoint_paths.len());
+    let mut checkpoints = checkpoint_paths.iter().map(|&(i, _)| i).peekable();
+    if checkpoints.next_if_eq(&0).is_some() {
+        snapshots.push(tree.snapshot());
+    }
+
+    // created only now, so a usage error never leaves an empty one behind
+    let mut curve = match error_curve {
+        Some(path) => {
+            let mut curve =
+                ErrorCurve::create(path, error_curve_step.unwrap_or(1)).map_err(Error::Output)?;
+            curve.refined(0, &tree).map_err(Error::Output)?;
+            Some(curve)
+        }
+        None => None,
+    };
+    // the error after every iteration, for -suggest-iter
+    let mut errors = Vec::new();
+    if suggest_iter {
+        errors.extend(tree.mse());
+    }
+
+    let all: Vec<usize> = (0..outputs.len()).collect();
+    push_frames(
+        &mut outputs,
+        &all,
+        &tree,
+        0,
+        &mut render_frame,
+        &render_heatmap,
+        &mut timer,
+    )
+    .map_err(Error::Output)?;
+    let mut stopped_at = None;
+    for i in 1..=iterations {
+        // what is refined by the time ctrl-c is pressed is saved the same as
+        // a tree that can't be split any further
+        if interrupt::requested() {
+            stopped_at = Some((i - 1, Stop::Interrupted));
+            break;
+        }
+        if let Some(target) = target_psnr.filter(|&t| tree.psnr().is_some_and(|p| p >= t)) {
+            stopped_at = Some((i - 1, Stop::Target(target)));
+            verbose!(
+                "{input_file}: the render reached {target} dB after iteration {}, stopping",
+                i - 1
+            );
+            break;
+        }
+        match timer.time("refine", || tree.refine()) {
+            Ok(Refined::Split) => (),
+            Ok(Refined::Exhausted) => {
+                stopped_at = Some((i - 1, Stop::Exhausted));
+                verbose!(
+                    "{input_file}: no region can be split after iteration {}, stopping",
+                    i - 1
+                );
+                break;
+            }
+            Err(err) => {
+                progress.finish();
+                return Err(err.to_string().into());
+            }
+        }
+        if checkpoints.next_if_eq(&i).is_some() {
+            snapshots.push(tree.snapshot());
+        }
+        let capturing: Vec<usize> = (0..outputs.len())
+            .filter(|&k| outputs[k].schedule.next_if_eq(&i).is_some())
+            .collect();
+        if let Err(err) = push_frames(
+            &mut outputs,
+            &capturing,
+            &tree,
+            i,
+            &mut render_frame,
+            &render_heatmap,
+            &mut timer,
+        ) {
+            progress.finish();
+            return Err(Error::Output(err));
+        }
+        if let Some(Err(err)) = curve.as_mut().map(|c| c.refined(i, &tree)) {
+            progress.finish();
+            return Err(Error::Output(err));
+        }
+        if suggest_iter {
+            errors.extend(tree.mse());
+        }
+        let captured = outputs.iter().map(|o| o.animation.captured()).sum();
+        progress.refine(i, tree.leaf_count(), animate.then_some(captured));
+    }
+    // the tree won't change again, so every capture point that is left gets
+    // the final render. this keeps frame counts the same as a full run,
+    // except after ctrl-c, which should be quick
+    if let Some((done, _)) = stopped_at.filter(|_| !interrupt::requested()) {
+        loop {
+            let capturing: Vec<usize> = (0..outputs.len())
+                .filter(|&k| outputs[k].schedule.next().is_some())
+                .collect();
+            if capturing.is_empty() {
+                break;
+            }
+            if let Err(err) = push_frames(
+                &mut outputs,
+                &capturing,
+                &tree,
+                done,
+                &mut render_frame,
+                &render_heatmap,
+                &mut timer,
+            ) {
+                progress.finish();
+                return Err(Error::Output(err));
+            }
+        }
+        snapshots.extend(checkpoints.map(|_| tree.snapshot()));
+    }
+    let done = stopped_at.map_or(iterations, |(done, _)| done);
+    report.set("stopped", || {
+        stopped_json(done, iterations, stopped_at.map(|(_, stop)| stop))
+    });
+    if let Some(curve) = &mut curve {
+        if let Err(err) = curve.finish(done, &tree) {
+            progress.finish();
+            return Err(Error::Output(err));
+        }
+    }
+    // the iterations refining got to and the mse after them, and the same
+    // at the knee
+    let suggestion = suggest_iter.then(|| {
+        // on a log scale like psnr, as the mse falls so fast at first that
+        // its own knee is only a few splits in. an mse of 0 is taken as the
+        // smallest one that isn't
+        let least = 1.0 / (3 * tree.height() * tree.width()) as f64;
+        let curve: Vec<f64> = errors.iter().map(|e| e.max(least).log10()).collect();
+        let (done, k) = (errors.len() - 1, knee(&curve));
+        ((done, errors[done]), (k, errors[k]))
+    });
+    if let Some(((done, _), (k, _))) = suggestion.filter(|_| apply) {
+        timer.time("refine", || tree.undo(done - k));
+    }
+    if let Some(n) = undo {
+        timer.time("refine", || tree.undo(n));
+    }
+    if interactive {
+        progress.finish();
+        session::run(&mut tree, input_file, outline, std::io::stdin().lock())
+            .map_err(Error::Input)?;
+    }
+    // worked out from the prefix sums, so before they are dropped
+    let measure = stats || report_file.is_some();
+    let mse = measure
+        .then(|| timer.time("stats", || tree.mse()))
+        .flatten();
+    // the prefix sums take far more memory than the leaves, and only the
+    // leaves are needed from here on
+    tree.finalize();
+    if let Some((x, y)) = leaf_at {
+        // checked against the image when it was loaded
+        if let Some(leaf) = tree.leaf_at(x, y) {
+            if porcelain {
+                leaf_record(input_file, x, y, &leaf, true).emit();
+            } else {
+                println!("{}", describe_leaf(x, y, &leaf, true));
+            }
+        }
+    }
+    if let Some(path) = encode {
+        let Ok(file) = File::create(path) else {
+            return Err(Error::Output("unable to create new file".into()));
+        };
+        timer
+            .time("encode", || {
+                let residual_of = source.as_ref().filter(|_| encode_lossless);
+                tree.serialize(BufWriter::new(file), encode_entropy, residual_of)
+            })
+            .map_err(Error::Output)?;
+    }
+    if let Some(path) = export_json {
+        let Ok(file) = File::create(path) else {
+            return Err(Error::Output("unable to create new file".into()));
+        };
+        timer
+            .time("encode", || tree.export_json(BufWriter::new(file), pretty))
+            .map_err(Error::Output)?;
+    }
+
+    if !outputs.is_empty() {
+        report.set("frames", || {
+            let frames = outputs.iter().flat_map(|output| {
+                output.frames.iter().enumerate().map(|(k, f)| {
+                    Json::object([
+                        ("output", Json::String(output.path.into())),
+                        ("frame", Json::Number(k as u64)),
+                        ("iteration", Json::Number(f.iteration as u64)),
+                        ("leaves", Json::Number(f.leaves as u64)),
+                        ("mse", f.mse.map_or(Json::Null, Json::Fraction)),
+                        (
+                            "psnr",
+                            f.mse.map_or(Json::Null, |mse| Json::Fraction(psnr(mse))),
+                        ),
+                    ])
+                })
+            });
+            Json::Array(frames.collect())
+        });
+    }
+    let mut result = Ok(());
+    for output in outputs {
+        result =
+            result.and_then(|_| timer.time("encode", || output.animation.finish(&mut progress)));
+        if let Some(h) = output.heatmap {
+            result = result.and_then(|_| timer.time("encode", || h.finish(&mut progress)));
+        }
+    }
+    progress.finish();
+    result.map_err(Error::Output)?;
+
+    // the still image is the main output unless that is an animation
+    if !animate_main {
+        let img = timer.time("render", || tree.render_rgb(outline, 1.0));
+        if let Err(err) = timer.time("encode", || img.save(&output_file)) {
+            return Err(Error::Output(err.to_string()));
+        }
+    }
+    for (snapshot, (_, path)) in snapshots.iter().zip(&checkpoint_paths) {
+        let img = timer.time("render", || snapshot.render_rgb(outline, 1.0));
+        if let Err(err) = timer.time("encode", || img.save(path)) {
+            return Err(Error::Output(err.to_string()));
+        }
+    }
+    if let Some(mse) = mse {
+        let size = |path: &str| {
+            fs::metadata(path)
+                .ok()
+                .filter(|m| m.is_file())
+                .map(|m| m.len())
+        };
+        let encoded = encode.as_deref().and_then(size);
+        // rendered again without outlines, the saved render may have them
+        let ssim = source
+            .as_ref()
+            .filter(|_| ssim)
+            .map(|source| timer.time("ssim", || image::ssim(&tree.render_rgb(None, 1.0), source)));
+        let run = RunStats {
+            mse,
+            ssim,
+            input_bytes: size(input_file),
+            output_bytes: size(main_output),
+            tree_bytes: encoded.unwrap_or_else(|| tree.packed_size()),
+            tree_estimated: encoded.is_none(),
+            raw_bytes: 3 * (tree.height() * tree.width()) as u64,
+            elapsed: start.elapsed(),
+        };
+        let tree_stats = tree.stats();
+        report.set("stats", || stats_json(&tree_stats, &run));
+        if stats && porcelain {
+            Record::new("stats")
+                .field("input", input_file)
+                .fields(&stats_line(&tree_stats, Some(&run)))
+                .emit();
+        } else if stats {
+            print!("{}", describe_stats(&tree_stats, Some(&run)));
+        }
+    }
+    if let Some(((done, done_mse), (k, mse))) = suggestion {
+        report.set("suggestion", || {
+            Json::object([
+                ("iterations", Json::Number(k as u64)),
+                ("mse", Json::Fraction(mse)),
+                ("psnr", Json::Fraction(psnr(mse))),
+                ("applied", Json::Bool(apply)),
+            ])
+        });
+        if porcelain {
+            Record::new("suggestion")
+                .field("input", input_file)
+                .field("iterations", k)
+                .field("psnr", format_psnr(mse))
+                .field("done", done)
+                .field("done_psnr", format_psnr(done_mse))
+                .field("applied", apply)
+                .emit();
+        } else {
+            println!(
+                "{input_file}: suggest -iter {k}, psnr {} dB against {} dB after {done}{}",
+                format_psnr(mse),
+                format_psnr(done_mse),
+                if apply { ", saved at it" } else { "" }
+            );
+        }
+    }
+    report.set("timings", || timer.to_json());
+    report.set("outputs", || {
+        Json::Array(paths.iter().map(|&p| Json::String(p.clone())).collect())
+    });
+    if let Some(report) = timer.report() {
+        eprint!("{report}");
+    }
+    if porcelain {
+        for path in &paths {
+            saved_record(input_file, path);
+        }
+    }
+    if open {
+        open::open(&open::System, main_output);
+    }
+    Ok(stopped_at.map(|(done, stop)| stopped_note(input_file, done, iterations, stop)))
+}
+
+/// how -decode renders a saved tree
+struct DecodeOptions {
+    outline: Option<RGB<u8>>,
+    scale: f64,
+    /// the most leaves the render can have
+    prune_to: Option<usize>,
+    /// the pixel whose leaf is printed
+    leaf_at: Option<(usize, usize)>,
+    stats: bool,
+    porcelain: bool,
+    timings: bool,
+}
+
+/// a tree saved with -encode or -export-json, and what was saved with it
+fn load_tree(tree_file: &str) -> Result<LoadedTree, Error> {
+    let Ok(data) = fs::read(tree_file) else {
+        return Err(Error::Input(format!(
+            "unable to open tree file `{tree_file}`"
+        )));
+    };
+    // anything that isn't a .qt file is taken to be -export-json output
+    if data.starts_with(&qt::MAGIC) {
+        return Tree::deserialize(data.as_slice()).map_err(|err| Error::Decode(err.to_string()));
+    }
+    let Ok(text) = std::str::from_utf8(&data) else {
+        return Err(Error::Decode(
+            "tree file is neither a .qt file nor json".into(),
+        ));
+    };
+    let tree = Json::parse(text)
+        .and_then(|json| Tree::from_json(&json))
+        .map_err(Error::Decode)?;
+    Ok(LoadedTree {
+        tree,
+        residual: None,
+        writer: None,
+    })
+}
+
+/// the tree saved in `tree_file` ready to be refined `iterations` more times
+/// over `data`, which has to be the image it was made from
+fn resume_tree(
+    tree_file: &str,
+    data: ImageData,
+    iterations: u32,
+    split: Option<Split>,
+) -> Result<Tree, Error> {
+    load_resumable(tree_file, split)?
+        .resume(data, iterations)
+        .map_err(|err| Error::Usage(format!("can't resume `{tree_file}`, {err}")))
+}
+
+/// the tree saved in `tree_file` if it can go on being refined with `split`
+fn load_resumable(tree_file: &str, split: Option<Split>) -> Result<Tree, Error> {
+    let LoadedTree { tree, residual, .. } = load_tree(tree_file)?;
+    // the residual only matches the tree it was saved with
+    if residual.is_some() {
+        return Err(Error::Usage(format!(
+            "can't resume `{tree_file}`, it was saved with -encode-lossless and already gives back the original"
+        )));
+    }
+    if let Some(split) = split.filter(|&s| s != tree.split()) {
+        return Err(Error::Usage(format!(
+            "can't resume `{tree_file}` with -split {}, it was split with {}",
+            split.name(),
+            tree.split().name()
+        )));
+    }
+    Ok(tree)
+}
+
+/// print the completion script for the shell named in `args`
+fn completions_main(program_name: &String, args: impl Iterator<Item = String>) -> i32 {
+    let args: Vec<String> = args.collect();
+    let script = match args.as_slice() {
+        [shell] => completions::script(shell),
+        _ => None,
+    };
+    match script {
+        Some(script) => {
+            print!("{script}");
+            0
+        }
+        None => {
+            error!(
+                "completions needs one shell, one of {}",
+                completions::SHELLS.join(", ")
+            );
+            error!("{}", completions_usage(program_name));
+            error::USAGE
+        }
+    }
+}
+
+/// `diff a b -o output`, render where two saved trees disagree
+fn diff_main(program_name: &String, mut args: impl Iterator<Item = String>) -> i32 {
+    let mut tree_files = Vec::new();
+    let mut output_file = None;
+    let mut force = false;
+    while let Some(arg) = args.next() {
+        if cli::canonical(&arg) == Some("-force") {
+            force = true;
+        } else if cli::canonical(&arg) == Some("-o") {
+            if let Some(of) = args.next() {
+                output_file = Some(of);
+            } else {
+                error!("output file not specified");
+                print_diff_usage(program_name);
+                return error::USAGE;
+            }
+        } else if cli::is_flag(&arg) {
+            error!("diff only takes -o and -force");
+            print_diff_usage(program_name);
+            return error::USAGE;
+        } else {
+            tree_files.push(arg);
+        }
+    }
+    let [a, b] = tree_files.as_slice() else {
+        error!("diff needs exactly two tree files");
+        print_diff_usage(program_name);
+        return error::USAGE;
+    };
+    let Some(output_file) = output_file else {
+        error!("diff needs an output file, give one with -o");
+        print_diff_usage(program_name);
+        return error::USAGE;
+    };
+    let output_file = match still_output(output_file) {
+        Ok(path) => path,
+        Err(err) => {
+            error!("{err}");
+            return error::USAGE;
+        }
+    };
+    for tree_file in [a, b] {
+        if let Err(err) = check_overwrites(tree_file, &[&output_file], force) {
+            error!("{err}");
+            return error::OUTPUT;
+        }
+    }
+    if let Err(err) = check_output_dirs(&[&output_file]) {
+        error!("{err}");
+        return error::OUTPUT;
+    }
+
+    let diff = || -> Result<String, Error> {
+        let first = load_tree(a)?.tree;
+        let second = load_tree(b)?.tree;
+        let diff = first.diff(&second).map_err(Error::Usage)?;
+        let img = diff.render(RGB::new(0, 0, 0), RGB::new(255, 255, 255));
+        img.save(&output_file)
+            .map_err(|err| Error::Output(err.to_string()))?;
+        if diff.is_empty() {
+            return Ok("the trees were split the same way everywhere".into());
+        }
+        let total = (first.width() * first.height()) as u64;
+        let differing = diff.differing_pixels();
+        Ok(format!(
+            "{differing} of {total} pixels ({:.2}%) are in regions split differently, {} regions are only in `{a}` and {} only in `{b}`",
+            100.0 * differing as f64 / total as f64,
+            diff.only_in_self.len(),
+            diff.only_in_other.len()
+        ))
+    };
+    match diff() {
+        Ok(summary) => {
+            println!("{summary}");
+            0
+        }
+        Err(err) => {
+            error!("{err}");
+            err.code()
+        }
+    }
+}
+
+/// render a tree saved with -encode or -export-json to `output_file`, or
+/// only print what `options` asks for without one
+fn decode_tree(
+    tree_file: &str,
+    output_file: Option<&str>,
+    options: &DecodeOptions,
+) -> Result<(), Error> {
+    let &DecodeOptions {
+        outline,
+        scale,
+        prune_to,
+        leaf_at,
+        stats,
+        porcelain,
+        timings,
+    } = options;
+    let mut timer = PhaseTimer::new(timings || log::enabled(Level::Verbose));
+    let LoadedTree {
+        mut tree,
+        residual,
+        writer,
+    } = timer.time("decode", || load_tree(tree_file))?;
+    if let Some(leaves) = prune_to {
+        let merges = tree
+            .leaf_count()
+            .saturating_sub(leaves)
+            .div_ceil(tree.leaves_per_split());
+        timer.time("prune", || tree.prune(merges));
+    }
+    if let Some((x, y)) = leaf_at {
+        let Some(leaf) = tree.leaf_at(x, y) else {
+            return Err(Error::Usage(format!(
+                "pixel {x},{y} is outside the {}x{} image",
+                tree.width(),
+                tree.height()
+            )));
+        };
+        if porcelain {
+            leaf_record(tree_file, x, y, &leaf, false).emit();
+        } else {
+            println!("{}", describe_leaf(x, y, &leaf, false));
+        }
+    }
+    if stats && porcelain {
+        Record::new("stats")
+            .field("input", tree_file)
+            .fields(&stats_line(&tree.stats(), None))
+            .emit();
+    } else if stats {
+        if let Some(writer) = writer {
+            println!("saved by comprs {writer}");
+        }
+        print!("{}", describe_stats(&tree.stats(), None));
+    }
+    if let Some(output_file) = output_file {
+        let mut img = timer.time("render", || tree.render_rgb(outline, scale));
+        // the residual only matches a full render of the whole tree
+        if let Some(residual) = residual {
+            if outline.is_none() && scale == 1.0 && prune_to.is_none() {
+                timer.time("render", || residual::apply(&mut img, &residual));
+            }
+        }
+        if let Err(err) = timer.time("encode", || img.save(output_file)) {
+            return Err(Error::Output(err.to_string()));
+        }
+        if porcelain {
+            saved_record(tree_file, output_file);
+        }
+    }
+    if let Some(report) = timer.report() {
+        eprint!("{report}");
+    }
+    Ok(())
 }
 
 fn main() {
@@ -0,0 +1,49 @@
+use std::{
+    io,
+    process::{Command, Stdio},
+};
+
+use crate::log::warning;
+
+// -open shows the output in whatever the desktop opens files of its kind
+// with. each platform has its own program for that, and none of them are
+// waited for, so comprs exits while the viewer stays open
+
+/// starts a program on a file
+pub trait Launcher {
+    fn launch(&self, path: &str) -> io::Result<()>;
+}
+
+/// the default viewer of the platform: open on macos, start on windows and
+/// xdg-open everywhere else
+pub struct System;
+
+impl Launcher for System {
+    fn launch(&self, path: &str) -> io::Result<()> {
+        let mut command = if cfg!(target_os = "macos") {
+            Command::new("open")
+        } else if cfg!(windows) {
+            // start takes the first quoted argument as a window title
+            let mut cmd = Command::new("cmd");
+            cmd.args(["/C", "start", ""]);
+            cmd
+        } else {
+            Command::new("xdg-open")
+        };
+        command
+            .arg(path)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .map(|_| ())
+    }
+}
+
+/// show `path` with `launcher`. the output is saved either way, so failing to
+/// is only a warning
+pub fn open(launcher: &impl Launcher, path: &str) {
+    if let Err(err) = launcher.launch(path) {
+        warning!("unable to open `{path}`: {err}");
+    }
+}
@@ -0,0 +1,253 @@
+use std::{borrow::Cow, io::Write};
+
+use ::gif::{DisposalMethod, Encoder, Frame};
+use color_quant::NeuQuant;
+use image::{codecs::gif::Repeat, imageops, Delay, RgbaImage};
+
+use crate::anim::{loops_to_repeat, FrameEncoder};
+
+/// how colors that aren't in the palette are approximated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dither {
+    None,
+    /// 4x4 bayer matrix
+    Ordered,
+    /// floyd-steinberg error diffusion
+    FloydSteinberg,
+}
+
+impl Dither {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "none" => Ok(Self::None),
+            "ordered" => Ok(Self::Ordered),
+            "fs" => Ok(Self::FloydSteinberg),
+            _ => Err(format!(
+                "invalid gif dither `{s}`, expected none|ordered|fs"
+            )),
+        }
+    }
+}
+
+const BAYER: [[i32; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// strength of ordered dithering, roughly the distance between palette colors
+const ORDERED_SPREAD: i32 = 32;
+
+/// 256 color palette for a gif
+pub struct Palette {
+    quant: NeuQuant,
+}
+
+impl Palette {
+    /// build a palette from the colors of `img`, `speed` is the same 1 to 30
+    /// tradeoff as `GifEncoder::new_with_speed`
+    pub fn from_image(img: &RgbaImage, speed: i32) -> Self {
+        Self {
+            quant: NeuQuant::new(speed, 256, img.as_raw()),
+        }
+    }
+
+    fn rgb(&self) -> Vec<u8> {
+        self.quant.color_map_rgb()
+    }
+
+    /// palette index of every pixel, leaves are flat so runs of the same
+    /// color skip the lookup
+    fn indexes(&self, img: &RgbaImage) -> Vec<u8> {
+        let mut ret = Vec::with_capacity((img.width() * img.height()) as usize);
+        let mut last: Option<(&[u8], u8)> = None;
+        for p in img.as_raw().chunks_exact(4) {
+            let index = match last {
+                Some((prev, i)) if prev == p => i,
+                _ => self.quant.index_of(p) as u8,
+            };
+            last = Some((p, index));
+            ret.push(index);
+        }
+        ret
+    }
+
+    /// palette index of every pixel with dithering, `origin` is the position of
+    /// `img` in the full frame so ordered patterns line up across delta frames
+    fn dithered_indexes(&self, img: &RgbaImage, dither: Dither, origin: (u32, u32)) -> Vec<u8> {
+        match dither {
+            Dither::None => self.indexes(img),
+            Dither::Ordered => img
+                .enumerate_pixels()
+                .map(|(x, y, p)| {
+                    let b = BAYER[((y + origin.1) % 4) as usize][((x + origin.0) % 4) as usize];
+                    let offset = (2 * b + 1 - 16) * ORDERED_SPREAD / 32;
+                    let c = |v: u8| (v as i32 + offset).clamp(0, 255) as u8;
+                    self.quant.index_of(&[c(p[0]), c(p[1]), c(p[2]), p[3]]) as u8
+                })
+                .collect(),
+            Dither::FloydSteinberg => self.diffuse(img),
+        }
+    }
+
+    fn diffuse(&self, img: &RgbaImage) -> Vec<u8> {
+        let (w, h) = (img.width() as usize, img.height() as usize);
+        // error carried into the current and next row, in 1/16ths, with a
+        // column of padding on each side
+        let mut cur = vec![[0i32; 3]; w + 2];
+        let mut next = vec![[0i32; 3]; w + 2];
+        let mut ret = Vec::with_capacity(w * h);
+        for y in 0..h {
+            for x in 0..w {
+                let p = img.get_pixel(x as u32, y as u32);
+                let want: [i32; 3] = std::array::from_fn(|c| p[c] as i32 + cur[x + 1][c] / 16);
+                let clamped = want.map(|v| v.clamp(0, 255) as u8);
+                let index = self
+                    .quant
+                    .index_of(&[clamped[0], clamped[1], clamped[2], p[3]]);
+                let got = self.quant.lookup(index).unwrap_or([0; 4]);
+                for c in 0..3 {
+                    let err = want[c] - got[c] as i32;
+                    cur[x + 2][c] += err * 7;
+                    next[x][c] += err * 3;
+                    next[x + 1][c] += err * 5;
+                    next[x + 2][c] += err;
+                }
+                ret.push(index as u8);
+            }
+            std::mem::swap(&mut cur, &mut next);
+            next.iter_mut().for_each(|e| *e = [0; 3]);
+        }
+        ret
+    }
+}
+
+/// gif encoder that quantizes frames itself instead of going through `GifEncoder`
+///
+/// with a palette every frame uses it as the global palette, so regions that
+/// don't change between frames keep exactly the same colors instead of
+/// shimmering. otherwise each frame gets its own palette like `GifEncoder`.
+///
+/// with `delta` only the bounding box of the pixels that changed since the
+/// previous frame is written, the rest of the previous frame is kept
+pub struct QuantizedGifEncoder<W: Write> {
+    encoder: Encoder<W>,
+    palette: Option<Palette>,
+    speed: i32,
+    delta: bool,
+    dither: Dither,
+    prev: Option<RgbaImage>,
+}
+
+impl<W: Write> QuantizedGifEncoder<W> {
+    pub fn new(
+        w: W,
+        width: u32,
+        height: u32,
+        palette: Option<Palette>,
+        speed: i32,
+        delta: bool,
+        loops: Option<u32>,
+    ) -> Result<Self, String> {
+        let (Ok(width), Ok(height)) = (u16::try_from(width), u16::try_from(height)) else {
+            return Err("image is too large for a gif".into());
+        };
+        let global = palette.as_ref().map(Palette::rgb).unwrap_or_default();
+        let Ok(mut encoder) = Encoder::new(w, width, height, &global) else {
+            return Err("error in encoding gif".into());
+        };
+        let repeat = match loops.map(loops_to_repeat).transpose()?.flatten() {
+            Some(Repeat::Infinite) => Some(::gif::Repeat::Infinite),
+            Some(Repeat::Finite(n)) => Some(::gif::Repeat::Finite(n)),
+            None => None,
+        };
+        if let Some(r) = repeat {
+            if encoder.set_repeat(r).is_err() {
+                return Err("error in encoding gif".into());
+            }
+        }
+        Ok(Self {
+            encoder,
+            palette,
+            speed,
+            delta,
+            dither: Dither::None,
+            prev: None,
+        })
+    }
+
+    pub fn with_dither(mut self, dither: Dither) -> Self {
+        self.dither = dither;
+        self
+    }
+
+    /// smallest (left, top, width, height) covering every pixel that differs from the previous frame
+    fn changed_region(&self, buf: &RgbaImage) -> (u32, u32, u32, u32) {
+        let (w, h) = buf.dimensions();
+        let Some(prev) = self.prev.as_ref().filter(|_| self.delta) else {
+            return (0, 0, w, h);
+        };
+
+        let (mut min_x, mut min_y, mut max_x, mut max_y) = (w, h, 0, 0);
+        for (x, y, p) in buf.enumerate_pixels() {
+            if prev.get_pixel(x, y) != p {
+                min_x = min_x.min(x);
+                min_y = min_y.min(y);
+                max_x = max_x.max(x);
+                max_y = max_y.max(y);
+            }
+        }
+        if min_x > max_x {
+            // nothing changed, a single pixel keeps the frame and its delay
+            return (0, 0, 1, 1);
+        }
+        (min_x, min_y, max_x - min_x + 1, max_y - min_y + 1)
+    }
+}
+
+impl<W: Write> FrameEncoder for QuantizedGifEncoder<W> {
+    fn encode(&mut self, buf: &RgbaImage, delay: Delay) -> Result<(), String> {
+        let (left, top, width, height) = self.changed_region(buf);
+        // quantizing needs a mutable copy of the region anyway
+        let mut region = imageops::crop_imm(buf, left, top, width, height).to_image();
+
+        let mut frame = match (&self.palette, self.dither) {
+            (Some(palette), dither) => Frame {
+                width: width as u16,
+                height: height as u16,
+                buffer: Cow::Owned(palette.dithered_indexes(&region, dither, (left, top))),
+                ..Frame::default()
+            },
+            (None, Dither::None) => {
+                Frame::from_rgba_speed(width as u16, height as u16, &mut region, self.speed)
+            }
+            (None, dither) => {
+                // dithering needs the palette up front, so build one just for this frame
+                let palette = Palette::from_image(&region, self.speed);
+                Frame {
+                    width: width as u16,
+                    height: height as u16,
+                    buffer: Cow::Owned(palette.dithered_indexes(&region, dither, (left, top))),
+                    palette: Some(palette.rgb()),
+                    ..Frame::default()
+                }
+            }
+        };
+        let (numer, denom) = delay.numer_denom_ms();
+        // gif delays are in centiseconds
+        frame.delay = (numer / denom.max(1) / 10).min(u16::MAX as u32) as u16;
+        frame.dispose = DisposalMethod::Keep;
+        frame.left = left as u16;
+        frame.top = top as u16;
+
+        if self.delta {
+            match &mut self.prev {
+                Some(prev) => prev.copy_from_slice(buf),
+                None => self.prev = Some(buf.clone()),
+            }
+        }
+        self.encoder
+            .write_frame(&frame)
+            .map_err(|_| "error in encoding gif".into())
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), String> {
+        Ok(())
+    }
+}
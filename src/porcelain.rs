@@ -0,0 +1,100 @@
+use std::{
+    fmt::Display,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{error, error::Error, interrupt};
+
+// -porcelain hands stdout over to records for scripts and guis: one line per
+// event, `event=<name>` and then key=value fields. nothing meant for people
+// is printed on stdout then, errors still go to stderr and are repeated in
+// the `result` record of the input they stopped. the events and their
+// fields are listed in the readme. fields can be added without a new
+// VERSION, removing or renaming one needs a new VERSION
+
+/// the version of the record format, in the `start` record
+pub const VERSION: u32 = 1;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// set once the flags are parsed, before any work starts
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// one line of key=value pairs, printed with `emit`
+pub struct Record(String);
+
+impl Record {
+    pub fn new(event: &str) -> Self {
+        Self(format!("event={event}"))
+    }
+
+    /// add `key=value`. values with spaces, quotes, `=` or nothing in them
+    /// are quoted, with `"` and `\` escaped by a `\`
+    pub fn field(mut self, key: &str, value: impl Display) -> Self {
+        let value = value.to_string();
+        let plain = !value.is_empty()
+            && !value
+                .chars()
+                .any(|c| c.is_whitespace() || c.is_control() || matches!(c, '"' | '\\' | '='));
+        self.0 += &if plain {
+            format!(" {key}={value}")
+        } else {
+            let escaped: String = value
+                .chars()
+                .flat_map(|c| match c {
+                    '"' | '\\' => vec!['\\', c],
+                    '\n' => vec!['\\', 'n'],
+                    c => vec![c],
+                })
+                .collect();
+            format!(" {key}=\"{escaped}\"")
+        };
+        self
+    }
+
+    /// add fields already written as key=value pairs
+    pub fn fields(mut self, pairs: &str) -> Self {
+        self.0 += " ";
+        self.0 += pairs;
+        self
+    }
+
+    /// print the record on its own line. a line is printed in one go, so
+    /// records of inputs compressed at once don't mix
+    pub fn emit(self) {
+        println!("{}", self.0);
+    }
+}
+
+/// the `start` record, before any work
+pub fn start() {
+    if enabled() {
+        Record::new("start")
+            .field("porcelain", VERSION)
+            .field("comprs", comprs::version())
+            .emit();
+    }
+}
+
+/// the `result` record of `input`, which failed with `err` unless it is
+/// `None`
+pub fn result(input: &str, err: Option<&Error>) {
+    if !enabled() {
+        return;
+    }
+    let record = Record::new("result").field("input", input);
+    match err {
+        Some(err) => record
+            .field("code", err.code())
+            .field("message", err)
+            .emit(),
+        None if interrupt::requested() => record.field("code", error::INTERRUPTED).emit(),
+        None => record.field("code", 0).emit(),
+    }
+}
@@ -0,0 +1,158 @@
+use std::{
+    io::{stderr, IsTerminal, Write},
+    time::{Duration, Instant},
+};
+
+use crate::porcelain::{self, Record};
+
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// -porcelain progress records are sparser, they are read by programs that
+/// don't need every redraw
+const RECORD_INTERVAL: Duration = Duration::from_millis(250);
+
+/// single status line on stderr, redrawn in place with a carriage return
+///
+/// disabled when stderr isn't a terminal so piped output stays clean
+pub struct Progress {
+    enabled: bool,
+    total: u32,
+    start: Instant,
+    last_draw: Option<Instant>,
+    /// the input named in -porcelain progress records, `None` without them
+    input: Option<String>,
+    last_record: Option<Instant>,
+}
+
+impl Progress {
+    pub fn new(total: u32, quiet: bool) -> Self {
+        Self {
+            enabled: !quiet && stderr().is_terminal(),
+            total,
+            start: Instant::now(),
+            last_draw: None,
+            input: None,
+            last_record: None,
+        }
+    }
+
+    /// also print progress records naming `input` when -porcelain is given
+    pub fn records(mut self, input: &str) -> Self {
+        if porcelain::enabled() {
+            self.input = Some(input.into());
+        }
+        self
+    }
+
+    /// the input a progress record is due for at `now`, if any
+    fn record_due(&mut self, now: Instant, force: bool) -> Option<&str> {
+        let input = self.input.as_deref()?;
+        if let Some(last) = self.last_record {
+            if !force && now - last < RECORD_INTERVAL {
+                return None;
+            }
+        }
+        self.last_record = Some(now);
+        Some(input)
+    }
+
+    /// whether a line should be drawn at `now`, checked before the line is
+    /// made so skipped updates cost next to nothing
+    fn due(&mut self, now: Instant, force: bool) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        if let Some(last) = self.last_draw {
+            if !force && now - last < REDRAW_INTERVAL {
+                return false;
+            }
+        }
+        self.last_draw = Some(now);
+        true
+    }
+
+    fn draw(&self, line: String) {
+        let mut err = stderr();
+        let _ = write!(err, "\r\x1b[2K{line}");
+        let _ = err.flush();
+    }
+
+    /// report refinement progress, `leaves` is the number of regions the tree
+    /// has and `frames` the number of animation frames captured so far
+    pub fn refine(&mut self, iteration: u32, leaves: usize, frames: Option<usize>) {
+        let now = Instant::now();
+        let total = self.total;
+        if let Some(input) = self.record_due(now, iteration == total) {
+            Record::new("progress")
+                .field("input", input)
+                .field("iter", iteration)
+                .field("iterations", total)
+                .field("leaves", leaves)
+                .emit();
+        }
+        if self.due(now, iteration == self.total) {
+            let elapsed = now - self.start;
+            self.draw(refine_line(iteration, self.total, leaves, frames, elapsed));
+        }
+    }
+
+    /// report encoding progress of buffered animation frames
+    pub fn encode(&mut self, frame: usize, frames: usize) {
+        let now = Instant::now();
+        if let Some(input) = self.record_due(now, frame == frames) {
+            Record::new("encode")
+                .field("input", input)
+                .field("frame", frame)
+                .field("frames", frames)
+                .emit();
+        }
+        if self.due(now, frame == frames) {
+            self.draw(encode_line(frame, frames, now - self.start));
+        }
+    }
+
+    /// end the status line so following output starts on a fresh line
+    pub fn finish(&mut self) {
+        if self.enabled && self.last_draw.is_some() {
+            eprintln!();
+            self.last_draw = None;
+        }
+    }
+}
+
+fn refine_line(
+    iteration: u32,
+    total: u32,
+    leaves: usize,
+    frames: Option<usize>,
+    elapsed: Duration,
+) -> String {
+    let mut line = format!("iteration {iteration}/{total}, {leaves} regions");
+    if let Some(f) = frames {
+        line += &format!(", {f} frames");
+    }
+    let secs = elapsed.as_secs_f64();
+    if iteration > 0 && secs > 0.0 {
+        let rate = iteration as f64 / secs;
+        let eta = (total - iteration) as f64 / rate;
+        line += &format!(", {rate:.0} splits/s, eta {}", clock(eta));
+    }
+    line + &format!(", {secs:.1}s")
+}
+
+/// `secs` as minutes and seconds once it is a minute or more
+fn clock(secs: f64) -> String {
+    let secs = secs.round() as u64;
+    if secs < 60 {
+        format!("{secs}s")
+    } else {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    }
+}
+
+fn encode_line(frame: usize, frames: usize, elapsed: Duration) -> String {
+    format!(
+        "encoding frame {frame}/{frames}, {:.1}s",
+        elapsed.as_secs_f64()
+    )
+}
@@ -1,5 +1,5 @@
 use std::{
-    fmt::Debug,
+    fmt::{self, Debug},
     ops::{Add, Sub},
 };
 
@@ -7,6 +7,70 @@ pub trait Zero {
     fn zero() -> Self;
 }
 
+/// addition that says when it overflows, so prefix sums can be built with
+/// every step checked
+pub trait Accumulate: Sized {
+    fn checked_add(self, rhs: Self) -> Option<Self>;
+}
+
+impl Accumulate for u32 {
+    fn checked_add(self, rhs: Self) -> Option<Self> {
+        u32::checked_add(self, rhs)
+    }
+}
+
+impl Accumulate for u64 {
+    fn checked_add(self, rhs: Self) -> Option<Self> {
+        u64::checked_add(self, rhs)
+    }
+}
+
+impl Accumulate for i64 {
+    fn checked_add(self, rhs: Self) -> Option<Self> {
+        i64::checked_add(self, rhs)
+    }
+}
+
+/// why a prefix sum array couldn't be built
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildError {
+    /// the array would have no cells, this side of it is 0
+    Empty(&'static str),
+    /// the sum of every cell up to and including this one doesn't fit
+    Overflow { row: usize, column: usize },
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildError::Empty(side) => write!(f, "array has {side} 0"),
+            BuildError::Overflow { row, column } => {
+                write!(f, "prefix sum overflows at row {row}, column {column}")
+            }
+        }
+    }
+}
+
+/// why a rectangle can't be summed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeError {
+    /// this (row, column) corner is outside the array
+    OutOfBounds((usize, usize)),
+    /// the top left corner is below or right of the bottom right one
+    Inverted,
+}
+
+impl fmt::Display for RangeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RangeError::OutOfBounds((i, j)) => write!(f, "corner {i},{j} is outside the array"),
+            RangeError::Inverted => {
+                f.write_str("top left corner is below or right of the bottom right one")
+            }
+        }
+    }
+}
+
 /// 2D prefix sum array for fast range sum queries
 pub struct PrefixSum2D<T>
 where
@@ -14,27 +78,548 @@ where
 {
     height: usize,
     width: usize,
-    data: Vec<Vec<T>>,
+    /// (height + 1) x (width + 1) row major, the first row and column are zero
+    data: Vec<T>,
 }
 
 impl<T> PrefixSum2D<T>
 where
     T: Add<Output = T> + Sub<Output = T> + Zero + Clone + Copy + Debug,
 {
-    pub fn new(arr: &Vec<Vec<T>>) -> Result<Self, String> {
-        let height = arr.len();
-        let width = match arr.first() {
-            Some(f) => f.len(),
-            None => return Err("array has height 0".into()),
+    /// build from an array of rows, which all have to be as long as the
+    /// first. `from_rows` and `from_fn` build it without the array
+    pub fn new(arr: &[Vec<T>]) -> Result<Self, String> {
+        Self::from_rows(arr.iter().map(|row| row.iter().copied()))
+    }
+
+    /// build from rows of cells, which all have to be as long as the first.
+    /// each row is summed as it comes, so the cells are never all held at
+    /// once
+    pub fn from_rows<R>(rows: impl IntoIterator<Item = R>) -> Result<Self, String>
+    where
+        R: IntoIterator<Item = T>,
+    {
+        let mut rows = rows.into_iter();
+        let Some(first) = rows.next() else {
+            return Err(BuildError::Empty("height").to_string());
         };
-        if width == 0 {
-            return Err("array has width 0".into());
+        // the width isn't known before the first row ends
+        let first: Vec<T> = first.into_iter().collect();
+        let width = first.len();
+        check_size(1, width).map_err(|err| err.to_string())?;
+
+        let mut sums = RowSums::new(rows.size_hint().0 + 1, width);
+        sums.cells().copy_from_slice(&first);
+        sums.push();
+        for (i, row) in rows.enumerate() {
+            let mut len = 0;
+            for cell in row {
+                if let Some(c) = sums.cells().get_mut(len) {
+                    *c = cell;
+                }
+                len += 1;
+            }
+            if len != width {
+                return Err(format!(
+                    "row {} has length {len}, expected width {width}",
+                    i + 1
+                ));
+            }
+            sums.push();
         }
 
-        let mut data = vec![vec![T::zero(); width + 1]; height + 1];
+        Ok(Self {
+            height: sums.rows(),
+            width,
+            data: sums.into_data(),
+        })
+    }
+
+    /// build with `f(i, j)` as the value of the cell at row `i`, column `j`.
+    /// cells are asked for row by row, each once
+    pub fn from_fn(
+        height: usize,
+        width: usize,
+        mut f: impl FnMut(usize, usize) -> T,
+    ) -> Result<Self, String> {
+        check_size(height, width).map_err(|err| err.to_string())?;
+
+        let mut sums = RowSums::new(height, width);
         for i in 0..height {
+            for (j, cell) in sums.cells().iter_mut().enumerate() {
+                *cell = f(i, j);
+            }
+            sums.push();
+        }
+
+        Ok(Self {
+            height,
+            width,
+            data: sums.into_data(),
+        })
+    }
+
+    /// build from whole rows at a time, `f(i, row)` writes the value of every
+    /// cell of row `i` into `row`. a tight loop over a row can be vectorized
+    /// where a call per cell can't, and with rayon the rows are built on
+    /// every core. `row` is always `width` long
+    pub fn from_row_fn(
+        height: usize,
+        width: usize,
+        f: impl Fn(usize, &mut [T]) + Sync,
+    ) -> Result<Self, String>
+    where
+        T: Send + Sync,
+    {
+        check_size(height, width).map_err(|err| err.to_string())?;
+
+        let data = fill(height, width, f);
+
+        Ok(Self {
+            height,
+            width,
+            data,
+        })
+    }
+
+    /// the same as `from_row_fn` with every addition checked, so a sum that
+    /// doesn't fit in `T` is an error naming the cell where it stopped
+    /// fitting instead of wrapping around. slower, and always on one core
+    pub fn try_from_row_fn(
+        height: usize,
+        width: usize,
+        f: impl Fn(usize, &mut [T]),
+    ) -> Result<Self, BuildError>
+    where
+        T: Accumulate,
+    {
+        check_size(height, width)?;
+
+        let data = try_fill(height, width, f)?;
+
+        Ok(Self {
+            height,
+            width,
+            data,
+        })
+    }
+
+    /// take back sums `raw` gave, for a `height` x `width` array
+    pub fn from_raw(height: usize, width: usize, data: Vec<T>) -> Result<Self, String> {
+        check_size(height, width).map_err(|err| err.to_string())?;
+        if data.len() != (height + 1) * (width + 1) {
+            return Err(format!(
+                "{} prefix sums can't be a {width}x{height} array",
+                data.len()
+            ));
+        }
+        Ok(Self {
+            height,
+            width,
+            data,
+        })
+    }
+
+    /// the (height + 1) x (width + 1) prefix sums, row major, for storing
+    /// them and `from_raw`
+    pub fn raw(&self) -> &[T] {
+        &self.data
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    fn at(&self, i: usize, j: usize) -> T {
+        self.data[i * (self.width + 1) + j]
+    }
+
+    /// the sum of values from `top_left` to `bottom_right` (inclusive), or
+    /// why that isn't a rectangle of the array
+    pub fn try_query_sum(
+        &self,
+        top_left: (usize, usize),
+        bottom_right: (usize, usize),
+    ) -> Result<T, RangeError> {
+        check_range(self.height, self.width, top_left, bottom_right)?;
+        Ok(self.query_sum(top_left, bottom_right))
+    }
+
+    /// get the sum of values from top_left to bottom_right (inclusive). both
+    /// corners have to be in the array with top_left above and left of
+    /// bottom_right, otherwise this panics or sums the wrong cells.
+    /// `try_query_sum` checks them first
+    pub fn query_sum(&self, top_left: (usize, usize), bottom_right: (usize, usize)) -> T {
+        let a = self.at(bottom_right.0 + 1, bottom_right.1 + 1);
+        let b = self.at(top_left.0, top_left.1);
+        let c = self.at(bottom_right.0 + 1, top_left.1);
+        let d = self.at(top_left.0, bottom_right.1 + 1);
+
+        // every intermediate is the sum of some region, so it can't overflow
+        // a type that holds the total of the whole array, or for signed
+        // values the total of all its positive or all its negative cells
+        (a - c) - (d - b)
+    }
+
+    /// the window from `top_left` to `bottom_right` (inclusive), which sums
+    /// as if it were an array of its own. the sums aren't copied, the view
+    /// borrows them
+    pub fn view(
+        &self,
+        top_left: (usize, usize),
+        bottom_right: (usize, usize),
+    ) -> Result<PrefixSum2DView<'_, T>, RangeError> {
+        PrefixSum2DView {
+            sums: self,
+            height: self.height,
+            width: self.width,
+            origin: (0, 0),
+        }
+        .view(top_left, bottom_right)
+    }
+
+    /// the sum of every cell in rows `top` to `bottom` (inclusive), or why
+    /// those aren't rows of the array
+    pub fn try_query_rows(&self, top: usize, bottom: usize) -> Result<T, RangeError> {
+        self.try_query_sum((top, 0), (bottom, self.width - 1))
+    }
+
+    /// the sum of every cell in columns `left` to `right` (inclusive), or why
+    /// those aren't columns of the array
+    pub fn try_query_cols(&self, left: usize, right: usize) -> Result<T, RangeError> {
+        self.try_query_sum((0, left), (self.height - 1, right))
+    }
+
+    /// the sum of every cell in rows `top` to `bottom` (inclusive). panics if
+    /// they aren't rows of the array
+    pub fn query_rows(&self, top: usize, bottom: usize) -> T {
+        self.try_query_rows(top, bottom)
+            .unwrap_or_else(|err| panic!("can't sum rows {top} to {bottom}: {err}"))
+    }
+
+    /// the sum of every cell in columns `left` to `right` (inclusive). panics
+    /// if they aren't columns of the array
+    pub fn query_cols(&self, left: usize, right: usize) -> T {
+        self.try_query_cols(left, right)
+            .unwrap_or_else(|err| panic!("can't sum columns {left} to {right}: {err}"))
+    }
+
+    /// the sum of row `r`
+    pub fn query_row(&self, r: usize) -> T {
+        self.query_rows(r, r)
+    }
+
+    /// the sum of column `c`
+    pub fn query_col(&self, c: usize) -> T {
+        self.query_cols(c, c)
+    }
+}
+
+/// a window of a `PrefixSum2D` made by `view`. positions are counted from
+/// the top left of the window
+#[derive(Clone, Copy)]
+pub struct PrefixSum2DView<'a, T>
+where
+    T: Add<Output = T> + Sub<Output = T> + Zero + Clone + Copy,
+{
+    sums: &'a PrefixSum2D<T>,
+    height: usize,
+    width: usize,
+    /// (row, column) of the window in the array
+    origin: (usize, usize),
+}
+
+impl<'a, T> PrefixSum2DView<'a, T>
+where
+    T: Add<Output = T> + Sub<Output = T> + Zero + Clone + Copy + Debug,
+{
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// the window from `top_left` to `bottom_right` (inclusive) of this one,
+    /// as a view of the same array
+    pub fn view(
+        &self,
+        top_left: (usize, usize),
+        bottom_right: (usize, usize),
+    ) -> Result<PrefixSum2DView<'a, T>, RangeError> {
+        check_range(self.height, self.width, top_left, bottom_right)?;
+        Ok(Self {
+            sums: self.sums,
+            height: bottom_right.0 - top_left.0 + 1,
+            width: bottom_right.1 - top_left.1 + 1,
+            origin: (self.origin.0 + top_left.0, self.origin.1 + top_left.1),
+        })
+    }
+
+    /// the sum of values from `top_left` to `bottom_right` (inclusive) of
+    /// the window, or why that isn't a rectangle of it
+    pub fn try_query_sum(
+        &self,
+        top_left: (usize, usize),
+        bottom_right: (usize, usize),
+    ) -> Result<T, RangeError> {
+        check_range(self.height, self.width, top_left, bottom_right)?;
+        Ok(self.query_sum(top_left, bottom_right))
+    }
+
+    /// `PrefixSum2D::query_sum` in the window, with the same requirements
+    pub fn query_sum(&self, top_left: (usize, usize), bottom_right: (usize, usize)) -> T {
+        let (i, j) = self.origin;
+        self.sums.query_sum(
+            (top_left.0 + i, top_left.1 + j),
+            (bottom_right.0 + i, bottom_right.1 + j),
+        )
+    }
+}
+
+/// whether `top_left` to `bottom_right` is a rectangle of a `height` x
+/// `width` array
+fn check_range(
+    height: usize,
+    width: usize,
+    top_left: (usize, usize),
+    bottom_right: (usize, usize),
+) -> Result<(), RangeError> {
+    for corner in [top_left, bottom_right] {
+        if corner.0 >= height || corner.1 >= width {
+            return Err(RangeError::OutOfBounds(corner));
+        }
+    }
+    if top_left.0 > bottom_right.0 || top_left.1 > bottom_right.1 {
+        return Err(RangeError::Inverted);
+    }
+    Ok(())
+}
+
+fn check_size(height: usize, width: usize) -> Result<(), BuildError> {
+    if height == 0 {
+        return Err(BuildError::Empty("height"));
+    }
+    if width == 0 {
+        return Err(BuildError::Empty("width"));
+    }
+    Ok(())
+}
+
+/// `fill` with checked additions. the first column of the buffer is all
+/// zeros, so column `k` of it is column `k - 1` of the array
+fn try_fill<T>(
+    height: usize,
+    width: usize,
+    f: impl Fn(usize, &mut [T]),
+) -> Result<Vec<T>, BuildError>
+where
+    T: Accumulate + Zero + Copy,
+{
+    let stride = width + 1;
+    let mut data = Vec::with_capacity((height + 1) * stride);
+    let mut acc = vec![T::zero(); stride];
+    data.extend_from_slice(&acc);
+    let mut row = vec![T::zero(); stride];
+    for i in 0..height {
+        f(i, &mut row[1..]);
+        for j in 0..width {
+            row[j + 1] = row[j]
+                .checked_add(row[j + 1])
+                .ok_or(BuildError::Overflow { row: i, column: j })?;
+        }
+        for (k, (a, &r)) in acc.iter_mut().zip(&row).enumerate() {
+            *a = a.checked_add(r).ok_or(BuildError::Overflow {
+                row: i,
+                column: k.saturating_sub(1),
+            })?;
+        }
+        data.extend_from_slice(&acc);
+    }
+    Ok(data)
+}
+
+/// prefix sums appended a row at a time. every row is built in a small
+/// buffer that stays in cache and then appended once, so the large buffer is
+/// written a single time and never zeroed first
+struct RowSums<T> {
+    width: usize,
+    data: Vec<T>,
+    /// prefix sums of every row so far, which is the row being appended
+    acc: Vec<T>,
+    /// a zero and then the cells of the next row
+    row: Vec<T>,
+}
+
+impl<T> RowSums<T>
+where
+    T: Add<Output = T> + Zero + Copy,
+{
+    /// room for `height` rows of `width` cells
+    fn new(height: usize, width: usize) -> Self {
+        let stride = width + 1;
+        let mut data = Vec::with_capacity((height + 1) * stride);
+        let acc = vec![T::zero(); stride];
+        data.extend_from_slice(&acc);
+        Self {
+            width,
+            data,
+            acc,
+            row: vec![T::zero(); stride],
+        }
+    }
+
+    /// the cells of the next row, to fill in before `push`
+    fn cells(&mut self) -> &mut [T] {
+        &mut self.row[1..]
+    }
+
+    fn push(&mut self) {
+        for j in 0..self.width {
+            self.row[j + 1] = self.row[j] + self.row[j + 1];
+        }
+        for (a, &r) in self.acc.iter_mut().zip(&self.row) {
+            *a = *a + r;
+        }
+        self.data.extend_from_slice(&self.acc);
+    }
+
+    /// rows pushed so far
+    fn rows(&self) -> usize {
+        self.data.len() / (self.width + 1) - 1
+    }
+
+    /// the (rows + 1) x (width + 1) buffer
+    fn into_data(self) -> Vec<T> {
+        self.data
+    }
+}
+
+/// a (height + 1) x (width + 1) buffer of prefix sums, built a row at a time
+#[cfg(not(feature = "rayon"))]
+fn fill<T>(height: usize, width: usize, f: impl Fn(usize, &mut [T])) -> Vec<T>
+where
+    T: Add<Output = T> + Zero + Copy,
+{
+    let mut sums = RowSums::new(height, width);
+    for i in 0..height {
+        f(i, sums.cells());
+        sums.push();
+    }
+    sums.into_data()
+}
+
+/// a (height + 1) x (width + 1) buffer of prefix sums. rows are summed in
+/// parallel, then columns are summed within bands of rows in parallel, then
+/// the total of every earlier band is added to each band
+#[cfg(feature = "rayon")]
+fn fill<T>(height: usize, width: usize, f: impl Fn(usize, &mut [T]) + Sync) -> Vec<T>
+where
+    T: Add<Output = T> + Zero + Copy + Send + Sync,
+{
+    use rayon::prelude::*;
+
+    let stride = width + 1;
+    let mut data = vec![T::zero(); (height + 1) * stride];
+    let rows = &mut data[stride..];
+    rows.par_chunks_mut(stride)
+        .enumerate()
+        .for_each(|(i, row)| {
+            f(i, &mut row[1..]);
             for j in 0..width {
-                data[i + 1][j + 1] = arr[i][j] + data[i][j + 1] + data[i + 1][j] - data[i][j];
+                row[j + 1] = row[j] + row[j + 1];
+            }
+        });
+
+    let band = height.div_ceil(rayon::current_num_threads()).max(1) * stride;
+    rows.par_chunks_mut(band).for_each(|chunk| {
+        for k in 1..chunk.len() / stride {
+            let (above, below) = chunk.split_at_mut(k * stride);
+            let above = &above[(k - 1) * stride..];
+            for j in 0..stride {
+                below[j] = below[j] + above[j];
+            }
+        }
+    });
+
+    // offsets[b] is the column sums of every band before b
+    let mut offsets = vec![vec![T::zero(); stride]];
+    for chunk in rows.chunks(band) {
+        let last = &chunk[chunk.len() - stride..];
+        let prev = offsets.last().unwrap();
+        let next = prev.iter().zip(last).map(|(&a, &b)| a + b).collect();
+        offsets.push(next);
+    }
+    rows.par_chunks_mut(band)
+        .zip(offsets)
+        .skip(1)
+        .for_each(|(chunk, offset)| {
+            for row in chunk.chunks_mut(stride) {
+                for j in 0..stride {
+                    row[j] = row[j] + offset[j];
+                }
+            }
+        });
+    data
+}
+
+/// 2D fenwick tree, range sums like `PrefixSum2D` that stay right when a
+/// cell changes. a query or an update takes O(log height * log width) where
+/// `PrefixSum2D` answers in O(1) but would have to be built again
+pub struct Fenwick2D<T>
+where
+    T: Add<Output = T> + Sub<Output = T> + Zero + Copy,
+{
+    height: usize,
+    width: usize,
+    /// height x width row major. cell (i, j), counting from 1, holds the sum
+    /// of the lowbit(i) rows up to i and lowbit(j) columns up to j
+    data: Vec<T>,
+}
+
+/// the lowest set bit of `i`, how many cells a fenwick cell covers
+fn lowbit(i: usize) -> usize {
+    i & i.wrapping_neg()
+}
+
+impl<T> Fenwick2D<T>
+where
+    T: Add<Output = T> + Sub<Output = T> + Zero + Copy,
+{
+    /// build from whole rows at a time like `PrefixSum2D::from_row_fn`, in
+    /// O(height * width)
+    pub fn from_row_fn(
+        height: usize,
+        width: usize,
+        f: impl Fn(usize, &mut [T]),
+    ) -> Result<Self, String> {
+        check_size(height, width).map_err(|err| err.to_string())?;
+
+        let mut data = vec![T::zero(); height * width];
+        for (i, row) in data.chunks_exact_mut(width).enumerate() {
+            f(i, row);
+            // every cell adds itself to the next cell covering it, which
+            // comes later, so it is whole by the time it is added on
+            for j in 1..=width {
+                let next = j + lowbit(j);
+                if next <= width {
+                    row[next - 1] = row[next - 1] + row[j - 1];
+                }
+            }
+        }
+        for i in 1..=height {
+            let next = i + lowbit(i);
+            if next <= height {
+                let (done, rest) = data.split_at_mut((next - 1) * width);
+                let (from, to) = (&done[(i - 1) * width..i * width], &mut rest[..width]);
+                for (t, &f) in to.iter_mut().zip(from) {
+                    *t = *t + f;
+                }
             }
         }
 
@@ -53,13 +638,902 @@ where
         self.width
     }
 
-    /// get the sum of values from top_left to bottom_right (inclusive)
+    /// change cell (i, j) from `old` to `new`, which has to be what the cell
+    /// holds now. taking the old value rather than a difference keeps
+    /// unsigned sums from going below zero on the way
+    pub fn update(&mut self, (i, j): (usize, usize), old: T, new: T) {
+        let mut i = i + 1;
+        while i <= self.height {
+            let mut j = j + 1;
+            while j <= self.width {
+                let cell = &mut self.data[(i - 1) * self.width + j - 1];
+                *cell = *cell - old + new;
+                j += lowbit(j);
+            }
+            i += lowbit(i);
+        }
+    }
+
+    /// the sum of the first `i` rows of the first `j` columns
+    fn prefix(&self, i: usize, j: usize) -> T {
+        let mut ret = T::zero();
+        let mut i = i;
+        while i > 0 {
+            let mut j = j;
+            while j > 0 {
+                ret = ret + self.data[(i - 1) * self.width + j - 1];
+                j -= lowbit(j);
+            }
+            i -= lowbit(i);
+        }
+        ret
+    }
+
+    /// the sum of values from `top_left` to `bottom_right` (inclusive), or
+    /// why that isn't a rectangle of the array
+    pub fn try_query_sum(
+        &self,
+        top_left: (usize, usize),
+        bottom_right: (usize, usize),
+    ) -> Result<T, RangeError> {
+        check_range(self.height, self.width, top_left, bottom_right)?;
+        Ok(self.query_sum(top_left, bottom_right))
+    }
+
+    /// get the sum of values from top_left to bottom_right (inclusive), with
+    /// the same requirements as `PrefixSum2D::query_sum`
     pub fn query_sum(&self, top_left: (usize, usize), bottom_right: (usize, usize)) -> T {
-        let a = self.data[bottom_right.0 + 1][bottom_right.1 + 1];
-        let b = self.data[top_left.0][top_left.1];
-        let c = self.data[bottom_right.0 + 1][top_left.1];
-        let d = self.data[top_left.0][bottom_right.1 + 1];
+        let a = self.prefix(bottom_right.0 + 1, bottom_right.1 + 1);
+        let b = self.prefix(top_left.0, top_left.1);
+        let c = self.prefix(bottom_right.0 + 1, top_left.1);
+        let d = self.prefix(top_left.0, bottom_right.1 + 1);
+
+        // the same order as `PrefixSum2D::query_sum`, so unsigned sums
+        // don't go below zero
+        (a - c) - (d - b)
+    }
+}
+
+/// 2D sparse table, the smallest and largest value of any rectangle, which
+/// prefix sums can't answer. `from_fn` keeps both for every rectangle with
+/// sides a power of two long, O(height * width * log height * log width) of
+/// them, and answers in O(1). `tiled_from_fn` keeps the values and that
+/// table for square tiles of them instead, about one value per cell, and
+/// looks through the cells of the tiles the rectangle only partly covers
+pub struct SparseTable2D<T: Ord + Copy> {
+    height: usize,
+    width: usize,
+    layout: Layout<T>,
+}
+
+enum Layout<T> {
+    Full(Levels<T>),
+    Tiled {
+        /// side of a tile, the ones on the bottom and right edge can be
+        /// shorter
+        tile: usize,
+        /// height x width row major
+        cells: Vec<T>,
+        /// a cell for every tile
+        tiles: Levels<T>,
+    },
+}
+
+/// (min, max) of every 2^k x 2^l rectangle of a height x width array
+struct Levels<T> {
+    width: usize,
+    /// how many sizes of column there are, log2(width) + 1
+    col_levels: usize,
+    /// the rectangles 2^k rows by 2^l columns at k * col_levels + l, row
+    /// major with a row for every top and a column for every left edge one
+    /// fits below and right of
+    levels: Vec<Vec<(T, T)>>,
+}
+
+fn merge<T: Ord>(a: (T, T), b: (T, T)) -> (T, T) {
+    (a.0.min(b.0), a.1.max(b.1))
+}
+
+impl<T: Ord + Copy> Levels<T> {
+    /// from the (min, max) of every cell, row major
+    fn new(height: usize, width: usize, cells: Vec<(T, T)>) -> Self {
+        let row_levels = height.ilog2() as usize + 1;
+        let col_levels = width.ilog2() as usize + 1;
+        let mut levels = Vec::with_capacity(row_levels * col_levels);
+        levels.push(cells);
+        for k in 0..row_levels {
+            let rows = height - (1 << k) + 1;
+            if k > 0 {
+                // two of the rectangles half as tall, one on the other
+                let (above, half) = (&levels[(k - 1) * col_levels], (1 << (k - 1)) * width);
+                let next = (0..rows * width)
+                    .map(|c| merge(above[c], above[c + half]))
+                    .collect();
+                levels.push(next);
+            }
+            for l in 1..col_levels {
+                // two of the rectangles half as wide, side by side
+                let narrower = &levels[k * col_levels + l - 1];
+                let (half, stride) = (1 << (l - 1), width - (1 << (l - 1)) + 1);
+                let cols = width - (1 << l) + 1;
+                let next = (0..rows)
+                    .flat_map(|i| {
+                        let row = &narrower[i * stride..(i + 1) * stride];
+                        (0..cols).map(move |j| merge(row[j], row[j + half]))
+                    })
+                    .collect();
+                levels.push(next);
+            }
+        }
+        Self {
+            width,
+            col_levels,
+            levels,
+        }
+    }
+
+    /// the rectangle is covered by the four largest power of two sized ones
+    /// in its corners, which can overlap
+    fn query(&self, top_left: (usize, usize), bottom_right: (usize, usize)) -> (T, T) {
+        let k = (bottom_right.0 - top_left.0 + 1).ilog2() as usize;
+        let l = (bottom_right.1 - top_left.1 + 1).ilog2() as usize;
+        let level = &self.levels[k * self.col_levels + l];
+        let stride = self.width - (1 << l) + 1;
+        let at = |i: usize, j: usize| level[i * stride + j];
+        let (lower, further) = (bottom_right.0 + 1 - (1 << k), bottom_right.1 + 1 - (1 << l));
+        merge(
+            merge(at(top_left.0, top_left.1), at(top_left.0, further)),
+            merge(at(lower, top_left.1), at(lower, further)),
+        )
+    }
+}
+
+impl<T: Ord + Copy> SparseTable2D<T> {
+    /// build with `f(i, j)` as the value of the cell at row `i`, column `j`,
+    /// asked for row by row like `PrefixSum2D::from_fn`. every query is O(1)
+    pub fn from_fn(
+        height: usize,
+        width: usize,
+        mut f: impl FnMut(usize, usize) -> T,
+    ) -> Result<Self, String> {
+        check_size(height, width).map_err(|err| err.to_string())?;
+
+        let cells = (0..height)
+            .flat_map(|i| (0..width).map(move |j| (i, j)))
+            .map(|(i, j)| {
+                let v = f(i, j);
+                (v, v)
+            })
+            .collect();
+
+        Ok(Self {
+            height,
+            width,
+            layout: Layout::Full(Levels::new(height, width, cells)),
+        })
+    }
+
+    /// the same as `from_fn` with the table kept for `tile` x `tile` tiles
+    /// rather than for cells. a query takes up to O(tile * (height + width))
+    /// for the cells of the tiles along its edges
+    pub fn tiled_from_fn(
+        height: usize,
+        width: usize,
+        tile: usize,
+        mut f: impl FnMut(usize, usize) -> T,
+    ) -> Result<Self, String> {
+        check_size(height, width).map_err(|err| err.to_string())?;
+        if tile == 0 {
+            return Err("tiles have to be at least 1 cell across".into());
+        }
+
+        let mut cells = Vec::with_capacity(height * width);
+        for i in 0..height {
+            for j in 0..width {
+                cells.push(f(i, j));
+            }
+        }
+        let (tile_rows, tile_cols) = (height.div_ceil(tile), width.div_ceil(tile));
+        let mut extremes: Vec<Option<(T, T)>> = vec![None; tile_rows * tile_cols];
+        for (i, row) in cells.chunks_exact(width).enumerate() {
+            for (j, &v) in row.iter().enumerate() {
+                let e = &mut extremes[i / tile * tile_cols + j / tile];
+                *e = Some(e.map_or((v, v), |e| merge(e, (v, v))));
+            }
+        }
+        // every tile has at least one cell
+        let extremes = extremes.into_iter().map(Option::unwrap).collect();
+
+        Ok(Self {
+            height,
+            width,
+            layout: Layout::Tiled {
+                tile,
+                cells,
+                tiles: Levels::new(tile_rows, tile_cols, extremes),
+            },
+        })
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// the smallest and largest value from `top_left` to `bottom_right`
+    /// (inclusive), or why that isn't a rectangle of the array
+    pub fn try_query_min_max(
+        &self,
+        top_left: (usize, usize),
+        bottom_right: (usize, usize),
+    ) -> Result<(T, T), RangeError> {
+        check_range(self.height, self.width, top_left, bottom_right)?;
+        Ok(self.query_min_max(top_left, bottom_right))
+    }
+
+    /// the smallest value from `top_left` to `bottom_right` (inclusive), or
+    /// why that isn't a rectangle of the array
+    pub fn try_query_min(
+        &self,
+        top_left: (usize, usize),
+        bottom_right: (usize, usize),
+    ) -> Result<T, RangeError> {
+        Ok(self.try_query_min_max(top_left, bottom_right)?.0)
+    }
+
+    /// the largest value from `top_left` to `bottom_right` (inclusive), or
+    /// why that isn't a rectangle of the array
+    pub fn try_query_max(
+        &self,
+        top_left: (usize, usize),
+        bottom_right: (usize, usize),
+    ) -> Result<T, RangeError> {
+        Ok(self.try_query_min_max(top_left, bottom_right)?.1)
+    }
+
+    /// the smallest value from top_left to bottom_right (inclusive), with the
+    /// same requirements as `PrefixSum2D::query_sum`
+    pub fn query_min(&self, top_left: (usize, usize), bottom_right: (usize, usize)) -> T {
+        self.query_min_max(top_left, bottom_right).0
+    }
+
+    /// the largest value from top_left to bottom_right (inclusive), with the
+    /// same requirements as `PrefixSum2D::query_sum`
+    pub fn query_max(&self, top_left: (usize, usize), bottom_right: (usize, usize)) -> T {
+        self.query_min_max(top_left, bottom_right).1
+    }
+
+    /// `query_min` and `query_max` at once
+    pub fn query_min_max(&self, top_left: (usize, usize), bottom_right: (usize, usize)) -> (T, T) {
+        let (tile, cells, tiles) = match &self.layout {
+            Layout::Full(levels) => return levels.query(top_left, bottom_right),
+            Layout::Tiled { tile, cells, tiles } => (*tile, cells, tiles),
+        };
+        // the tiles wholly inside, the last tile of the array counts when it
+        // is reached even if it is short
+        let whole = |first: usize, last: usize, len: usize| {
+            let start = first.div_ceil(tile);
+            let end = if last + 1 == len {
+                len.div_ceil(tile)
+            } else {
+                (last + 1) / tile
+            };
+            start..end.max(start)
+        };
+        let tile_rows = whole(top_left.0, bottom_right.0, self.height);
+        let tile_cols = whole(top_left.1, bottom_right.1, self.width);
+        let mut found = None;
+        let (mut skip_rows, mut skip_cols) = (0..0, 0..0);
+        if !tile_rows.is_empty() && !tile_cols.is_empty() {
+            found = Some(tiles.query(
+                (tile_rows.start, tile_cols.start),
+                (tile_rows.end - 1, tile_cols.end - 1),
+            ));
+            skip_rows = tile_rows.start * tile..(tile_rows.end * tile).min(self.height);
+            skip_cols = tile_cols.start * tile..(tile_cols.end * tile).min(self.width);
+        }
+
+        let mut scan = |row: &[T]| {
+            for &v in row {
+                found = Some(found.map_or((v, v), |f| merge(f, (v, v))));
+            }
+        };
+        for i in top_left.0..=bottom_right.0 {
+            let row = &cells[i * self.width..(i + 1) * self.width];
+            if skip_rows.contains(&i) {
+                scan(&row[top_left.1..skip_cols.start]);
+                scan(&row[skip_cols.end..=bottom_right.1]);
+            } else {
+                scan(&row[top_left.1..=bottom_right.1]);
+            }
+        }
+        // the rectangle has at least one cell
+        found.unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl Zero for i64 {
+        fn zero() -> Self {
+            0
+        }
+    }
+
+    impl Zero for u64 {
+        fn zero() -> Self {
+            0
+        }
+    }
+
+    /// a height x width array of values that aren't all the same
+    fn grid(height: usize, width: usize) -> Vec<Vec<i64>> {
+        (0..height)
+            .map(|i| {
+                (0..width)
+                    .map(|j| (i * 7 + j * 13) as i64 % 11 - 5)
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn brute_sum(arr: &[Vec<i64>], top_left: (usize, usize), bottom_right: (usize, usize)) -> i64 {
+        arr[top_left.0..=bottom_right.0]
+            .iter()
+            .flat_map(|row| &row[top_left.1..=bottom_right.1])
+            .sum()
+    }
+
+    /// every rectangle of `arr` summed by `sum`, against the brute force sum
+    fn check_all(arr: &[Vec<i64>], sum: impl Fn((usize, usize), (usize, usize)) -> i64) {
+        let (height, width) = (arr.len(), arr[0].len());
+        for top in 0..height {
+            for left in 0..width {
+                for bottom in top..height {
+                    for right in left..width {
+                        let (tl, br) = ((top, left), (bottom, right));
+                        assert_eq!(sum(tl, br), brute_sum(arr, tl, br), "{tl:?} to {br:?}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn new_sums_every_rectangle() {
+        for (height, width) in [(1, 1), (1, 7), (6, 1), (5, 8)] {
+            let arr = grid(height, width);
+            let psa = PrefixSum2D::new(&arr).unwrap();
+            assert_eq!((psa.height(), psa.width()), (height, width));
+            check_all(&arr, |tl, br| psa.query_sum(tl, br));
+        }
+    }
+
+    #[test]
+    fn new_matches_from_row_fn() {
+        let arr = grid(9, 4);
+        let psa = PrefixSum2D::new(&arr).unwrap();
+        let rows =
+            PrefixSum2D::from_row_fn(9, 4, |i, row: &mut [i64]| row.copy_from_slice(&arr[i]));
+        assert_eq!(psa.raw(), rows.unwrap().raw());
+    }
+
+    /// xorshift, enough to make up arrays without a dependency
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn grid(&mut self, height: usize, width: usize) -> Vec<Vec<i64>> {
+            (0..height)
+                .map(|_| (0..width).map(|_| self.below(2001) as i64 - 1000).collect())
+                .collect()
+        }
+    }
+
+    #[test]
+    fn constructors_agree_on_random_arrays() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..200 {
+            let (height, width) = (1 + rng.below(17), 1 + rng.below(17));
+            let arr = rng.grid(height, width);
+            let psa = PrefixSum2D::new(&arr).unwrap();
+            let from_fn = PrefixSum2D::from_fn(height, width, |i, j| arr[i][j]).unwrap();
+            let from_rows = PrefixSum2D::from_rows(arr.iter().map(|row| row.iter().copied()));
+            let from_row_fn = PrefixSum2D::from_row_fn(height, width, |i, row: &mut [i64]| {
+                row.copy_from_slice(&arr[i])
+            })
+            .unwrap();
+            assert_eq!(psa.raw(), from_fn.raw(), "{height}x{width}");
+            assert_eq!(psa.raw(), from_rows.unwrap().raw(), "{height}x{width}");
+            assert_eq!(psa.raw(), from_row_fn.raw(), "{height}x{width}");
+            assert_eq!((from_fn.height(), from_fn.width()), (height, width));
+        }
+    }
+
+    /// prefix sums the old way, a Vec of its own for every row with a row
+    /// and a column of zeros in front, indexed [row][column]
+    struct Nested {
+        data: Vec<Vec<i64>>,
+    }
+
+    impl Nested {
+        fn new(arr: &[Vec<i64>]) -> Self {
+            let (height, width) = (arr.len(), arr[0].len());
+            let mut data = vec![vec![0; width + 1]; height + 1];
+            for i in 0..height {
+                for j in 0..width {
+                    data[i + 1][j + 1] = arr[i][j] + data[i][j + 1] + data[i + 1][j] - data[i][j];
+                }
+            }
+            Self { data }
+        }
+
+        fn query_sum(&self, top_left: (usize, usize), bottom_right: (usize, usize)) -> i64 {
+            let a = self.data[bottom_right.0 + 1][bottom_right.1 + 1];
+            let b = self.data[top_left.0][top_left.1];
+            let c = self.data[bottom_right.0 + 1][top_left.1];
+            let d = self.data[top_left.0][bottom_right.1 + 1];
+            a + b - c - d
+        }
+    }
+
+    #[test]
+    fn flat_layout_matches_nested_rows() {
+        let mut rng = Rng(0xbb67_ae85_84ca_a73b);
+        for _ in 0..100 {
+            let (height, width) = (1 + rng.below(17), 1 + rng.below(17));
+            let arr = rng.grid(height, width);
+            let psa = PrefixSum2D::new(&arr).unwrap();
+            let nested = Nested::new(&arr);
+            assert_eq!(psa.raw(), nested.data.concat(), "{height}x{width}");
+            for _ in 0..50 {
+                let (top, left) = (rng.below(height), rng.below(width));
+                let bottom = top + rng.below(height - top);
+                let right = left + rng.below(width - left);
+                let (tl, br) = ((top, left), (bottom, right));
+                assert_eq!(
+                    psa.query_sum(tl, br),
+                    nested.query_sum(tl, br),
+                    "{tl:?} to {br:?}"
+                );
+            }
+        }
+    }
+
+    /// the bands rows are summed in depend on the number of threads, so
+    /// every split of the rows has to add up the same as one pass
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn parallel_sums_match_serial_on_any_thread_count() {
+        let mut rng = Rng(0x3c6e_f372_fe94_f82b);
+        for threads in [1, 2, 3, 8] {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            for _ in 0..50 {
+                let (height, width) = (1 + rng.below(40), 1 + rng.below(17));
+                let arr = rng.grid(height, width);
+                let psa = pool.install(|| PrefixSum2D::new(&arr).unwrap());
+                let mut serial = RowSums::new(height, width);
+                for row in &arr {
+                    serial.cells().copy_from_slice(row);
+                    serial.push();
+                }
+                let case = format!("{height}x{width} on {threads} threads");
+                assert_eq!(psa.raw(), serial.into_data(), "{case}");
+            }
+        }
+    }
+
+    #[test]
+    fn from_fn_asks_for_each_cell_once_row_by_row() {
+        let mut asked = Vec::new();
+        PrefixSum2D::from_fn(3, 2, |i, j| {
+            asked.push((i, j));
+            0i64
+        })
+        .unwrap();
+        assert_eq!(asked, [(0, 0), (0, 1), (1, 0), (1, 1), (2, 0), (2, 1)]);
+    }
+
+    #[test]
+    fn from_rows_takes_rows_without_a_size() {
+        // filter hides the length, so the sums grow as the rows come
+        let arr = grid(6, 3);
+        let rows = arr
+            .iter()
+            .filter(|_| true)
+            .map(|row| row.iter().copied().filter(|_| true));
+        let psa = PrefixSum2D::from_rows(rows).unwrap();
+        assert_eq!(psa.raw(), PrefixSum2D::new(&arr).unwrap().raw());
+        check_all(&arr, |tl, br| psa.query_sum(tl, br));
+    }
+
+    #[test]
+    fn from_rows_rejects_ragged_rows() {
+        let rows = |lens: &[usize]| {
+            lens.iter()
+                .map(|&len| (0..len as i64).collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        };
+        for (lens, err) in [
+            (&[3, 2, 3][..], "row 1 has length 2, expected width 3"),
+            (&[3, 3, 4], "row 2 has length 4, expected width 3"),
+            (&[2, 0], "row 1 has length 0, expected width 2"),
+            (&[0, 2], "array has width 0"),
+        ] {
+            let rows = rows(lens);
+            let psa = PrefixSum2D::from_rows(rows.iter().map(|row| row.iter().copied()));
+            assert_eq!(psa.err().unwrap(), err, "{lens:?}");
+        }
+        let none = PrefixSum2D::<i64>::from_rows(std::iter::empty::<Vec<i64>>());
+        assert_eq!(none.err().unwrap(), "array has height 0");
+    }
+
+    #[test]
+    fn from_fn_rejects_empty_arrays() {
+        assert_eq!(
+            PrefixSum2D::from_fn(0, 3, |_, _| 0i64).err().unwrap(),
+            "array has height 0"
+        );
+        assert_eq!(
+            PrefixSum2D::from_fn(3, 0, |_, _| 0i64).err().unwrap(),
+            "array has width 0"
+        );
+    }
+
+    #[test]
+    fn strips_match_brute_force_on_random_arrays() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..100 {
+            let (height, width) = (1 + rng.below(12), 1 + rng.below(12));
+            let arr = rng.grid(height, width);
+            let psa = PrefixSum2D::new(&arr).unwrap();
+            let (last_row, last_col) = (height - 1, width - 1);
+            for top in 0..height {
+                for bottom in top..height {
+                    let brute = brute_sum(&arr, (top, 0), (bottom, last_col));
+                    assert_eq!(psa.query_rows(top, bottom), brute, "rows {top} to {bottom}");
+                }
+                let brute = brute_sum(&arr, (top, 0), (top, last_col));
+                assert_eq!(psa.query_row(top), brute, "row {top}");
+            }
+            for left in 0..width {
+                for right in left..width {
+                    let brute = brute_sum(&arr, (0, left), (last_row, right));
+                    assert_eq!(
+                        psa.query_cols(left, right),
+                        brute,
+                        "columns {left} to {right}"
+                    );
+                }
+                let brute = brute_sum(&arr, (0, left), (last_row, left));
+                assert_eq!(psa.query_col(left), brute, "column {left}");
+            }
+        }
+    }
+
+    #[test]
+    fn strips_outside_the_array_are_errors() {
+        let psa = PrefixSum2D::new(&grid(4, 6)).unwrap();
+        assert_eq!(
+            psa.try_query_rows(2, 4),
+            Err(RangeError::OutOfBounds((4, 5)))
+        );
+        assert_eq!(psa.try_query_rows(3, 1), Err(RangeError::Inverted));
+        assert_eq!(
+            psa.try_query_cols(6, 6),
+            Err(RangeError::OutOfBounds((0, 6)))
+        );
+        assert_eq!(psa.try_query_cols(5, 2), Err(RangeError::Inverted));
+        assert_eq!(psa.try_query_rows(0, 3), Ok(psa.query_sum((0, 0), (3, 5))));
+    }
+
+    #[test]
+    #[should_panic(expected = "can't sum rows 4 to 4: corner 4,0 is outside the array")]
+    fn query_row_panics_below_the_array() {
+        PrefixSum2D::new(&grid(4, 6)).unwrap().query_row(4);
+    }
+
+    #[test]
+    #[should_panic(expected = "can't sum columns 3 to 2: top left corner is below or right")]
+    fn query_cols_panics_when_inverted() {
+        PrefixSum2D::new(&grid(4, 6)).unwrap().query_cols(3, 2);
+    }
+
+    /// every rectangle of `arr` against the brute force min and max, with
+    /// the table built as one big table and as tiles of several sizes
+    fn check_min_max(arr: &[Vec<i64>]) {
+        let (height, width) = (arr.len(), arr[0].len());
+        let full = SparseTable2D::from_fn(height, width, |i, j| arr[i][j]).unwrap();
+        let mut tables = vec![("full".to_string(), full)];
+        for tile in [1, 2, 3, 4, 7, 16] {
+            let tiled = SparseTable2D::tiled_from_fn(height, width, tile, |i, j| arr[i][j]);
+            tables.push((format!("tile {tile}"), tiled.unwrap()));
+        }
+        for top in 0..height {
+            for left in 0..width {
+                for bottom in top..height {
+                    for right in left..width {
+                        let cells = arr[top..=bottom].iter().flat_map(|row| &row[left..=right]);
+                        let min = *cells.clone().min().unwrap();
+                        let max = *cells.max().unwrap();
+                        let (tl, br) = ((top, left), (bottom, right));
+                        for (name, table) in &tables {
+                            assert_eq!(
+                                table.query_min_max(tl, br),
+                                (min, max),
+                                "{name} {height}x{width} {tl:?} to {br:?}"
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn min_max_match_brute_force_on_awkward_sizes() {
+        let mut rng = Rng(0xd1b5_4a32_d192_ed03);
+        let sizes = [
+            (1, 1),
+            (1, 2),
+            (2, 1),
+            (1, 13),
+            (17, 1),
+            (2, 3),
+            (5, 7),
+            (11, 13),
+            (16, 16),
+            (17, 19),
+            (31, 3),
+        ];
+        for (height, width) in sizes {
+            check_min_max(&rng.grid(height, width));
+        }
+        for _ in 0..20 {
+            let (height, width) = (1 + rng.below(20), 1 + rng.below(20));
+            check_min_max(&rng.grid(height, width));
+        }
+    }
+
+    #[test]
+    fn min_max_match_brute_force_on_strips_and_prime_sizes() {
+        let mut rng = Rng(0x94d0_49bb_1331_11eb);
+        // a single row or column only ever has 1 x 2^l or 2^k x 1 levels
+        for n in 1..=33 {
+            check_min_max(&rng.grid(1, n));
+            check_min_max(&rng.grid(n, 1));
+        }
+        // no side a power of two or a multiple of a tile, so the corner
+        // rectangles overlap and the last tiles are short
+        for (height, width) in [(3, 5), (5, 3), (7, 11), (13, 2), (11, 13), (17, 7), (19, 5)] {
+            check_min_max(&rng.grid(height, width));
+        }
+    }
+
+    #[test]
+    fn min_max_of_repeated_values() {
+        // few distinct values, so the min and max are often in several cells
+        let mut rng = Rng(0x8ebc_6af0_9c88_c6e3);
+        let arr: Vec<Vec<i64>> = (0..9)
+            .map(|_| (0..14).map(|_| rng.below(3) as i64).collect())
+            .collect();
+        check_min_max(&arr);
+    }
+
+    #[test]
+    fn min_max_outside_the_array_are_errors() {
+        let arr = grid(5, 3);
+        let full = SparseTable2D::from_fn(5, 3, |i, j| arr[i][j]).unwrap();
+        let tiled = SparseTable2D::tiled_from_fn(5, 3, 2, |i, j| arr[i][j]).unwrap();
+        for table in [full, tiled] {
+            assert_eq!((table.height(), table.width()), (5, 3));
+            assert_eq!(
+                table.try_query_min((0, 0), (5, 2)),
+                Err(RangeError::OutOfBounds((5, 2)))
+            );
+            assert_eq!(
+                table.try_query_max((0, 3), (1, 2)),
+                Err(RangeError::OutOfBounds((0, 3)))
+            );
+            assert_eq!(
+                table.try_query_max((2, 1), (1, 2)),
+                Err(RangeError::Inverted)
+            );
+            assert_eq!(table.try_query_min((0, 0), (4, 2)), Ok(-5));
+            assert_eq!(table.try_query_max((0, 0), (4, 2)), Ok(5));
+        }
+    }
+
+    #[test]
+    fn min_max_tables_reject_empty_arrays() {
+        let err = SparseTable2D::from_fn(0, 4, |_, _| 0).err().unwrap();
+        assert_eq!(err, "array has height 0");
+        let err = SparseTable2D::tiled_from_fn(4, 0, 2, |_, _| 0)
+            .err()
+            .unwrap();
+        assert_eq!(err, "array has width 0");
+        let err = SparseTable2D::tiled_from_fn(4, 4, 0, |_, _| 0)
+            .err()
+            .unwrap();
+        assert_eq!(err, "tiles have to be at least 1 cell across");
+    }
+
+    #[test]
+    fn views_sum_like_a_cropped_copy() {
+        let mut rng = Rng(0x6a09_e667_f3bc_c908);
+        for _ in 0..50 {
+            let (height, width) = (1 + rng.below(13), 1 + rng.below(13));
+            let arr = rng.grid(height, width);
+            let psa = PrefixSum2D::new(&arr).unwrap();
+            let (top, left) = (rng.below(height), rng.below(width));
+            let (bottom, right) = (
+                top + rng.below(height - top),
+                left + rng.below(width - left),
+            );
+            let view = psa.view((top, left), (bottom, right)).unwrap();
+            let cropped: Vec<Vec<i64>> = arr[top..=bottom]
+                .iter()
+                .map(|row| row[left..=right].to_vec())
+                .collect();
+            assert_eq!(
+                (view.height(), view.width()),
+                (bottom - top + 1, right - left + 1)
+            );
+            check_all(&cropped, |tl, br| view.query_sum(tl, br));
+            check_all(&cropped, |tl, br| view.try_query_sum(tl, br).unwrap());
+
+            // a view of the view is the same as the view of the array there
+            let (h, w) = (view.height(), view.width());
+            let nested = view.view((h / 2, w / 2), (h - 1, w - 1)).unwrap();
+            let direct = psa
+                .view((top + h / 2, left + w / 2), (bottom, right))
+                .unwrap();
+            assert_eq!(
+                nested.query_sum((0, 0), (nested.height() - 1, nested.width() - 1)),
+                direct.query_sum((0, 0), (direct.height() - 1, direct.width() - 1))
+            );
+        }
+    }
+
+    #[test]
+    fn views_outside_the_array_are_errors() {
+        let psa = PrefixSum2D::new(&grid(4, 6)).unwrap();
+        assert_eq!(
+            psa.view((1, 1), (4, 2)).err(),
+            Some(RangeError::OutOfBounds((4, 2)))
+        );
+        assert_eq!(psa.view((2, 3), (1, 3)).err(), Some(RangeError::Inverted));
+        let view = psa.view((1, 2), (3, 4)).unwrap();
+        // inside the array but outside the window
+        assert_eq!(
+            view.try_query_sum((0, 0), (2, 3)),
+            Err(RangeError::OutOfBounds((2, 3)))
+        );
+        assert_eq!(
+            view.view((0, 0), (3, 0)).err(),
+            Some(RangeError::OutOfBounds((3, 0)))
+        );
+    }
+
+    #[test]
+    fn new_rejects_ragged_rows() {
+        let mut short = grid(4, 5);
+        short[2].pop();
+        let mut long = grid(4, 5);
+        long[3].push(1);
+        let mut empty = grid(4, 5);
+        empty[1].clear();
+        for (arr, err) in [
+            (short, "row 2 has length 4, expected width 5"),
+            (long, "row 3 has length 6, expected width 5"),
+            (empty, "row 1 has length 0, expected width 5"),
+        ] {
+            assert_eq!(PrefixSum2D::new(&arr).err().unwrap(), err);
+        }
+    }
+
+    #[test]
+    fn new_rejects_empty_arrays() {
+        assert_eq!(
+            PrefixSum2D::<i64>::new(&[]).err().unwrap(),
+            "array has height 0"
+        );
+        assert_eq!(
+            PrefixSum2D::<i64>::new(&[vec![]]).err().unwrap(),
+            "array has width 0"
+        );
+    }
+
+    #[test]
+    fn fenwick_updates_match_brute_force_and_rebuilt_prefix_sums() {
+        let mut rng = Rng(0xa54f_f53a_5f1d_36f1);
+        let sizes = [
+            (1, 1),
+            (1, 9),
+            (1, 16),
+            (9, 1),
+            (16, 1),
+            (5, 8),
+            (13, 7),
+            (16, 16),
+        ];
+        for (height, width) in sizes {
+            let mut arr = rng.grid(height, width);
+            let mut fenwick = Fenwick2D::from_row_fn(height, width, |i, row: &mut [i64]| {
+                row.copy_from_slice(&arr[i])
+            })
+            .unwrap();
+            assert_eq!((fenwick.height(), fenwick.width()), (height, width));
+            check_all(&arr, |tl, br| fenwick.query_sum(tl, br));
+            for round in 0..20 {
+                for _ in 0..1 + rng.below(height * width) {
+                    let (i, j) = (rng.below(height), rng.below(width));
+                    let new = rng.below(2001) as i64 - 1000;
+                    fenwick.update((i, j), arr[i][j], new);
+                    arr[i][j] = new;
+                }
+                let rebuilt = PrefixSum2D::new(&arr).unwrap();
+                let context = format!("{height}x{width} round {round}");
+                for top in 0..height {
+                    for left in 0..width {
+                        for bottom in top..height {
+                            for right in left..width {
+                                let (tl, br) = ((top, left), (bottom, right));
+                                let sum = fenwick.query_sum(tl, br);
+                                assert_eq!(sum, brute_sum(&arr, tl, br), "{context} {tl:?} {br:?}");
+                                assert_eq!(
+                                    sum,
+                                    rebuilt.query_sum(tl, br),
+                                    "{context} {tl:?} {br:?}"
+                                );
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fenwick_updates_of_unsigned_sums_dont_wrap() {
+        let mut fenwick = Fenwick2D::from_row_fn(3, 4, |_, row: &mut [u64]| row.fill(1)).unwrap();
+        fenwick.update((1, 2), 1, 0);
+        fenwick.update((0, 0), 1, 9);
+        assert_eq!(fenwick.query_sum((1, 2), (1, 2)), 0);
+        assert_eq!(fenwick.query_sum((0, 0), (2, 3)), 19);
+        assert_eq!(fenwick.query_sum((1, 1), (2, 3)), 5);
+    }
 
-        a + b - c - d
+    #[test]
+    fn fenwick_queries_outside_the_array_are_errors() {
+        let fenwick = Fenwick2D::from_row_fn(3, 4, |_, row: &mut [i64]| row.fill(1)).unwrap();
+        assert_eq!(fenwick.try_query_sum((0, 0), (2, 3)), Ok(12));
+        assert!(fenwick.try_query_sum((0, 0), (3, 3)).is_err());
+        assert!(fenwick.try_query_sum((0, 0), (2, 4)).is_err());
+        assert!(fenwick.try_query_sum((2, 1), (1, 3)).is_err());
+        assert_eq!(
+            Fenwick2D::<i64>::from_row_fn(0, 4, |_, _| ())
+                .err()
+                .unwrap(),
+            "array has height 0"
+        );
     }
 }
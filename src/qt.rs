@@ -0,0 +1,560 @@
+use std::{fmt::Display, io::Write};
+
+use crate::image::MAX_INPUT_PIXELS;
+
+// a .qt file is a refined tree without the image it came from, integers are
+// little endian:
+//
+//   MAGIC, VERSION (u16), width, height (u32 each), flags (1 byte)
+//   number of splits (u32), every split turns one leaf into four, into two
+//   with BINARY_SPLITS or into nine with GRID_SPLITS
+//   crc32 of every byte before it and of the payload
+//
+// then the payload:
+//
+//   one bit per node in preorder, 1 if it was split, msb first and padded
+//   with ADAPTIVE_SPLITS, one byte per split node in preorder saying where
+//   it was split
+//   rgb of every leaf in preorder, 3 bytes each
+//
+// or with ENTROPY_CODED, one range coded stream (see entropy.rs) of the root
+// color and then, for every node in preorder, whether and where it was split
+// and the colors of its children. the color of every node is kept, and each
+// child's is coded as its difference from its parent's
+//
+// with RESIDUAL the payload starts with the lengths of the tree and of the
+// residual (u32 each), and the residual follows the tree as a png, see
+// residual.rs
+//
+// with WRITER the payload starts, before anything else, with the version of
+// comprs that wrote the file as `comprs -V` prints it, one byte for its
+// length and then that many bytes of utf-8
+//
+// children are visited nw, ne, sw, se, top, bottom or left, right, or row by
+// row in a grid, and cover the same regions `Tree` gives them, so the bounds
+// of every node follow from the dimensions and where each node was split. the
+// version only changes when older readers couldn't make sense of a file, new
+// optional parts get a flag instead
+
+pub const MAGIC: [u8; 4] = *b"CPQT";
+pub const VERSION: u16 = 3;
+
+/// nodes weren't all split through the middle
+pub const ADAPTIVE_SPLITS: u8 = 1;
+/// nodes were split in two instead of four
+pub const BINARY_SPLITS: u8 = 2;
+/// the payload is range coded instead of packed
+pub const ENTROPY_CODED: u8 = 4;
+/// the residual of a full render is stored after the tree
+pub const RESIDUAL: u8 = 8;
+/// nodes were split in nine through the thirds, never with ADAPTIVE_SPLITS
+/// or BINARY_SPLITS
+pub const GRID_SPLITS: u8 = 16;
+/// the version of comprs that wrote the file is stored
+pub const WRITER: u8 = 32;
+/// every flag this version understands
+const KNOWN_FLAGS: u8 =
+    ADAPTIVE_SPLITS | BINARY_SPLITS | ENTROPY_CODED | RESIDUAL | GRID_SPLITS | WRITER;
+
+/// bytes from the start of the file to the end of the crc
+const HEADER_LEN: usize = 4 + 2 + 4 + 4 + 1 + 4 + 4;
+
+/// why a tree file can't be read
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    Unreadable,
+    /// doesn't start with MAGIC
+    NotATreeFile,
+    /// ends before its header or its payload does
+    Truncated,
+    UnsupportedVersion(u16),
+    /// flags this version doesn't know, the file needs a newer comprs
+    UnknownFlags(u8),
+    /// the crc in the header doesn't match the rest of the file
+    ChecksumMismatch {
+        expected: u32,
+        found: u32,
+    },
+    /// the header says the image is `width` x `height` and one of them is 0
+    EmptyImage {
+        width: u32,
+        height: u32,
+    },
+    /// the header says the image is larger than any comprs can compress, so
+    /// it can't be rendered either
+    TooLarge {
+        width: u32,
+        height: u32,
+    },
+    /// bytes left over after the payload
+    TrailingData,
+    /// the payload doesn't describe a tree that fits the header
+    Corrupt(String),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unreadable => write!(f, "unable to read tree file"),
+            Self::NotATreeFile => write!(f, "not a comprs tree file"),
+            Self::Truncated => write!(f, "tree file is truncated"),
+            Self::UnsupportedVersion(v) if *v > VERSION => write!(
+                f,
+                "tree file is version {v}, which needs a newer comprs. this one only reads version {VERSION}"
+            ),
+            Self::UnsupportedVersion(v) => write!(
+                f,
+                "tree file is version {v}, which was made by an older comprs. this one only reads version {VERSION}"
+            ),
+            Self::UnknownFlags(flags) => write!(
+                f,
+                "tree file uses features this version of comprs doesn't know (flags {flags:#04x})"
+            ),
+            Self::ChecksumMismatch { expected, found } => write!(
+                f,
+                "tree file is corrupt, its checksum is {found:08x} but its header says {expected:08x}"
+            ),
+            Self::EmptyImage { width, height } => {
+                write!(f, "tree file is corrupt, the image is {width}x{height}")
+            }
+            Self::TooLarge { width, height } => write!(
+                f,
+                "tree file is corrupt, {width}x{height} is larger than any image comprs can compress"
+            ),
+            Self::TrailingData => write!(f, "tree file is corrupt, there is data after the tree"),
+            Self::Corrupt(reason) => write!(f, "tree file is corrupt, {reason}"),
+        }
+    }
+}
+
+/// packs bits msb first, the last byte is padded with zeros
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl BitWriter {
+    pub fn with_capacity(bits: usize) -> Self {
+        Self {
+            bytes: Vec::with_capacity(bits.div_ceil(8)),
+            len: 0,
+        }
+    }
+
+    pub fn push(&mut self, bit: bool) {
+        if self.len.is_multiple_of(8) {
+            self.bytes.push(0);
+        }
+        if bit {
+            *self.bytes.last_mut().unwrap() |= 0x80 >> (self.len % 8);
+        }
+        self.len += 1;
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// the start of the payload, before the tree: the version of comprs if
+/// `header` has WRITER and the lengths of the tree and `residual` if it has
+/// RESIDUAL
+fn payload_prefix(header: &Header, tree_len: usize, residual_len: usize) -> Vec<u8> {
+    let mut ret = Vec::new();
+    if header.flags & WRITER != 0 {
+        let version = crate::version();
+        // far longer than any version, but the length has to fit in a byte
+        let version = &version.as_bytes()[..version.len().min(u8::MAX as usize)];
+        ret.push(version.len() as u8);
+        ret.extend(version);
+    }
+    if header.flags & RESIDUAL != 0 {
+        ret.extend((tree_len as u32).to_le_bytes());
+        ret.extend((residual_len as u32).to_le_bytes());
+    }
+    ret
+}
+
+/// bytes in the file `write` makes of `header`, a tree of `tree_len` bytes
+/// and a residual of `residual_len`
+pub fn file_len(header: &Header, tree_len: usize, residual_len: usize) -> u64 {
+    (HEADER_LEN + payload_prefix(header, tree_len, residual_len).len()) as u64
+        + tree_len as u64
+        + residual_len as u64
+}
+
+/// write a file with `header` followed by `tree`, this version of comprs if
+/// the header has WRITER and `residual` if it has RESIDUAL
+pub fn write(
+    w: &mut impl Write,
+    header: &Header,
+    tree: &[u8],
+    residual: &[u8],
+) -> std::io::Result<()> {
+    let prefix = payload_prefix(header, tree.len(), residual.len());
+    let payload = [&prefix, tree, residual];
+    let mut fields = Vec::with_capacity(HEADER_LEN);
+    fields.extend(MAGIC);
+    fields.extend(VERSION.to_le_bytes());
+    fields.extend(header.width.to_le_bytes());
+    fields.extend(header.height.to_le_bytes());
+    fields.push(header.flags);
+    fields.extend(header.splits.to_le_bytes());
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(&fields);
+    for part in payload {
+        crc.update(part);
+    }
+    fields.extend(crc.finalize().to_le_bytes());
+    w.write_all(&fields)?;
+    payload.iter().try_for_each(|part| w.write_all(part))
+}
+
+/// reads bits msb first
+pub struct BitReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, pos: 0 }
+    }
+}
+
+impl Iterator for BitReader<'_> {
+    type Item = bool;
+
+    fn next(&mut self) -> Option<bool> {
+        let byte = self.bytes.get(self.pos / 8)?;
+        let bit = byte & (0x80 >> (self.pos % 8)) != 0;
+        self.pos += 1;
+        Some(bit)
+    }
+}
+
+pub struct Header {
+    pub flags: u8,
+    pub width: u32,
+    pub height: u32,
+    pub splits: u32,
+}
+
+impl Header {
+    /// children made by every split
+    fn arity(&self) -> u64 {
+        if self.flags & GRID_SPLITS != 0 {
+            9
+        } else if self.flags & BINARY_SPLITS != 0 {
+            2
+        } else {
+            4
+        }
+    }
+
+    /// (split structure, split codes, leaf colors) bytes in a packed payload
+    pub fn payload_parts(&self) -> (u64, u64, u64) {
+        let splits = self.splits as u64;
+        let arity = self.arity();
+        let codes = if self.flags & ADAPTIVE_SPLITS != 0 {
+            splits
+        } else {
+            0
+        };
+        let colors = 3 * (1 + (arity - 1) * splits);
+        ((1 + arity * splits).div_ceil(8), codes, colors)
+    }
+
+    /// bytes in a packed payload, without anything `write` puts before it
+    pub fn packed_len(&self) -> u64 {
+        let (structure, codes, colors) = self.payload_parts();
+        structure + codes + colors
+    }
+}
+
+/// the parts of a .qt file
+pub struct Parts<'a> {
+    pub header: Header,
+    /// the version of comprs that wrote it, if it says
+    pub writer: Option<&'a str>,
+    pub tree: &'a [u8],
+    pub residual: Option<&'a [u8]>,
+}
+
+/// parse and check the header at the start of `data` and split up the
+/// payload after it, which matches the crc. packed trees are also as long as
+/// the header says, the length of a range coded one is only known once it's
+/// decoded
+pub fn read(data: &[u8]) -> Result<Parts<'_>, Error> {
+    if data.len() < MAGIC.len() || data[..MAGIC.len()] != MAGIC {
+        return Err(Error::NotATreeFile);
+    }
+    // checked before anything else, other versions can lay out the rest of
+    // the header differently
+    let Some(version) = data.get(4..6) else {
+        return Err(Error::Truncated);
+    };
+    let version = u16::from_le_bytes(version.try_into().unwrap());
+    if version != VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+    if data.len() < HEADER_LEN {
+        return Err(Error::Truncated);
+    }
+    let (fields, payload) = data.split_at(HEADER_LEN - 4);
+    let (expected, payload) = payload.split_at(4);
+    let u32_at = |i: usize| u32::from_le_bytes(fields[i..i + 4].try_into().unwrap());
+    let header = Header {
+        width: u32_at(6),
+        height: u32_at(10),
+        flags: fields[14],
+        splits: u32_at(15),
+    };
+    // unknown flags can change the size of the payload, and a newer file
+    // should say so rather than look truncated
+    if header.flags & !KNOWN_FLAGS != 0 {
+        return Err(Error::UnknownFlags(header.flags));
+    }
+    if header.flags & GRID_SPLITS != 0 && header.flags & (ADAPTIVE_SPLITS | BINARY_SPLITS) != 0 {
+        return Err(Error::Corrupt(
+            "its flags say nodes were split in a grid and some other way".into(),
+        ));
+    }
+    let (width, height) = (header.width, header.height);
+    if width == 0 || height == 0 {
+        return Err(Error::EmptyImage { width, height });
+    }
+    if width as u64 * height as u64 > MAX_INPUT_PIXELS {
+        return Err(Error::TooLarge { width, height });
+    }
+    let (writer, body) = if header.flags & WRITER != 0 {
+        let Some((&len, rest)) = payload.split_first() else {
+            return Err(Error::Truncated);
+        };
+        let Some(version) = rest.get(..len as usize) else {
+            return Err(Error::Truncated);
+        };
+        let Ok(version) = std::str::from_utf8(version) else {
+            return Err(Error::Corrupt(
+                "the version of comprs that wrote it isn't text".into(),
+            ));
+        };
+        (Some(version), &rest[len as usize..])
+    } else {
+        (None, payload)
+    };
+    let (tree, residual) = if header.flags & RESIDUAL != 0 {
+        let Some(lengths) = body.get(..8) else {
+            return Err(Error::Truncated);
+        };
+        let len_at = |i: usize| u32::from_le_bytes(lengths[i..i + 4].try_into().unwrap()) as u64;
+        let (tree_len, residual_len) = (len_at(0), len_at(4));
+        let rest = &body[8..];
+        if (rest.len() as u64) < tree_len + residual_len {
+            return Err(Error::Truncated);
+        }
+        if rest.len() as u64 > tree_len + residual_len {
+            return Err(Error::TrailingData);
+        }
+        let (tree, residual) = rest.split_at(tree_len as usize);
+        (tree, Some(residual))
+    } else {
+        (body, None)
+    };
+    if header.flags & ENTROPY_CODED == 0 {
+        let len = header.packed_len();
+        if (tree.len() as u64) < len {
+            return Err(Error::Truncated);
+        }
+        if tree.len() as u64 > len {
+            return Err(Error::TrailingData);
+        }
+    }
+
+    let expected = u32::from_le_bytes(expected.try_into().unwrap());
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(fields);
+    crc.update(payload);
+    let found = crc.finalize();
+    if found != expected {
+        return Err(Error::ChecksumMismatch { expected, found });
+    }
+    Ok(Parts {
+        header,
+        writer,
+        tree,
+        residual,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+    use crate::{
+        image::ImageData,
+        tree::{Split, Tree},
+    };
+
+    /// a file with `header` and a packed tree that never splits
+    fn unsplit(width: u32, height: u32) -> Vec<u8> {
+        let header = Header {
+            flags: 0,
+            width,
+            height,
+            splits: 0,
+        };
+        let mut ret = Vec::new();
+        write(&mut ret, &header, &[0, 10, 20, 30], &[]).unwrap();
+        ret
+    }
+
+    /// `data` with its crc worked out again, so changes to it get past the
+    /// check
+    fn with_crc(mut data: Vec<u8>) -> Vec<u8> {
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(&data[..HEADER_LEN - 4]);
+        crc.update(&data[HEADER_LEN..]);
+        data[HEADER_LEN - 4..HEADER_LEN].copy_from_slice(&crc.finalize().to_le_bytes());
+        data
+    }
+
+    /// files `serialize` writes of a refined tree, packed, range coded and
+    /// with a residual
+    fn saved_trees() -> Vec<(String, Vec<u8>)> {
+        let colors = RgbImage::from_fn(16, 12, |x, y| {
+            let edge = if x * 3 > 16 + y { 200 } else { 20 };
+            Rgb([edge, (x * 11 + y * 5) as u8, ((x ^ y) * 7) as u8])
+        });
+        let mut ret = Vec::new();
+        for split in [Split::Midpoint, Split::KdAdaptive, Split::Grid9] {
+            let data = ImageData::from_rgb8(&colors).unwrap();
+            let mut tree = Tree::with_capacity_hint(data, 24).with_split(split);
+            for _ in 0..24 {
+                tree.refine().unwrap();
+            }
+            for (kind, entropy_coded, original) in [
+                ("packed", false, None),
+                ("range coded", true, None),
+                ("residual", false, Some(&colors)),
+            ] {
+                let mut saved = Vec::new();
+                tree.serialize(&mut saved, entropy_coded, original).unwrap();
+                ret.push((format!("{} {kind}", split.name()), saved));
+            }
+        }
+        ret
+    }
+
+    #[test]
+    fn headers_are_checked_before_the_payload() {
+        let data = unsplit(3, 2);
+        assert!(read(&data).is_ok());
+
+        for len in 0..4 {
+            assert_eq!(
+                read(&data[..len]).err(),
+                Some(Error::NotATreeFile),
+                "{len} bytes"
+            );
+        }
+        for magic in [b"CPQX", b"\x89PNG", b"cpqt"] {
+            let mut bad = data.clone();
+            bad[..4].copy_from_slice(magic);
+            assert_eq!(read(&bad).err(), Some(Error::NotATreeFile), "{magic:?}");
+        }
+        for version in [0, 2, 4, u16::MAX] {
+            let mut bad = data.clone();
+            bad[4..6].copy_from_slice(&version.to_le_bytes());
+            assert_eq!(
+                read(&bad).err(),
+                Some(Error::UnsupportedVersion(version)),
+                "version {version}"
+            );
+        }
+        let older = Error::UnsupportedVersion(2).to_string();
+        let newer = Error::UnsupportedVersion(4).to_string();
+        assert!(older.contains("older comprs"), "{older}");
+        assert!(newer.contains("newer comprs"), "{newer}");
+
+        for (width, height) in [(0, 2), (3, 0), (0, 0)] {
+            assert_eq!(
+                read(&unsplit(width, height)).err(),
+                Some(Error::EmptyImage { width, height }),
+                "{width}x{height}"
+            );
+        }
+
+        let mut bad = data.clone();
+        bad[14] |= 0x80;
+        assert_eq!(read(&with_crc(bad)).err(), Some(Error::UnknownFlags(0x80)));
+
+        let mut bad = data.clone();
+        let last = bad.len() - 1;
+        bad[last] ^= 1;
+        let expected = u32::from_le_bytes(data[HEADER_LEN - 4..HEADER_LEN].try_into().unwrap());
+        assert!(matches!(
+            read(&bad).err(),
+            Some(Error::ChecksumMismatch { expected: e, found }) if e == expected && found != expected
+        ));
+
+        assert_eq!(read(&data[..HEADER_LEN - 1]).err(), Some(Error::Truncated));
+        assert_eq!(read(&data[..data.len() - 1]).err(), Some(Error::Truncated));
+        let mut longer = data.clone();
+        longer.push(0);
+        assert_eq!(read(&with_crc(longer)).err(), Some(Error::TrailingData));
+    }
+
+    #[test]
+    fn damaged_files_are_errors_not_panics() {
+        for (context, saved) in saved_trees() {
+            assert!(Tree::deserialize(saved.as_slice()).is_ok(), "{context}");
+            for len in 0..saved.len() {
+                assert!(
+                    Tree::deserialize(&saved[..len]).is_err(),
+                    "{context} cut to {len} bytes"
+                );
+            }
+            for bit in 0..saved.len() * 8 {
+                let mut bad = saved.clone();
+                bad[bit / 8] ^= 0x80 >> (bit % 8);
+                assert!(
+                    Tree::deserialize(bad.as_slice()).is_err(),
+                    "{context} with bit {bit} flipped"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn damaged_payloads_with_matching_crcs_never_panic() {
+        // a flipped color can still be a valid tree, but anything that
+        // loads has to render
+        for (context, saved) in saved_trees() {
+            for bit in HEADER_LEN * 8..saved.len() * 8 {
+                let mut bad = saved.clone();
+                bad[bit / 8] ^= 0x80 >> (bit % 8);
+                if let Ok(loaded) = Tree::deserialize(with_crc(bad).as_slice()) {
+                    let render = loaded.tree.render_rgb(None, 1.0);
+                    assert_eq!(render.dimensions(), (16, 12), "{context} bit {bit}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn headers_larger_than_any_input_are_rejected() {
+        for (width, height) in [(60000, 60000), (u32::MAX, 1), (32768, 16385)] {
+            let data = unsplit(width, height);
+            assert_eq!(
+                read(&data).err(),
+                Some(Error::TooLarge { width, height }),
+                "{width}x{height}"
+            );
+        }
+        let data = unsplit(32768, 16384);
+        let parts = read(&data).unwrap();
+        assert_eq!((parts.header.width, parts.header.height), (32768, 16384));
+    }
+}
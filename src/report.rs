@@ -0,0 +1,158 @@
+use std::{fs, time::Duration};
+
+use ::image::ImageFormat;
+
+use crate::{cli, error::Error, image::probe, json::Json};
+
+// -report writes one json file saying what a run did, for ci to keep with
+// its outputs. at the top:
+//
+//   comprs: the version that wrote it
+//   options: every flag as it was resolved from the config file, the
+//     environment and the command line, true for flags without a value and
+//     an array for flags given more than once
+//
+// then the fields of the entry of the input, or with several inputs
+//
+//   files: the entry of every input in the order they were found
+//   summary: how many were processed, skipped and failed, and the seconds
+//     it took
+//
+// an entry has
+//
+//   input: path, and width, height, format, color and bytes when it can be
+//     read
+//   error: why it failed, null if it didn't
+//   stopped: reason (iterations, exhausted, interrupted or target), done and the
+//     iterations asked for
+//   stats: what -stats prints
+//   suggestion: with -suggest-iter, the iterations at the knee, their mse
+//     and psnr, and whether the outputs were taken back to them
+//   frames: with animations, every frame captured from the tree, in the
+//     order it was, as its output, its index there, the iteration, leaves,
+//     mse and psnr. the original at either end and tweens aren't captured,
+//     and -gif-direction can play them in another order
+//   timings: phases, with the milliseconds and calls of each, and counts
+//   outputs: every file and directory written
+//
+// an input that failed only has what its run got to
+
+/// what one input has for its entry, filled in as its run goes. a disabled
+/// report still runs everything it is given, it just keeps nothing
+pub struct Report {
+    enabled: bool,
+    fields: Vec<(&'static str, Json)>,
+}
+
+impl Report {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            fields: Vec::new(),
+        }
+    }
+
+    /// set `key` of the entry to what `value` gives, which is only worked
+    /// out when the report is enabled
+    pub fn set(&mut self, key: &'static str, value: impl FnOnce() -> Json) {
+        if !self.enabled {
+            return;
+        }
+        let value = value();
+        match self.fields.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, v)) => *v = value,
+            None => self.fields.push((key, value)),
+        }
+    }
+
+    /// the entry of `input_file`, whose run failed with `error` if it did
+    pub fn entry(self, input_file: &str, error: Option<&Error>) -> Json {
+        let mut input = vec![("path", Json::String(input_file.into()))];
+        if let Ok((w, h, color)) = probe(input_file) {
+            input.push(("width", Json::Number(w as u64)));
+            input.push(("height", Json::Number(h as u64)));
+            if let Ok(format) = ImageFormat::from_path(input_file) {
+                let format = format!("{format:?}").to_lowercase();
+                input.push(("format", Json::String(format)));
+            }
+            input.push(("color", Json::String(color)));
+        }
+        if let Ok(meta) = fs::metadata(input_file) {
+            input.push(("bytes", Json::Number(meta.len())));
+        }
+        let error = match error {
+            Some(err) => Json::String(err.to_string()),
+            None => Json::Null,
+        };
+        let mut fields = vec![("input", Json::object(input)), ("error", error)];
+        fields.extend(self.fields);
+        Json::object(fields)
+    }
+}
+
+/// the flags in `args`, after the config file and the environment were
+/// merged into them, as the options of a report. input files are left out
+pub fn options(args: &[String]) -> Json {
+    let mut ret: Vec<(String, Json)> = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let Some(flag) = cli::flags().find(|f| f.name == arg || f.long == arg) else {
+            continue;
+        };
+        let value = if flag.value.is_empty() {
+            Json::Bool(true)
+        } else {
+            match args.next() {
+                Some(value) => Json::String(value.clone()),
+                None => continue,
+            }
+        };
+        match ret.iter_mut().find(|(name, _)| name == flag.name) {
+            Some((_, Json::Array(values))) => values.push(value),
+            Some((_, first)) => *first = Json::Array(vec![first.clone(), value]),
+            None => ret.push((flag.name.into(), value)),
+        }
+    }
+    Json::Object(ret)
+}
+
+/// the report of a run of a single input
+pub fn single(options: &Json, entry: Json) -> Json {
+    let mut fields = header(options);
+    if let Json::Object(entry) = entry {
+        fields.extend(entry);
+    }
+    Json::Object(fields)
+}
+
+/// the report of a run of several inputs, `skipped` files found in
+/// directories weren't images
+pub fn batch(options: &Json, entries: Vec<Json>, skipped: usize, elapsed: Duration) -> Json {
+    let failed = entries
+        .iter()
+        .filter(|e| e.get("error").is_some_and(|err| *err != Json::Null))
+        .count();
+    let summary = Json::object([
+        ("processed", Json::Number((entries.len() - failed) as u64)),
+        ("skipped", Json::Number(skipped as u64)),
+        ("failed", Json::Number(failed as u64)),
+        ("seconds", Json::Fraction(elapsed.as_secs_f64())),
+    ]);
+    let mut fields = header(options);
+    fields.push(("files".into(), Json::Array(entries)));
+    fields.push(("summary".into(), summary));
+    Json::Object(fields)
+}
+
+fn header(options: &Json) -> Vec<(String, Json)> {
+    vec![
+        ("comprs".into(), Json::String(comprs::version())),
+        ("options".into(), options.clone()),
+    ]
+}
+
+/// write `report` to `path`
+pub fn write(path: &str, report: &Json) -> Result<(), Error> {
+    fs::write(path, report.pretty())
+        .map_err(|err| Error::Output(format!("unable to write report `{path}`: {err}")))
+}
@@ -0,0 +1,51 @@
+use image::{
+    codecs::png::{CompressionType, FilterType, PngEncoder},
+    ExtendedColorType, ImageEncoder, ImageFormat, RgbImage,
+};
+
+use crate::qt;
+
+// the residual of a render is what has to be added to it to get the original
+// back. every channel is stored as the difference plus 128, wrapping around,
+// so a residual of a good render is mostly flat gray that png compresses well
+// and adding it back always gives the exact original
+
+/// the residual of `render` against `original` as a png
+pub fn encode(original: &RgbImage, render: &RgbImage) -> Result<Vec<u8>, String> {
+    let mut residual = render.clone();
+    for (r, o) in residual.iter_mut().zip(original.iter()) {
+        *r = o.wrapping_sub(*r).wrapping_add(128);
+    }
+    let mut ret = Vec::new();
+    PngEncoder::new_with_quality(&mut ret, CompressionType::Best, FilterType::Adaptive)
+        .write_image(
+            residual.as_raw(),
+            residual.width(),
+            residual.height(),
+            ExtendedColorType::Rgb8,
+        )
+        .map_err(|_| "error in encoding residual".to_string())?;
+    Ok(ret)
+}
+
+/// the residual in `png`, which has to be `width` x `height`
+pub fn decode(png: &[u8], width: u32, height: u32) -> Result<RgbImage, qt::Error> {
+    let Ok(img) = image::load_from_memory_with_format(png, ImageFormat::Png) else {
+        return Err(qt::Error::Corrupt("its residual isn't a valid png".into()));
+    };
+    if img.width() != width || img.height() != height {
+        return Err(qt::Error::Corrupt(format!(
+            "its residual is {}x{} but its image is {width}x{height}",
+            img.width(),
+            img.height()
+        )));
+    }
+    Ok(img.into_rgb8())
+}
+
+/// add `residual` to a full size render of the tree it was made for
+pub fn apply(render: &mut RgbImage, residual: &RgbImage) {
+    for (r, d) in render.iter_mut().zip(residual.iter()) {
+        *r = r.wrapping_add(d.wrapping_sub(128));
+    }
+}
@@ -0,0 +1,131 @@
+use std::io::{stderr, BufRead, IsTerminal, Write};
+
+use crate::{
+    check_overwrites, describe_stats, format_psnr, hex_to_rgb,
+    image::RGB,
+    log::error,
+    parse_pixel,
+    progress::Progress,
+    still_output,
+    tree::{Refined, Tree},
+};
+
+// -interactive keeps the refined tree and its prefix sums around and takes
+// commands from stdin, so trying more iterations or another outline doesn't
+// decode the image and build the prefix sums again
+
+const HELP: &str = "commands:
+  refine n        split the tree n more times
+  outline hex     outline regions in this color when saving, e.g. outline #333333
+  outline none    stop outlining
+  paint x,y hex   change a pixel of the image, refining goes on from there, e.g. paint 10,20 #ff0000
+  save file       save the image as it is now
+  stats           print the numbers of -stats for the tree as it is now
+  help            print this
+  quit            stop, then write the outputs given on the command line";
+
+/// run the commands in `input` against `tree` until `quit` or the end of
+/// the input. a command that fails prints why and the session goes on
+pub fn run(
+    tree: &mut Tree,
+    input_file: &str,
+    mut outline: Option<RGB<u8>>,
+    input: impl BufRead,
+) -> Result<(), String> {
+    let prompt = || {
+        if std::io::stdin().is_terminal() {
+            eprint!("> ");
+            let _ = stderr().flush();
+        }
+    };
+    prompt();
+    for line in input.lines() {
+        let Ok(line) = line else {
+            return Err("unable to read command".into());
+        };
+        let mut words = line.split_whitespace();
+        let result = match (words.next(), words.next(), words.next()) {
+            (None, _, _) => Ok(()),
+            (Some("quit" | "exit"), None, _) => return Ok(()),
+            (Some("refine"), Some(n), None) => match n.parse() {
+                Ok(n) => {
+                    refine(tree, n);
+                    Ok(())
+                }
+                Err(_) => Err("invalid number of iterations".into()),
+            },
+            (Some("outline"), Some("none"), None) => {
+                outline = None;
+                Ok(())
+            }
+            (Some("outline"), Some(hex), None) => hex_to_rgb(hex).map(|rgb| outline = Some(rgb)),
+            (Some("paint"), Some(at), Some(hex)) if words.next().is_none() => paint(tree, at, hex),
+            (Some("save"), Some(path), None) => save(tree, input_file, outline, path),
+            (Some("stats"), None, _) => {
+                print!("{}", describe_stats(&tree.stats(), None));
+                println!("squared error   {:>12}", tree.stats().error);
+                if let Some(mse) = tree.mse() {
+                    println!("mse             {mse:>12.3}");
+                    println!("psnr (dB)       {:>12}", format_psnr(mse));
+                }
+                Ok(())
+            }
+            (Some("style"), _, _) => {
+                Err("comprs only renders regions as flat rectangles, there are no styles".into())
+            }
+            _ => {
+                println!("{HELP}");
+                Ok(())
+            }
+        };
+        if let Err(err) = result {
+            error!("{err}");
+        }
+        prompt();
+    }
+    Ok(())
+}
+
+/// split `tree` up to `n` more times with the progress line
+fn refine(tree: &mut Tree, n: u32) {
+    let mut progress = Progress::new(n, false);
+    for i in 1..=n {
+        match tree.refine() {
+            Ok(Refined::Split) => progress.refine(i, tree.leaf_count(), None),
+            Ok(Refined::Exhausted) => {
+                progress.finish();
+                println!(
+                    "every region is fully refined, stopped after {} of {n}",
+                    i - 1
+                );
+                break;
+            }
+            Err(err) => {
+                progress.finish();
+                error!("{err}");
+                break;
+            }
+        }
+    }
+    progress.finish();
+    println!("{} regions", tree.leaf_count());
+}
+
+/// change the pixel `at`, given as x,y, of the image the tree is refined from
+/// to `hex`
+fn paint(tree: &mut Tree, at: &str, hex: &str) -> Result<(), String> {
+    let Some((x, y)) = parse_pixel(at) else {
+        return Err(format!("invalid pixel `{at}`, expected x,y"));
+    };
+    tree.paint(x, y, hex_to_rgb(hex)?)
+}
+
+fn save(tree: &Tree, input_file: &str, outline: Option<RGB<u8>>, path: &str) -> Result<(), String> {
+    let path = still_output(path.into())?;
+    // saving over an earlier save is the point, only the input is kept safe
+    check_overwrites(input_file, &[&path], true)?;
+    let img = tree.render_rgb(outline, 1.0);
+    img.save(&path).map_err(|err| err.to_string())?;
+    println!("saved `{path}`");
+    Ok(())
+}
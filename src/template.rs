@@ -0,0 +1,220 @@
+// names of outputs made from the input and the run, for -name-template. the
+// default names and the names of checkpoints are templates too, so every
+// name comprs makes up goes through the same expansion
+
+/// what a template can use, each in braces
+pub const TOKENS: [&str; 7] = ["stem", "ext", "iter", "leaves", "split", "w", "h"];
+
+/// the output of an input when neither -o nor -name-template is given
+pub const DEFAULT: &str = "{stem}-comprs.{ext}";
+
+/// a checkpoint when there is no -name-template, `stem` and `ext` are then
+/// those of the output it is a checkpoint of
+pub const CHECKPOINT: &str = "{stem}-{iter}.{ext}";
+
+#[derive(Debug, Clone)]
+pub struct Template(Vec<Part>);
+
+#[derive(Debug, Clone)]
+enum Part {
+    Text(String),
+    Token(&'static str),
+}
+
+/// what the tokens stand for
+pub struct Values<'a> {
+    /// the file name without its extension
+    pub stem: &'a str,
+    pub ext: &'a str,
+    pub iter: u32,
+    pub leaves: usize,
+    pub split: &'a str,
+    /// only read when the template has {w} or {h}
+    pub size: (u32, u32),
+}
+
+impl Template {
+    /// `template` with every token checked. `{{` and `}}` are literal braces
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut rest = template;
+        while let Some(i) = rest.find(['{', '}']) {
+            text += &rest[..i];
+            let (brace, after) = rest[i..].split_at(1);
+            if after.starts_with(brace) {
+                text += brace;
+                rest = &after[1..];
+                continue;
+            }
+            if brace == "}" {
+                return Err(format!(
+                    "unmatched }} in name template `{template}`, a literal brace is written }}}}"
+                ));
+            }
+            let Some(end) = after.find('}') else {
+                return Err(format!(
+                    "unclosed {{ in name template `{template}`, a literal brace is written {{{{"
+                ));
+            };
+            let name = &after[..end];
+            let Some(&token) = TOKENS.iter().find(|&&t| t == name) else {
+                let valid: Vec<String> = TOKENS.iter().map(|t| format!("{{{t}}}")).collect();
+                return Err(format!(
+                    "unknown token {{{name}}} in name template, the tokens are {}",
+                    valid.join(", ")
+                ));
+            };
+            if !text.is_empty() {
+                parts.push(Part::Text(std::mem::take(&mut text)));
+            }
+            parts.push(Part::Token(token));
+            rest = &after[end + 1..];
+        }
+        text += rest;
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(Self(parts))
+    }
+
+    /// whether `token` appears in the template
+    pub fn uses(&self, token: &str) -> bool {
+        self.0
+            .iter()
+            .any(|p| matches!(p, Part::Token(t) if *t == token))
+    }
+
+    pub fn expand(&self, values: &Values) -> String {
+        let mut ret = String::new();
+        for part in &self.0 {
+            match *part {
+                Part::Text(ref text) => ret += text,
+                Part::Token("stem") => ret += values.stem,
+                Part::Token("ext") => ret += values.ext,
+                Part::Token("iter") => ret += &values.iter.to_string(),
+                Part::Token("leaves") => ret += &values.leaves.to_string(),
+                Part::Token("split") => ret += values.split,
+                Part::Token("w") => ret += &values.size.0.to_string(),
+                Part::Token("h") => ret += &values.size.1.to_string(),
+                Part::Token(token) => unreachable!("{token} is not in TOKENS"),
+            }
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALUES: Values = Values {
+        stem: "photo.v2",
+        ext: "png",
+        iter: 5000,
+        leaves: 15001,
+        split: "kd-adaptive",
+        size: (640, 480),
+    };
+
+    fn expand(template: &str) -> String {
+        Template::parse(template).unwrap().expand(&VALUES)
+    }
+
+    #[test]
+    fn every_token_expands() {
+        for (template, expected) in [
+            ("{stem}", "photo.v2"),
+            ("{ext}", "png"),
+            ("{iter}", "5000"),
+            ("{leaves}", "15001"),
+            ("{split}", "kd-adaptive"),
+            ("{w}", "640"),
+            ("{h}", "480"),
+            (
+                "out/{stem}_{w}x{h}-{split}-{iter}-{leaves}.{ext}",
+                "out/photo.v2_640x480-kd-adaptive-5000-15001.png",
+            ),
+            ("{iter}{iter}", "50005000"),
+            ("plain.gif", "plain.gif"),
+            ("", ""),
+        ] {
+            assert_eq!(expand(template), expected, "{template}");
+        }
+        assert_eq!(expand(DEFAULT), "photo.v2-comprs.png");
+        assert_eq!(expand(CHECKPOINT), "photo.v2-5000.png");
+        // each on its own is found by `uses`
+        for token in TOKENS {
+            assert!(Template::parse(&format!("{{{token}}}"))
+                .unwrap()
+                .uses(token));
+        }
+    }
+
+    #[test]
+    fn uses_finds_only_tokens() {
+        let template = Template::parse("{stem}-w-{{h}}.{ext}").unwrap();
+        assert!(template.uses("stem") && template.uses("ext"));
+        assert!(!template.uses("w") && !template.uses("h") && !template.uses("iter"));
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        for (template, expected) in [
+            ("{{stem}}", "{stem}"),
+            ("{{{stem}}}", "{photo.v2}"),
+            ("a{{b}}c", "a{b}c"),
+            ("{{", "{"),
+            ("}}", "}"),
+            ("}}{{", "}{"),
+            ("{{{{", "{{"),
+        ] {
+            assert_eq!(expand(template), expected, "{template}");
+        }
+    }
+
+    #[test]
+    fn unknown_tokens_and_stray_braces_are_errors() {
+        let tokens = "the tokens are {stem}, {ext}, {iter}, {leaves}, {split}, {w}, {h}";
+        for (template, err) in [
+            (
+                "{name}",
+                format!("unknown token {{name}} in name template, {tokens}"),
+            ),
+            (
+                "{}",
+                format!("unknown token {{}} in name template, {tokens}"),
+            ),
+            (
+                "{Stem}",
+                format!("unknown token {{Stem}} in name template, {tokens}"),
+            ),
+            (
+                "{ stem }",
+                format!("unknown token {{ stem }} in name template, {tokens}"),
+            ),
+            (
+                "{stem{ext}}",
+                format!("unknown token {{stem{{ext}} in name template, {tokens}"),
+            ),
+            (
+                "{stem",
+                "unclosed { in name template `{stem`, a literal brace is written {{".into(),
+            ),
+            (
+                "a{{b{",
+                "unclosed { in name template `a{{b{`, a literal brace is written {{".into(),
+            ),
+            (
+                "stem}",
+                "unmatched } in name template `stem}`, a literal brace is written }}".into(),
+            ),
+            (
+                "{stem}}",
+                "unmatched } in name template `{stem}}`, a literal brace is written }}".into(),
+            ),
+        ] {
+            assert_eq!(Template::parse(template).err().unwrap(), err, "{template}");
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use ::image::{imageops, RgbImage};
+use rayon::prelude::*;
+
+use crate::{
+    image::{ImageData, ImageDataView, RegionSource, RGB},
+    interrupt,
+    timing::PhaseTimer,
+    tree::{Refined, Split, Tree},
+};
+
+/// where each of `k` nearly equal runs of `n` pixels starts, followed by `n`
+fn bounds(n: u32, k: u32) -> Vec<u32> {
+    (0..=k)
+        .map(|i| (i as u64 * n as u64 / k as u64) as u32)
+        .collect()
+}
+
+/// (x, y, width, height) of every tile of a `k` x `k` grid over a `w` x `h`
+/// image, row by row
+fn tile_rects(w: u32, h: u32, k: u32) -> Vec<(u32, u32, u32, u32)> {
+    let (xs, ys) = (bounds(w, k), bounds(h, k));
+    ys.windows(2)
+        .flat_map(|y| {
+            xs.windows(2)
+                .map(move |x| (x[0], y[0], x[1] - x[0], y[1] - y[0]))
+        })
+        .collect()
+}
+
+/// split `iterations` between tiles in proportion to `weights`, the leftover
+/// from rounding down goes to the largest remainders so the parts always sum
+/// to `iterations`. with no weight at all the split is even
+pub fn distribute(iterations: u32, weights: &[u64]) -> Vec<u32> {
+    let total: u128 = weights.iter().map(|&w| w as u128).sum();
+    let shares: Vec<(u32, u128)> = if total == 0 {
+        let n = weights.len() as u128;
+        (0..weights.len())
+            .map(|_| ((iterations as u128 / n) as u32, iterations as u128 % n))
+            .collect()
+    } else {
+        weights
+            .iter()
+            .map(|&w| {
+                let exact = iterations as u128 * w as u128;
+                ((exact / total) as u32, exact % total)
+            })
+            .collect()
+    };
+
+    let mut ret: Vec<u32> = shares.iter().map(|&(q, _)| q).collect();
+    let left = iterations - ret.iter().sum::<u32>();
+    let mut order: Vec<usize> = (0..shares.len()).collect();
+    // stable, so equal remainders go to the earlier tiles
+    order.sort_by_key(|&i| std::cmp::Reverse(shares[i].1));
+    for &i in order.iter().take(left as usize) {
+        ret[i] += 1;
+    }
+    ret
+}
+
+/// compress `source` as a `k` x `k` grid of tiles that are refined on every
+/// core at once, each with its own tree. the budget of `iterations` is shared
+/// out by how much error each tile starts with. regions never cross a tile
+/// edge, so seams can show between tiles, and with `outline` every tile edge
+/// is drawn. returns the stitched image and the number of splits made, which
+/// is less than `iterations` when tiles ran out of regions to split
+pub fn compress_tiles(
+    source: &RgbImage,
+    k: u32,
+    iterations: u32,
+    split: Option<Split>,
+    outline: Option<RGB<u8>>,
+    timer: &mut PhaseTimer,
+) -> Result<(RgbImage, u32), String> {
+    let (w, h) = source.dimensions();
+    if k > w || k > h {
+        return Err(format!(
+            "can't split a {w}x{h} image into {k}x{k} tiles, every tile needs at least one pixel"
+        ));
+    }
+    let rects = tile_rects(w, h, k);
+
+    // the sums of the whole image are built once, every tile is a view of
+    // them. a region of a tile sums the same either way
+    let whole = timer.time("construct", || ImageData::from_rgb8(source))?;
+    let data = rects
+        .iter()
+        .map(|&(x, y, tw, th)| {
+            let (x, y, tw, th) = (x as usize, y as usize, tw as usize, th as usize);
+            whole.view((y, x), (y + th - 1, x + tw - 1))
+        })
+        .collect::<Result<Vec<ImageDataView>, String>>()?;
+    let weights: Vec<u64> = data
+        .iter()
+        .map(|d| d.metric((0, 0), (d.height() - 1, d.width() - 1)))
+        .collect();
+    let budgets = distribute(iterations, &weights);
+    let mut trees: Vec<Tree<ImageDataView>> = data
+        .into_iter()
+        .zip(&budgets)
+        .map(|(d, &budget)| {
+            Tree::with_capacity_hint(d, budget).with_split(split.unwrap_or(Split::Midpoint))
+        })
+        .collect();
+
+    let done = timer.time("refine", || {
+        trees
+            .par_iter_mut()
+            .zip(&budgets)
+            .map(|(tree, &budget)| {
+                let mut done = 0;
+                while done < budget && !interrupt::requested() {
+                    match tree.refine().map_err(|err| err.to_string())? {
+                        Refined::Split => done += 1,
+                        Refined::Exhausted => break,
+                    }
+                }
+                tree.finalize();
+                Ok(done)
+            })
+            .collect::<Result<Vec<u32>, String>>()
+    })?;
+
+    let img = timer.time("render", || {
+        let mut img = RgbImage::new(w, h);
+        let tiles: Vec<RgbImage> = trees
+            .par_iter()
+            .map(|t| t.render_rgb(outline, 1.0))
+            .collect();
+        for (tile, &(x, y, _, _)) in tiles.iter().zip(&rects) {
+            imageops::replace(&mut img, tile, x as i64, y as i64);
+        }
+        img
+    });
+    Ok((img, done.iter().sum()))
+}
+
+#[cfg(test)]
+mod tests {
+    use ::image::Rgb;
+
+    use super::*;
+
+    /// a width x height image with edges and gradients that cross the tiles
+    fn pattern(width: u32, height: u32) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, y| {
+            let edge = if x * 3 > width + y { 200 } else { 20 };
+            Rgb([edge, (x * 11 + y * 5) as u8, ((x ^ y) * 7) as u8])
+        })
+    }
+
+    fn compress(source: &RgbImage, k: u32, iterations: u32, split: Split) -> (RgbImage, u32) {
+        let mut timer = PhaseTimer::new(false);
+        compress_tiles(source, k, iterations, Some(split), None, &mut timer).unwrap()
+    }
+
+    /// `tree` refined up to `budget` times, and how many splits it made
+    fn refine<S: RegionSource>(tree: &mut Tree<S>, budget: u32) -> u32 {
+        let mut splits = 0;
+        while splits < budget && tree.refine().unwrap() == Refined::Split {
+            splits += 1;
+        }
+        splits
+    }
+
+    #[test]
+    fn tiles_cover_every_pixel_once() {
+        for (w, h) in [
+            (1, 1),
+            (7, 7),
+            (16, 16),
+            (17, 9),
+            (100, 3),
+            (3, 100),
+            (64, 61),
+        ] {
+            for k in 1..=w.min(h).min(9) {
+                let rects = tile_rects(w, h, k);
+                assert_eq!(rects.len(), (k * k) as usize);
+                let mut covered = vec![0; (w * h) as usize];
+                for &(x, y, tw, th) in &rects {
+                    // as even as whole pixels allow
+                    assert!([w / k, w.div_ceil(k)].contains(&tw), "{w}x{h} k {k}: {tw}");
+                    assert!([h / k, h.div_ceil(k)].contains(&th), "{w}x{h} k {k}: {th}");
+                    for py in y..y + th {
+                        for px in x..x + tw {
+                            covered[(py * w + px) as usize] += 1;
+                        }
+                    }
+                }
+                assert!(covered.iter().all(|&c| c == 1), "{w}x{h} k {k}");
+            }
+        }
+    }
+
+    #[test]
+    fn one_tile_renders_like_the_whole_image() {
+        let source = pattern(45, 37);
+        for split in [Split::Midpoint, Split::Kd, Split::KdAdaptive, Split::Grid9] {
+            // the last is more than any of them has regions to split
+            for iterations in [0, 1, 60, 1_000_000] {
+                let (img, done) = compress(&source, 1, iterations, split);
+                let data = ImageData::from_rgb8(&source).unwrap();
+                let mut tree = Tree::new(data, iterations.min(5000)).with_split(split);
+                let splits = refine(&mut tree, iterations);
+                let context = format!("{} {iterations}", split.name());
+                assert_eq!(done, splits, "{context}");
+                assert_eq!(img, tree.render_rgb(None, 1.0), "{context}");
+            }
+        }
+    }
+
+    #[test]
+    fn stitched_tiles_render_like_a_tree_of_each_tile() {
+        for (w, h, k) in [(45, 37, 2), (45, 37, 3), (31, 64, 4), (10, 10, 10)] {
+            let source = pattern(w, h);
+            let (img, done) = compress(&source, k, 300, Split::Kd);
+            let rects = tile_rects(w, h, k);
+            let whole = ImageData::from_rgb8(&source).unwrap();
+            let weights: Vec<u64> = rects
+                .iter()
+                .map(|&(x, y, tw, th)| {
+                    let (x, y) = (x as usize, y as usize);
+                    whole.metric((y, x), (y + th as usize - 1, x + tw as usize - 1))
+                })
+                .collect();
+            let mut expected = RgbImage::new(w, h);
+            let mut splits = 0;
+            for (&(x, y, tw, th), budget) in rects.iter().zip(distribute(300, &weights)) {
+                let crop = imageops::crop_imm(&source, x, y, tw, th).to_image();
+                let data = ImageData::from_rgb8(&crop).unwrap();
+                let mut tree = Tree::new(data, budget).with_split(Split::Kd);
+                splits += refine(&mut tree, budget);
+                imageops::replace(
+                    &mut expected,
+                    &tree.render_rgb(None, 1.0),
+                    x as i64,
+                    y as i64,
+                );
+            }
+            assert_eq!(done, splits, "{w}x{h} k {k}");
+            assert_eq!(img, expected, "{w}x{h} k {k}");
+        }
+        // tiles of one pixel are the image itself
+        let source = pattern(6, 6);
+        assert_eq!(compress(&source, 6, 100, Split::Kd), (source, 0));
+    }
+
+    #[test]
+    fn more_tiles_than_pixels_across_is_an_error() {
+        let err = compress_tiles(
+            &pattern(5, 8),
+            6,
+            10,
+            None,
+            None,
+            &mut PhaseTimer::new(false),
+        );
+        assert_eq!(
+            err.err().unwrap(),
+            "can't split a 5x8 image into 6x6 tiles, every tile needs at least one pixel"
+        );
+    }
+}
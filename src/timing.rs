@@ -0,0 +1,123 @@
+use std::time::{Duration, Instant};
+
+use crate::json::Json;
+
+/// wall clock time spent in each phase of a run, for -timings and -report
+pub struct PhaseTimer {
+    enabled: bool,
+    /// whether `report` has a table, a timer only recording for -report
+    /// doesn't
+    shown: bool,
+    /// in the order each phase was first seen
+    phases: Vec<Phase>,
+    /// things that were counted rather than timed, also in order
+    counts: Vec<(&'static str, u64)>,
+}
+
+struct Phase {
+    name: &'static str,
+    total: Duration,
+    count: u64,
+}
+
+impl PhaseTimer {
+    /// a disabled timer still runs everything it is given, it just records nothing
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            shown: enabled,
+            phases: Vec::new(),
+            counts: Vec::new(),
+        }
+    }
+
+    /// also record when `recording` is set, without a table from `report`
+    pub fn recording(mut self, recording: bool) -> Self {
+        self.enabled |= recording;
+        self
+    }
+
+    /// run `f` and add its duration to `name`
+    pub fn time<T>(&mut self, name: &'static str, f: impl FnOnce() -> T) -> T {
+        if !self.enabled {
+            return f();
+        }
+        let start = Instant::now();
+        let ret = f();
+        self.add(name, start.elapsed());
+        ret
+    }
+
+    /// add one measurement of `elapsed` to `name`
+    pub fn add(&mut self, name: &'static str, elapsed: Duration) {
+        if !self.enabled {
+            return;
+        }
+        match self.phases.iter_mut().find(|p| p.name == name) {
+            Some(p) => {
+                p.total += elapsed;
+                p.count += 1;
+            }
+            None => self.phases.push(Phase {
+                name,
+                total: elapsed,
+                count: 1,
+            }),
+        }
+    }
+
+    /// add `n` to the count of `name`
+    pub fn count(&mut self, name: &'static str, n: u64) {
+        if !self.enabled {
+            return;
+        }
+        match self.counts.iter_mut().find(|(c, _)| *c == name) {
+            Some((_, total)) => *total += n,
+            None => self.counts.push((name, n)),
+        }
+    }
+
+    /// a table with the total, number of calls and time per 1000 calls of
+    /// every phase, or nothing when disabled
+    pub fn report(&self) -> Option<String> {
+        if !self.shown {
+            return None;
+        }
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        let mut ret = format!(
+            "{:<10} {:>12} {:>10} {:>16}\n",
+            "phase", "total", "calls", "per 1000 calls"
+        );
+        for p in &self.phases {
+            ret += &format!("{:<10} {:>10.1}ms {:>10}", p.name, ms(p.total), p.count);
+            // a rate means nothing for a phase that only ran once
+            if p.count > 1 {
+                let per_1000 = ms(p.total) * 1000.0 / p.count as f64;
+                ret += &format!(" {per_1000:>14.1}ms");
+            }
+            ret += "\n";
+        }
+        let total: Duration = self.phases.iter().map(|p| p.total).sum();
+        ret += &format!("{:<10} {:>10.1}ms\n", "total", ms(total));
+        for (name, n) in &self.counts {
+            ret += &format!("{name:<16} {n:>6}\n");
+        }
+        Some(ret)
+    }
+    /// the milliseconds and calls of every phase and every count, in the
+    /// order they were first seen
+    pub fn to_json(&self) -> Json {
+        let phases = self.phases.iter().map(|p| {
+            let ms = Json::Fraction(p.total.as_secs_f64() * 1000.0);
+            (
+                p.name,
+                Json::object([("ms", ms), ("calls", Json::Number(p.count))]),
+            )
+        });
+        let counts = self.counts.iter().map(|&(name, n)| (name, Json::Number(n)));
+        Json::object([
+            ("phases", Json::object(phases)),
+            ("counts", Json::object(counts)),
+        ])
+    }
+}
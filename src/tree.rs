@@ -1,29 +1,112 @@
-use std::collections::{BinaryHeap, VecDeque};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashSet},
+    fmt::{self, Display},
+    io::{Read, Write},
+    ops::Range,
+    sync::Arc,
+};
 
 use image::{ImageBuffer, Pixel, Rgb, RgbImage, Rgba, RgbaImage};
 
-use crate::image::{ImageData, RGB};
+use crate::{
+    entropy::{ColorModel, Model, RangeDecoder, RangeEncoder},
+    image::{ImageData, RegionSource, MAX_INPUT_PIXELS, RGB},
+    json::Json,
+    qt::{self, BitWriter},
+    residual,
+};
 
+/// children are stored next to each other in the node array, nw, ne, sw, se
+/// after a quad split, top, bottom or left, right after a binary one and row
+/// by row after a grid split
+#[derive(Clone, Copy)]
 struct NodeChildren {
-    nw: usize,
-    ne: usize,
-    sw: usize,
-    se: usize,
+    first: usize,
+    count: usize,
+}
+
+impl NodeChildren {
+    fn indexes(self) -> Range<usize> {
+        self.first..self.first + self.count
+    }
+}
+
+/// (top left, bottom right) of a region, inclusive
+pub type Bounds = ((usize, usize), (usize, usize));
+
+/// where a node is split, each position is the last row or column of its
+/// first child
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Cut {
+    /// into four after a row and a column
+    Quad(usize, usize),
+    /// into top and bottom after a row
+    Rows(usize),
+    /// into left and right after a column
+    Columns(usize),
+    /// into nine after two rows and two columns, given as the last row and
+    /// column of the nw child and of the middle one
+    Grid((usize, usize), (usize, usize)),
+}
+
+/// the regions of the children a cut makes, in the order they're stored
+enum ChildBounds {
+    Quad([Bounds; 4]),
+    Binary([Bounds; 2]),
+    Grid([Bounds; 9]),
+}
+
+impl ChildBounds {
+    fn as_slice(&self) -> &[Bounds] {
+        match self {
+            Self::Quad(bounds) => bounds,
+            Self::Binary(bounds) => bounds,
+            Self::Grid(bounds) => bounds,
+        }
+    }
 }
 
 // children are stored as indexes in node array
 struct Node {
     top_left: (usize, usize),
     bottom_right: (usize, usize),
+    /// computed once when the node is created, renders read it every frame
+    average: RGB<u64>,
+    /// total squared error of the region, also computed once
+    metric: u64,
+    /// splits between the root and this node
+    depth: u32,
 
     children: Option<NodeChildren>,
 }
 
 impl Node {
-    pub fn leaf(top_left: (usize, usize), bottom_right: (usize, usize)) -> Self {
+    /// a leaf of a tree loaded from a file, without an image to compute its
+    /// color or error from. the color is filled in by the loader
+    fn frozen(top_left: (usize, usize), bottom_right: (usize, usize), depth: u32) -> Self {
+        Self {
+            top_left,
+            bottom_right,
+            average: RGB::new(0, 0, 0),
+            metric: 0,
+            depth,
+            children: None,
+        }
+    }
+
+    pub fn leaf(
+        top_left: (usize, usize),
+        bottom_right: (usize, usize),
+        depth: u32,
+        image_data: &impl RegionSource,
+    ) -> Self {
         Self {
             top_left,
             bottom_right,
+            average: image_data.average(top_left, bottom_right),
+            metric: image_data.metric(top_left, bottom_right),
+            depth,
             children: None,
         }
     }
@@ -36,53 +119,300 @@ impl Node {
         (self.bottom_right.1 as u64) - (self.top_left.1 as u64)
     }
 
-    fn can_split(&self) -> bool {
-        self.width() > 1 && self.height() > 1
+    fn area(&self) -> u64 {
+        (self.height() + 1) * (self.width() + 1)
+    }
+
+    /// quad and grid splits need room on both sides, binary splits on either
+    fn can_split(&self, split: Split) -> bool {
+        if split.is_binary() {
+            self.width() > 1 || self.height() > 1
+        } else {
+            self.width() > 1 && self.height() > 1
+        }
+    }
+
+    /// the last row and column of the nw child when the node is split `k`
+    /// eighths of the way down and `l` eighths of the way across
+    fn split_point(&self, (k, l): (usize, usize)) -> (usize, usize) {
+        let (top, left) = self.top_left;
+        let (bottom, right) = self.bottom_right;
+        (top + (bottom - top) * k / 8, left + (right - left) * l / 8)
+    }
+
+    /// the cut of a `split` that isn't adaptive. it goes through the middle,
+    /// and binary cuts go across the longer side and split rows when the node
+    /// is square. grid cuts go a third and two thirds of the way, rounded
+    /// down, so the last row and column of children get what's left over
+    fn fixed_cut(&self, split: Split) -> Cut {
+        let (split_h, split_w) = self.split_point((4, 4));
+        if split == Split::Grid9 {
+            let (top, left) = self.top_left;
+            let third_h = (self.height() as usize + 1) / 3;
+            let third_w = (self.width() as usize + 1) / 3;
+            Cut::Grid(
+                (top + third_h - 1, left + third_w - 1),
+                (top + 2 * third_h - 1, left + 2 * third_w - 1),
+            )
+        } else if !split.is_binary() {
+            Cut::Quad(split_h, split_w)
+        } else if self.height() >= self.width() {
+            Cut::Rows(split_h)
+        } else {
+            Cut::Columns(split_w)
+        }
+    }
+
+    fn child_bounds(&self, cut: Cut) -> ChildBounds {
+        let (top_left, bottom_right) = (self.top_left, self.bottom_right);
+        match cut {
+            Cut::Quad(split_h, split_w) => ChildBounds::Quad([
+                (top_left, (split_h, split_w)),
+                ((top_left.0, split_w + 1), (split_h, bottom_right.1)),
+                ((split_h + 1, top_left.1), (bottom_right.0, split_w)),
+                ((split_h + 1, split_w + 1), bottom_right),
+            ]),
+            Cut::Rows(split_h) => ChildBounds::Binary([
+                (top_left, (split_h, bottom_right.1)),
+                ((split_h + 1, top_left.1), bottom_right),
+            ]),
+            Cut::Columns(split_w) => ChildBounds::Binary([
+                (top_left, (bottom_right.0, split_w)),
+                ((top_left.0, split_w + 1), bottom_right),
+            ]),
+            Cut::Grid((h1, w1), (h2, w2)) => {
+                let rows = [(top_left.0, h1), (h1 + 1, h2), (h2 + 1, bottom_right.0)];
+                let columns = [(top_left.1, w1), (w1 + 1, w2), (w2 + 1, bottom_right.1)];
+                ChildBounds::Grid(std::array::from_fn(|i| {
+                    let (top, bottom) = rows[i / 3];
+                    let (left, right) = columns[i % 3];
+                    ((top, left), (bottom, right))
+                }))
+            }
+        }
+    }
+
+    /// where `split` cuts the node. adaptive splits keep the candidate that
+    /// leaves the least error in the children, ties go to the middle and,
+    /// for binary splits, to the longer side
+    fn cut(&self, image_data: &impl RegionSource, split: Split) -> Cut {
+        let error = |cut: &Cut| -> u64 {
+            let bounds = self.child_bounds(*cut);
+            bounds
+                .as_slice()
+                .iter()
+                .map(|&(top_left, bottom_right)| image_data.metric(top_left, bottom_right))
+                .sum()
+        };
+        match split {
+            Split::Midpoint | Split::Kd | Split::Grid9 => self.fixed_cut(split),
+            Split::Adaptive => SPLIT_EIGHTHS
+                .into_iter()
+                .flat_map(|k| SPLIT_EIGHTHS.map(|l| (k, l)))
+                .map(|eighths| {
+                    let (split_h, split_w) = self.split_point(eighths);
+                    Cut::Quad(split_h, split_w)
+                })
+                .min_by_key(error)
+                .unwrap(),
+            Split::KdAdaptive => {
+                // a side too thin to split has no candidates
+                let rows = (self.height() > 1)
+                    .then(|| SPLIT_EIGHTHS.map(|k| Cut::Rows(self.split_point((k, 4)).0)));
+                let columns = (self.width() > 1)
+                    .then(|| SPLIT_EIGHTHS.map(|l| Cut::Columns(self.split_point((4, l)).1)));
+                let (first, second) = if self.height() >= self.width() {
+                    (rows, columns)
+                } else {
+                    (columns, rows)
+                };
+                first
+                    .into_iter()
+                    .chain(second)
+                    .flatten()
+                    .min_by_key(error)
+                    .unwrap()
+            }
+        }
     }
 
-    fn split(&self) -> Option<(Node, Node, Node, Node)> {
-        // guarantees that node is split into 4 children
-        if !self.can_split() {
+    /// the regions of the children `split` would make, if the node is large
+    /// enough to split
+    fn split(&self, image_data: &impl RegionSource, split: Split) -> Option<ChildBounds> {
+        if !self.can_split(split) {
             return None;
         }
+        Some(self.child_bounds(self.cut(image_data, split)))
+    }
 
-        let split_h = (self.top_left.0 + self.bottom_right.0) / 2;
-        let split_w = (self.top_left.1 + self.bottom_right.1) / 2;
+    /// the byte a .qt file stores for an adaptive `cut` of this node. quad
+    /// cuts are the indexes in `SPLIT_EIGHTHS` of their row and column, binary
+    /// cuts the index of their row, or of their column after all the rows
+    fn cut_code(&self, cut: Cut) -> u8 {
+        // small regions can have several candidates on the same row or column,
+        // any of them gives the same bounds back
+        let row = |split_h: usize| {
+            SPLIT_EIGHTHS
+                .iter()
+                .position(|&k| self.split_point((k, 4)).0 == split_h)
+                .unwrap()
+        };
+        let column = |split_w: usize| {
+            SPLIT_EIGHTHS
+                .iter()
+                .position(|&l| self.split_point((4, l)).1 == split_w)
+                .unwrap()
+        };
+        let n = SPLIT_EIGHTHS.len();
+        let code = match cut {
+            Cut::Quad(split_h, split_w) => row(split_h) * n + column(split_w),
+            Cut::Rows(split_h) => row(split_h),
+            Cut::Columns(split_w) => n + column(split_w),
+            Cut::Grid(..) => unreachable!("grid splits aren't adaptive"),
+        };
+        code as u8
+    }
 
-        let nw_node = Node::leaf(self.top_left, (split_h, split_w));
-        let ne_node = Node::leaf(
-            (self.top_left.0, split_w + 1),
-            (split_h, self.bottom_right.1),
-        );
-        let sw_node = Node::leaf(
-            (split_h + 1, self.top_left.1),
-            (self.bottom_right.0, split_w),
-        );
-        let se_node = Node::leaf((split_h + 1, split_w + 1), self.bottom_right);
+    /// the cut `cut_code` gave `code`, if it is one this node can be split at
+    fn cut_from_code(&self, code: u8, binary: bool) -> Option<Cut> {
+        let n = SPLIT_EIGHTHS.len();
+        let code = code as usize;
+        let row = |i: usize| self.split_point((SPLIT_EIGHTHS[i], 4)).0;
+        let column = |i: usize| self.split_point((4, SPLIT_EIGHTHS[i])).1;
+        if !binary {
+            (code < n * n).then(|| Cut::Quad(row(code / n), column(code % n)))
+        } else if code < n {
+            (self.height() > 1).then(|| Cut::Rows(row(code)))
+        } else if code < 2 * n {
+            (self.width() > 1).then(|| Cut::Columns(column(code - n)))
+        } else {
+            None
+        }
+    }
+}
+
+/// where nodes are split
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Split {
+    /// into four through the middle row and column
+    Midpoint,
+    /// into four through whichever of a few rows and columns around the
+    /// middle leaves the least error in the children, so an edge just off
+    /// the middle doesn't end up inside two of them
+    Adaptive,
+    /// into two through the middle of the longer side
+    Kd,
+    /// into two through whichever of a few rows or columns around the middle
+    /// leaves the least error in the children
+    KdAdaptive,
+    /// into nine through the rows and columns a third and two thirds of the
+    /// way across
+    Grid9,
+}
+
+impl Split {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "midpoint" => Ok(Self::Midpoint),
+            "adaptive" => Ok(Self::Adaptive),
+            "kd" => Ok(Self::Kd),
+            "kd-adaptive" => Ok(Self::KdAdaptive),
+            "grid9" => Ok(Self::Grid9),
+            _ => Err(format!(
+                "invalid split `{s}`, expected midpoint|adaptive|kd|kd-adaptive|grid9"
+            )),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Midpoint => "midpoint",
+            Self::Adaptive => "adaptive",
+            Self::Kd => "kd",
+            Self::KdAdaptive => "kd-adaptive",
+            Self::Grid9 => "grid9",
+        }
+    }
+
+    fn is_binary(self) -> bool {
+        matches!(self, Self::Kd | Self::KdAdaptive)
+    }
+
+    fn is_adaptive(self) -> bool {
+        matches!(self, Self::Adaptive | Self::KdAdaptive)
+    }
+
+    /// how many children every split makes
+    pub fn children(self) -> usize {
+        match self {
+            Self::Midpoint | Self::Adaptive => 4,
+            Self::Kd | Self::KdAdaptive => 2,
+            Self::Grid9 => 9,
+        }
+    }
+
+    /// how many different bytes `Node::cut_code` can give
+    fn cut_codes(self) -> usize {
+        let n = SPLIT_EIGHTHS.len();
+        if self.is_binary() {
+            2 * n
+        } else {
+            n * n
+        }
+    }
+
+    fn qt_flags(self) -> u8 {
+        let mut flags = 0;
+        if self.is_adaptive() {
+            flags |= qt::ADAPTIVE_SPLITS;
+        }
+        if self.is_binary() {
+            flags |= qt::BINARY_SPLITS;
+        }
+        if self == Self::Grid9 {
+            flags |= qt::GRID_SPLITS;
+        }
+        flags
+    }
 
-        Some((nw_node, ne_node, sw_node, se_node))
+    /// `qt::read` already turned down grid splits with any other flag
+    fn from_qt_flags(flags: u8) -> Self {
+        if flags & qt::GRID_SPLITS != 0 {
+            return Self::Grid9;
+        }
+        match (
+            flags & qt::BINARY_SPLITS != 0,
+            flags & qt::ADAPTIVE_SPLITS != 0,
+        ) {
+            (false, false) => Self::Midpoint,
+            (false, true) => Self::Adaptive,
+            (true, false) => Self::Kd,
+            (true, true) => Self::KdAdaptive,
+        }
     }
 }
 
+/// how far across a node, in eighths, adaptive splits try splitting it. the
+/// middle is first so it wins ties
+const SPLIT_EIGHTHS: [usize; 5] = [4, 2, 3, 5, 6];
+
 struct OrdNode {
     node_index: usize,
     metric: u64,
 }
 
 impl OrdNode {
-    pub fn new(nodes: &Vec<Node>, index: usize, image_data: &ImageData) -> Self {
-        let top_left = nodes[index].top_left;
-        let bottom_right = nodes[index].bottom_right;
+    pub fn new(nodes: &[Node], index: usize) -> Self {
         Self {
             node_index: index,
-            metric: image_data.metric(top_left, bottom_right),
+            metric: nodes[index].metric,
         }
     }
 }
 
 impl PartialEq for OrdNode {
     fn eq(&self, other: &Self) -> bool {
-        self.metric == other.metric
+        self.cmp(other).is_eq()
     }
 }
 
@@ -90,143 +420,2589 @@ impl Eq for OrdNode {}
 
 impl PartialOrd for OrdNode {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.metric.cmp(&other.metric))
+        Some(self.cmp(other))
     }
 }
 
+/// the largest error first, and the oldest node of equal ones, so the order
+/// doesn't depend on how the queue got its entries
 impl Ord for OrdNode {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.metric.cmp(&other.metric)
+        self.metric
+            .cmp(&other.metric)
+            .then(other.node_index.cmp(&self.node_index))
+    }
+}
+
+/// a region that isn't split, as `Tree::leaf_at` finds it. positions are
+/// (row, column) like everywhere else in the tree, and inclusive
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Leaf {
+    pub top_left: (usize, usize),
+    pub bottom_right: (usize, usize),
+    /// splits between the root and the leaf
+    pub depth: u32,
+    pub color: RGB<u8>,
+    /// total squared error of the region, 0 in trees loaded from a file
+    pub metric: u64,
+}
+
+/// the leaves of a tree at one point of its refinement, without the prefix
+/// sums or the rest of the nodes
+pub struct TreeSnapshot {
+    leaves: Vec<Leaf>,
+    /// (height, width) of the image
+    dimensions: (usize, usize),
+}
+
+impl TreeSnapshot {
+    /// the same image `Tree::render_rgb` gave when the snapshot was taken
+    pub fn render_rgb(&self, outline: Option<RGB<u8>>, scale: f64) -> RgbImage {
+        let size = scaled_dimensions(self.dimensions, scale);
+        let mut ret = RgbImage::new(size.1 as u32, size.0 as u32);
+        let regions = self.leaves.iter().map(|leaf| {
+            let c = leaf.color;
+            (leaf.top_left, leaf.bottom_right, Rgb([c.r, c.g, c.b]))
+        });
+        let rects = scaled_rects(self.dimensions, size, regions);
+        paint(
+            ret.as_mut(),
+            size.1,
+            &rects,
+            outline.map(|c| Rgb([c.r, c.g, c.b])),
+        );
+        ret
+    }
+}
+
+/// where two trees of the same image were split differently, from
+/// `Tree::diff`
+pub struct TreeDiff {
+    /// leaves of the first tree that aren't leaves of the second. they cover
+    /// exactly the pixels where the trees disagree
+    pub only_in_self: Vec<Bounds>,
+    /// leaves of the second tree that aren't leaves of the first, covering
+    /// the same pixels
+    pub only_in_other: Vec<Bounds>,
+    /// (height, width) of the image
+    dimensions: (usize, usize),
+}
+
+impl TreeDiff {
+    /// whether the trees were split the same. each side covers the pixels
+    /// the other does, so either both are empty or neither is
+    pub fn is_empty(&self) -> bool {
+        debug_assert_eq!(self.only_in_self.is_empty(), self.only_in_other.is_empty());
+        self.only_in_self.is_empty() && self.only_in_other.is_empty()
+    }
+
+    /// pixels in regions the trees split differently
+    pub fn differing_pixels(&self) -> u64 {
+        self.only_in_self
+            .iter()
+            .map(|&((top, left), (bottom, right))| ((bottom - top + 1) * (right - left + 1)) as u64)
+            .sum()
+    }
+
+    /// paint where the trees agree `same` and where they don't `different`
+    pub fn render(&self, same: RGB<u8>, different: RGB<u8>) -> RgbImage {
+        let (h, w) = self.dimensions;
+        let mut ret = RgbImage::from_pixel(w as u32, h as u32, Rgb([same.r, same.g, same.b]));
+        let pixel = Rgb([different.r, different.g, different.b]);
+        let regions = self.only_in_self.iter().map(|&(tl, br)| (tl, br, pixel));
+        let rects = scaled_rects(self.dimensions, self.dimensions, regions);
+        paint(ret.as_mut(), w, &rects, None);
+        ret
+    }
+}
+
+/// the shape of a tree and how well it fits its image, from `Tree::stats`
+#[derive(Debug, Clone, PartialEq)]
+pub struct TreeStats {
+    pub leaves: usize,
+    /// nodes that are still part of the tree, leaves included
+    pub nodes: usize,
+    /// splits between the root and the deepest leaf
+    pub max_depth: u32,
+    pub mean_depth: f64,
+    /// leaves by area, the `k`th entry counts the leaves of `2^k` up to
+    /// `2^(k+1) - 1` pixels. it ends at the largest leaf
+    pub leaf_areas: Vec<usize>,
+    /// total squared error of the leaves, 0 in trees loaded from a file
+    pub error: u64,
+}
+
+/// a tree read back from a file and what was saved along with it
+pub struct LoadedTree {
+    pub tree: Tree,
+    /// the residual saved by -encode-lossless
+    pub residual: Option<RgbImage>,
+    /// the version of comprs that saved it, if the file says
+    pub writer: Option<String>,
+}
+
+/// what a call to `Tree::refine` did
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Refined {
+    /// a leaf was split
+    Split,
+    /// every leaf is too small to split, the tree is unchanged
+    Exhausted,
+}
+
+/// why `Tree::refine` couldn't split a leaf
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefineError {
+    /// `finalize` released the image, there is nothing to split from
+    Finalized,
+}
+
+impl fmt::Display for RefineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RefineError::Finalized => f.write_str("tree has been finalized, it can't be refined"),
+        }
     }
 }
 
-pub struct Tree {
-    image_data: ImageData,
+/// a tree refined from `S`, the whole of an image or a view of one
+pub struct Tree<S = ImageData> {
+    /// only needed to split nodes, dropped by `finalize`. it is never
+    /// written to, so trees can share it across threads
+    image_data: Option<Arc<S>>,
     nodes: Vec<Node>,
     pq: BinaryHeap<OrdNode>,
+    /// entries in `pq` that can still be split
+    splittable: usize,
+    /// indexes of the current leaves in no particular order, kept up to date
+    /// as nodes are split and merged so renders don't walk the tree. nodes
+    /// merged by `prune` leave their children in the arena, so the arena
+    /// can't be used instead
+    leaves: Vec<usize>,
+    /// where each leaf is in `leaves`, by node index. stale for nodes that
+    /// aren't leaves
+    leaf_slots: Vec<usize>,
+    /// nodes split by `refine`, oldest first, which `undo` takes back
+    history: Vec<usize>,
+    /// of a render of the leaves against `image_data`, summed over every
+    /// channel of every pixel. kept up to date by `refine` and `undo` and
+    /// counted again by anything else that changes the leaves, 0 without
+    /// image data
+    squared_error: u128,
     dimensions: (usize, usize),
+    split: Split,
+    /// the splits the tree was made with room for, which `with_split` makes
+    /// room for again once it knows how many nodes a split adds
+    capacity_hint: usize,
 }
 
 const MAX_ALPHA: u8 = 100;
 
-impl Tree {
-    pub fn new(image_data: ImageData) -> Self {
+// trees are refined on other threads with -jobs and -tiles, and
+// `trees_refine_over_shared_data_on_two_threads` refines two over the same
+// image data at once
+const _: () = {
+    fn assert_send_sync<T: Send + Sync>() {}
+    let _ = assert_send_sync::<Tree>;
+    let _ = assert_send_sync::<TreeSnapshot>;
+};
+
+impl<S: RegionSource> Tree<S> {
+    /// a tree over `image_data` with room for `iterations` splits, the data
+    /// behind an `Arc` of its own. `new_shared` takes one that other trees
+    /// refine too
+    pub fn new(image_data: S, iterations: u32) -> Self {
+        Self::new_shared(Arc::new(image_data), iterations)
+    }
+
+    /// a tree with room for `iterations` splits, so refining that many times
+    /// never reallocates the node arena or the queue. no more room is made
+    /// than the image has pixels for
+    pub fn with_capacity_hint(image_data: S, iterations: u32) -> Self {
+        Self::new(image_data, iterations)
+    }
+
+    /// `with_capacity_hint` over image data that other trees can refine at
+    /// the same time, on any thread
+    pub fn new_shared(image_data: Arc<S>, iterations: u32) -> Self {
         let dimensions = (image_data.height(), image_data.width());
-        let root = Node::leaf((0, 0), (dimensions.0 - 1, dimensions.1 - 1));
+        let root = Node::leaf(
+            (0, 0),
+            (dimensions.0 - 1, dimensions.1 - 1),
+            0,
+            &*image_data,
+        );
         let nodes = vec![root];
         let mut pq = BinaryHeap::new();
-        pq.push(OrdNode::new(&nodes, 0, &image_data));
+        pq.push(OrdNode::new(&nodes, 0));
+        let splittable = nodes[0].can_split(Split::Midpoint) as usize;
+        let squared_error = leaf_error(&*image_data, &nodes[0]);
 
-        Self {
-            image_data,
+        let mut tree = Self {
+            image_data: Some(image_data),
             nodes,
             pq,
+            splittable,
+            leaves: vec![0],
+            leaf_slots: vec![0],
+            history: Vec::new(),
+            squared_error,
             dimensions,
+            split: Split::Midpoint,
+            capacity_hint: iterations as usize,
+        };
+        tree.reserve_splits(tree.capacity_hint);
+        tree
+    }
+
+    /// split nodes with `split` instead of into four through the middle,
+    /// before any refining. room is made for the hint's splits of this kind
+    pub fn with_split(mut self, split: Split) -> Self {
+        self.split = split;
+        self.splittable = self.nodes[0].can_split(split) as usize;
+        self.reserve_splits(self.capacity_hint);
+        self
+    }
+
+    /// make room for `splits` more splits, or as many as the image has room
+    /// for if that is fewer: every split adds `children - 1` leaves, and a
+    /// leaf has at least one pixel. only a hint, so memory that can't be had
+    /// leaves the arrays to grow as they fill rather than aborting
+    fn reserve_splits(&mut self, splits: usize) {
+        let (height, width) = self.dimensions;
+        let added = self.split.children() - 1;
+        let splits = splits.min((height * width - self.leaf_count()) / added);
+        let nodes = self.nodes.len() + splits * (added + 1);
+        let _ = self.nodes.try_reserve_exact(nodes - self.nodes.len());
+        let _ = self
+            .leaf_slots
+            .try_reserve_exact(nodes - self.leaf_slots.len());
+        let _ = self.leaves.try_reserve_exact(splits * added);
+        // each split takes one entry off the queue and puts its children on
+        let _ = self.pq.try_reserve_exact(splits * added);
+    }
+
+    pub fn height(&self) -> usize {
+        self.dimensions.0
+    }
+
+    pub fn width(&self) -> usize {
+        self.dimensions.1
+    }
+
+    pub fn leaf_count(&self) -> usize {
+        self.leaves.len()
+    }
+
+    /// the leaf that covers the pixel at column `x` and row `y`, found by
+    /// walking down from the root. `None` if the pixel is outside the image
+    pub fn leaf_at(&self, x: usize, y: usize) -> Option<Leaf> {
+        let (height, width) = self.dimensions;
+        if x >= width || y >= height {
+            return None;
+        }
+        let mut cur = 0;
+        while let Some(children) = self.nodes[cur].children {
+            // children cover their parent exactly, so one always matches
+            cur = children.indexes().find(|&c| {
+                let node = &self.nodes[c];
+                (node.top_left.0..=node.bottom_right.0).contains(&y)
+                    && (node.top_left.1..=node.bottom_right.1).contains(&x)
+            })?;
+        }
+        Some(self.leaf(cur))
+    }
+
+    fn leaf(&self, index: usize) -> Leaf {
+        let node = &self.nodes[index];
+        Leaf {
+            top_left: node.top_left,
+            bottom_right: node.bottom_right,
+            depth: node.depth,
+            color: rgb8(node.average),
+            metric: node.metric,
+        }
+    }
+
+    /// the current leaves, in preorder, which can be rendered however far the
+    /// tree is refined after
+    pub fn snapshot(&self) -> TreeSnapshot {
+        let leaves = self
+            .preorder()
+            .into_iter()
+            .filter(|&i| self.nodes[i].children.is_none())
+            .map(|i| self.leaf(i))
+            .collect();
+        TreeSnapshot {
+            leaves,
+            dimensions: self.dimensions,
         }
     }
 
-    fn push_node(&mut self, node: Node) -> usize {
-        let ret = self.nodes.len();
-        self.nodes.push(node);
+    /// counts of the leaves by depth and area, and the error they leave, from
+    /// the leaf list without walking the tree
+    pub fn stats(&self) -> TreeStats {
+        let leaves = self.leaf_count();
+        let mut ret = TreeStats {
+            leaves,
+            // every split adds one node that isn't a leaf
+            nodes: leaves + self.splits(),
+            max_depth: 0,
+            mean_depth: 0.0,
+            leaf_areas: Vec::new(),
+            error: 0,
+        };
+        let mut total_depth = 0;
+        for i in self.leaves() {
+            let node = &self.nodes[i];
+            ret.max_depth = ret.max_depth.max(node.depth);
+            total_depth += node.depth as u64;
+            let bucket = node.area().ilog2() as usize;
+            if bucket >= ret.leaf_areas.len() {
+                ret.leaf_areas.resize(bucket + 1, 0);
+            }
+            ret.leaf_areas[bucket] += 1;
+            ret.error += node.metric;
+        }
+        ret.mean_depth = total_depth as f64 / leaves as f64;
         ret
     }
 
-    pub fn refine(&mut self) -> Result<(), String> {
+    /// the mean squared error of every channel of a render of the tree, at
+    /// its size and without outlines, against the image it is refined from,
+    /// the same as comparing the two pixel by pixel. it is kept up to date
+    /// from the prefix sums as leaves are split, so nothing is rendered and
+    /// asking after every split costs nothing. the squared error in `stats`
+    /// sums the leaf metrics, which are of the exact mean and round on the
+    /// way, so it is close to this times the number of channels and pixels
+    /// but not the same. `None` once the tree is finalized
+    pub fn mse(&self) -> Option<f64> {
+        self.image_data.as_ref()?;
+        let (height, width) = self.dimensions;
+        Some(self.squared_error as f64 / (3 * height * width) as f64)
+    }
+
+    /// the peak signal to noise ratio of the same render against the image,
+    /// in decibels, from `mse`. infinite once the render is exact, `None`
+    /// once the tree is finalized
+    pub fn psnr(&self) -> Option<f64> {
+        self.mse().map(crate::image::psnr)
+    }
+
+    /// count `squared_error` again from every leaf, after changes that
+    /// `refine` and `undo` don't keep track of
+    fn recount_squared_error(&mut self) {
+        self.squared_error = match self.image_data.as_deref() {
+            Some(image_data) => self
+                .leaves()
+                .map(|i| leaf_error(image_data, &self.nodes[i]))
+                .sum(),
+            None => 0,
+        };
+    }
+
+    /// leaves added by every split, and taken away by every merge
+    pub fn leaves_per_split(&self) -> usize {
+        self.split.children() - 1
+    }
+
+    /// number of splits it takes to get from the root to the current leaves
+    fn splits(&self) -> usize {
+        (self.leaf_count() - 1) / self.leaves_per_split()
+    }
+
+    /// release the prefix sums and the queue once refinement is over, leaving
+    /// only what rendering needs. `refine` fails after this
+    pub fn finalize(&mut self) {
+        self.image_data = None;
+        self.squared_error = 0;
+        self.pq = BinaryHeap::new();
+        self.history = Vec::new();
+        // refinement may have stopped short of the capacity hint
+        self.nodes.shrink_to_fit();
+        self.leaf_slots.shrink_to_fit();
+    }
+
+    /// true when no leaf can be split any further
+    pub fn is_fully_refined(&self) -> bool {
+        self.splittable == 0
+    }
+
+    /// split the leaf with the most error. running out of leaves to split is
+    /// not an error, the tree is left as it is so it can still be rendered
+    pub fn refine(&mut self) -> Result<Refined, RefineError> {
+        let Some(image_data) = self.image_data.as_deref() else {
+            return Err(RefineError::Finalized);
+        };
+        if self.is_fully_refined() {
+            return Ok(Refined::Exhausted);
+        }
         loop {
             let Some(top) = self.pq.pop() else {
-                return Err("no more nodes to refine".into());
+                return Ok(Refined::Exhausted);
             };
 
-            if let Some((nw, ne, sw, se)) = self.nodes[top.node_index].split() {
-                let nw_index = self.push_node(nw);
-                let ne_index = self.push_node(ne);
-                let sw_index = self.push_node(sw);
-                let se_index = self.push_node(se);
+            if let Some(bounds) = self.nodes[top.node_index].split(image_data, self.split) {
+                let children = NodeChildren {
+                    first: self.nodes.len(),
+                    count: bounds.as_slice().len(),
+                };
+                self.squared_error -= leaf_error(image_data, &self.nodes[top.node_index]);
+                let depth = self.nodes[top.node_index].depth + 1;
+                for &(top_left, bottom_right) in bounds.as_slice() {
+                    let child = Node::leaf(top_left, bottom_right, depth, image_data);
+                    self.squared_error += leaf_error(image_data, &child);
+                    self.nodes.push(child);
+                }
+                self.nodes[top.node_index].children = Some(children);
+                self.history.push(top.node_index);
 
-                let top_node_mut = &mut self.nodes[top.node_index];
-                top_node_mut.children = Some(NodeChildren {
-                    nw: nw_index,
-                    ne: ne_index,
-                    sw: sw_index,
-                    se: se_index,
-                });
+                // the first child takes the place of its parent in the list
+                let slot = self.leaf_slots[top.node_index];
+                self.leaves[slot] = children.first;
+                self.leaf_slots.push(slot);
+                for c in children.indexes().skip(1) {
+                    self.leaf_slots.push(self.leaves.len());
+                    self.leaves.push(c);
+                }
 
-                for ind in [nw_index, ne_index, sw_index, se_index].into_iter() {
-                    self.pq
-                        .push(OrdNode::new(&self.nodes, ind, &self.image_data));
+                self.splittable -= 1;
+                for ind in children.indexes() {
+                    self.splittable += self.nodes[ind].can_split(self.split) as usize;
+                    self.pq.push(OrdNode::new(&self.nodes, ind));
                 }
-                return Ok(());
+                return Ok(Refined::Split);
             }
             // else can't split, go again
         }
     }
 
-    pub fn render<T>(
-        &self,
-        color_to_pixel: fn(RGB<u64>) -> T,
-        outline: Option<RGB<u8>>,
-    ) -> ImageBuffer<T, Vec<u8>>
-    where
-        T: Pixel<Subpixel = u8>,
-    {
-        let (h, w) = self.dimensions;
-        let mut ret = ImageBuffer::new(w as u32, h as u32);
-
-        let outline_pixel = match outline {
-            Some(c) => Some(color_to_pixel(c.into())),
-            None => None,
-        };
-
-        let mut q = VecDeque::new();
-        q.push_back(0); // root node
-        while let Some(cur) = q.pop_front() {
-            let node = &self.nodes[cur];
-            if let Some(NodeChildren { nw, ne, sw, se }) = node.children {
-                q.push_back(nw);
-                q.push_back(ne);
-                q.push_back(sw);
-                q.push_back(se);
-            } else {
-                let (start_y, start_x) = node.top_left;
-                let (end_y, end_x) = node.bottom_right;
-                let color = self.image_data.average(node.top_left, node.bottom_right);
-                let pixel = color_to_pixel(color);
-                for x in start_x..=end_x {
-                    for y in start_y..=end_y {
-                        ret.put_pixel(x as u32, y as u32, pixel);
-                    }
+    /// take back the last `n` splits `refine` made, newest first, or all of
+    /// them if there are fewer, and return how many were taken back. the
+    /// tree is left as it was before those splits, so refining again makes
+    /// them again. splits from before the last `prune` or `finalize` can't
+    /// be taken back, and like `prune` a `Canvas` of the tree won't see them
+    /// go
+    pub fn undo(&mut self, n: usize) -> usize {
+        let mut done = 0;
+        while done < n {
+            let Some(parent) = self.history.pop() else {
+                break;
+            };
+            // later splits were taken back first, so the children are still
+            // leaves and the last nodes in the arena
+            let children = self.nodes[parent].children.take().unwrap();
+            for c in children.indexes() {
+                self.splittable -= self.nodes[c].can_split(self.split) as usize;
+            }
+            // there is history only while there is image data
+            if let Some(image_data) = self.image_data.as_deref() {
+                for c in children.indexes() {
+                    self.squared_error -= leaf_error(image_data, &self.nodes[c]);
+                }
+                self.squared_error += leaf_error(image_data, &self.nodes[parent]);
+            }
+            for c in children.indexes() {
+                let slot = self.leaf_slots[c];
+                self.leaves.swap_remove(slot);
+                if let Some(&moved) = self.leaves.get(slot) {
+                    self.leaf_slots[moved] = slot;
                 }
+            }
+            self.leaf_slots[parent] = self.leaves.len();
+            self.leaves.push(parent);
+            self.nodes.truncate(children.first);
+            self.leaf_slots.truncate(children.first);
+            self.splittable += 1;
+            self.pq.push(OrdNode::new(&self.nodes, parent));
+            done += 1;
+        }
+        // the children's entries point past the arena now
+        let len = self.nodes.len();
+        self.pq.retain(|entry| entry.node_index < len);
+        done
+    }
 
-                if let Some(p) = outline_pixel {
-                    for y in [start_y, end_y].into_iter() {
-                        for x in start_x..=end_x {
-                            ret.put_pixel(x as u32, y as u32, p);
-                        }
-                    }
+    /// the leaves of this tree and of `other` that the other doesn't have,
+    /// which is where they were split differently. both have to be trees of
+    /// images of the same size
+    pub fn diff<R: RegionSource>(&self, other: &Tree<R>) -> Result<TreeDiff, String> {
+        if self.dimensions != other.dimensions {
+            let ((h, w), (oh, ow)) = (self.dimensions, other.dimensions);
+            return Err(format!("can't diff a {w}x{h} tree against a {ow}x{oh} one"));
+        }
+        let (ours, theirs) = (self.leaf_bounds(), other.leaf_bounds());
+        let only = |a: &[Bounds], b: &[Bounds]| {
+            let b: HashSet<&Bounds> = b.iter().collect();
+            a.iter().filter(|r| !b.contains(r)).copied().collect()
+        };
+        Ok(TreeDiff {
+            only_in_self: only(&ours, &theirs),
+            only_in_other: only(&theirs, &ours),
+            dimensions: self.dimensions,
+        })
+    }
 
-                    for x in [start_x, end_x].into_iter() {
-                        for y in start_y..=end_y {
-                            ret.put_pixel(x as u32, y as u32, p);
-                        }
-                    }
+    /// indexes of all leaf nodes, in no particular order
+    fn leaves(&self) -> impl Iterator<Item = usize> + '_ {
+        self.leaves.iter().copied()
+    }
+
+    /// the region of every leaf, in the order of `leaves`
+    fn leaf_bounds(&self) -> Vec<Bounds> {
+        self.leaves()
+            .map(|i| (self.nodes[i].top_left, self.nodes[i].bottom_right))
+            .collect()
+    }
+
+    /// find the leaves again by walking the tree, after changes that
+    /// `refine` and `undo` don't keep track of
+    fn index_leaves(&mut self) {
+        let leaves: Vec<usize> = self
+            .preorder()
+            .into_iter()
+            .filter(|&i| self.nodes[i].children.is_none())
+            .collect();
+        self.leaf_slots = vec![0; self.nodes.len()];
+        for (slot, &i) in leaves.iter().enumerate() {
+            self.leaf_slots[i] = slot;
+        }
+        self.leaves = leaves;
+    }
+
+    /// indexes of every node, parents before their children and children in
+    /// the order they're stored
+    fn preorder(&self) -> Vec<usize> {
+        let mut ret = Vec::with_capacity(self.nodes.len());
+        let mut stack = vec![0];
+        while let Some(cur) = stack.pop() {
+            ret.push(cur);
+            if let Some(children) = self.nodes[cur].children {
+                stack.extend(children.indexes().rev());
+            }
+        }
+        ret
+    }
+
+    /// write the tree in the .qt format, the split structure and the color of
+    /// every leaf, which is all a render needs. `entropy_coded` range codes
+    /// the payload, which makes the file smaller but slower to read and write.
+    /// with the `original` image the tree was refined from, the residual of
+    /// a full render is stored too, so decoding can give back the original
+    pub fn serialize(
+        &self,
+        mut w: impl Write,
+        entropy_coded: bool,
+        original: Option<&RgbImage>,
+    ) -> Result<(), String> {
+        let mut flags = self.split.qt_flags() | qt::WRITER;
+        let payload = if entropy_coded {
+            flags |= qt::ENTROPY_CODED;
+            self.entropy_payload()
+        } else {
+            self.packed_payload()
+        };
+        let residual = match original {
+            Some(original) => {
+                flags |= qt::RESIDUAL;
+                residual::encode(original, &self.render_rgb(None, 1.0))?
+            }
+            None => Vec::new(),
+        };
+        qt::write(&mut w, &self.qt_header(flags), &payload, &residual)
+            .and_then(|_| w.flush())
+            .map_err(|_| "error in writing tree".into())
+    }
+
+    /// bytes in the .qt file `serialize` writes without range coding or the
+    /// residual, from the same layout the reader checks, so nothing is
+    /// written. range coding and the residual depend on the colors, so
+    /// they are only known once written
+    pub fn packed_size(&self) -> u64 {
+        let header = self.qt_header(self.split.qt_flags() | qt::WRITER);
+        qt::file_len(&header, header.packed_len() as usize, 0)
+    }
+
+    fn qt_header(&self, flags: u8) -> qt::Header {
+        let (height, width) = self.dimensions;
+        qt::Header {
+            flags,
+            width: width as u32,
+            height: height as u32,
+            splits: self.splits() as u32,
+        }
+    }
+
+    fn packed_payload(&self) -> Vec<u8> {
+        let order = self.preorder();
+        let adaptive = self.split.is_adaptive();
+        let mut structure = BitWriter::with_capacity(order.len());
+        let mut split_codes = Vec::new();
+        let mut colors = Vec::with_capacity(3 * self.leaf_count());
+        for &i in &order {
+            let node = &self.nodes[i];
+            structure.push(node.children.is_some());
+            match &node.children {
+                Some(children) if adaptive => {
+                    split_codes.push(node.cut_code(self.cut(node, children)));
                 }
+                Some(_) => {}
+                None => {
+                    let c = node.average;
+                    colors.extend([c.r as u8, c.g as u8, c.b as u8]);
+                }
+            }
+        }
+        let mut payload = structure.into_bytes();
+        payload.extend(split_codes);
+        payload.extend(colors);
+        payload
+    }
+
+    fn entropy_payload(&self) -> Vec<u8> {
+        let mut enc = RangeEncoder::new();
+        let mut models = PayloadModels::new(self.split);
+        models
+            .colors
+            .encode(&mut enc, rgb8(self.nodes[0].average), RGB::new(0, 0, 0), 0);
+        for i in self.preorder() {
+            let node = &self.nodes[i];
+            models
+                .split_model(node.depth)
+                .encode(&mut enc, node.children.is_some() as usize);
+            let Some(children) = node.children else {
+                continue;
+            };
+            if self.split.is_adaptive() {
+                let code = node.cut_code(self.cut(node, &children));
+                models.cut.encode(&mut enc, code as usize);
+            }
+            let siblings = &self.nodes[children.indexes()];
+            for (k, child) in siblings.iter().enumerate() {
+                let (pred, kind) = predict_child(node, siblings, k);
+                models
+                    .colors
+                    .encode(&mut enc, rgb8(child.average), pred, kind);
             }
         }
+        enc.finish()
+    }
 
+    /// write `stats` and every leaf as json, in preorder: its position and
+    /// size in pixels, how many splits deep it is and its color. `pretty`
+    /// puts each leaf on its own line, otherwise there is no whitespace at all
+    pub fn export_json(&self, mut out: impl Write, pretty: bool) -> Result<(), String> {
+        let (height, width) = self.dimensions;
+        let splits = self.splits();
+        let stats = self.stats();
+        // newline, indent and the space after separators
+        let (nl, tab, sp) = if pretty {
+            ("\n", "  ", " ")
+        } else {
+            ("", "", "")
+        };
+        let field = |name: &str, value: &dyn Display| format!("\"{name}\":{sp}{value}");
+
+        let mut write = || -> std::io::Result<()> {
+            let header = [
+                field("width", &width),
+                field("height", &height),
+                field("iterations", &splits),
+                field("split", &format!("\"{}\"", self.split.name())),
+            ];
+            write!(
+                out,
+                "{{{nl}{tab}\"header\":{sp}{{{}}},",
+                header.join(&format!(",{sp}"))
+            )?;
+            let areas: Vec<String> = stats.leaf_areas.iter().map(|n| n.to_string()).collect();
+            let stats = [
+                field("leaves", &stats.leaves),
+                field("nodes", &stats.nodes),
+                field("max_depth", &stats.max_depth),
+                field("mean_depth", &format!("{:.2}", stats.mean_depth)),
+                field(
+                    "leaf_areas",
+                    &format!("[{}]", areas.join(&format!(",{sp}"))),
+                ),
+                field("error", &stats.error),
+            ];
+            write!(
+                out,
+                "{nl}{tab}\"stats\":{sp}{{{}}},",
+                stats.join(&format!(",{sp}"))
+            )?;
+            write!(out, "{nl}{tab}\"leaves\":{sp}[")?;
+            let leaves = self
+                .preorder()
+                .into_iter()
+                .filter(|&i| self.nodes[i].children.is_none());
+            for (k, i) in leaves.enumerate() {
+                let node = &self.nodes[i];
+                let c = node.average;
+                // colors are the only strings and never need escaping
+                let color = format!("\"#{:02x}{:02x}{:02x}\"", c.r, c.g, c.b);
+                let leaf = [
+                    field("x", &node.top_left.1),
+                    field("y", &node.top_left.0),
+                    field("w", &(node.width() + 1)),
+                    field("h", &(node.height() + 1)),
+                    field("depth", &node.depth),
+                    field("color", &color),
+                ];
+                let sep = if k == 0 { "" } else { "," };
+                write!(
+                    out,
+                    "{sep}{nl}{tab}{tab}{{{}}}",
+                    leaf.join(&format!(",{sp}"))
+                )?;
+            }
+            write!(out, "{nl}{tab}]{nl}}}{nl}")?;
+            out.flush()
+        };
+        write().map_err(|_| "error in writing json".into())
+    }
+
+    /// a finalized tree that was loaded from a file, every node already has
+    /// a color
+    fn frozen(nodes: Vec<Node>, dimensions: (usize, usize), split: Split) -> Self {
+        let mut ret = Self {
+            image_data: None,
+            nodes,
+            pq: BinaryHeap::new(),
+            splittable: 0,
+            leaves: Vec::new(),
+            leaf_slots: Vec::new(),
+            history: Vec::new(),
+            squared_error: 0,
+            dimensions,
+            split,
+            capacity_hint: 0,
+        };
+        ret.index_leaves();
         ret
     }
 
-    pub fn render_rgb(&self, outline: Option<RGB<u8>>) -> RgbImage {
-        self.render(
-            |color| Rgb([color.r as u8, color.g as u8, color.b as u8]),
-            outline,
-        )
+    /// a tree loaded from a file made refinable again with the `image_data`
+    /// it was refined from, with room for `iterations` more splits. colors
+    /// and errors are computed again from the image and every leaf goes back
+    /// in the queue, so refining goes on where it stopped. the loaded splits
+    /// can't be taken back with `undo`
+    pub fn resume<R: RegionSource>(
+        self,
+        image_data: R,
+        iterations: u32,
+    ) -> Result<Tree<R>, String> {
+        let (height, width) = self.dimensions;
+        if (image_data.height(), image_data.width()) != self.dimensions {
+            return Err(format!(
+                "the tree is of a {width}x{height} image but the image is {}x{}",
+                image_data.width(),
+                image_data.height()
+            ));
+        }
+        let mut tree = Tree {
+            image_data: None,
+            nodes: self.nodes,
+            pq: BinaryHeap::new(),
+            splittable: 0,
+            leaves: self.leaves,
+            leaf_slots: self.leaf_slots,
+            history: self.history,
+            squared_error: 0,
+            dimensions: self.dimensions,
+            split: self.split,
+            capacity_hint: iterations as usize,
+        };
+        for node in tree.nodes.iter_mut() {
+            *node = Node {
+                children: node.children,
+                ..Node::leaf(node.top_left, node.bottom_right, node.depth, &image_data)
+            };
+        }
+        for &i in &tree.leaves {
+            tree.splittable += tree.nodes[i].can_split(tree.split) as usize;
+            tree.pq.push(OrdNode::new(&tree.nodes, i));
+        }
+        tree.reserve_splits(tree.capacity_hint);
+        tree.image_data = Some(Arc::new(image_data));
+        tree.recount_squared_error();
+        Ok(tree)
     }
 
-    pub fn render_rgba(&self, outline: Option<RGB<u8>>) -> RgbaImage {
-        self.render(
-            |color| Rgba([color.r as u8, color.g as u8, color.b as u8, MAX_ALPHA]),
-            outline,
-        )
+    /// where nodes are split
+    pub fn split(&self) -> Split {
+        self.split
+    }
+
+    /// undo the `n` splits that add the least error when undone, or every
+    /// split if there are fewer, and return how many were undone. only nodes
+    /// whose children are all leaves can be merged, which adds the squared
+    /// distance of each child's color from the merged color, times its area.
+    /// the children of merged nodes are left unused in the arena, and a
+    /// `Canvas` of the tree won't see the merges
+    pub fn prune(&mut self, n: usize) -> usize {
+        let mut parent = vec![None; self.nodes.len()];
+        let mut merges = BinaryHeap::new();
+        for i in self.preorder() {
+            let Some(children) = self.nodes[i].children else {
+                continue;
+            };
+            for c in children.indexes() {
+                parent[c] = Some(i);
+            }
+            if let Some(cost) = self.merge_cost(i) {
+                merges.push(Reverse((cost, i)));
+            }
+        }
+
+        let mut done = 0;
+        while done < n {
+            let Some(Reverse((_, i))) = merges.pop() else {
+                break;
+            };
+            self.nodes[i].children = None;
+            done += 1;
+            if let Some(p) = parent[i] {
+                if let Some(cost) = self.merge_cost(p) {
+                    merges.push(Reverse((cost, p)));
+                }
+            }
+        }
+
+        // queue entries of merged children are stale, and merged nodes can
+        // be split again. merged children stay in the arena, so `undo` can't
+        // take back any split made before
+        self.history.clear();
+        self.index_leaves();
+        if self.image_data.is_some() {
+            self.splittable = self
+                .leaves()
+                .filter(|&i| self.nodes[i].can_split(self.split))
+                .count();
+            self.pq = self
+                .leaves()
+                .map(|i| OrdNode::new(&self.nodes, i))
+                .collect();
+        }
+        self.recount_squared_error();
+        done
+    }
+
+    /// the error added by merging the children of node `i` into it, if they
+    /// are all leaves
+    fn merge_cost(&self, i: usize) -> Option<u128> {
+        let children = self.nodes[i].children?;
+        let merged = self.nodes[i].average;
+        let mut cost = 0;
+        for c in children.indexes() {
+            let child = &self.nodes[c];
+            if child.children.is_some() {
+                return None;
+            }
+            let d = |a: u64, b: u64| (a.abs_diff(b) as u128).pow(2);
+            let avg = child.average;
+            let dist = d(avg.r, merged.r) + d(avg.g, merged.g) + d(avg.b, merged.b);
+            cost += dist * child.area() as u128;
+        }
+        Some(cost)
+    }
+
+    /// the cut that made the `children` of `node`, from where its first
+    /// child ends, and its middle one for grid cuts
+    fn cut(&self, node: &Node, children: &NodeChildren) -> Cut {
+        let (split_h, split_w) = self.nodes[children.first].bottom_right;
+        if children.count == 9 {
+            Cut::Grid(
+                (split_h, split_w),
+                self.nodes[children.first + 4].bottom_right,
+            )
+        } else if children.count == 4 {
+            Cut::Quad(split_h, split_w)
+        } else if split_w == node.bottom_right.1 {
+            Cut::Rows(split_h)
+        } else {
+            Cut::Columns(split_w)
+        }
+    }
+
+    /// (height, width) of a render at `scale`, never smaller than 1x1
+    pub fn scaled_dimensions(&self, scale: f64) -> (usize, usize) {
+        scaled_dimensions(self.dimensions, scale)
+    }
+
+    /// output rectangles of the leaves in `indexes` for an image of `size`
+    /// (height, width)
+    fn leaf_rects<T>(
+        &self,
+        indexes: impl IntoIterator<Item = usize>,
+        leaf_pixel: impl Fn(&Node) -> T,
+        size: (usize, usize),
+    ) -> Vec<Rect<T>> {
+        let regions = indexes.into_iter().map(|i| {
+            let node = &self.nodes[i];
+            (node.top_left, node.bottom_right, leaf_pixel(node))
+        });
+        scaled_rects(self.dimensions, size, regions)
+    }
+
+    /// fill every leaf with the pixel returned by `leaf_pixel`, in an image of
+    /// `size` (height, width)
+    fn render_leaves<T>(
+        &self,
+        leaf_pixel: impl Fn(&Node) -> T,
+        outline_pixel: Option<T>,
+        size: (usize, usize),
+    ) -> ImageBuffer<T, Vec<u8>>
+    where
+        T: Pixel<Subpixel = u8> + Send + Sync,
+    {
+        let mut ret = ImageBuffer::new(size.1 as u32, size.0 as u32);
+        let rects = self.leaf_rects(self.leaves(), leaf_pixel, size);
+        paint(ret.as_mut(), size.1, &rects, outline_pixel);
+        ret
+    }
+
+    pub fn render<T>(
+        &self,
+        color_to_pixel: fn(RGB<u64>) -> T,
+        outline: Option<RGB<u8>>,
+        size: (usize, usize),
+    ) -> ImageBuffer<T, Vec<u8>>
+    where
+        T: Pixel<Subpixel = u8> + Send + Sync,
+    {
+        let outline_pixel = outline.map(|c| color_to_pixel(c.into()));
+        self.render_leaves(|node| color_to_pixel(node.average), outline_pixel, size)
+    }
+
+    /// color each leaf by its remaining per-pixel error, from black (no error)
+    /// through red and yellow to white (the largest error in the tree)
+    pub fn render_heatmap(&self, outline: Option<RGB<u8>>, scale: f64) -> RgbaImage {
+        let (h, w) = self.scaled_dimensions(scale);
+        let mut ret = RgbaImage::new(w as u32, h as u32);
+        self.render_heatmap_into(&mut ret, outline);
+        ret
+    }
+
+    /// `render_heatmap` at the size of `img`, every pixel of it is overwritten
+    pub fn render_heatmap_into(&self, img: &mut RgbaImage, outline: Option<RGB<u8>>) {
+        let error = |node: &Node| {
+            let area = (node.height() + 1) * (node.width() + 1);
+            node.metric as f64 / area as f64
+        };
+        let max_error = self
+            .leaves()
+            .map(|i| error(&self.nodes[i]))
+            .fold(0.0, f64::max);
+
+        let outline_pixel = outline.map(|c| Rgba([c.r, c.g, c.b, MAX_ALPHA]));
+        let size = (img.height() as usize, img.width() as usize);
+        let rects = self.leaf_rects(
+            self.leaves(),
+            |node| {
+                let t = if max_error > 0.0 {
+                    (error(node) / max_error).sqrt()
+                } else {
+                    0.0
+                };
+                let channel = |offset: f64| ((3.0 * t - offset).clamp(0.0, 1.0) * 255.0) as u8;
+                Rgba([channel(0.0), channel(1.0), channel(2.0), MAX_ALPHA])
+            },
+            size,
+        );
+        paint(img.as_mut(), size.1, &rects, outline_pixel);
+    }
+
+    pub fn render_rgb(&self, outline: Option<RGB<u8>>, scale: f64) -> RgbImage {
+        self.render(
+            |color| Rgb([color.r as u8, color.g as u8, color.b as u8]),
+            outline,
+            self.scaled_dimensions(scale),
+        )
+    }
+}
+
+impl Tree {
+    /// rebuild a tree saved by `serialize`, with what was saved along with
+    /// it. it is already finalized, and the error of each region isn't saved
+    /// so heatmaps of it are blank
+    pub fn deserialize(mut r: impl Read) -> Result<LoadedTree, qt::Error> {
+        let mut data = Vec::new();
+        if r.read_to_end(&mut data).is_err() {
+            return Err(qt::Error::Unreadable);
+        }
+        let qt::Parts {
+            header,
+            writer,
+            tree: payload,
+            residual,
+        } = qt::read(&data)?;
+        let split = Split::from_qt_flags(header.flags);
+        let dimensions = (header.height as usize, header.width as usize);
+        let root = Node::frozen((0, 0), (dimensions.0 - 1, dimensions.1 - 1), 0);
+        let mut loader = Loader {
+            nodes: vec![root],
+            node_count: 1 + split.children() * header.splits as usize,
+            split,
+        };
+        if header.flags & qt::ENTROPY_CODED != 0 {
+            loader.read_entropy_coded(payload)?;
+        } else {
+            loader.read_packed(&header, payload)?;
+            fill_averages(&mut loader.nodes);
+        }
+        let Loader {
+            nodes, node_count, ..
+        } = loader;
+        if nodes.len() != node_count {
+            let arity = split.children();
+            return Err(qt::Error::Corrupt(format!(
+                "it has {} splits but its header says {}",
+                (nodes.len() - 1) / arity,
+                (node_count - 1) / arity
+            )));
+        }
+        let residual = residual
+            .map(|png| residual::decode(png, header.width, header.height))
+            .transpose()?;
+        Ok(LoadedTree {
+            tree: Self::frozen(nodes, dimensions, split),
+            residual,
+            writer: writer.map(String::from),
+        })
+    }
+
+    /// rebuild a tree from json written by `export_json`. the leaves have to
+    /// be in the same order and cover the same regions, which follow from
+    /// their depths, so only trees split through the middle can be loaded.
+    /// json without a split in its header is from a quad tree
+    pub fn from_json(json: &Json) -> Result<Self, String> {
+        let Some(header) = json.get("header") else {
+            return Err("json has no header".into());
+        };
+        let dimension = |key: &str| match header.get(key).and_then(Json::as_u64) {
+            Some(n) if n > 0 => Ok(n),
+            _ => Err(format!("json header has no valid {key}")),
+        };
+        let (width, height) = (dimension("width")?, dimension("height")?);
+        if width.saturating_mul(height) > MAX_INPUT_PIXELS {
+            return Err(format!(
+                "{width}x{height} in the json is larger than any image comprs can compress"
+            ));
+        }
+        let split = match header.get("split") {
+            None => Split::Midpoint,
+            Some(s) => match s.as_str().map(Split::parse) {
+                Some(Ok(split)) => split,
+                _ => return Err("json header has no valid split".into()),
+            },
+        };
+        if split.is_adaptive() {
+            return Err(format!(
+                "json of a tree made with -split {} can't be loaded, where its regions were split isn't saved. use -encode instead",
+                split.name()
+            ));
+        }
+        let Some(leaves) = json.get("leaves").and_then(Json::as_array) else {
+            return Err("json has no leaves".into());
+        };
+
+        let dimensions = (height as usize, width as usize);
+        let mut nodes = vec![Node::frozen(
+            (0, 0),
+            (dimensions.0 - 1, dimensions.1 - 1),
+            0,
+        )];
+        let mut leaves = leaves.iter().enumerate().peekable();
+        let mut stack = vec![0];
+        while let Some(cur) = stack.pop() {
+            let Some(&(k, leaf)) = leaves.peek() else {
+                return Err("json leaves don't cover the whole image".into());
+            };
+            let field = |key: &str| {
+                let value = leaf.get(key).and_then(Json::as_u64);
+                value.ok_or_else(|| format!("json leaf {k} has no valid {key}"))
+            };
+            let leaf_depth = field("depth")?;
+            if leaf_depth > nodes[cur].depth as u64 {
+                if !nodes[cur].can_split(split) {
+                    return Err(format!(
+                        "json leaf {k} is deeper than its region can be split"
+                    ));
+                }
+                let cut = nodes[cur].fixed_cut(split);
+                let children = split_frozen(&mut nodes, cur, cut);
+                stack.extend(children.indexes().rev());
+                continue;
+            }
+
+            let node = &mut nodes[cur];
+            let bounds = [field("x")?, field("y")?, field("w")?, field("h")?];
+            let expected = [
+                node.top_left.1,
+                node.top_left.0,
+                node.width() as usize + 1,
+                node.height() as usize + 1,
+            ];
+            if leaf_depth < node.depth as u64
+                || bounds.iter().zip(expected).any(|(&a, b)| a != b as u64)
+            {
+                return Err(format!(
+                    "json leaf {k} isn't where the leaves before it say it should be"
+                ));
+            }
+            let color = leaf.get("color").and_then(Json::as_str);
+            let Some(rgb) = color.and_then(|c| c.strip_prefix('#')).and_then(parse_hex) else {
+                return Err(format!("json leaf {k} has no valid color"));
+            };
+            node.average = rgb.into();
+            leaves.next();
+        }
+        if let Some((k, _)) = leaves.next() {
+            return Err(format!(
+                "json leaf {k} is outside the image, every region is covered before it"
+            ));
+        }
+        fill_averages(&mut nodes);
+        Ok(Self::frozen(nodes, dimensions, split))
+    }
+
+    /// change the pixel at column `x` and row `y` of the image the tree is
+    /// refined from to `color`. every node covering it takes its color and
+    /// error from the changed image, so the leaf is rendered in its new
+    /// color and refining goes on from its new error. where nodes were
+    /// already split doesn't change
+    pub fn paint(&mut self, x: usize, y: usize, color: RGB<u8>) -> Result<(), String> {
+        let Some(image_data) = &mut self.image_data else {
+            return Err("tree has been finalized, it can't be painted".into());
+        };
+        let Some(image_data) = Arc::get_mut(image_data) else {
+            return Err("other trees are refined from the same image, it can't be painted".into());
+        };
+        image_data.update_pixel(y, x, color)?;
+        let mut cur = 0;
+        loop {
+            let node = &mut self.nodes[cur];
+            node.average = image_data.average(node.top_left, node.bottom_right);
+            node.metric = image_data.metric(node.top_left, node.bottom_right);
+            let Some(children) = node.children else {
+                break;
+            };
+            // children cover their parent exactly, so one always matches
+            cur = children
+                .indexes()
+                .find(|&c| {
+                    let node = &self.nodes[c];
+                    (node.top_left.0..=node.bottom_right.0).contains(&y)
+                        && (node.top_left.1..=node.bottom_right.1).contains(&x)
+                })
+                .unwrap();
+        }
+        // the queue has the leaf under its old error
+        self.pq.retain(|entry| entry.node_index != cur);
+        self.pq.push(OrdNode::new(&self.nodes, cur));
+        // the pixel is in one leaf, but the image changed under all of them
+        self.recount_squared_error();
+        Ok(())
+    }
+}
+
+fn scaled_dimensions((h, w): (usize, usize), scale: f64) -> (usize, usize) {
+    let scaled = |n: usize| ((n as f64 * scale).round() as usize).clamp(1, n);
+    (scaled(h), scaled(w))
+}
+
+/// output rectangles of `regions` of an image of `dimensions`, given as
+/// (top left, bottom right, pixel), for an image of `size` (height, width).
+/// each output pixel takes the color of the region under it, so scaling down
+/// never touches a full resolution buffer
+fn scaled_rects<T>(
+    dimensions: (usize, usize),
+    size: (usize, usize),
+    regions: impl Iterator<Item = ((usize, usize), (usize, usize), T)>,
+) -> Vec<Rect<T>> {
+    let (h, w) = dimensions;
+    let (sh, sw) = size;
+    // first output pixel whose sample lands on or after source pixel `n`
+    let to_y = |n: usize| (n * sh).div_ceil(h);
+    let to_x = |n: usize| (n * sw).div_ceil(w);
+
+    let mut ret = Vec::new();
+    for (top_left, bottom_right, pixel) in regions {
+        let (start_y, start_x) = (to_y(top_left.0), to_x(top_left.1));
+        let (end_y, end_x) = (to_y(bottom_right.0 + 1), to_x(bottom_right.1 + 1));
+        if start_y == end_y || start_x == end_x {
+            // too small to cover any output pixel
+            continue;
+        }
+        ret.push(Rect {
+            top_left: (start_y, start_x),
+            bottom_right: (end_y - 1, end_x - 1),
+            pixel,
+        });
+    }
+    ret
+}
+
+/// split `nodes[cur]` of a tree loaded from a file at `cut`
+fn split_frozen(nodes: &mut Vec<Node>, cur: usize, cut: Cut) -> NodeChildren {
+    let bounds = nodes[cur].child_bounds(cut);
+    let children = NodeChildren {
+        first: nodes.len(),
+        count: bounds.as_slice().len(),
+    };
+    let depth = nodes[cur].depth + 1;
+    for &(top_left, bottom_right) in bounds.as_slice() {
+        nodes.push(Node::frozen(top_left, bottom_right, depth));
+    }
+    nodes[cur].children = Some(children);
+    children
+}
+
+/// give every node that has children the average of their colors, weighted
+/// by their areas. for loaders of files that only have the colors of leaves,
+/// which push children after their parents
+fn fill_averages(nodes: &mut [Node]) {
+    for i in (0..nodes.len()).rev() {
+        let Some(children) = nodes[i].children else {
+            continue;
+        };
+        let sum = children
+            .indexes()
+            .map(|c| {
+                let area = nodes[c].area();
+                let avg = nodes[c].average;
+                RGB::new(avg.r * area, avg.g * area, avg.b * area)
+            })
+            .fold(RGB::new(0, 0, 0), |a, b| a + b);
+        nodes[i].average = sum / nodes[i].area();
+    }
+}
+
+/// the nodes of a tree being read from a .qt file
+struct Loader {
+    nodes: Vec<Node>,
+    /// how many nodes the header says there are
+    node_count: usize,
+    split: Split,
+}
+
+impl Loader {
+    /// split `cur` where `code` says, or through the middle if splits aren't
+    /// adaptive, after checking the file is allowed to split it
+    fn split(&mut self, cur: usize, code: Option<u8>) -> Result<NodeChildren, qt::Error> {
+        let split = self.split;
+        if self.nodes.len() + split.children() > self.node_count {
+            return Err(self.too_many_splits());
+        }
+        let node = &self.nodes[cur];
+        if !node.can_split(split) {
+            return Err(qt::Error::Corrupt(
+                "a region too small to split was split".into(),
+            ));
+        }
+        let cut = match code {
+            Some(code) => node.cut_from_code(code, split.is_binary()),
+            None => Some(node.fixed_cut(split)),
+        };
+        let Some(cut) = cut else {
+            return Err(qt::Error::Corrupt(
+                "a region is split in an unknown place".into(),
+            ));
+        };
+        Ok(split_frozen(&mut self.nodes, cur, cut))
+    }
+
+    fn too_many_splits(&self) -> qt::Error {
+        qt::Error::Corrupt(format!(
+            "it has more than the {} splits in its header",
+            (self.node_count - 1) / self.split.children()
+        ))
+    }
+
+    /// read a packed payload, which only has the colors of leaves
+    fn read_packed(&mut self, header: &qt::Header, payload: &[u8]) -> Result<(), qt::Error> {
+        // `qt::read` checked the payload holds exactly these
+        let (structure_len, codes_len, _) = header.payload_parts();
+        let (structure, rest) = payload.split_at(structure_len as usize);
+        let (split_codes, colors) = rest.split_at(codes_len as usize);
+        let mut bits = qt::BitReader::new(structure);
+        let mut split_codes = split_codes.iter();
+        let mut colors = colors.chunks_exact(3);
+
+        self.nodes.reserve(self.node_count - 1);
+        let mut stack = vec![0];
+        while let Some(cur) = stack.pop() {
+            // the length check leaves enough bits and colors for every node
+            // of as many splits as the header has, and `split` stops at those
+            if !bits.next().unwrap() {
+                let c = colors.next().unwrap();
+                self.nodes[cur].average = RGB::new(c[0], c[1], c[2]).into();
+                continue;
+            }
+            // but the code of a split comes before it
+            let code = if self.split.is_adaptive() {
+                let Some(&code) = split_codes.next() else {
+                    return Err(self.too_many_splits());
+                };
+                Some(code)
+            } else {
+                None
+            };
+            let children = self.split(cur, code)?;
+            stack.extend(children.indexes().rev());
+        }
+        Ok(())
+    }
+
+    /// read a range coded payload, which has the color of every node
+    fn read_entropy_coded(&mut self, payload: &[u8]) -> Result<(), qt::Error> {
+        let mut dec = RangeDecoder::new(payload);
+        let mut models = PayloadModels::new(self.split);
+        let root = models.colors.decode(&mut dec, RGB::new(0, 0, 0), 0);
+        self.nodes[0].average = root.into();
+        let mut stack = vec![0];
+        while let Some(cur) = stack.pop() {
+            // a truncated payload decodes as zeros, stop before they pile up
+            if dec.overrun() {
+                return Err(qt::Error::Truncated);
+            }
+            if models.split_model(self.nodes[cur].depth).decode(&mut dec) == 0 {
+                continue;
+            }
+            let code = self
+                .split
+                .is_adaptive()
+                .then(|| models.cut.decode(&mut dec) as u8);
+            let children = self.split(cur, code)?;
+            for (k, c) in children.indexes().enumerate() {
+                let siblings = &self.nodes[children.indexes()];
+                let (pred, kind) = predict_child(&self.nodes[cur], siblings, k);
+                self.nodes[c].average = models.colors.decode(&mut dec, pred, kind).into();
+            }
+            stack.extend(children.indexes().rev());
+        }
+        if dec.overrun() {
+            return Err(qt::Error::Truncated);
+        }
+        if dec.has_trailing_data() {
+            return Err(qt::Error::TrailingData);
+        }
+        Ok(())
+    }
+}
+
+/// the models of everything in a range coded payload, which the writer and
+/// the reader have to use in the same order
+struct PayloadModels {
+    /// whether a node was split, one per depth up to `SPLIT_DEPTHS`
+    splits: Vec<Model>,
+    cut: Model,
+    colors: ColorModel,
+}
+
+/// nodes deeper than this share a model for whether they were split
+const SPLIT_DEPTHS: usize = 16;
+
+impl PayloadModels {
+    fn new(split: Split) -> Self {
+        Self {
+            splits: (0..SPLIT_DEPTHS).map(|_| Model::new(2)).collect(),
+            cut: Model::new(split.cut_codes()),
+            colors: ColorModel::new(),
+        }
+    }
+
+    fn split_model(&mut self, depth: u32) -> &mut Model {
+        &mut self.splits[(depth as usize).min(SPLIT_DEPTHS - 1)]
+    }
+}
+
+/// the prediction the `k`th of `siblings` is coded against, and its kind.
+/// every child but the last is predicted to be its parent's color. the last
+/// is predicted to be the color that makes the average of all of them the
+/// parent's, which only needs the colors of the siblings before it
+fn predict_child(parent: &Node, siblings: &[Node], k: usize) -> (RGB<u8>, usize) {
+    if k + 1 < siblings.len() {
+        return (rgb8(parent.average), 0);
+    }
+    let (last, before) = siblings.split_last().unwrap();
+    let channel = |f: fn(RGB<u64>) -> u64| {
+        let total = (f(parent.average) * parent.area()) as i128;
+        let rest: i128 = before
+            .iter()
+            .map(|c| (f(c.average) * c.area()) as i128)
+            .sum();
+        (total - rest).div_euclid(last.area() as i128).clamp(0, 255) as u8
+    };
+    let pred = RGB::new(channel(|c| c.r), channel(|c| c.g), channel(|c| c.b));
+    (pred, 1)
+}
+
+fn rgb8(color: RGB<u64>) -> RGB<u8> {
+    RGB::new(color.r as u8, color.g as u8, color.b as u8)
+}
+
+/// the squared error of rendering `node` in its color over `image_data`
+fn leaf_error(image_data: &impl RegionSource, node: &Node) -> u128 {
+    image_data.squared_error(node.top_left, node.bottom_right, rgb8(node.average))
+}
+
+/// `rrggbb` as a color
+fn parse_hex(hex: &str) -> Option<RGB<u8>> {
+    if hex.len() != 6 {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+    Some(RGB::new(channel(0)?, channel(2)?, channel(4)?))
+}
+
+fn rgba_pixel(color: RGB<u64>) -> Rgba<u8> {
+    Rgba([color.r as u8, color.g as u8, color.b as u8, MAX_ALPHA])
+}
+
+/// an rgba render that is kept between frames, so only the parts of the tree
+/// that were split since the last update are painted again
+pub struct Canvas {
+    img: RgbaImage,
+    outline: Option<RGB<u8>>,
+    /// nodes that existed at the last update
+    painted: usize,
+}
+
+impl Canvas {
+    pub fn new(tree: &Tree, outline: Option<RGB<u8>>, scale: f64) -> Self {
+        let (h, w) = tree.scaled_dimensions(scale);
+        Self {
+            img: RgbaImage::new(w as u32, h as u32),
+            outline,
+            painted: 0,
+        }
+    }
+
+    /// bring the canvas up to date with `tree`, which must be the tree it was
+    /// created with. nodes are only ever appended and a split node's children
+    /// cover it, so painting the new leaves is the same as a full render_rgba
+    pub fn update(&mut self, tree: &Tree) -> &RgbaImage {
+        let size = (self.img.height() as usize, self.img.width() as usize);
+        let new_leaves =
+            (self.painted..tree.nodes.len()).filter(|&i| tree.nodes[i].children.is_none());
+        let rects = tree.leaf_rects(new_leaves, |node| rgba_pixel(node.average), size);
+        let outline_pixel = self.outline.map(|c| rgba_pixel(c.into()));
+        paint(self.img.as_mut(), size.1, &rects, outline_pixel);
+        self.painted = tree.nodes.len();
+        &self.img
+    }
+}
+
+/// a leaf in output pixel coordinates, inclusive
+struct Rect<T> {
+    top_left: (usize, usize),
+    bottom_right: (usize, usize),
+    pixel: T,
+}
+
+/// paint `rects` into the rows of `samples`, an image `width` pixels wide
+#[cfg(not(feature = "rayon"))]
+fn paint<T>(samples: &mut [u8], width: usize, rects: &[Rect<T>], outline_pixel: Option<T>)
+where
+    T: Pixel<Subpixel = u8>,
+{
+    paint_band(samples, 0, width, rects, outline_pixel);
+}
+
+/// paint `rects` into the rows of `samples`, an image `width` pixels wide.
+/// leaves never overlap, so disjoint bands of rows are painted in parallel
+#[cfg(feature = "rayon")]
+fn paint<T>(samples: &mut [u8], width: usize, rects: &[Rect<T>], outline_pixel: Option<T>)
+where
+    T: Pixel<Subpixel = u8> + Send + Sync,
+{
+    use rayon::prelude::*;
+
+    let row_len = width * T::CHANNEL_COUNT as usize;
+    if row_len == 0 {
+        return;
+    }
+    let height = samples.len() / row_len;
+    let band = height.div_ceil(rayon::current_num_threads()).max(1);
+    samples
+        .par_chunks_mut(band * row_len)
+        .enumerate()
+        .for_each(|(i, rows)| paint_band(rows, i * band, width, rects, outline_pixel));
+}
+
+/// paint the parts of `rects` that fall in `samples`, which holds whole rows
+/// of the image starting at row `first_row`
+fn paint_band<T>(
+    samples: &mut [u8],
+    first_row: usize,
+    width: usize,
+    rects: &[Rect<T>],
+    outline_pixel: Option<T>,
+) where
+    T: Pixel<Subpixel = u8>,
+{
+    let channels = T::CHANNEL_COUNT as usize;
+    let rows = samples.len() / (width * channels);
+    let last_row = first_row + rows;
+    let mut fill_row = |y: usize, start_x: usize, end_x: usize, pixel: T| {
+        if !(first_row..last_row).contains(&y) {
+            return;
+        }
+        let row_start = (y - first_row) * width;
+        let row =
+            &mut samples[(row_start + start_x) * channels..(row_start + end_x + 1) * channels];
+        for dest in row.chunks_exact_mut(channels) {
+            dest.copy_from_slice(pixel.channels());
+        }
+    };
+
+    for rect in rects {
+        let (start_y, start_x) = rect.top_left;
+        let (end_y, end_x) = rect.bottom_right;
+        if end_y < first_row || start_y >= last_row {
+            continue;
+        }
+        let rows = start_y.max(first_row)..=end_y.min(last_row - 1);
+        for y in rows.clone() {
+            fill_row(y, start_x, end_x, rect.pixel);
+        }
+
+        if let Some(p) = outline_pixel {
+            fill_row(start_y, start_x, end_x, p);
+            fill_row(end_y, start_x, end_x, p);
+            for y in rows {
+                fill_row(y, start_x, start_x, p);
+                fill_row(y, end_x, end_x, p);
+            }
+        }
+    }
+}
+
+/// memory added to the node arena and queue by one `split`
+pub fn bytes_per_split(split: Split) -> usize {
+    let children = split.children();
+    children * std::mem::size_of::<Node>() + (children - 1) * std::mem::size_of::<OrdNode>()
+}
+
+/// convert an image to RGBA with the same alpha that `Canvas` renders with
+pub fn to_rgba(img: &RgbImage) -> RgbaImage {
+    let (w, h) = img.dimensions();
+    RgbaImage::from_fn(w, h, |x, y| {
+        let Rgb([r, g, b]) = *img.get_pixel(x, y);
+        Rgba([r, g, b, MAX_ALPHA])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use image::imageops;
+
+    use super::*;
+
+    const SPLITS: [Split; 5] = [
+        Split::Midpoint,
+        Split::Adaptive,
+        Split::Kd,
+        Split::KdAdaptive,
+        Split::Grid9,
+    ];
+
+    /// a width x height image with edges and gradients, so splits have
+    /// something to find
+    fn pattern(width: u32, height: u32) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, y| {
+            let edge = if x * 3 > width + y { 200 } else { 20 };
+            Rgb([edge, (x * 11 + y * 5) as u8, ((x ^ y) * 7) as u8])
+        })
+    }
+
+    /// refine `tree` up to `iterations` times, and what each call did
+    fn refine<S: RegionSource>(tree: &mut Tree<S>, iterations: u32) -> Vec<Refined> {
+        (0..iterations).map(|_| tree.refine().unwrap()).collect()
+    }
+
+    #[test]
+    fn trees_over_views_match_trees_over_cropped_copies() {
+        let colors = pattern(40, 33);
+        let data = ImageData::from_rgb8(&colors).unwrap();
+        for (x, y, w, h) in [
+            (0, 0, 40, 33),
+            (7, 3, 1, 1),
+            (2, 30, 37, 1),
+            (11, 5, 29, 23),
+        ] {
+            let view = data.view((y, x), (y + h - 1, x + w - 1)).unwrap();
+            let crop = imageops::crop_imm(&colors, x as u32, y as u32, w as u32, h as u32);
+            let crop = ImageData::from_rgb8(&crop.to_image()).unwrap();
+            for split in SPLITS {
+                let mut ours = Tree::with_capacity_hint(view, 60).with_split(split);
+                let mut theirs =
+                    Tree::with_capacity_hint(crop.view((0, 0), (h - 1, w - 1)).unwrap(), 60)
+                        .with_split(split);
+                let context = format!("{w}x{h} at {x},{y} with {}", split.name());
+                assert_eq!(refine(&mut ours, 60), refine(&mut theirs, 60), "{context}");
+                assert_eq!(ours.mse(), theirs.mse(), "{context}");
+                assert_eq!(ours.leaf_count(), theirs.leaf_count(), "{context}");
+                assert!(ours.diff(&theirs).unwrap().is_empty(), "{context}");
+                assert_eq!(
+                    ours.render_rgb(None, 1.0),
+                    theirs.render_rgb(None, 1.0),
+                    "{context}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn trees_over_views_match_trees_over_owned_copies() {
+        // the cropped copy refined as an image of its own, not a view of it
+        let colors = pattern(40, 33);
+        let data = ImageData::from_rgb8(&colors).unwrap();
+        let view = data.view((5, 11), (27, 39)).unwrap();
+        let crop = imageops::crop_imm(&colors, 11, 5, 29, 23).to_image();
+        let mut ours = Tree::with_capacity_hint(view, 40);
+        let mut theirs = Tree::with_capacity_hint(ImageData::from_rgb8(&crop).unwrap(), 40);
+        assert_eq!(refine(&mut ours, 40), refine(&mut theirs, 40));
+        assert_eq!(ours.render_rgb(None, 1.0), theirs.render_rgb(None, 1.0));
+        assert_eq!(ours.mse(), theirs.mse());
+    }
+
+    /// a render with a `put_pixel` for every pixel instead of filling rows:
+    /// every leaf a pixel at a time down each column in the color `color`
+    /// gives it, then its outline
+    fn put_pixel_render<S: RegionSource>(
+        tree: &Tree<S>,
+        outline: Option<RGB<u8>>,
+        color: impl Fn(&Node) -> RGB<u64>,
+    ) -> RgbImage {
+        let (h, w) = tree.dimensions;
+        let mut ret = RgbImage::new(w as u32, h as u32);
+        let pixel = |c: RGB<u64>| Rgb([c.r as u8, c.g as u8, c.b as u8]);
+        for i in tree.leaves() {
+            let node = &tree.nodes[i];
+            let (start_y, start_x) = node.top_left;
+            let (end_y, end_x) = node.bottom_right;
+            let fill = pixel(color(node));
+            for x in start_x..=end_x {
+                for y in start_y..=end_y {
+                    ret.put_pixel(x as u32, y as u32, fill);
+                }
+            }
+            if let Some(p) = outline.map(|c| pixel(c.into())) {
+                for y in [start_y, end_y] {
+                    for x in start_x..=end_x {
+                        ret.put_pixel(x as u32, y as u32, p);
+                    }
+                }
+                for x in [start_x, end_x] {
+                    for y in start_y..=end_y {
+                        ret.put_pixel(x as u32, y as u32, p);
+                    }
+                }
+            }
+        }
+        ret
+    }
+
+    #[test]
+    fn row_fills_match_put_pixel_renders() {
+        let colors = pattern(40, 33);
+        for split in SPLITS {
+            let data = ImageData::from_rgb8(&colors).unwrap();
+            let mut tree = Tree::with_capacity_hint(data, 80).with_split(split);
+            for splits in [0, 1, 5, 20, 80] {
+                let more = splits - tree.splits() as u32;
+                refine(&mut tree, more);
+                for outline in [None, Some(RGB::new(255, 0, 128))] {
+                    let context = format!("{} after {splits} splits, {outline:?}", split.name());
+                    let expected = put_pixel_render(&tree, outline, |node| node.average);
+                    assert_eq!(tree.render_rgb(outline, 1.0), expected, "{context}");
+                }
+            }
+        }
+    }
+
+    /// bands of rows follow the number of threads, and leaves and outlines
+    /// cross their edges, so every split of the rows has to paint the same
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn parallel_paints_match_serial_on_any_thread_count() {
+        let colors = pattern(40, 33);
+        let pool = |threads| {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap()
+        };
+        let serial = pool(1);
+        for split in SPLITS {
+            let data = ImageData::from_rgb8(&colors).unwrap();
+            let mut tree = Tree::with_capacity_hint(data, 60).with_split(split);
+            refine(&mut tree, 60);
+            for outline in [None, Some(RGB::new(255, 0, 128))] {
+                let expected = put_pixel_render(&tree, outline, |node| node.average);
+                for scale in [1.0, 0.3, 2.5] {
+                    let one = serial.install(|| tree.render_rgb(outline, scale));
+                    let heatmap = serial.install(|| tree.render_heatmap(outline, scale));
+                    for threads in [2, 3, 8, 64] {
+                        let context = format!("{} {outline:?} x{scale} on {threads}", split.name());
+                        let pool = pool(threads);
+                        let render = pool.install(|| tree.render_rgb(outline, scale));
+                        if scale == 1.0 {
+                            assert_eq!(render, expected, "{context}");
+                        }
+                        assert_eq!(render, one, "{context}");
+                        let parallel = pool.install(|| tree.render_heatmap(outline, scale));
+                        assert_eq!(parallel, heatmap, "{context}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn canvas_frames_match_full_renders() {
+        let colors = pattern(40, 33);
+        for split in SPLITS {
+            for outline in [None, Some(RGB::new(255, 0, 128))] {
+                for scale in [1.0, 0.5, 3.0] {
+                    let data = ImageData::from_rgb8(&colors).unwrap();
+                    let mut tree = Tree::with_capacity_hint(data, 100).with_split(split);
+                    let mut canvas = Canvas::new(&tree, outline, scale);
+                    // frames a split apart and frames many splits apart
+                    for (frame, delta) in [0, 1, 1, 3, 10, 25, 60].into_iter().enumerate() {
+                        refine(&mut tree, delta);
+                        let context =
+                            format!("{} {outline:?} x{scale} frame {frame}", split.name());
+                        let full = to_rgba(&tree.render_rgb(outline, scale));
+                        assert_eq!(canvas.update(&tree), &full, "{context}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn cached_averages_render_like_queried_ones() {
+        let colors = pattern(40, 33);
+        let image = ImageData::from_rgb8(&colors).unwrap();
+        let queried = |node: &Node| image.average(node.top_left, node.bottom_right);
+        for split in SPLITS {
+            let data = ImageData::from_rgb8(&colors).unwrap();
+            let mut tree = Tree::with_capacity_hint(data, 90).with_split(split);
+            for (step, splits) in [0, 1, 10, 40].into_iter().enumerate() {
+                refine(&mut tree, splits);
+                let context = format!("{} step {step}", split.name());
+                for i in tree.leaves() {
+                    assert_eq!(tree.nodes[i].average, queried(&tree.nodes[i]), "{context}");
+                }
+                let expected = put_pixel_render(&tree, None, queried);
+                assert_eq!(tree.render_rgb(None, 1.0), expected, "{context}");
+            }
+            tree.undo(20);
+            let expected = put_pixel_render(&tree, None, queried);
+            assert_eq!(
+                tree.render_rgb(None, 1.0),
+                expected,
+                "{} undone",
+                split.name()
+            );
+            // the image data is gone, the averages aren't
+            tree.finalize();
+            assert_eq!(
+                tree.render_rgb(None, 1.0),
+                expected,
+                "{} finalized",
+                split.name()
+            );
+        }
+    }
+
+    #[test]
+    fn finalized_trees_render_the_same_and_refuse_refine() {
+        let colors = pattern(40, 33);
+        for split in SPLITS {
+            let data = ImageData::from_rgb8(&colors).unwrap();
+            let mut tree = Tree::with_capacity_hint(data, 50).with_split(split);
+            refine(&mut tree, 50);
+            let (render, heatmap) = (tree.render_rgb(None, 1.0), tree.render_heatmap(None, 1.0));
+            let (leaves, stats) = (tree.leaf_count(), tree.stats());
+
+            tree.finalize();
+            assert_eq!(
+                tree.refine(),
+                Err(RefineError::Finalized),
+                "{}",
+                split.name()
+            );
+            assert_eq!(tree.render_rgb(None, 1.0), render, "{}", split.name());
+            assert_eq!(tree.render_heatmap(None, 1.0), heatmap, "{}", split.name());
+            assert_eq!(tree.leaf_count(), leaves);
+            assert_eq!(tree.stats().leaves, stats.leaves);
+            assert_eq!(tree.mse(), None);
+            // still refused after trying, and nothing changed
+            assert_eq!(tree.refine(), Err(RefineError::Finalized));
+            assert_eq!(tree.leaf_count(), leaves);
+        }
+        assert_eq!(
+            RefineError::Finalized.to_string(),
+            "tree has been finalized, it can't be refined"
+        );
+    }
+
+    /// how much room each array of `tree` has
+    fn capacities<S>(tree: &Tree<S>) -> [usize; 4] {
+        [
+            tree.nodes.capacity(),
+            tree.leaf_slots.capacity(),
+            tree.leaves.capacity(),
+            tree.pq.capacity(),
+        ]
+    }
+
+    /// refine until `iterations` splits are made or none can be, and check
+    /// no array had to grow on the way
+    fn refine_in_place<S: RegionSource>(tree: &mut Tree<S>, iterations: u32, context: &str) {
+        let room = capacities(tree);
+        for i in 0..iterations {
+            if tree.refine().unwrap() == Refined::Exhausted {
+                break;
+            }
+            assert_eq!(capacities(tree), room, "{context} after {} splits", i + 1);
+        }
+    }
+
+    #[test]
+    fn capacity_hints_leave_room_for_every_split() {
+        let colors = pattern(40, 33);
+        for split in SPLITS {
+            let data = ImageData::from_rgb8(&colors).unwrap();
+            let mut tree = Tree::with_capacity_hint(data, 80).with_split(split);
+            refine_in_place(&mut tree, 80, split.name());
+            assert_eq!(tree.splits(), 80, "{}", split.name());
+        }
+    }
+
+    #[test]
+    fn capacity_hints_are_capped_by_the_image_size() {
+        // far more splits than a 4x4 image can take, which would be hundreds
+        // of gigabytes if the hint were taken as it is
+        let colors = pattern(4, 4);
+        for split in SPLITS {
+            let data = ImageData::from_rgb8(&colors).unwrap();
+            let mut tree = Tree::with_capacity_hint(data, 2_000_000_000).with_split(split);
+            // a tree of leaves of at least a pixel has fewer than two nodes
+            // for every pixel, whatever the split
+            assert!(
+                tree.nodes.capacity() < 2 * 16,
+                "{} has room for {} nodes",
+                split.name(),
+                tree.nodes.capacity()
+            );
+            refine_in_place(&mut tree, u32::MAX, split.name());
+            assert!(tree.leaf_count() <= 16);
+        }
+    }
+
+    #[test]
+    fn resumed_trees_cap_their_hint_and_leave_room() {
+        let colors = pattern(12, 9);
+        for split in SPLITS {
+            let data = ImageData::from_rgb8(&colors).unwrap();
+            let mut tree = Tree::with_capacity_hint(data, 5).with_split(split);
+            refine(&mut tree, 5);
+            let mut saved = Vec::new();
+            tree.serialize(&mut saved, false, None).unwrap();
+
+            let loaded = Tree::deserialize(saved.as_slice()).unwrap().tree;
+            let data = ImageData::from_rgb8(&colors).unwrap();
+            let mut tree = loaded.resume(data, u32::MAX).unwrap();
+            let leaves = 108 - tree.leaf_count();
+            let most = tree.nodes.len() + leaves / (split.children() - 1) * split.children();
+            assert!(tree.nodes.capacity() <= most, "{}", split.name());
+            refine_in_place(&mut tree, u32::MAX, split.name());
+        }
+    }
+
+    /// the leaves and their depths found by walking the tree breadth first
+    /// from the root, the way renders found them before the leaf list
+    fn bfs_leaves<S>(tree: &Tree<S>) -> Vec<(usize, u32)> {
+        let mut ret = Vec::new();
+        let mut queue = VecDeque::from([(0, 0)]);
+        while let Some((cur, depth)) = queue.pop_front() {
+            match tree.nodes[cur].children {
+                Some(children) => queue.extend(children.indexes().map(|c| (c, depth + 1))),
+                None => ret.push((cur, depth)),
+            }
+        }
+        ret
+    }
+
+    /// the leaf list holds exactly the leaves a walk finds, one per split
+    /// more than the root, and each knows where it is in the list
+    fn assert_leaf_list<S: RegionSource>(tree: &Tree<S>, context: &str) {
+        let walked = bfs_leaves(tree);
+        let mut expected: Vec<usize> = walked.iter().map(|&(i, _)| i).collect();
+        let mut listed = tree.leaves.clone();
+        expected.sort_unstable();
+        listed.sort_unstable();
+        assert_eq!(listed, expected, "{context}");
+
+        let mut splits = 0;
+        let mut queue = VecDeque::from([0]);
+        while let Some(cur) = queue.pop_front() {
+            if let Some(children) = tree.nodes[cur].children {
+                splits += 1;
+                queue.extend(children.indexes());
+            }
+        }
+        let added = tree.split.children() - 1;
+        assert_eq!(tree.leaf_count(), 1 + added * splits, "{context}");
+        for (slot, &i) in tree.leaves.iter().enumerate() {
+            assert_eq!(tree.leaf_slots[i], slot, "{context}");
+        }
+        for &(i, depth) in &walked {
+            assert_eq!(tree.nodes[i].depth, depth, "{context}");
+        }
+    }
+
+    /// `stats` against the same numbers counted from a walk of the tree
+    fn assert_stats<S: RegionSource>(tree: &Tree<S>, context: &str) {
+        let walked = bfs_leaves(tree);
+        let stats = tree.stats();
+        let depths = walked.iter().map(|&(_, depth)| depth);
+        let mut areas = Vec::new();
+        for &(i, _) in &walked {
+            let bucket = tree.nodes[i].area().ilog2() as usize;
+            areas.resize(areas.len().max(bucket + 1), 0);
+            areas[bucket] += 1;
+        }
+        let splits = (walked.len() - 1) / (tree.split.children() - 1);
+        assert_eq!(stats.leaves, walked.len(), "{context}");
+        assert_eq!(stats.nodes, walked.len() + splits, "{context}");
+        assert_eq!(stats.max_depth, depths.clone().max().unwrap(), "{context}");
+        let mean = depths.map(u64::from).sum::<u64>() as f64 / walked.len() as f64;
+        assert_eq!(stats.mean_depth, mean, "{context}");
+        assert_eq!(stats.leaf_areas, areas, "{context}");
+        let error: u64 = walked.iter().map(|&(i, _)| tree.nodes[i].metric).sum();
+        assert_eq!(stats.error, error, "{context}");
+    }
+
+    #[test]
+    fn leaf_list_matches_a_walk_of_the_tree() {
+        let colors = pattern(40, 33);
+        for split in SPLITS {
+            let name = split.name();
+            let data = ImageData::from_rgb8(&colors).unwrap();
+            let mut tree = Tree::with_capacity_hint(data, 120).with_split(split);
+            assert_leaf_list(&tree, name);
+            for i in 0..120 {
+                tree.refine().unwrap();
+                assert_leaf_list(&tree, &format!("{name} after {} splits", i + 1));
+                assert_stats(&tree, &format!("{name} after {} splits", i + 1));
+            }
+            tree.undo(17);
+            assert_leaf_list(&tree, &format!("{name} after undo"));
+            assert_stats(&tree, &format!("{name} after undo"));
+            tree.prune(9);
+            assert_leaf_list(&tree, &format!("{name} after prune"));
+            assert_stats(&tree, &format!("{name} after prune"));
+            refine(&mut tree, 10);
+            assert_leaf_list(&tree, &format!("{name} refined after prune"));
+            tree.paint(3, 5, RGB::new(255, 0, 0)).unwrap();
+            assert_leaf_list(&tree, &format!("{name} after paint"));
+            tree.finalize();
+            assert_leaf_list(&tree, &format!("{name} after finalize"));
+            assert_stats(&tree, &format!("{name} after finalize"));
+        }
+    }
+
+    #[test]
+    fn leaf_list_of_loaded_trees_matches_a_walk_of_the_tree() {
+        let colors = pattern(40, 33);
+        for split in SPLITS {
+            let data = ImageData::from_rgb8(&colors).unwrap();
+            let mut tree = Tree::with_capacity_hint(data, 70).with_split(split);
+            refine(&mut tree, 70);
+            let stats = tree.stats();
+            for entropy_coded in [false, true] {
+                let mut saved = Vec::new();
+                tree.serialize(&mut saved, entropy_coded, None).unwrap();
+                let loaded = Tree::deserialize(saved.as_slice()).unwrap().tree;
+                let context = format!("{} entropy coded {entropy_coded}", split.name());
+                assert_leaf_list(&loaded, &context);
+                assert_stats(&loaded, &context);
+                assert_eq!(loaded.stats().max_depth, stats.max_depth, "{context}");
+                assert_eq!(loaded.stats().leaf_areas, stats.leaf_areas, "{context}");
+
+                let data = ImageData::from_rgb8(&colors).unwrap();
+                let mut resumed = loaded.resume(data, 20).unwrap();
+                assert_leaf_list(&resumed, &format!("{context} resumed"));
+                refine(&mut resumed, 20);
+                assert_leaf_list(&resumed, &format!("{context} refined after resume"));
+                assert_stats(&resumed, &format!("{context} refined after resume"));
+            }
+        }
+        // json only holds trees split through the middle
+        let data = ImageData::from_rgb8(&colors).unwrap();
+        let mut tree = Tree::with_capacity_hint(data, 70);
+        refine(&mut tree, 70);
+        let mut json = Vec::new();
+        tree.export_json(&mut json, false).unwrap();
+        let json = Json::parse(std::str::from_utf8(&json).unwrap()).unwrap();
+        let loaded = Tree::from_json(&json).unwrap();
+        assert_leaf_list(&loaded, "json");
+        assert_stats(&loaded, "json");
+    }
+
+    /// the bounds, depth and color of every leaf of `tree` in preorder, what
+    /// a .qt file keeps of it
+    fn saved_leaves<S: RegionSource>(tree: &Tree<S>) -> Vec<(Bounds, u32, RGB<u8>)> {
+        let snapshot = tree.snapshot();
+        snapshot
+            .leaves
+            .into_iter()
+            .map(|leaf| ((leaf.top_left, leaf.bottom_right), leaf.depth, leaf.color))
+            .collect()
+    }
+
+    /// check `saved`, a .qt file of `tree`, loads back as the same tree
+    fn assert_loads_back<S: RegionSource>(tree: &Tree<S>, saved: &[u8], context: &str) {
+        let loaded = Tree::deserialize(saved).unwrap();
+        assert_eq!(loaded.writer.as_deref(), Some(crate::version().as_str()));
+        assert!(loaded.residual.is_none(), "{context}");
+        let loaded = loaded.tree;
+        assert_eq!(loaded.split(), tree.split(), "{context}");
+        assert_eq!(loaded.splits(), tree.splits(), "{context}");
+        assert_eq!(saved_leaves(&loaded), saved_leaves(tree), "{context}");
+        for outline in [None, Some(RGB::new(0, 255, 0))] {
+            let render = tree.render_rgb(outline, 1.0);
+            assert_eq!(loaded.render_rgb(outline, 1.0), render, "{context}");
+        }
+    }
+
+    #[test]
+    fn packed_and_range_coded_files_round_trip() {
+        for split in SPLITS {
+            for (width, height) in [(1, 1), (40, 33), (7, 64)] {
+                let colors = pattern(width, height);
+                let data = ImageData::from_rgb8(&colors).unwrap();
+                let mut tree = Tree::with_capacity_hint(data, 200).with_split(split);
+                for splits in [0, 1, 30, 200] {
+                    refine(&mut tree, splits);
+                    let context = format!("{} {width}x{height} after {splits} more", split.name());
+                    let mut saved = Vec::new();
+                    tree.serialize(&mut saved, false, None).unwrap();
+                    assert_eq!(saved.len() as u64, tree.packed_size(), "{context}");
+                    assert_loads_back(&tree, &saved, &context);
+
+                    let mut saved = Vec::new();
+                    tree.serialize(&mut saved, true, None).unwrap();
+                    assert_loads_back(&tree, &saved, &format!("{context} range coded"));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn range_coding_shrinks_trees_of_photos() {
+        let photo = image::open(concat!(env!("CARGO_MANIFEST_DIR"), "/images/flower.jpg"))
+            .unwrap()
+            .thumbnail(240, 240)
+            .into_rgb8();
+        for (split, splits) in [(Split::Midpoint, 3000), (Split::Grid9, 1500)] {
+            let data = ImageData::from_rgb8(&photo).unwrap();
+            let mut tree = Tree::with_capacity_hint(data, splits).with_split(split);
+            refine(&mut tree, splits);
+            let (mut packed, mut range_coded) = (Vec::new(), Vec::new());
+            tree.serialize(&mut packed, false, None).unwrap();
+            tree.serialize(&mut range_coded, true, None).unwrap();
+            // about a third smaller
+            let ratio = range_coded.len() as f64 / packed.len() as f64;
+            assert!(ratio < 0.7, "{}: {ratio}", split.name());
+            assert_loads_back(&tree, &range_coded, split.name());
+        }
+    }
+
+    #[test]
+    fn residuals_give_back_the_original() {
+        // noise at the ends of the range makes the residual wrap around
+        let noise = RgbImage::from_fn(29, 17, |x, y| {
+            let v = (x * 7919 + y * 104729) % 5;
+            Rgb([
+                [0, 255, 1, 254, 128][v as usize],
+                (x * 9) as u8,
+                255 - (y * 15) as u8,
+            ])
+        });
+        for colors in [pattern(40, 33), noise] {
+            for split in SPLITS {
+                let data = ImageData::from_rgb8(&colors).unwrap();
+                let mut tree = Tree::with_capacity_hint(data, 50).with_split(split);
+                refine(&mut tree, 50);
+                for entropy_coded in [false, true] {
+                    let context = format!("{} range coded {entropy_coded}", split.name());
+                    let mut saved = Vec::new();
+                    tree.serialize(&mut saved, entropy_coded, Some(&colors))
+                        .unwrap();
+                    let loaded = Tree::deserialize(saved.as_slice()).unwrap();
+                    let mut render = loaded.tree.render_rgb(None, 1.0);
+                    assert_eq!(render, tree.render_rgb(None, 1.0), "{context}");
+                    assert_ne!(render, colors, "{context}");
+                    residual::apply(&mut render, &loaded.residual.unwrap());
+                    assert_eq!(render, colors, "{context}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn json_leaves_cover_the_image_once() {
+        let colors = pattern(40, 33);
+        for split in SPLITS {
+            let data = ImageData::from_rgb8(&colors).unwrap();
+            let mut tree = Tree::with_capacity_hint(data, 90).with_split(split);
+            refine(&mut tree, 90);
+            let mut parsed = Vec::new();
+            for pretty in [false, true] {
+                let context = format!("{} pretty {pretty}", split.name());
+                let mut out = Vec::new();
+                tree.export_json(&mut out, pretty).unwrap();
+                let text = String::from_utf8(out).unwrap();
+                assert_eq!(text.contains('\n'), pretty, "{context}");
+                let json = Json::parse(&text).unwrap();
+
+                let header = json.get("header").unwrap();
+                let number = |key| header.get(key).and_then(Json::as_u64);
+                assert_eq!(number("width"), Some(40), "{context}");
+                assert_eq!(number("height"), Some(33), "{context}");
+                assert_eq!(number("iterations"), Some(90), "{context}");
+                let name = header.get("split").and_then(Json::as_str);
+                assert_eq!(name, Some(split.name()), "{context}");
+
+                let leaves = json.get("leaves").and_then(Json::as_array).unwrap();
+                assert_eq!(leaves.len(), tree.leaf_count(), "{context}");
+                let mut covered = vec![0; 40 * 33];
+                let mut found = Vec::new();
+                for leaf in leaves {
+                    let field = |key| leaf.get(key).and_then(Json::as_u64).unwrap() as usize;
+                    let (x, y, w, h) = (field("x"), field("y"), field("w"), field("h"));
+                    for row in y..y + h {
+                        for col in x..x + w {
+                            covered[row * 40 + col] += 1;
+                        }
+                    }
+                    let color = leaf.get("color").and_then(Json::as_str).unwrap();
+                    let color = parse_hex(color.strip_prefix('#').unwrap()).unwrap();
+                    let bounds = ((y, x), (y + h - 1, x + w - 1));
+                    found.push((bounds, field("depth") as u32, color));
+                }
+                assert!(covered.iter().all(|&n| n == 1), "{context}");
+                assert_eq!(found, saved_leaves(&tree), "{context}");
+                parsed.push(json);
+            }
+            // the same values either way, only the whitespace differs
+            assert_eq!(parsed[0], parsed[1], "{}", split.name());
+        }
+    }
+
+    #[test]
+    fn pruning_merges_the_cheapest_splits_first() {
+        let colors = pattern(40, 33);
+        for split in SPLITS {
+            let name = split.name();
+            let refined = || {
+                let data = ImageData::from_rgb8(&colors).unwrap();
+                let mut tree = Tree::with_capacity_hint(data, 100).with_split(split);
+                refine(&mut tree, 100);
+                tree
+            };
+
+            // a single merge is never worse than taking back the last split,
+            // give or take the rounding of averages to whole colors
+            let (mut pruned, mut undone) = (refined(), refined());
+            assert_eq!(pruned.prune(1), 1, "{name}");
+            undone.undo(1);
+            let (merged, taken_back) = (pruned.mse().unwrap(), undone.mse().unwrap());
+            assert!(
+                merged <= taken_back * 1.001,
+                "{name}: {merged} > {taken_back}"
+            );
+
+            let mut tree = refined();
+            let mut mse = tree.mse().unwrap();
+            let mut leaves = tree.leaf_count();
+            for n in [1, 4, 15, 30] {
+                assert_eq!(tree.prune(n), n, "{name}");
+                assert_eq!(
+                    tree.leaf_count(),
+                    leaves - n * tree.leaves_per_split(),
+                    "{name}"
+                );
+                assert_leaf_list(&tree, &format!("{name} pruned {n}"));
+                // fewer leaves never fit the image better
+                let coarser = tree.mse().unwrap();
+                assert!(coarser >= mse, "{name} pruned {n}: {coarser} < {mse}");
+                (mse, leaves) = (coarser, tree.leaf_count());
+            }
+            // asking for more than there is merges everything
+            let left = tree.splits();
+            assert_eq!(tree.prune(1000), left, "{name}");
+            assert_eq!(tree.leaf_count(), 1, "{name}");
+            assert_eq!(tree.prune(1), 0, "{name}");
+            let c = rgb8(tree.nodes[0].average);
+            let render = tree.render_rgb(None, 1.0);
+            assert!(render.pixels().all(|p| p.0 == [c.r, c.g, c.b]), "{name}");
+        }
+    }
+
+    /// how many leaves `split` takes to get a tree of `colors` to `psnr`, or
+    /// None if it runs out of leaves to split first
+    fn leaves_to_reach(colors: &RgbImage, split: Split, psnr: f64) -> Option<usize> {
+        let data = ImageData::from_rgb8(colors).unwrap();
+        let mut tree = Tree::with_capacity_hint(data, 1000).with_split(split);
+        while tree.psnr().unwrap() < psnr {
+            if tree.refine().unwrap() == Refined::Exhausted {
+                return None;
+            }
+        }
+        Some(tree.leaf_count())
+    }
+
+    #[test]
+    fn adaptive_splits_find_off_center_edges_with_fewer_leaves() {
+        for edge in [24, 26, 40] {
+            let colors = RgbImage::from_fn(64, 64, |x, _| {
+                if x < edge {
+                    Rgb([20, 40, 60])
+                } else {
+                    Rgb([220, 200, 180])
+                }
+            });
+            let midpoint = leaves_to_reach(&colors, Split::Midpoint, 40.0).unwrap();
+            let adaptive = leaves_to_reach(&colors, Split::Adaptive, 40.0).unwrap();
+            assert!(
+                adaptive < midpoint,
+                "edge at {edge}: adaptive took {adaptive} leaves, midpoint {midpoint}"
+            );
+        }
+    }
+
+    #[test]
+    fn kd_splits_follow_horizontal_gradients_with_fewer_leaves() {
+        // the color only changes down the image, so only cuts across it help
+        let colors = RgbImage::from_fn(64, 64, |_, y| {
+            Rgb([(y * 4) as u8, (y * 2) as u8, 255 - (y * 3) as u8])
+        });
+        for (kd, quad) in [
+            (Split::Kd, Split::Midpoint),
+            (Split::KdAdaptive, Split::Adaptive),
+        ] {
+            let binary = leaves_to_reach(&colors, kd, 38.0).unwrap();
+            let quarters = leaves_to_reach(&colors, quad, 38.0).unwrap();
+            assert!(
+                binary < quarters,
+                "{} took {binary} leaves, {} {quarters}",
+                kd.name(),
+                quad.name()
+            );
+        }
+    }
+
+    /// the leaves of `tree` in the order of their bounds, which doesn't
+    /// depend on the order they were split in
+    fn sorted_leaves<S: RegionSource>(tree: &Tree<S>) -> Vec<(Bounds, u32, RGB<u8>)> {
+        let mut ret = saved_leaves(tree);
+        ret.sort_by_key(|&(bounds, ..)| bounds);
+        ret
+    }
+
+    /// check `tree` has the leaves and error of a fresh tree of `colors`
+    /// refined as many times, and goes on refining the same way
+    fn assert_like_fresh<S: RegionSource>(tree: &mut Tree<S>, colors: &RgbImage, context: &str) {
+        let data = ImageData::from_rgb8(colors).unwrap();
+        let mut fresh = Tree::with_capacity_hint(data, 200).with_split(tree.split());
+        refine(&mut fresh, tree.splits() as u32);
+        for step in ["", " refined again"] {
+            assert_eq!(
+                sorted_leaves(tree),
+                sorted_leaves(&fresh),
+                "{context}{step}"
+            );
+            assert_eq!(tree.mse(), fresh.mse(), "{context}{step}");
+            assert_eq!(
+                tree.render_rgb(None, 1.0),
+                fresh.render_rgb(None, 1.0),
+                "{context}{step}"
+            );
+            refine(tree, 20);
+            refine(&mut fresh, 20);
+        }
+    }
+
+    #[test]
+    fn undone_trees_match_fresh_ones() {
+        let colors = pattern(64, 48);
+        for split in SPLITS {
+            let name = split.name();
+            let data = ImageData::from_rgb8(&colors).unwrap();
+            let mut tree = Tree::with_capacity_hint(data, 200).with_split(split);
+            for (refines, undos) in [(40, 15), (10, 30), (25, 1), (0, 4), (60, 0)] {
+                refine(&mut tree, refines);
+                let splits = tree.splits();
+                assert_eq!(tree.undo(undos), undos, "{name}");
+                assert_eq!(tree.splits(), splits - undos, "{name}");
+                assert_like_fresh(&mut tree, &colors, &format!("{name} at {splits} splits"));
+            }
+            // only as many as there are
+            let splits = tree.splits();
+            assert_eq!(tree.undo(1000), splits, "{name}");
+            assert_eq!(tree.leaf_count(), 1, "{name}");
+            assert_eq!(tree.undo(1), 0, "{name}");
+            assert_like_fresh(&mut tree, &colors, &format!("{name} undone"));
+        }
+    }
+
+    #[test]
+    fn resumed_trees_match_a_straight_run() {
+        let colors = pattern(40, 33);
+        for split in SPLITS {
+            let data = ImageData::from_rgb8(&colors).unwrap();
+            let mut tree = Tree::with_capacity_hint(data, 100).with_split(split);
+            refine(&mut tree, 100);
+            for entropy_coded in [false, true] {
+                let context = format!("{} range coded {entropy_coded}", split.name());
+                let mut saved = Vec::new();
+                tree.serialize(&mut saved, entropy_coded, None).unwrap();
+                let loaded = Tree::deserialize(saved.as_slice()).unwrap().tree;
+                let data = ImageData::from_rgb8(&colors).unwrap();
+                let mut resumed = loaded.resume(data, 100).unwrap();
+                assert_eq!(resumed.undo(1), 0, "{context}");
+                refine(&mut resumed, 100);
+                assert_like_fresh(&mut resumed, &colors, &context);
+            }
+        }
+
+        let data = ImageData::from_rgb8(&pattern(40, 33)).unwrap();
+        let tree = Tree::with_capacity_hint(data, 0);
+        let other = ImageData::from_rgb8(&pattern(33, 40)).unwrap();
+        assert_eq!(
+            tree.resume(other, 10).err().unwrap(),
+            "the tree is of a 40x33 image but the image is 33x40"
+        );
+    }
+
+    /// the mse of the render of `tree` against `colors`, pixel by pixel
+    fn rendered_mse<S: RegionSource>(tree: &Tree<S>, colors: &RgbImage) -> f64 {
+        let render = tree.render_rgb(None, 1.0);
+        let squared: u64 = render
+            .iter()
+            .zip(colors.iter())
+            .map(|(&a, &b)| (a.abs_diff(b) as u64).pow(2))
+            .sum();
+        squared as f64 / colors.as_raw().len() as f64
+    }
+
+    #[test]
+    fn mse_matches_the_render_pixel_by_pixel() {
+        let noise = RgbImage::from_fn(23, 19, |x, y| {
+            let v = (x * 7919 + y * 104729) % 251;
+            Rgb([v as u8, (v * 3) as u8, 255 - (x * y) as u8])
+        });
+        for colors in [pattern(1, 1), pattern(7, 64), pattern(40, 33), noise] {
+            for split in SPLITS {
+                let context = format!("{} {:?}", split.name(), colors.dimensions());
+                let data = ImageData::from_rgb8(&colors).unwrap();
+                let mut tree = Tree::with_capacity_hint(data, 150).with_split(split);
+                for (step, splits) in [0, 1, 10, 50, 150].into_iter().enumerate() {
+                    refine(&mut tree, splits);
+                    let mse = tree.mse().unwrap();
+                    assert_eq!(mse, rendered_mse(&tree, &colors), "{context} step {step}");
+                }
+                tree.undo(30);
+                assert_eq!(
+                    tree.mse().unwrap(),
+                    rendered_mse(&tree, &colors),
+                    "{context} undone"
+                );
+                tree.prune(30);
+                assert_eq!(
+                    tree.mse().unwrap(),
+                    rendered_mse(&tree, &colors),
+                    "{context} pruned"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn psnr_follows_the_mse_until_finalized() {
+        let colors = pattern(40, 33);
+        let data = ImageData::from_rgb8(&colors).unwrap();
+        let mut tree = Tree::with_capacity_hint(data, 100);
+        let mut last = tree.psnr().unwrap();
+        for _ in 0..10 {
+            refine(&mut tree, 10);
+            let psnr = tree.psnr().unwrap();
+            assert_eq!(psnr, crate::image::psnr(rendered_mse(&tree, &colors)));
+            assert!(psnr >= last, "{psnr} after {last}");
+            last = psnr;
+        }
+        tree.finalize();
+        assert_eq!(tree.psnr(), None);
+
+        let flat = RgbImage::from_pixel(5, 3, Rgb([9, 8, 7]));
+        let tree = Tree::with_capacity_hint(ImageData::from_rgb8(&flat).unwrap(), 1);
+        assert_eq!(tree.psnr(), Some(f64::INFINITY));
+    }
+
+    #[test]
+    fn trees_refine_over_shared_data_on_two_threads() {
+        let colors = pattern(120, 90);
+        let data = Arc::new(ImageData::from_rgb8(&colors).unwrap());
+        let [midpoint, kd] = std::thread::scope(|scope| {
+            [Split::Midpoint, Split::KdAdaptive]
+                .map(|split| {
+                    let data = Arc::clone(&data);
+                    scope.spawn(move || {
+                        let mut tree = Tree::new_shared(data, 500).with_split(split);
+                        refine(&mut tree, 500);
+                        tree
+                    })
+                })
+                .map(|t| t.join().unwrap())
+        });
+        for (shared, split) in [(&midpoint, Split::Midpoint), (&kd, Split::KdAdaptive)] {
+            let data = ImageData::from_rgb8(&colors).unwrap();
+            let mut alone = Tree::new(data, 500).with_split(split);
+            refine(&mut alone, 500);
+            assert_eq!(
+                sorted_leaves(shared),
+                sorted_leaves(&alone),
+                "{}",
+                split.name()
+            );
+            assert_eq!(shared.mse(), alone.mse(), "{}", split.name());
+        }
+        assert_eq!(Arc::strong_count(&data), 3);
+        drop((midpoint, kd));
+        assert_eq!(Arc::strong_count(&data), 1);
+    }
+
+    #[test]
+    fn leaf_at_finds_the_one_leaf_covering_each_pixel() {
+        let (width, height) = (37, 23);
+        let colors = pattern(width as u32, height as u32);
+        for split in SPLITS {
+            let data = ImageData::from_rgb8(&colors).unwrap();
+            let mut tree = Tree::new(data, 120).with_split(split);
+            refine(&mut tree, 120);
+            let leaves = tree.snapshot().leaves;
+            for y in 0..height {
+                for x in 0..width {
+                    let context = format!("{} at {x},{y}", split.name());
+                    let covers = |leaf: &Leaf| {
+                        (leaf.top_left.0..=leaf.bottom_right.0).contains(&y)
+                            && (leaf.top_left.1..=leaf.bottom_right.1).contains(&x)
+                    };
+                    let found = tree.leaf_at(x, y).unwrap();
+                    assert!(covers(&found), "{context}: {found:?}");
+                    let covering: Vec<&Leaf> = leaves.iter().filter(|l| covers(l)).collect();
+                    assert_eq!(covering, [&found], "{context}");
+                }
+            }
+            for (x, y) in [(width, 0), (0, height), (width, height), (usize::MAX, 5)] {
+                assert_eq!(tree.leaf_at(x, y), None, "{} at {x},{y}", split.name());
+            }
+        }
+
+        let data = ImageData::from_rgb8(&colors).unwrap();
+        let tree = Tree::new(data, 0);
+        let root = tree.leaf_at(width - 1, height - 1).unwrap();
+        assert_eq!(
+            (root.top_left, root.bottom_right),
+            ((0, 0), (height - 1, width - 1))
+        );
+        assert_eq!(tree.leaf_at(width, height - 1), None);
+    }
+
+    #[test]
+    fn snapshots_dont_change_as_the_tree_goes_on() {
+        let colors = pattern(40, 33);
+        for split in SPLITS {
+            let data = ImageData::from_rgb8(&colors).unwrap();
+            let mut tree = Tree::new(data, 200).with_split(split);
+            refine(&mut tree, 50);
+            let snapshot = tree.snapshot();
+            let leaves = snapshot.leaves.clone();
+            let render = tree.render_rgb(None, 1.0);
+            let outlined = tree.render_rgb(Some(RGB::new(255, 0, 0)), 0.5);
+            assert_eq!(snapshot.render_rgb(None, 1.0), render, "{}", split.name());
+
+            refine(&mut tree, 150);
+            tree.undo(20);
+            tree.prune(40);
+            assert_ne!(tree.render_rgb(None, 1.0), render, "{}", split.name());
+            assert_eq!(snapshot.leaves, leaves, "{}", split.name());
+            assert_eq!(snapshot.render_rgb(None, 1.0), render, "{}", split.name());
+            assert_eq!(
+                snapshot.render_rgb(Some(RGB::new(255, 0, 0)), 0.5),
+                outlined,
+                "{}",
+                split.name()
+            );
+        }
+    }
+
+    #[test]
+    fn trees_diff_by_the_children_of_the_splits_they_dont_share() {
+        let colors = pattern(40, 33);
+        for split in SPLITS {
+            let tree = |splits| {
+                let data = ImageData::from_rgb8(&colors).unwrap();
+                let mut tree = Tree::new(data, splits).with_split(split);
+                refine(&mut tree, splits);
+                tree
+            };
+            let (fewer, more) = (tree(30), tree(31));
+            let context = split.name();
+
+            for diff in [fewer.diff(&fewer).unwrap(), fewer.diff(&tree(30)).unwrap()] {
+                assert!(diff.is_empty(), "{context}");
+                assert!(diff.only_in_other.is_empty(), "{context}");
+                assert_eq!(diff.differing_pixels(), 0, "{context}");
+            }
+
+            let diff = fewer.diff(&more).unwrap();
+            assert!(!diff.is_empty(), "{context}");
+            let [((top, left), (bottom, right))] = diff.only_in_self[..] else {
+                panic!("{context}: {:?}", diff.only_in_self);
+            };
+            let area = |((t, l), (b, r)): Bounds| (b - t + 1) * (r - l + 1);
+            let parent = ((top, left), (bottom, right));
+            assert_eq!(diff.differing_pixels(), area(parent) as u64, "{context}");
+            // exactly the children of the split, which tile their parent
+            let children = &diff.only_in_other;
+            assert_eq!(children.len(), more.leaves_per_split() + 1, "{context}");
+            assert_eq!(
+                children.iter().map(|&c| area(c)).sum::<usize>(),
+                area(parent)
+            );
+            for &((t, l), (b, r)) in children {
+                assert!(
+                    top <= t && left <= l && b <= bottom && r <= right,
+                    "{context}"
+                );
+            }
+
+            let back = more.diff(&fewer).unwrap();
+            assert_eq!(back.only_in_self, diff.only_in_other, "{context}");
+            assert_eq!(back.only_in_other, diff.only_in_self, "{context}");
+        }
     }
 }
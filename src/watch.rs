@@ -0,0 +1,136 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{error, interrupt, log::info};
+
+// -watch compresses again whenever an input changes. files are polled
+// rather than watched through the os, which needs no dependency and works
+// the same everywhere, and a poll every few hundred milliseconds costs
+// nothing next to compressing
+
+/// how often the inputs are looked at
+const POLL: Duration = Duration::from_millis(200);
+
+/// how long the inputs have to stay the same after a change before they are
+/// compressed again, so a file written in several goes is only read once
+/// it is whole
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// every file being watched, with when it was modified and its size. a
+/// file that can't be read has neither
+type Snapshot = Vec<(PathBuf, Option<(SystemTime, u64)>)>;
+
+/// the files `paths` stand for as they are now. directories stand for the
+/// files in them that aren't hidden, and those of their subdirectories too
+/// with `recursive`
+fn snapshot(paths: &[String], recursive: bool) -> Snapshot {
+    let mut ret = Vec::new();
+    for path in paths {
+        add(&mut ret, Path::new(path), recursive, true);
+    }
+    ret
+}
+
+fn add(snapshot: &mut Snapshot, path: &Path, recursive: bool, given: bool) {
+    if path.is_dir() {
+        if !given && !recursive {
+            return;
+        }
+        let Ok(entries) = fs::read_dir(path) else {
+            return;
+        };
+        let mut entries: Vec<PathBuf> = entries.filter_map(|e| Some(e.ok()?.path())).collect();
+        entries.sort();
+        for entry in entries {
+            let hidden = entry
+                .file_name()
+                .is_some_and(|n| n.to_string_lossy().starts_with('.'));
+            if !hidden {
+                add(snapshot, &entry, recursive, false);
+            }
+        }
+        return;
+    }
+    let stamp = fs::metadata(path)
+        .ok()
+        .and_then(|m| Some((m.modified().ok()?, m.len())));
+    snapshot.push((path.to_path_buf(), stamp));
+}
+
+/// tells when the inputs have changed and then stayed the same for
+/// `DEBOUNCE`. the time is passed in so it only depends on what it is given
+struct Debounce {
+    last: Snapshot,
+    changed_at: Option<Instant>,
+}
+
+impl Debounce {
+    fn new(snapshot: Snapshot) -> Self {
+        Self {
+            last: snapshot,
+            changed_at: None,
+        }
+    }
+
+    /// whether to compress again, given the inputs as they are at `now`
+    fn settled(&mut self, snapshot: Snapshot, now: Instant) -> bool {
+        if snapshot != self.last {
+            self.last = snapshot;
+            self.changed_at = Some(now);
+            return false;
+        }
+        match self.changed_at {
+            Some(at) if now.duration_since(at) >= DEBOUNCE => {
+                self.changed_at = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// the time of day in utc, for the line before each run
+fn clock() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+        % 86400;
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// call `run` once, then again each time the files `paths` stand for
+/// change, until ctrl-c. `run` is told whether it is running again and
+/// returns its exit code, which only ends the watch for a usage or output
+/// error on the first run, like outputs that exist without -force, since
+/// editing the input can't fix those. returns the code
+/// of a run ctrl-c stopped, 0 if it was pressed while waiting
+pub fn run(paths: &[String], recursive: bool, mut run: impl FnMut(bool) -> i32) -> i32 {
+    info!("[{} UTC] compressing", clock());
+    let code = run(false);
+    if [error::USAGE, error::OUTPUT].contains(&code) || interrupt::requested() {
+        return code;
+    }
+    info!("watching for changes, ctrl-c to stop");
+    // outputs written next to the inputs are part of the snapshot taken
+    // after a run, so writing them doesn't count as a change
+    let mut debounce = Debounce::new(snapshot(paths, recursive));
+    loop {
+        thread::sleep(POLL);
+        if interrupt::requested() {
+            return 0;
+        }
+        if !debounce.settled(snapshot(paths, recursive), Instant::now()) {
+            continue;
+        }
+        info!("[{} UTC] inputs changed, compressing again", clock());
+        let code = run(true);
+        if interrupt::requested() {
+            return code;
+        }
+        debounce = Debounce::new(snapshot(paths, recursive));
+    }
+}
@@ -0,0 +1,791 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+use image::{Rgb, RgbImage};
+
+/// a directory of its own in the temp directory for a test, removed when
+/// dropped
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("comprs-cli-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    fn path(&self, name: &str) -> String {
+        self.0.join(name).to_str().unwrap().to_string()
+    }
+
+    /// a width x height png whose pixels aren't all the same
+    fn image(&self, name: &str, width: u32, height: u32) -> String {
+        let path = self.path(name);
+        RgbImage::from_fn(width, height, |x, y| {
+            Rgb([(x * 37 + y * 11) as u8, (x * y) as u8, (200 + x - y) as u8])
+        })
+        .save(&path)
+        .unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn comprs(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_comprs"))
+        .args(args)
+        .env_clear()
+        .output()
+        .unwrap()
+}
+
+fn stderr(out: &Output) -> String {
+    String::from_utf8_lossy(&out.stderr).into_owned()
+}
+
+#[test]
+fn huge_iteration_counts_on_tiny_images_dont_abort() {
+    let dir = TempDir::new("huge-iter");
+    let input = dir.image("tiny.png", 4, 4);
+    let output = dir.path("out.png");
+    for split in ["midpoint", "kd", "grid9"] {
+        let out = comprs(&[
+            "compress",
+            &input,
+            "-iter",
+            "2000000000",
+            "-split",
+            split,
+            "-o",
+            &output,
+            "-force",
+        ]);
+        assert_eq!(out.status.code(), Some(0), "{split}: {}", stderr(&out));
+        assert!(Path::new(&output).exists());
+    }
+}
+
+/// a .qt file of a small image refined a few times, saved in `dir`
+fn tree(dir: &TempDir, name: &str, iterations: &str) -> String {
+    let input = dir.image(&format!("{name}.png"), 16, 16);
+    let tree = dir.path(&format!("{name}.qt"));
+    let out = comprs(&[
+        "compress",
+        &input,
+        "-iter",
+        iterations,
+        "-o",
+        &dir.path(&format!("{name}-render.png")),
+        "-encode",
+        &tree,
+    ]);
+    assert_eq!(out.status.code(), Some(0), "{}", stderr(&out));
+    tree
+}
+
+#[test]
+fn rendering_trees_refuses_to_overwrite_without_force() {
+    let dir = TempDir::new("overwrite");
+    let a = tree(&dir, "a", "5");
+    let b = tree(&dir, "b", "9");
+    let input = dir.image("in.png", 16, 16);
+    let output = dir.path("out.png");
+    let commands: [&[&str]; 5] = [
+        &["compress", &input, "-iter", "5", "-o", &output],
+        &["decode", &a, "-o", &output],
+        &["restyle", &a, "-outline", "FF0000", "-o", &output],
+        &["diff", &a, &b, "-o", &output],
+        &["-decode", &a, "-o", &output],
+    ];
+    for args in commands {
+        fs::write(&output, "keep me").unwrap();
+        let out = comprs(args);
+        assert_eq!(out.status.code(), Some(5), "{args:?}");
+        assert!(
+            stderr(&out).contains("already exists, use -force to overwrite it"),
+            "{args:?}: {}",
+            stderr(&out)
+        );
+        assert_eq!(fs::read(&output).unwrap(), b"keep me", "{args:?}");
+
+        for force in ["-force", "--force"] {
+            fs::write(&output, "keep me").unwrap();
+            let out = comprs(&[args, &[force]].concat());
+            assert_eq!(out.status.code(), Some(0), "{args:?}: {}", stderr(&out));
+            assert!(image::open(&output).is_ok(), "{args:?} {force}");
+        }
+    }
+}
+
+#[test]
+fn rendering_trees_never_overwrites_the_tree() {
+    let dir = TempDir::new("overwrite-input");
+    let input = dir.image("in.png", 16, 16);
+    // -export-json takes any name, so a tree can look like an image
+    let a = dir.path("a.png");
+    let out = comprs(&[
+        "compress",
+        &input,
+        "-iter",
+        "5",
+        "-o",
+        &dir.path("render.png"),
+        "-export-json",
+        &a,
+    ]);
+    assert_eq!(out.status.code(), Some(0), "{}", stderr(&out));
+    let b = tree(&dir, "b", "9");
+    let saved = fs::read(&a).unwrap();
+    let commands: [&[&str]; 4] = [
+        &["decode", &a, "-o", &a, "-force"],
+        &["restyle", &a, "-scale", "0.5", "-o", &a, "-force"],
+        &["diff", &b, &a, "-o", &a, "-force"],
+        &["-decode", &a, "-o", &a, "-force"],
+    ];
+    for args in commands {
+        let out = comprs(args);
+        assert_eq!(out.status.code(), Some(5), "{args:?}");
+        assert!(
+            stderr(&out).contains("is the input file"),
+            "{args:?}: {}",
+            stderr(&out)
+        );
+        assert_eq!(fs::read(&a).unwrap(), saved, "{args:?}");
+    }
+}
+
+#[test]
+fn misused_flags_are_usage_errors() {
+    let dir = TempDir::new("misuse");
+    let input = dir.image("in.png", 16, 16);
+    let output = dir.path("out.png");
+    let tree = tree(&dir, "a", "5");
+    let cases: [(&[&str], &str); 4] = [
+        (
+            &["compress", &input, "-iters", "500", "-o", &output],
+            "unknown flag `-iters`, did you mean -iter?",
+        ),
+        (
+            &["compress", &input, "--iteratons", "5", "-o", &output],
+            "unknown flag `--iteratons`, did you mean --iterations?",
+        ),
+        (
+            &["compress", &input, "-xyzzy", "-iter", "5", "-o", &output],
+            "unknown flag `-xyzzy`",
+        ),
+        (
+            &["decode", &tree, "-iter", "5", "-o", &output],
+            "decode doesn't take -iter",
+        ),
+    ];
+    for (args, reason) in cases {
+        let out = comprs(args);
+        let err = stderr(&out);
+        assert_eq!(out.status.code(), Some(2), "{args:?}: {err}");
+        assert!(err.contains(reason), "{args:?}: {err}");
+        assert!(err.contains("usage:"), "{args:?}: {err}");
+    }
+    assert!(!Path::new(&output).exists());
+}
+
+#[test]
+fn long_flags_do_what_short_ones_do() {
+    let dir = TempDir::new("long-flags");
+    let input = dir.image("in.png", 16, 16);
+    let (short, long) = (dir.path("short.png"), dir.path("long.png"));
+    for args in [
+        ["-iter", "30", "-o", &short, "-outline", "00FF00"],
+        [
+            "--iterations",
+            "30",
+            "--output",
+            &long,
+            "--outline",
+            "00FF00",
+        ],
+    ] {
+        let out = comprs(&[&["compress", &input][..], &args].concat());
+        assert_eq!(out.status.code(), Some(0), "{args:?}: {}", stderr(&out));
+    }
+    assert_eq!(pixels(&long), pixels(&short));
+}
+
+#[test]
+fn help_lists_the_flags_of_a_command_by_group() {
+    for help in ["-h", "--help"] {
+        let out = comprs(&["compress", help]);
+        assert_eq!(out.status.code(), Some(0), "{help}: {}", stderr(&out));
+        let text = String::from_utf8(out.stdout).unwrap();
+        for line in ["refining:", "trees:", "-iter, --iterations iterations"] {
+            assert!(text.contains(line), "{help} has no `{line}`: {text}");
+        }
+        // compressing doesn't animate
+        assert!(!text.contains("animation:"), "{help}: {text}");
+        assert!(!text.contains("-fps"), "{help}: {text}");
+    }
+}
+
+#[test]
+fn missing_output_directories_fail_before_refining() {
+    let dir = TempDir::new("output-dirs");
+    let input = dir.image("in.png", 16, 16);
+    let missing = dir.path("missing");
+    let file = dir.image("file.png", 2, 2);
+    let in_missing = format!("{missing}/out.png");
+    let in_file = format!("{file}/out.png");
+    let tree = tree(&dir, "a", "5");
+    let no_dir = format!("directory `{missing}` doesn't exist");
+    let not_dir = format!("`{file}` isn't a directory");
+    let cases: [(&[&str], &str); 7] = [
+        (
+            &["compress", &input, "-iter", "5", "-o", &in_missing],
+            &no_dir,
+        ),
+        (
+            &["compress", &input, "-iter", "5", "-o", &in_file],
+            &not_dir,
+        ),
+        (
+            &[
+                "compress",
+                &input,
+                "-iter",
+                "5",
+                "-o",
+                &in_missing,
+                "-dry-run",
+            ],
+            &no_dir,
+        ),
+        (
+            &[
+                "compress",
+                &input,
+                "-iter",
+                "5",
+                "-encode",
+                &format!("{missing}/t.qt"),
+            ],
+            &no_dir,
+        ),
+        (
+            &["compress", &input, "-iter", "5", "-out-dir", &file],
+            &not_dir,
+        ),
+        (&["decode", &tree, "-o", &in_missing], &no_dir),
+        (&["diff", &tree, &tree, "-o", &in_missing], &no_dir),
+    ];
+    for (args, reason) in cases {
+        let out = comprs(args);
+        let err = stderr(&out);
+        assert_eq!(out.status.code(), Some(5), "{args:?}: {err}");
+        assert!(err.contains(reason), "{args:?}: {err}");
+    }
+    assert!(!Path::new(&missing).exists());
+    assert!(!Path::new(&dir.path("in-comprs.png")).exists());
+}
+
+#[test]
+fn frames_dir_errors_name_the_directory() {
+    let dir = TempDir::new("frames-dir");
+    let input = dir.image("in.png", 16, 16);
+    let file = dir.image("file.png", 2, 2);
+    let out = comprs(&[
+        "animate",
+        &input,
+        "-iter",
+        "5",
+        "-gif",
+        "1",
+        "-frames-dir",
+        &file,
+        "-dry-run",
+    ]);
+    let err = stderr(&out);
+    assert_eq!(out.status.code(), Some(5), "{err}");
+    assert!(err.contains(&format!("`{file}`")), "{err}");
+}
+
+#[test]
+fn missing_inputs_are_named() {
+    let dir = TempDir::new("missing-input");
+    let input = dir.path("nope.png");
+    for args in [
+        &["compress", &input, "-iter", "5"][..],
+        &["compress", &input, "-iter", "5", "-dry-run"],
+    ] {
+        let out = comprs(args);
+        let err = stderr(&out);
+        assert_eq!(out.status.code(), Some(3), "{args:?}: {err}");
+        assert!(
+            err.contains(&format!("unable to open image `{input}`")),
+            "{args:?}: {err}"
+        );
+    }
+}
+
+#[test]
+fn each_kind_of_failure_has_its_own_exit_code() {
+    let dir = TempDir::new("exit-codes");
+    let input = dir.image("in.png", 16, 16);
+    let (missing, garbage) = (dir.path("nope.png"), dir.path("garbage.png"));
+    fs::write(&garbage, "not an image").unwrap();
+    let (output, in_missing) = (dir.path("out.png"), dir.path("missing/out.png"));
+    let cases: [(&[&str], i32); 6] = [
+        (&[&input, "-iter", "5", "-o", &output], 0),
+        // running out of splits isn't a failure
+        (&[&input, "-iter", "100000", "-o", &output], 0),
+        (&[&input, "-iter", "lots", "-o", &output], 2),
+        (&[&missing, "-iter", "5", "-o", &output], 3),
+        (&[&garbage, "-iter", "5", "-o", &output], 4),
+        (&[&input, "-iter", "5", "-o", &in_missing], 5),
+    ];
+    for (args, code) in cases {
+        let out = comprs(&[&["compress"], args, &["-force"]].concat());
+        assert_eq!(out.status.code(), Some(code), "{args:?}: {}", stderr(&out));
+    }
+}
+
+#[test]
+fn multi_page_tiff_warnings_stay_off_stdout() {
+    use tiff::encoder::{colortype::RGB8, TiffEncoder};
+
+    let dir = TempDir::new("tiff-pages");
+    let input = dir.path("pages.tiff");
+    let mut encoder = TiffEncoder::new(fs::File::create(&input).unwrap()).unwrap();
+    for page in [[10; 4 * 4 * 3], [200; 4 * 4 * 3]] {
+        encoder.write_image::<RGB8>(4, 4, &page).unwrap();
+    }
+    drop(encoder);
+    let out = comprs(&[
+        "compress",
+        &input,
+        "-iter",
+        "3",
+        "-o",
+        &dir.path("out.png"),
+        "-stats",
+    ]);
+    let err = stderr(&out);
+    assert_eq!(out.status.code(), Some(0), "{err}");
+    assert!(
+        err.contains("warning: tiff has multiple pages, only the first page is used"),
+        "{err}"
+    );
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.contains("tree/raw rgb"), "{stdout}");
+    assert!(!stdout.contains("pages"), "{stdout}");
+}
+
+/// the delay of every frame of the gif at `path` in hundredths of a second,
+/// and how often it repeats
+fn gif_frames(path: &str) -> (Vec<u16>, gif::Repeat) {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options.read_info(fs::File::open(path).unwrap()).unwrap();
+    let mut delays = Vec::new();
+    while let Some(frame) = decoder.read_next_frame().unwrap() {
+        delays.push(frame.delay);
+    }
+    (delays, decoder.repeat())
+}
+
+/// the frames of a gif of 20 iterations captured every 5, made with `flags`
+fn animate(dir: &TempDir, flags: &[&str]) -> (Vec<u16>, gif::Repeat) {
+    let input = dir.image("in.png", 16, 16);
+    let output = dir.path("out.gif");
+    let args = [
+        "animate", &input, "-iter", "20", "-gif", "5", "-o", &output, "-force",
+    ];
+    let out = comprs(&[&args, flags].concat());
+    assert_eq!(out.status.code(), Some(0), "{flags:?}: {}", stderr(&out));
+    gif_frames(&output)
+}
+
+#[test]
+fn fps_sets_the_delay_of_every_frame() {
+    let dir = TempDir::new("gif-fps");
+    for (fps, delay) in [("20", 5), ("4", 25), ("100", 1)] {
+        let (delays, _) = animate(&dir, &["-fps", fps]);
+        // iterations 0, 5, 10, 15 and 20, the last one held
+        assert_eq!(delays.len(), 5, "{fps}");
+        assert_eq!(delays[..4], [delay; 4], "{fps}");
+    }
+    // without -fps frames follow each other with no delay
+    let (delays, _) = animate(&dir, &[]);
+    assert_eq!(delays[..4], [0; 4]);
+
+    let input = dir.image("in.png", 16, 16);
+    let out = comprs(&["compress", &input, "-iter", "5", "-fps", "10"]);
+    let err = stderr(&out);
+    assert_eq!(out.status.code(), Some(2), "{err}");
+    assert!(err.contains("compress doesn't take -fps"), "{err}");
+}
+
+#[test]
+fn hold_last_lengthens_only_the_last_frame() {
+    let dir = TempDir::new("gif-hold");
+    let cases: [(&[&str], [u16; 5]); 3] = [
+        (&["-fps", "10"], [10, 10, 10, 10, 200]),
+        (&["-fps", "10", "-hold-last", "1500"], [10, 10, 10, 10, 150]),
+        (&["-hold-last", "0", "-fps", "20"], [5, 5, 5, 5, 0]),
+    ];
+    for (flags, expected) in cases {
+        let (delays, _) = animate(&dir, flags);
+        assert_eq!(delays, expected, "{flags:?}");
+    }
+}
+
+#[test]
+fn loop_sets_the_netscape_repeat_count() {
+    use gif::Repeat;
+
+    let dir = TempDir::new("gif-loop");
+    // the extension counts repeats after the first play, 1 leaves it out
+    for (loops, repeat) in [
+        ("0", Repeat::Infinite),
+        ("1", Repeat::Finite(0)),
+        ("3", Repeat::Finite(2)),
+        ("65536", Repeat::Finite(65535)),
+    ] {
+        let (delays, found) = animate(&dir, &["-loop", loops]);
+        assert_eq!(found, repeat, "-loop {loops}");
+        assert_eq!(delays.len(), 5, "-loop {loops}");
+    }
+
+    let input = dir.image("in.png", 16, 16);
+    for loops in ["65537", "-1", "forever"] {
+        let out = comprs(&["animate", &input, "-iter", "5", "-gif", "1", "-loop", loops]);
+        assert_eq!(
+            out.status.code(),
+            Some(2),
+            "-loop {loops}: {}",
+            stderr(&out)
+        );
+    }
+    let out = comprs(&["compress", &input, "-iter", "5", "-loop", "2"]);
+    let err = stderr(&out);
+    assert_eq!(out.status.code(), Some(2), "{err}");
+    assert!(err.contains("compress doesn't take -loop"), "{err}");
+}
+
+fn pixels(path: &str) -> RgbImage {
+    image::open(path).unwrap().into_rgb8()
+}
+
+#[test]
+fn decoded_trees_match_the_direct_render() {
+    let dir = TempDir::new("decode-round-trip");
+    let input = dir.image("in.png", 40, 33);
+    let tree = dir.path("t.qt");
+    for (split, outline) in [("midpoint", None), ("kd-adaptive", Some("00FF00"))] {
+        let render = dir.path("render.png");
+        let mut args = vec![
+            "compress", &input, "-iter", "60", "-split", split, "-o", &render, "-encode", &tree,
+            "-force",
+        ];
+        args.extend(outline.iter().flat_map(|o| ["-outline", o]));
+        let out = comprs(&args);
+        assert_eq!(out.status.code(), Some(0), "{split}: {}", stderr(&out));
+
+        let decoded = dir.path("decoded.png");
+        let mut args = vec!["decode", &tree, "-o", &decoded, "-force"];
+        args.extend(outline.iter().flat_map(|o| ["-outline", o]));
+        let out = comprs(&args);
+        assert_eq!(out.status.code(), Some(0), "{split}: {}", stderr(&out));
+        assert_eq!(pixels(&decoded), pixels(&render), "{split}");
+
+        let out = comprs(&["decode", &tree, "-o", &decoded, "-force", "-scale", "0.5"]);
+        assert_eq!(out.status.code(), Some(0), "{split}: {}", stderr(&out));
+        assert_eq!(pixels(&decoded).dimensions(), (20, 17), "{split}");
+    }
+}
+
+#[test]
+fn lossless_trees_decode_to_the_original() {
+    let dir = TempDir::new("decode-lossless");
+    let input = dir.image("in.png", 40, 33);
+    let (tree, render) = (dir.path("t.qt"), dir.path("render.png"));
+    for (split, entropy) in [("midpoint", false), ("kd-adaptive", true)] {
+        let mut args = vec![
+            "compress",
+            &input,
+            "-iter",
+            "30",
+            "-split",
+            split,
+            "-o",
+            &render,
+            "-encode",
+            &tree,
+            "-encode-lossless",
+            "-force",
+        ];
+        args.extend(entropy.then_some("-encode-entropy"));
+        let out = comprs(&args);
+        assert_eq!(out.status.code(), Some(0), "{split}: {}", stderr(&out));
+        assert_ne!(pixels(&render), pixels(&input), "{split}");
+
+        let decoded = dir.path("decoded.png");
+        let out = comprs(&["decode", &tree, "-o", &decoded, "-force"]);
+        assert_eq!(out.status.code(), Some(0), "{split}: {}", stderr(&out));
+        assert_eq!(pixels(&decoded), pixels(&input), "{split}");
+
+        // the residual only fits a plain full size render
+        let out = comprs(&[
+            "decode", &tree, "-o", &decoded, "-force", "-outline", "00FF00",
+        ]);
+        assert_eq!(out.status.code(), Some(0), "{split}: {}", stderr(&out));
+        assert_ne!(pixels(&decoded), pixels(&input), "{split}");
+    }
+}
+
+#[test]
+fn resumed_runs_save_the_tree_of_a_straight_run() {
+    let dir = TempDir::new("resume");
+    let input = dir.image("in.png", 40, 33);
+    let (half, resumed, straight) = (
+        dir.path("half.qt"),
+        dir.path("resumed.qt"),
+        dir.path("straight.qt"),
+    );
+    let render = dir.path("render.png");
+    let compress = |extra: &[&str]| {
+        let mut args = vec!["compress", &input, "-o", &render, "-force"];
+        args.extend(extra);
+        comprs(&args)
+    };
+    for (iter, extra) in [
+        ("100", vec!["-encode", &half]),
+        ("100", vec!["-resume", &half, "-encode", &resumed]),
+        ("200", vec!["-encode", &straight]),
+    ] {
+        let mut args = vec!["-iter", iter];
+        args.extend(extra);
+        let out = compress(&args);
+        assert_eq!(out.status.code(), Some(0), "{args:?}: {}", stderr(&out));
+    }
+    assert_eq!(fs::read(&resumed).unwrap(), fs::read(&straight).unwrap());
+
+    let lossless = dir.path("lossless.qt");
+    let out = compress(&["-iter", "10", "-encode", &lossless, "-encode-lossless"]);
+    assert_eq!(out.status.code(), Some(0), "{}", stderr(&out));
+    let out = compress(&["-iter", "10", "-resume", &lossless]);
+    assert_eq!(out.status.code(), Some(2));
+    assert!(
+        stderr(&out).contains("saved with -encode-lossless"),
+        "{}",
+        stderr(&out)
+    );
+
+    let other = dir.image("other.png", 33, 40);
+    let out = comprs(&[
+        "compress", &other, "-o", &render, "-force", "-iter", "10", "-resume", &half,
+    ]);
+    assert_eq!(out.status.code(), Some(2));
+    assert!(
+        stderr(&out).contains("the tree is of a 40x33 image but the image is 33x40"),
+        "{}",
+        stderr(&out)
+    );
+}
+
+#[test]
+fn restyled_trees_match_a_render_with_the_same_style() {
+    let dir = TempDir::new("restyle");
+    let input = dir.image("in.png", 40, 33);
+    let (qt, json) = (dir.path("t.qt"), dir.path("t.json"));
+    let out = comprs(&[
+        "compress",
+        &input,
+        "-iter",
+        "60",
+        "-o",
+        &dir.path("plain.png"),
+        "-encode",
+        &qt,
+        "-export-json",
+        &json,
+    ]);
+    assert_eq!(out.status.code(), Some(0), "{}", stderr(&out));
+    let outlined = dir.path("outlined.png");
+    let out = comprs(&[
+        "compress", &input, "-iter", "60", "-o", &outlined, "-outline", "FF00FF",
+    ]);
+    assert_eq!(out.status.code(), Some(0), "{}", stderr(&out));
+
+    let restyled = dir.path("restyled.png");
+    for tree in [&qt, &json] {
+        let out = comprs(&[
+            "restyle", tree, "-outline", "FF00FF", "-o", &restyled, "-force",
+        ]);
+        assert_eq!(out.status.code(), Some(0), "{tree}: {}", stderr(&out));
+        assert_eq!(pixels(&restyled), pixels(&outlined), "{tree}");
+
+        let out = comprs(&["restyle", tree, "-scale", "0.25", "-o", &restyled, "-force"]);
+        assert_eq!(out.status.code(), Some(0), "{tree}: {}", stderr(&out));
+        assert_eq!(pixels(&restyled).dimensions(), (10, 8), "{tree}");
+
+        let out = comprs(&["restyle", tree, "-o", &restyled, "-force"]);
+        let err = stderr(&out);
+        assert_eq!(out.status.code(), Some(2), "{tree}: {err}");
+        assert!(err.contains("restyle needs -outline or -scale"), "{err}");
+    }
+}
+
+#[test]
+fn damaged_trees_are_decode_errors() {
+    let dir = TempDir::new("decode-damaged");
+    let tree = tree(&dir, "a", "20");
+    let saved = fs::read(&tree).unwrap();
+    let damaged = dir.path("damaged.qt");
+    let mut flipped = saved.clone();
+    *flipped.last_mut().unwrap() ^= 1;
+    let mut newer = saved.clone();
+    newer[4..6].copy_from_slice(&9u16.to_le_bytes());
+    let cases: [(&[u8], &str); 5] = [
+        (&saved[..saved.len() - 1], "tree file is truncated"),
+        (&saved[..10], "tree file is truncated"),
+        (&[0xff, 0xfe, 0], "tree file is neither a .qt file nor json"),
+        (&flipped, "tree file is corrupt, its checksum is"),
+        (&newer, "tree file is version 9, which needs a newer comprs"),
+    ];
+    for (data, reason) in cases {
+        fs::write(&damaged, data).unwrap();
+        let out = comprs(&["decode", &damaged, "-o", &dir.path("out.png")]);
+        let err = stderr(&out);
+        assert_eq!(out.status.code(), Some(4), "{reason}: {err}");
+        assert!(err.contains(reason), "{reason}: {err}");
+        assert!(!Path::new(&dir.path("out.png")).exists(), "{reason}");
+    }
+}
+
+#[test]
+fn trees_too_large_to_render_are_decode_errors() {
+    let dir = TempDir::new("huge-tree");
+    let tree = dir.path("huge.qt");
+    let header = comprs::qt::Header {
+        flags: 0,
+        width: 60000,
+        height: 60000,
+        splits: 0,
+    };
+    let mut data = Vec::new();
+    comprs::qt::write(&mut data, &header, &[0, 10, 20, 30], &[]).unwrap();
+    fs::write(&tree, data).unwrap();
+    let out = comprs(&["decode", &tree, "-o", &dir.path("out.png")]);
+    let err = stderr(&out);
+    assert_eq!(out.status.code(), Some(4), "{err}");
+    assert!(
+        err.contains("60000x60000 is larger than any image comprs can compress"),
+        "{err}"
+    );
+}
+
+#[test]
+fn stats_show_small_tree_ratios() {
+    let dir = TempDir::new("stats-ratio");
+    let input = dir.image("in.png", 256, 128);
+    let out = comprs(&[
+        "compress",
+        &input,
+        "-iter",
+        "3",
+        "-o",
+        &dir.path("out.png"),
+        "-stats",
+    ]);
+    assert_eq!(out.status.code(), Some(0), "{}", stderr(&out));
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    let line = stdout
+        .lines()
+        .find(|l| l.starts_with("tree/raw rgb"))
+        .unwrap();
+    let percent: f64 = line
+        .split_whitespace()
+        .last()
+        .unwrap()
+        .trim_end_matches('%')
+        .parse()
+        .unwrap();
+    // 10 leaves of 3 bytes and a few bytes of header against 98304
+    assert!(percent > 0.0 && percent < 0.1, "{line}");
+}
+
+#[test]
+fn refining_stops_once_the_target_psnr_is_reached() {
+    let dir = TempDir::new("target-psnr");
+    let input = dir.image("in.png", 64, 64);
+    let output = dir.path("out.png");
+    let curve = dir.path("curve.csv");
+    let out = comprs(&[
+        "compress",
+        &input,
+        "-iter",
+        "100000",
+        "-target-psnr",
+        "15",
+        "-o",
+        &output,
+        "-error-curve",
+        &curve,
+        "-porcelain",
+    ]);
+    assert_eq!(out.status.code(), Some(0), "{}", stderr(&out));
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    let stopped = stdout
+        .lines()
+        .find(|l| l.starts_with("event=stopped "))
+        .unwrap_or_else(|| panic!("no stopped record in {stdout}"));
+    assert!(stopped.contains(" reason=target"), "{stopped}");
+
+    // iteration,leaves,mse,psnr for every iteration, the last where it stopped
+    let rows: Vec<(u32, f64)> = fs::read_to_string(&curve)
+        .unwrap()
+        .lines()
+        .skip(1)
+        .map(|l| {
+            let fields: Vec<&str> = l.split(',').collect();
+            (fields[0].parse().unwrap(), fields[3].parse().unwrap())
+        })
+        .collect();
+    let &(done, psnr) = rows.last().unwrap();
+    assert!(done > 0 && done < 100000, "{done}");
+    assert!(stopped.contains(&format!(" done={done} ")), "{stopped}");
+    assert!(psnr >= 15.0, "{psnr}");
+    assert!(rows[rows.len() - 2].1 < 15.0, "{rows:?}");
+
+    let (render, original) = (pixels(&output), pixels(&input));
+    let squared: u64 = render
+        .iter()
+        .zip(original.iter())
+        .map(|(&a, &b)| (a.abs_diff(b) as u64).pow(2))
+        .sum();
+    let mse = squared as f64 / original.as_raw().len() as f64;
+    assert!(10.0 * (255.0 * 255.0 / mse).log10() >= 15.0, "{mse}");
+
+    for target in ["0", "-3", "inf", "lots"] {
+        let out = comprs(&[
+            "compress",
+            &input,
+            "-iter",
+            "5",
+            "-target-psnr",
+            target,
+            "-o",
+            &output,
+            "-force",
+        ]);
+        assert_eq!(out.status.code(), Some(2), "{target}: {}", stderr(&out));
+        assert!(stderr(&out).contains("invalid target psnr"), "{target}");
+    }
+}